@@ -1,57 +1,236 @@
-#include <iostream>
+// mkfs: make the file system of `kernel/src/fs/filesystem.rs` on a disk image
+// and copy the given files into its root directory.
+// The layout constants must be kept in sync with the kernel
+#include <cstdint>
+#include <cstring>
 #include <fstream>
+#include <iostream>
 #include <string>
 #include <vector>
 using namespace std;
 
-const int header_size = 1024 * 1024;
-const int page_size = 1024 * 4;
+const uint32_t BLOCK_SIZE = 512;
+const uint32_t FS_MAGIC = 0x10203040;
+const uint32_t SUPERBLOCK_NO = 1;
+const uint32_t ROOT_INUM = 1;
+const uint32_t N_INODES = 200;
 
-inline unsigned align_val(unsigned val) {
-    unsigned o = page_size - 1;
-    return (val + o) & ~o;
-}
+const uint32_t N_DIRECT = 11;
+const uint32_t N_INDIRECT = BLOCK_SIZE / sizeof(uint32_t);
+const uint32_t DIR_NAME_LEN = 14;
+
+const uint16_t T_DIR = 1;
+const uint16_t T_FILE = 2;
 
-struct File {
-    ssize_t offset;
-    ssize_t size;
-    string name;
+struct SuperBlock {
+    uint32_t magic;
+    uint32_t size;
+    uint32_t n_blocks;
+    uint32_t n_inodes;
+    uint32_t inode_start;
+    uint32_t bitmap_start;
 };
 
-int main(int argc, char** argv) {
-    cout << "Making simple filesystem..." << endl;
+struct DiskInode {
+    uint16_t type;
+    uint16_t major;
+    uint16_t minor;
+    uint16_t nlink;
+    uint32_t size;
+    uint32_t addrs[N_DIRECT + 2];
+};
+
+struct DirEntry {
+    uint16_t inum;
+    char name[DIR_NAME_LEN];
+};
+
+const uint32_t INODES_PER_BLOCK = BLOCK_SIZE / sizeof(DiskInode);
+const uint32_t BITS_PER_BLOCK = BLOCK_SIZE * 8;
+
+fstream hdd;
+SuperBlock sb;
+uint32_t next_free_block; // data blocks are handed out in order
+uint32_t next_free_inum = ROOT_INUM;
+
+void read_block(uint32_t block_no, uint8_t *buf) {
+    hdd.seekg((streamoff)block_no * BLOCK_SIZE);
+    hdd.read((char *)buf, BLOCK_SIZE);
+}
+
+void write_block(uint32_t block_no, const uint8_t *buf) {
+    hdd.seekp((streamoff)block_no * BLOCK_SIZE);
+    hdd.write((const char *)buf, BLOCK_SIZE);
+}
+
+uint32_t alloc_block() {
+    if (next_free_block >= sb.size) {
+        cerr << "disk full" << endl;
+        exit(1);
+    }
+    uint8_t zeroed[BLOCK_SIZE] = {0};
+    write_block(next_free_block, zeroed);
+    return next_free_block++;
+}
+
+void read_inode(uint32_t inum, DiskInode *inode) {
+    uint8_t buf[BLOCK_SIZE];
+    read_block(sb.inode_start + inum / INODES_PER_BLOCK, buf);
+    memcpy(inode, buf + (inum % INODES_PER_BLOCK) * sizeof(DiskInode), sizeof(DiskInode));
+}
+
+void write_inode(uint32_t inum, const DiskInode *inode) {
+    uint8_t buf[BLOCK_SIZE];
+    uint32_t block_no = sb.inode_start + inum / INODES_PER_BLOCK;
+    read_block(block_no, buf);
+    memcpy(buf + (inum % INODES_PER_BLOCK) * sizeof(DiskInode), inode, sizeof(DiskInode));
+    write_block(block_no, buf);
+}
+
+uint32_t alloc_inode(uint16_t type) {
+    // the kernel looks for inodes below `N_INODES` only
+    if (next_free_inum >= N_INODES) {
+        cerr << "out of inodes" << endl;
+        exit(1);
+    }
+    DiskInode inode;
+    memset(&inode, 0, sizeof(inode));
+    inode.type = type;
+    inode.nlink = 1;
+    write_inode(next_free_inum, &inode);
+    return next_free_inum++;
+}
+
+// entry `idx` of an indirect block, allocated if missing
+uint32_t indirect(uint32_t block_no, uint32_t idx) {
+    uint8_t buf[BLOCK_SIZE];
+    read_block(block_no, buf);
+    uint32_t *addrs = (uint32_t *)buf;
+    if (addrs[idx] == 0) {
+        addrs[idx] = alloc_block();
+        write_block(block_no, buf);
+    }
+    return addrs[idx];
+}
+
+// the disk block of the `n`th block of the file, allocated if missing
+uint32_t block_of(DiskInode *inode, uint32_t n) {
+    if (n < N_DIRECT) {
+        if (inode->addrs[n] == 0) inode->addrs[n] = alloc_block();
+        return inode->addrs[n];
+    }
+    n -= N_DIRECT;
+    if (n < N_INDIRECT) {
+        if (inode->addrs[N_DIRECT] == 0) inode->addrs[N_DIRECT] = alloc_block();
+        return indirect(inode->addrs[N_DIRECT], n);
+    }
+    n -= N_INDIRECT;
+    if (n < N_INDIRECT * N_INDIRECT) {
+        if (inode->addrs[N_DIRECT + 1] == 0) inode->addrs[N_DIRECT + 1] = alloc_block();
+        return indirect(indirect(inode->addrs[N_DIRECT + 1], n / N_INDIRECT), n % N_INDIRECT);
+    }
+    cerr << "file too large" << endl;
+    exit(1);
+}
+
+// append `len` bytes to the end of the inode
+void append(uint32_t inum, const uint8_t *data, uint32_t len) {
+    DiskInode inode;
+    read_inode(inum, &inode);
+    uint8_t buf[BLOCK_SIZE];
+    uint32_t pos = inode.size;
+    uint32_t end = inode.size + len;
+    while (pos < end) {
+        uint32_t block_no = block_of(&inode, pos / BLOCK_SIZE);
+        uint32_t block_offset = pos % BLOCK_SIZE;
+        uint32_t n = min(BLOCK_SIZE - block_offset, end - pos);
+        read_block(block_no, buf);
+        memcpy(buf + block_offset, data + (pos - inode.size), n);
+        write_block(block_no, buf);
+        pos += n;
+    }
+    inode.size = end;
+    write_inode(inum, &inode);
+}
+
+void dir_link(uint32_t dir, const string &name, uint32_t inum) {
+    DirEntry entry;
+    memset(&entry, 0, sizeof(entry));
+    entry.inum = inum;
+    strncpy(entry.name, name.c_str(), DIR_NAME_LEN);
+    append(dir, (const uint8_t *)&entry, sizeof(entry));
+}
+
+int main(int argc, char **argv) {
+    static_assert(sizeof(DiskInode) == 64, "DiskInode must match the kernel");
+    static_assert(sizeof(DirEntry) == 16, "DirEntry must match the kernel");
+    if (argc < 2) {
+        cerr << "usage: mkfs image files..." << endl;
+        return 1;
+    }
+
+    cout << "Making file system..." << endl;
     cout << "Using " << argv[1] << " as target image" << endl;
-    ofstream hdd(argv[1], ios::binary | ios::out);
-    vector<File> files;
-    ssize_t cum_sz = header_size;
+    hdd.open(argv[1], ios::binary | ios::in | ios::out);
+    if (!hdd) {
+        cerr << "cannot open " << argv[1] << endl;
+        return 1;
+    }
+    hdd.seekg(0, ios::end);
+    uint32_t size = hdd.tellg() / BLOCK_SIZE;
+
+    // see `SuperBlock::new`
+    uint32_t n_inode_blocks = N_INODES / INODES_PER_BLOCK + 1;
+    uint32_t n_bitmap_blocks = size / BITS_PER_BLOCK + 1;
+    sb.magic = FS_MAGIC;
+    sb.size = size;
+    sb.n_inodes = N_INODES;
+    sb.inode_start = SUPERBLOCK_NO + 1;
+    sb.bitmap_start = sb.inode_start + n_inode_blocks;
+    // at least one data block, like the kernel expects
+    if (size <= sb.bitmap_start + n_bitmap_blocks) {
+        cerr << argv[1] << ": " << size << " blocks, too small for a file system" << endl;
+        return 1;
+    }
+    sb.n_blocks = size - (sb.bitmap_start + n_bitmap_blocks);
+    next_free_block = size - sb.n_blocks;
+    cout << size << " blocks, " << sb.n_blocks << " data blocks, " << N_INODES << " inodes" << endl;
+
+    uint8_t buf[BLOCK_SIZE] = {0};
+    for (uint32_t block_no = 0; block_no < next_free_block; block_no++) write_block(block_no, buf);
+    memcpy(buf, &sb, sizeof(sb));
+    write_block(SUPERBLOCK_NO, buf);
+
+    // the root directory is its own parent
+    uint32_t root = alloc_inode(T_DIR);
+    dir_link(root, ".", root);
+    dir_link(root, "..", root);
+
     for (int i = 2; i < argc; i++) {
         string filename(argv[i]);
         auto pos = filename.find_last_of('/');
-        string fsname = filename.substr(pos, filename.size() - pos);
-        cout << "Processing " << filename << " (" << fsname << " in fs)" << endl;
+        string fsname = pos == string::npos ? filename : filename.substr(pos + 1);
+        if (fsname.size() > DIR_NAME_LEN) {
+            cerr << fsname << ": name too long" << endl;
+            return 1;
+        }
+        cout << "Processing " << filename << " (/" << fsname << " in fs)" << endl;
         ifstream file(filename, ios::binary | ios::in);
-        file.seekg(0, ios::end);
-        auto sz = file.tellg();
-        files.push_back({ cum_sz, sz, fsname });
-        file.seekg(sz);
-        hdd.seekp(cum_sz);
-        cout << "Write file of size " << sz << " to pos " << cum_sz << endl;
-        cum_sz += align_val(sz);
-        file.seekg(0);
-        for (int i = 0; i < sz; i++) hdd.put(file.get());
-        for (int i = sz; i < align_val(sz); i++) hdd.put(0);
-    }
-    cout << "Writing header..." << endl;
-    cout << "Little endian, ssize_t=" << sizeof(ssize_t) << endl;
-    for (int i = 0; i < files.size(); i++) {
-        auto &p = files[i];
-        hdd.seekp(i * 1024);
-        ssize_t sz = p.size;
-        ssize_t offset = p.offset;
-        hdd.write((char*) &sz, sizeof(ssize_t));
-        hdd.write((char*) &offset, sizeof(ssize_t));
-        hdd.write(p.name.c_str(), p.name.length() + 1);
-    }
-    cout << files.size() << " files written." << endl;
+        vector<uint8_t> data((istreambuf_iterator<char>(file)), istreambuf_iterator<char>());
+
+        uint32_t inum = alloc_inode(T_FILE);
+        append(inum, data.data(), data.size());
+        dir_link(root, fsname, inum);
+    }
+
+    // mark the used blocks in the bitmap, they are allocated in order
+    for (uint32_t b = 0; b < n_bitmap_blocks; b++) {
+        memset(buf, 0, BLOCK_SIZE);
+        for (uint32_t bit = 0; bit < BITS_PER_BLOCK; bit++) {
+            if (b * BITS_PER_BLOCK + bit < next_free_block) buf[bit / 8] |= 1 << (bit % 8);
+        }
+        write_block(sb.bitmap_start + b, buf);
+    }
+    cout << argc - 2 << " files written, " << next_free_block << " blocks used." << endl;
     return 0;
 }
@@ -1,17 +1,24 @@
 use alloc::vec::Vec;
 
 use lazy_static::lazy_static;
-use spin::mutex::Mutex;
 
 use crate::{
+    common::kernel_mutex::KernelMutex,
     info,
     mm::{
         arithmetics::PG_ROUND_UP,
         layout::{__heap_size, __heap_start, PAGE_SIZE},
-        memory::PhysAddr,
+        memory::{Frame, FrameRange, PhysAddr},
     },
+    warn,
 };
 
+/// Zero the pages when they are freed, so that nothing of a previous owner,
+/// e.g. another process, is left for the next one to read.
+/// It costs a page write on every free, on top of `FrameGuard::allocate_zeroed`
+/// zeroing on allocation. Frames handed out by `allocate_one_frame` are only clean with it
+pub const ZERO_ON_FREE: bool = true;
+
 // Since we already have an allocator in the kernel heap space (1MB)
 // we can now use dynamiclly allocate kernel data structures,
 // including Rust containers!!!
@@ -24,7 +31,15 @@ pub struct FrameAllocator {
     /// how many contiguous blocks it should free
     pub page_allocated: Vec<usize>,
 
-    /// start of the heap
+    /// indices of the free pages, so that allocating
+    /// and deallocating a single page is O(1)
+    pub free_list: Vec<usize>,
+
+    /// number of `FrameGuard`s referring to each allocated page,
+    /// a single page is freed once it drops to zero
+    pub ref_counts: Vec<usize>,
+
+    /// start of the heap, page aligned, indices are in pages from here
     pub base_addr: usize,
 }
 
@@ -32,22 +47,55 @@ impl FrameAllocator {
     /// create a new allocator instalce
     /// NOTE: base_addr should be initialised later
     pub fn new(base_addr: usize, n_pages: usize) -> Self {
+        // any larger alignment is fine, the stride is always `PAGE_SIZE`
+        assert_eq!(
+            base_addr % PAGE_SIZE,
+            0,
+            "FrameAllocator::new: base {:?} not page aligned",
+            base_addr as *const usize
+        );
+        // reversed so that lower pages are handed out first
+        // NOTE: it never grows beyond `n_pages`, reserve it once
+        let mut free_list = Vec::with_capacity(n_pages);
+        free_list.extend((0..n_pages).rev());
         Self {
             page_allocated: vec![0; n_pages],
+            free_list,
+            ref_counts: vec![0; n_pages],
             base_addr,
         }
     }
 
-    fn allocate(&mut self, size: usize) -> *mut u8 {
-        // we can only allocate `PAGE_SIZE` aligned
+    /// address of the page at index `idx`
+    fn page_addr(&self, idx: usize) -> usize {
+        self.base_addr + idx * PAGE_SIZE
+    }
+
+    /// `None` if there are not enough free pages
+    fn allocate(&mut self, size: usize) -> Option<*mut u8> {
+        // `size` only decides how many pages, we can only allocate `PAGE_SIZE` aligned
         let npages = PG_ROUND_UP(size) / PAGE_SIZE;
+        if npages == 1 {
+            return self.allocate_one();
+        }
+        let i = self.allocate_contiguous(npages)?;
+        // debug!("FrameAllocator::allocate: allocated page with pa: {:?}", ptr);
+        Some(self.page_addr(i) as *mut u8)
+    }
+
+    /// allocate `npages` contiguous pages, returns the index of the first one
+    fn allocate_contiguous(&mut self, npages: usize) -> Option<usize> {
         for i in 0..self.page_allocated.len() {
             // find the first unallocated spot
             if self.page_allocated[i] == 0 {
+                // not enough pages left from here
+                if i + npages > self.page_allocated.len() {
+                    break;
+                }
                 // find contiguois memory that fits
                 let mut found = true;
                 for j in 0..npages {
-                    if !self.page_allocated[i + j] == 0 {
+                    if self.page_allocated[i + j] != 0 {
                         found = false;
                         break;
                     }
@@ -56,33 +104,88 @@ impl FrameAllocator {
                     // allocate these pages by setting their entries to non-zero
                     for j in 0..npages {
                         self.page_allocated[i + j] = npages;
+                        self.ref_counts[i + j] = 1;
                     }
-                    let ptr = (self.base_addr + i * size) as *mut u8;
-                    // debug!("FrameAllocator::allocate: allocated page with pa: {:?}", ptr);
-                    return ptr;
+                    // multi-page allocations are rare, it's fine to be O(n) here
+                    self.free_list.retain(|&idx| idx < i || idx >= i + npages);
+                    return Some(i);
                 }
                 // if we cannot find this round, we find the next unallocated memory and try again
             }
         }
-        panic!("FrameAllocator::allocate: no available page!");
+        None
+    }
+
+    fn allocate_one(&mut self) -> Option<*mut u8> {
+        let idx = self.free_list.pop()?;
+        assert_eq!(self.page_allocated[idx], 0);
+        self.page_allocated[idx] = 1;
+        self.ref_counts[idx] = 1;
+        Some(self.page_addr(idx) as *mut u8)
+    }
+
+    /// index of the page at `pa`, `None` if the allocator does not manage it,
+    /// e.g. the trampoline inside the kernel binary
+    fn page_index(&self, pa: usize) -> Option<usize> {
+        let idx = pa.checked_sub(self.base_addr)? / PAGE_SIZE;
+        (idx < self.page_allocated.len()).then_some(idx)
+    }
+
+    /// number of pages currently allocated
+    fn n_allocated(&self) -> usize {
+        self.page_allocated.iter().filter(|&&n| n != 0).count()
+    }
+
+    /// pages allocated and pages managed in total
+    pub fn stats(&self) -> (usize, usize) {
+        (self.n_allocated(), self.page_allocated.len())
+    }
+
+    /// number of pages in the longest run of free pages
+    fn largest_free_run(&self) -> usize {
+        let mut largest = 0;
+        let mut run = 0;
+        for &n in &self.page_allocated {
+            run = if n == 0 { run + 1 } else { 0 };
+            largest = largest.max(run);
+        }
+        largest
+    }
+
+    /// Percentage of the free pages outside of the largest free run, 0 if all of them
+    /// are contiguous, or none is free. A high one fails large `allocate_contiguous`
+    /// with plenty of pages free
+    pub fn fragmentation_ratio(&self) -> usize {
+        let n_free = self.free_list.len();
+        if n_free == 0 {
+            return 0;
+        }
+        (n_free - self.largest_free_run()) * 100 / n_free
     }
 
-    /// deallocate address
+    /// deallocate address, the pages are zeroed with `ZERO_ON_FREE`
     fn deallocate(&mut self, addr: *mut u8) {
-        let begin_idx = (addr as usize - self.base_addr) / PAGE_SIZE;
+        let begin_idx = self
+            .page_index(addr as usize)
+            .expect("FrameAllocator::deallocate: page is not allocated by us");
         let npages = self.page_allocated[begin_idx];
+        if ZERO_ON_FREE {
+            unsafe { core::ptr::write_bytes(addr, 0, npages * PAGE_SIZE) };
+        }
         for id in begin_idx..begin_idx + npages {
             assert_eq!(self.page_allocated[id], npages);
             self.page_allocated[id] = 0;
+            self.ref_counts[id] = 0;
+            self.free_list.push(id);
         }
     }
 }
 
 lazy_static! {
-    pub static ref FRAME_ALLOCATOR: Mutex<FrameAllocator> = {
+    pub static ref FRAME_ALLOCATOR: KernelMutex<FrameAllocator> = {
         let n_pages = __heap_size() / PAGE_SIZE; // if it cannot fit inside the kernel heap, an alloc error will occur
         let allocator = FrameAllocator::new(__heap_start(), n_pages);
-        Mutex::new(allocator)
+        KernelMutex::new("FRAME_ALLOCATOR", allocator)
     };
 }
 pub fn init() {
@@ -92,20 +195,236 @@ pub fn init() {
 }
 
 // public interface
-pub fn allocate_one_frame() -> PhysAddr {
-    let pa = FRAME_ALLOCATOR.lock().allocate(PAGE_SIZE) as usize;
+/// `None` once every frame is allocated, the caller decides whether it can fail
+pub fn allocate_one_frame() -> Option<PhysAddr> {
+    let pa = FRAME_ALLOCATOR.lock().allocate(PAGE_SIZE)? as usize;
     // info!(
     //     "frame_allocator::allocate_one_frame: allocated frame at pa {:?}",
     //     pa as *const usize
     // );
-    PhysAddr::new(pa)
+    Some(PhysAddr::new(pa))
 }
 
+/// Drop a reference to the frame at `pa`, it is freed with the last one.
+/// Frames not managed by the allocator are never freed
 pub fn deallocate_one_frame(pa: PhysAddr) {
     let pa = pa.as_usize();
     // info!(
     //     "frame_allocator::deallocate_one_frame: deallocated frame at pa {:?}",
     //     pa as *const usize
     // );
-    FRAME_ALLOCATOR.lock().deallocate(pa as *mut u8);
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let Some(idx) = allocator.page_index(pa) else {
+        warn!(
+            "frame_allocator::deallocate_one_frame: frame at pa {:?} is not allocated by us",
+            pa as *const usize
+        );
+        return;
+    };
+    assert!(
+        allocator.ref_counts[idx] > 0,
+        "frame_allocator::deallocate_one_frame: frame at pa {:?} freed twice",
+        pa as *const usize
+    );
+    allocator.ref_counts[idx] -= 1;
+    if allocator.ref_counts[idx] == 0 {
+        allocator.deallocate(pa as *mut u8);
+    }
+}
+
+/// Add a reference to the allocated frame at `pa`, e.g. when it is shared copy-on-write
+pub fn share_one_frame(pa: PhysAddr) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let idx = allocator
+        .page_index(pa.as_usize())
+        .expect("frame_allocator::share_one_frame: frame is not allocated by us");
+    assert!(allocator.ref_counts[idx] > 0);
+    allocator.ref_counts[idx] += 1;
+}
+
+/// number of references to the frame at `pa`, 0 if it is free or not managed by the allocator
+pub fn frame_ref_count(pa: PhysAddr) -> usize {
+    let allocator = FRAME_ALLOCATOR.lock();
+    allocator
+        .page_index(pa.as_usize())
+        .map_or(0, |idx| allocator.ref_counts[idx])
+}
+
+/// Allocate `n` physically contiguous frames, e.g. for DMA.
+/// Returns `None` if there is no contiguous run of `n` free frames
+pub fn allocate_contiguous_frames(n: usize) -> Option<FrameRange> {
+    if n == 0 {
+        return None;
+    }
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let i = allocator.allocate_contiguous(n)?;
+    let begin = Frame::from_phys_addr(PhysAddr::new(allocator.page_addr(i)));
+    let end = Frame::from_ppn(begin.number + n);
+    Some(FrameRange::new(begin, end))
+}
+
+/// The range must be the one returned by `allocate_contiguous_frames`
+pub fn deallocate_contiguous_frames(range: FrameRange) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let pa = range.get_begin().get_base_phys_addr().as_usize();
+    let begin_idx = allocator
+        .page_index(pa)
+        .expect("frame_allocator::deallocate_contiguous_frames: range is not allocated by us");
+    assert_eq!(
+        allocator.page_allocated[begin_idx],
+        range.n_pages(),
+        "frame_allocator::deallocate_contiguous_frames: range does not match its allocation"
+    );
+    allocator.deallocate(pa as *mut u8);
+}
+
+pub fn n_allocated_frames() -> usize {
+    FRAME_ALLOCATOR.lock().n_allocated()
+}
+
+/// see `FrameAllocator::stats`
+pub fn frame_stats() -> (usize, usize) {
+    FRAME_ALLOCATOR.lock().stats()
+}
+
+pub fn n_free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().free_list.len()
+}
+
+pub fn print_frame_status() {
+    let allocator = FRAME_ALLOCATOR.lock();
+    let (allocated, total) = allocator.stats();
+    info!(
+        "---------------- FRAME USAGE: {:?}% ---------------------",
+        allocated * 100 / total,
+    );
+    info!(
+        "used: {:?} pages, total: {:?} pages, largest free run: {:?} pages, fragmented: {:?}%",
+        allocated,
+        total,
+        allocator.largest_free_run(),
+        allocator.fragmentation_ratio()
+    );
+    info!("-------------------------------------------------------");
+}
+
+/// allocate and free 10k frames, the allocator should be back to where it was
+pub fn test_allocate_many_frames() {
+    const N_FRAMES: usize = 10_000;
+    let baseline = n_allocated_frames();
+    let mut frames = Vec::with_capacity(N_FRAMES);
+    for _ in 0..N_FRAMES {
+        frames.push(allocate_one_frame().unwrap());
+    }
+    // each allocation marks a distinct page
+    assert_eq!(n_allocated_frames(), baseline + N_FRAMES);
+
+    for pa in frames {
+        deallocate_one_frame(pa);
+    }
+    assert_eq!(n_allocated_frames(), baseline);
+}
+
+/// a contiguous allocation amid scattered single pages should not overlap any of them
+pub fn test_allocate_contiguous_amid_scattered() {
+    const N_PAGES: usize = 4;
+    let baseline = n_allocated_frames();
+
+    // leave one-page holes between allocated pages
+    let mut held = Vec::new();
+    for i in 0..16 {
+        let pa = allocate_one_frame().unwrap();
+        if i % 2 == 0 {
+            held.push(pa);
+        } else {
+            deallocate_one_frame(pa);
+        }
+    }
+
+    let block = FRAME_ALLOCATOR
+        .lock()
+        .allocate(N_PAGES * PAGE_SIZE)
+        .unwrap() as usize;
+    for pa in &held {
+        let pa = pa.as_usize();
+        assert!(pa < block || pa >= block + N_PAGES * PAGE_SIZE);
+    }
+    assert_eq!(n_allocated_frames(), baseline + held.len() + N_PAGES);
+
+    FRAME_ALLOCATOR.lock().deallocate(block as *mut u8);
+    for pa in held {
+        deallocate_one_frame(pa);
+    }
+    assert_eq!(n_allocated_frames(), baseline);
+}
+
+/// a shared frame should be freed only when its last reference is dropped
+pub fn test_shared_frame_freed_once() {
+    let baseline = n_allocated_frames();
+    let pa = allocate_one_frame().unwrap();
+    share_one_frame(pa);
+    assert_eq!(frame_ref_count(pa), 2);
+
+    deallocate_one_frame(pa);
+    assert_eq!(n_allocated_frames(), baseline + 1);
+    deallocate_one_frame(pa);
+    assert_eq!(n_allocated_frames(), baseline);
+    assert_eq!(frame_ref_count(pa), 0);
+}
+
+/// with `ZERO_ON_FREE`, a freed frame should come back zeroed
+pub fn test_zero_on_free() {
+    if !ZERO_ON_FREE {
+        return;
+    }
+    let pa = allocate_one_frame().unwrap();
+    let page = unsafe { core::slice::from_raw_parts_mut(pa.as_usize() as *mut u8, PAGE_SIZE) };
+    page.fill(0xA5);
+    deallocate_one_frame(pa);
+
+    // the free list hands out the last freed page first
+    let again = allocate_one_frame().unwrap();
+    assert_eq!(again.as_usize(), pa.as_usize());
+    let page = unsafe { core::slice::from_raw_parts(again.as_usize() as *const u8, PAGE_SIZE) };
+    assert!(page.iter().all(|&b| b == 0));
+    deallocate_one_frame(again);
+}
+
+/// interleaved allocations and frees should be reported as fragmented until all is freed
+pub fn test_fragmentation_ratio() {
+    const N_PAGES: usize = 8;
+    // an allocator of its own over real frames, freeing zeroes them
+    let range = allocate_contiguous_frames(N_PAGES).unwrap();
+    let base = range.get_begin().get_base_phys_addr().as_usize();
+    let mut allocator = FrameAllocator::new(base, N_PAGES);
+    assert_eq!(allocator.fragmentation_ratio(), 0);
+
+    let pages: Vec<_> = (0..N_PAGES)
+        .map(|_| allocator.allocate(PAGE_SIZE).unwrap())
+        .collect();
+    assert_eq!(allocator.fragmentation_ratio(), 0);
+    // free every other page, no two free pages are next to each other
+    for &page in pages.iter().step_by(2) {
+        allocator.deallocate(page);
+    }
+    assert_eq!(allocator.largest_free_run(), 1);
+    assert_eq!(allocator.fragmentation_ratio(), 75);
+    assert!(allocator.allocate(2 * PAGE_SIZE).is_none());
+
+    // the first three pages are free, a little over two pages fits there
+    allocator.deallocate(pages[1]);
+    assert_eq!(allocator.largest_free_run(), 3);
+    assert_eq!(allocator.fragmentation_ratio(), 40);
+    let block = allocator.allocate(2 * PAGE_SIZE + 1).unwrap();
+    assert_eq!(block as usize, base);
+    assert!(allocator.allocate(2 * PAGE_SIZE).is_none());
+
+    allocator.deallocate(block);
+    for &page in pages.iter().skip(3).step_by(2) {
+        allocator.deallocate(page);
+    }
+    assert_eq!(allocator.largest_free_run(), N_PAGES);
+    assert_eq!(allocator.fragmentation_ratio(), 0);
+
+    deallocate_contiguous_frames(range);
 }
@@ -1,13 +1,159 @@
 //! We use buddy alocator here
 
-use core::borrow::Borrow;
+use alloc::boxed::Box;
+use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use buddy_system_allocator::LockedHeap;
+use spin::Mutex;
 
-use crate::info;
-use crate::mm::layout::KERNEL_HEAP_SIZE;
+use crate::mm::layout::{KERNEL_HEAP_SIZE, PAGE_SIZE};
 use crate::mm::memory::PhysAddr;
+use crate::{error, info};
+
+/// Fill freed blocks with `POISON` and hold them back in a quarantine for a while,
+/// a write to one meanwhile, e.g. through a dangling `Box`, is reported once the block
+/// is handed out again or leaves the quarantine, see `PoisonedHeap`.
+/// It costs a fill on every free and a check of the block on its way out, it is only
+/// built with the `heap_poison` feature, which the `Makefile` turns on for debug builds
+pub const HEAP_POISON: bool = cfg!(feature = "heap_poison");
+
+/// repeated over freed blocks, in little-endian bytes
+const POISON: u32 = 0xDEAD_BEEF;
+
+/// freed blocks held back before they go back to the buddy allocator
+const QUARANTINE_LEN: usize = 32;
+
+/// larger blocks are freed right away, filling and checking them costs too much
+const MAX_POISONED_SIZE: usize = PAGE_SIZE;
+
+/// writes to freed blocks found so far
+static POISON_HITS: AtomicUsize = AtomicUsize::new(0);
+
+/// the most recently freed blocks, oldest first from `next`
+struct Quarantine {
+    blocks: [Option<(usize, Layout)>; QUARANTINE_LEN],
+    next: usize,
+}
+
+impl Quarantine {
+    /// the oldest block is pushed out once it is full
+    fn push(&mut self, addr: usize, layout: Layout) -> Option<(usize, Layout)> {
+        let evicted = self.blocks[self.next].replace((addr, layout));
+        self.next = (self.next + 1) % QUARANTINE_LEN;
+        evicted
+    }
+
+    /// Take the oldest block freed with `layout` out, to hand it out again.
+    /// The oldest gives a dangling pointer to it the longest time to be caught
+    fn take(&mut self, layout: Layout) -> Option<usize> {
+        let slot = (0..QUARANTINE_LEN)
+            .map(|i| (self.next + i) % QUARANTINE_LEN)
+            .find(|&slot| matches!(self.blocks[slot], Some((_, freed)) if freed == layout))?;
+        self.blocks[slot].take().map(|(addr, _)| addr)
+    }
+}
+
+/// the `POISON` byte at `offset` of a block
+fn poison_byte(offset: usize) -> u8 {
+    POISON.to_le_bytes()[offset % 4]
+}
+
+unsafe fn poison(ptr: *mut u8, size: usize) {
+    for offset in 0..size {
+        ptr.add(offset).write_volatile(poison_byte(offset));
+    }
+}
+
+/// the address of the first byte of the block that is not `POISON`
+unsafe fn find_corrupted(ptr: *const u8, size: usize) -> Option<usize> {
+    (0..size)
+        .find(|&offset| ptr.add(offset).read_volatile() != poison_byte(offset))
+        .map(|offset| ptr as usize + offset)
+}
+
+/// `error!` does not allocate, it is fine to call from the allocator
+fn report_corrupted(addr: usize) {
+    error!(
+        "heap_allocator: freed memory at {:?} written, use after free?",
+        addr as *const u8
+    );
+    POISON_HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The buddy allocator, with freed blocks poisoned and quarantined with `HEAP_POISON`
+pub struct PoisonedHeap {
+    heap: LockedHeap,
+    quarantine: Mutex<Quarantine>,
+}
+
+impl PoisonedHeap {
+    const fn empty() -> Self {
+        Self {
+            heap: LockedHeap::empty(),
+            quarantine: Mutex::new(Quarantine {
+                blocks: [None; QUARANTINE_LEN],
+                next: 0,
+            }),
+        }
+    }
+
+    /// hand the quarantined blocks back to the buddy allocator, e.g. when it runs out
+    fn release_quarantine(&self) {
+        let blocks = {
+            let mut quarantine = self.quarantine.lock();
+            core::mem::replace(&mut quarantine.blocks, [None; QUARANTINE_LEN])
+        };
+        for (addr, layout) in blocks.into_iter().flatten() {
+            unsafe {
+                check_block(addr, layout);
+                self.heap.dealloc(addr as *mut u8, layout);
+            }
+        }
+    }
+}
+
+/// Report a write to a block since it was quarantined.
+/// It only scans the block, and without holding the quarantine
+unsafe fn check_block(addr: usize, layout: Layout) {
+    if let Some(corrupted) = find_corrupted(addr as *const u8, layout.size()) {
+        report_corrupted(corrupted);
+    }
+}
+
+unsafe impl GlobalAlloc for PoisonedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !HEAP_POISON {
+            return self.heap.alloc(layout);
+        }
+        // reuse a block freed the same way, it should still be poisoned
+        let reused = self.quarantine.lock().take(layout);
+        if let Some(addr) = reused {
+            check_block(addr, layout);
+            return addr as *mut u8;
+        }
+        let ptr = self.heap.alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // the quarantine may hold what it needs
+        self.release_quarantine();
+        self.heap.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !HEAP_POISON || layout.size() > MAX_POISONED_SIZE {
+            return self.heap.dealloc(ptr, layout);
+        }
+        poison(ptr, layout.size());
+        let evicted = self.quarantine.lock().push(ptr as usize, layout);
+        if let Some((addr, layout)) = evicted {
+            check_block(addr, layout);
+            self.heap.dealloc(addr as *mut u8, layout);
+        }
+    }
+}
 
 // we define the KERNEL_HEAP_SIZE here, may be move to another file
 // in Bytes
@@ -15,7 +161,7 @@ use crate::mm::memory::PhysAddr;
 // the global allocator for the kernel
 // Note that kernel threads share the same page table
 #[global_allocator]
-static KERNEL_HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static KERNEL_HEAP_ALLOCATOR: PoisonedHeap = PoisonedHeap::empty();
 
 // Since it is uninitialised and staically allocated,
 // It lives in the .bss section of the kernel binary
@@ -42,7 +188,10 @@ pub fn init() {
             panic!("heap_allocator::init: heap start address not page aligned!");
         }
 
-        KERNEL_HEAP_ALLOCATOR.lock().init(start, KERNEL_HEAP_SIZE);
+        KERNEL_HEAP_ALLOCATOR
+            .heap
+            .lock()
+            .init(start, KERNEL_HEAP_SIZE);
         info!(
             "Kernel heap space initialised: start:{:?}, size:{:?}",
             start, KERNEL_HEAP_SIZE
@@ -50,8 +199,9 @@ pub fn init() {
     }
 }
 
+/// bytes allocated, requested and in total, the quarantined blocks are still allocated
 pub fn kernel_heap_status() -> (usize, usize, usize) {
-    let allocator = KERNEL_HEAP_ALLOCATOR.borrow().lock();
+    let allocator = KERNEL_HEAP_ALLOCATOR.heap.lock();
     let actual = allocator.stats_alloc_actual();
     let user = allocator.stats_alloc_user();
     let total = allocator.stats_total_bytes();
@@ -67,3 +217,30 @@ pub fn print_kernel_heap_status() {
     info!("used: {:?} KB, total: {:?} KB", actual / 1024, total / 1024);
     info!("-------------------------------------------------------");
 }
+
+/// a write through a dangling `Box` should be reported by the next allocation of its block
+pub fn test_heap_poison() {
+    // a layout nothing else in the kernel frees, so that the block comes back to us
+    #[repr(align(64))]
+    struct Canary([u64; 24]);
+
+    if !HEAP_POISON {
+        return;
+    }
+    let hits = POISON_HITS.load(Ordering::Relaxed);
+    let dangling = Box::into_raw(Box::new(Canary([0; 24])));
+    drop(unsafe { Box::from_raw(dangling) });
+    // untouched, reused silently
+    let again = Box::into_raw(Box::new(Canary([0; 24])));
+    assert_eq!(again, dangling);
+    assert_eq!(POISON_HITS.load(Ordering::Relaxed), hits);
+    drop(unsafe { Box::from_raw(again) });
+
+    unsafe { (*dangling).0[1] = 42 };
+    let reused = Box::new(Canary([0; 24]));
+    assert_eq!(POISON_HITS.load(Ordering::Relaxed), hits + 1);
+    // other layouts do not check it
+    let other = Box::new(0u8);
+    assert_eq!(POISON_HITS.load(Ordering::Relaxed), hits + 1);
+    drop((reused, other));
+}
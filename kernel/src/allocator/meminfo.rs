@@ -0,0 +1,49 @@
+//! Memory usage returned by `meminfo`, shared with `user/src/meminfo.rs`
+
+use super::{frame_allocator, heap_allocator};
+
+/// `MemInfo::magic`, bump it when the layout changes so that
+/// user programs built against an older one can tell
+pub const MEMINFO_MAGIC: u64 = 0x4d45_4d49_4e46_4f01; // "MEMINFO" version 1
+
+/// `#[repr(C)]` without padding, user programs define the same layout
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemInfo {
+    pub magic: u64,
+    /// bytes of the kernel heap
+    pub heap_used: u64,
+    pub heap_total: u64,
+    /// pages of physical memory
+    pub frames_used: u64,
+    pub frames_total: u64,
+}
+
+impl MemInfo {
+    pub fn collect() -> Self {
+        let (heap_used, _, heap_total) = heap_allocator::kernel_heap_status();
+        let (frames_used, frames_total) = frame_allocator::frame_stats();
+        Self {
+            magic: MEMINFO_MAGIC,
+            heap_used: heap_used as u64,
+            heap_total: heap_total as u64,
+            frames_used: frames_used as u64,
+            frames_total: frames_total as u64,
+        }
+    }
+}
+
+/// an allocated frame should show up in the next snapshot
+pub fn test_meminfo_counts_frames() {
+    let before = MemInfo::collect();
+    assert_eq!(before.magic, MEMINFO_MAGIC);
+    assert!(before.frames_used <= before.frames_total);
+    assert!(before.heap_used <= before.heap_total);
+
+    let pa = frame_allocator::allocate_one_frame().unwrap();
+    let after = MemInfo::collect();
+    assert_eq!(after.frames_used, before.frames_used + 1);
+    assert_eq!(after.frames_total, before.frames_total);
+    frame_allocator::deallocate_one_frame(pa);
+    assert_eq!(MemInfo::collect().frames_used, before.frames_used);
+}
@@ -1,5 +1,7 @@
 pub mod frame_allocator;
 pub mod heap_allocator;
+pub mod meminfo;
+pub mod slab;
 
 pub fn init() {
     // we should first init the heap allocator
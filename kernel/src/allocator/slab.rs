@@ -0,0 +1,216 @@
+//! Caches of fixed-size kernel objects carved out of whole frames,
+//! so that objects churned by `fork` and `exit` do not fragment the kernel heap
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr,
+};
+use spin::Mutex;
+
+use crate::mm::{layout::PAGE_SIZE, memory::FrameGuard};
+
+use super::frame_allocator;
+
+/// one frame cut into cells, the free ones are indexed by `free`
+struct Slab {
+    // only kept to own the frame, dropping it recycles the frame
+    _frame: FrameGuard,
+    free: Vec<u16>,
+}
+
+struct SlabCacheInner {
+    /// by the physical address of their frames
+    slabs: BTreeMap<usize, Slab>,
+    /// those with a free cell
+    partial: BTreeSet<usize>,
+}
+
+/// `T`-sized cells out of frames of the frame allocator.
+/// A frame is returned to it as soon as its last cell is freed
+pub struct SlabCache<T> {
+    inner: Mutex<SlabCacheInner>,
+    _marker: PhantomData<T>,
+}
+
+// Safety: the cells are only handed out, what is stored in them is the caller's business
+unsafe impl<T> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// cells are aligned to `T`, and large enough for it
+    const CELL_SIZE: usize = size_of::<T>().next_multiple_of(align_of::<T>());
+    const CELLS_PER_SLAB: usize = PAGE_SIZE / Self::CELL_SIZE;
+
+    pub const fn new() -> Self {
+        assert!(size_of::<T>() > 0 && Self::CELL_SIZE <= PAGE_SIZE && align_of::<T>() <= PAGE_SIZE);
+        Self {
+            inner: Mutex::new(SlabCacheInner {
+                slabs: BTreeMap::new(),
+                partial: BTreeSet::new(),
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// An uninitialised cell for a `T`, null if the frames run out
+    pub fn alloc(&self) -> *mut T {
+        let mut inner = self.inner.lock();
+        let base = match inner.partial.first() {
+            Some(&base) => base,
+            None => {
+                let Some(frame) = FrameGuard::try_allocate_zeroed() else {
+                    return ptr::null_mut();
+                };
+                let base = frame.get_frame().get_base_phys_addr().as_usize();
+                // reversed so that lower cells are handed out first
+                let free = (0..Self::CELLS_PER_SLAB as u16).rev().collect();
+                inner.slabs.insert(
+                    base,
+                    Slab {
+                        _frame: frame,
+                        free,
+                    },
+                );
+                inner.partial.insert(base);
+                base
+            }
+        };
+        let slab = inner.slabs.get_mut(&base).unwrap();
+        let cell = slab.free.pop().unwrap() as usize;
+        if slab.free.is_empty() {
+            inner.partial.remove(&base);
+        }
+        (base + cell * Self::CELL_SIZE) as *mut T
+    }
+
+    /// Give back a cell, what it holds should have been dropped already.
+    ///
+    /// # Safety
+    /// `ptr` must come from `alloc` of this cache and not have been freed since
+    pub unsafe fn free(&self, ptr: *mut T) {
+        let addr = ptr as usize;
+        let base = addr & !(PAGE_SIZE - 1);
+        let mut inner = self.inner.lock();
+        let slab = inner
+            .slabs
+            .get_mut(&base)
+            .expect("SlabCache::free: not allocated by this cache");
+        let cell = (addr - base) / Self::CELL_SIZE;
+        assert_eq!(addr - base, cell * Self::CELL_SIZE);
+        debug_assert!(
+            !slab.free.contains(&(cell as u16)),
+            "SlabCache::free: freed twice"
+        );
+        slab.free.push(cell as u16);
+        if slab.free.len() == Self::CELLS_PER_SLAB {
+            // dropping the guard recycles the frame
+            inner.slabs.remove(&base);
+            inner.partial.remove(&base);
+        } else {
+            inner.partial.insert(base);
+        }
+    }
+
+    /// frames it holds at the moment
+    pub fn n_slabs(&self) -> usize {
+        self.inner.lock().slabs.len()
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Like a `Box`, but in a cell of a `SlabCache`
+pub struct SlabBox<T: 'static> {
+    ptr: *mut T,
+    cache: &'static SlabCache<T>,
+}
+
+// Safety: it owns the `T` like a `Box` does
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> SlabBox<T> {
+    /// `None` if the frames run out
+    pub fn try_new(cache: &'static SlabCache<T>, value: T) -> Option<Self> {
+        let ptr = cache.alloc();
+        if ptr.is_null() {
+            return None;
+        }
+        unsafe { ptr.write(value) };
+        Some(Self { ptr, cache })
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr);
+            self.cache.free(self.ptr);
+        }
+    }
+}
+
+/// allocate and free 10k objects, every frame taken should be given back
+pub fn test_slab_cache_stress() {
+    const N_OBJECTS: usize = 10_000;
+    let cache: SlabCache<[u64; 5]> = SlabCache::new();
+    let baseline = frame_allocator::n_allocated_frames();
+
+    let mut objects = Vec::with_capacity(N_OBJECTS);
+    for i in 0..N_OBJECTS {
+        let ptr = cache.alloc();
+        assert!(!ptr.is_null());
+        unsafe { ptr.write([i as u64; 5]) };
+        objects.push(ptr);
+    }
+    let per_slab = PAGE_SIZE / size_of::<[u64; 5]>();
+    assert_eq!(cache.n_slabs(), N_OBJECTS.div_ceil(per_slab));
+    assert_eq!(
+        frame_allocator::n_allocated_frames(),
+        baseline + cache.n_slabs()
+    );
+    // no two cells overlap
+    for (i, &ptr) in objects.iter().enumerate() {
+        assert_eq!(unsafe { ptr.read() }, [i as u64; 5]);
+    }
+
+    // free every other one first, then the rest
+    for &ptr in objects.iter().step_by(2) {
+        unsafe { cache.free(ptr) };
+    }
+    for &ptr in objects.iter().skip(1).step_by(2) {
+        unsafe { cache.free(ptr) };
+    }
+    assert_eq!(cache.n_slabs(), 0);
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+}
@@ -1,4 +1,12 @@
-use crate::mm::layout::CLINT_MTIME_BASE;
+use crate::{
+    clint::CLINT_FREQ_HZ,
+    cpu::CPUS,
+    info,
+    mm::{
+        layout::{CLINT_MTIME_BASE, VIRT_TEST_BASE},
+        memory::VirtAddr,
+    },
+};
 use core::{arch::asm, time::Duration};
 use riscv::register::sstatus;
 
@@ -10,10 +18,53 @@ pub fn hart_id() -> usize {
     hart_id
 }
 
-pub fn time() -> Duration {
+/// raw value of the CLINT `mtime` register, `CLINT_FREQ_HZ` ticks per second
+pub fn ticks() -> u64 {
     let mtime = CLINT_MTIME_BASE as *mut u64;
-    let time = unsafe { mtime.read_volatile() };
-    Duration::from_nanos(time)
+    unsafe { mtime.read_volatile() }
+}
+
+/// time since boot
+pub fn time() -> Duration {
+    let ticks = ticks();
+    let secs = ticks / CLINT_FREQ_HZ;
+    let nanos = (ticks % CLINT_FREQ_HZ) * 1_000_000_000 / CLINT_FREQ_HZ;
+    Duration::new(secs, nanos as u32)
+}
+
+// what the `sifive_test` finisher of the qemu virt machine takes:
+// `FINISHER_PASS` powers off with exit code 0,
+// `FINISHER_FAIL` with the exit code in the upper 16 bits
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+
+/// Power off the machine, qemu exits with `exit_code`.
+/// Only the low 16 bits of it are kept
+pub fn shutdown(exit_code: u32) -> ! {
+    info!(
+        "arch::shutdown: powering off with exit code {:?}",
+        exit_code
+    );
+    crate::print::flush();
+    let value = match exit_code {
+        0 => FINISHER_PASS,
+        code => (code & 0xFFFF) << 16 | FINISHER_FAIL,
+    };
+    unsafe { (VIRT_TEST_BASE as *mut u32).write_volatile(value) };
+    // the write takes effect after a while
+    crate::abort();
+}
+
+/// Busy-wait for at least `micros` microseconds, e.g. between the steps a device
+/// asks for when initialising. It counts `mtime` ticks rather than loop iterations,
+/// so it does not depend on the CPU frequency
+pub fn delay_us(micros: u64) {
+    let start = ticks();
+    let n_ticks = micros.saturating_mul(CLINT_FREQ_HZ) / 1_000_000;
+    // NOTE: wrapping so that it still ends if `mtime` wraps around
+    while ticks().wrapping_sub(start) < n_ticks {
+        core::hint::spin_loop();
+    }
 }
 
 pub fn intr_off() {
@@ -27,3 +78,86 @@ pub fn intr_on() {
         sstatus::set_sie(); // set the Supervisor Interrupt Enabled bit
     }
 }
+
+/// Disable interrupts, nested with `pop_off` like xv6: they are only enabled again
+/// when every `push_off` is matched, and only if they were enabled before the first.
+/// Unlike a bare `intr_off` and `intr_on`, a callee cannot enable them behind our back
+pub fn push_off() {
+    let was_on = sstatus::read().sie();
+    intr_off();
+    // NOTE: do not call it holding the write lock of this hart's `CPUS` entry
+    CPUS[hart_id()].read().push_intr_off(was_on);
+}
+
+/// undo a `push_off`, interrupts should still be disabled
+pub fn pop_off() {
+    assert!(
+        !sstatus::read().sie(),
+        "arch::pop_off: interrupts are enabled"
+    );
+    if CPUS[hart_id()].read().pop_intr_off() {
+        intr_on();
+    }
+}
+
+/// Flush the TLB entries of the page containing `va`, in every address space.
+/// It is enough after changing the mapping of a single page, e.g. resolving a
+/// lazy or copy-on-write fault, or unmapping a page before its frame is freed.
+/// Switching page tables or rewriting many mappings still needs `sfence_vma_all`.
+/// NOTE: either form only flushes the calling hart. With multiple harts running
+/// the same address space, the others have to be asked to flush as well
+pub fn sfence_vma_addr(va: VirtAddr) {
+    unsafe {
+        asm!("sfence.vma {}, zero", in(reg) va.as_usize());
+    }
+}
+
+/// Continue in `f(arg)` on the stack below `sp`, abandoning the current one.
+/// Nothing on it is dropped, and it may be reused as soon as `f` lets go of it
+pub fn switch_stack(sp: usize, f: extern "C" fn(usize) -> !, arg: usize) -> ! {
+    unsafe {
+        asm!(
+            "mv sp, {sp}",
+            "jr {f}",
+            sp = in(reg) sp,
+            f = in(reg) f,
+            in("a0") arg,
+            options(noreturn)
+        )
+    }
+}
+
+/// only the outermost `pop_off` should restore the interrupt state
+pub fn test_push_off_nesting() {
+    let was_on = sstatus::read().sie();
+    let depth = CPUS[hart_id()].read().intr_depth();
+    push_off();
+    push_off();
+    assert_eq!(CPUS[hart_id()].read().intr_depth(), depth + 2);
+    pop_off();
+    assert!(!sstatus::read().sie());
+    pop_off();
+    assert_eq!(sstatus::read().sie(), was_on);
+    assert_eq!(CPUS[hart_id()].read().intr_depth(), depth);
+
+    // enabled in between, still restored
+    intr_on();
+    push_off();
+    push_off();
+    pop_off();
+    assert!(!sstatus::read().sie());
+    pop_off();
+    assert!(sstatus::read().sie());
+    if !was_on {
+        intr_off();
+    }
+}
+
+/// `delay_us` should wait for at least as many ticks as asked
+pub fn test_delay_us() {
+    const MICROS: u64 = 1000;
+    let start = ticks();
+    delay_us(MICROS);
+    assert!(ticks().wrapping_sub(start) >= MICROS * CLINT_FREQ_HZ / 1_000_000);
+    delay_us(0);
+}
@@ -1,3 +1,8 @@
+use core::{
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use crate::mm::layout::{CLINT_MTIMECMP_BASE, CLINT_MTIME_BASE};
 use crate::{
     arch::hart_id,
@@ -8,11 +13,20 @@ use riscv::register::*;
 // core local interruptor (CLINT), which contains the timer.
 pub const CLINT_BASE: usize = 0x200_0000;
 
+/// `mtime` ticks per second, 10MHz on the QEMU virt machine
+pub const CLINT_FREQ_HZ: u64 = 10_000_000;
+
 #[allow(non_snake_case)]
 pub const fn CLINT_TIMECMP(hart: usize) -> usize {
     CLINT_MTIMECMP_BASE + 8 * hart
 }
 
+/// machine software interrupt pending bit of `hart`, writing 1 raises it
+#[allow(non_snake_case)]
+pub const fn CLINT_MSIP(hart: usize) -> usize {
+    CLINT_BASE + 4 * hart
+}
+
 /// scratch area for timer trap to save information: 64 bytes per core
 // static mut TIMER_SCRATCH: [[u64; 8]; NCPUS] = [[0; 8]; NCPUS];
 /// this init the timer scratch for each cpu
@@ -25,6 +39,9 @@ struct TimerScratch {
     mtime_addr: usize,
     mtimecmp_addr: usize,
     interval: usize,
+    msip_addr: usize,
+    /// set by a timer interrupt, to tell it from a software one, see `take_tick`
+    ticked: usize,
 }
 
 // We allocate a `TimerScratch` for each CPU
@@ -34,6 +51,8 @@ static mut TIMER_SCRATCHES: [TimerScratch; N_CPUS] = [TimerScratch {
     mtime_addr: 0,
     mtimecmp_addr: 0,
     interval: 0,
+    msip_addr: 0,
+    ticked: 0,
 }; N_CPUS];
 
 pub unsafe fn timer_init() {
@@ -51,11 +70,14 @@ pub unsafe fn timer_init() {
     // scratch[3]: adress for CLINT MTIME register
     // scratch[4]: address for CLINT MTIMECMP register
     // scratch[5]: desired interval (in cycles) between timer interrupts
+    // scratch[6]: address for CLINT MSIP register of this hart
+    // scratch[7]: set on a timer interrupt
     let scratch = &mut TIMER_SCRATCHES[id];
     mscratch::write(scratch as *const TimerScratch as usize); // mscratch register is only accessable in M-mode
     scratch.mtime_addr = mtime as usize;
     scratch.mtimecmp_addr = mtimecmp as usize;
     scratch.interval = interval as usize;
+    scratch.msip_addr = CLINT_MSIP(id);
 
     // set M-mode trap handler to `__timervec` in `kernelvec.S`
     mtvec::write(__timervec as usize, mtvec::TrapMode::Direct);
@@ -64,5 +86,22 @@ pub unsafe fn timer_init() {
     mstatus::set_mie(); // `mie` (machine interrupt enabled) bit in `mstatus` register
 
     // enable M-mode timer interrupt
-    mie::set_mtimer() // `mtimer` bit in `mie` register
+    mie::set_mtimer(); // `mtimer` bit in `mie` register
+                       // and software interrupts from other harts, see `send_soft_interrupt`
+    mie::set_msoft();
+}
+
+/// Raise a S-mode software interrupt on `hart`, through a machine software interrupt
+/// `__timervec` forwards, e.g. for `mm::tlb_shootdown`
+pub fn send_soft_interrupt(hart: usize) {
+    let msip = CLINT_MSIP(hart) as *mut u32;
+    unsafe { msip.write_volatile(1) };
+}
+
+/// Whether a timer interrupt has been taken on the calling hart since the last call,
+/// a S-mode software interrupt may only be a `send_soft_interrupt` from another hart
+pub fn take_tick() -> bool {
+    // written by `__timervec` in M-mode, which may interrupt us any time
+    let ticked = unsafe { AtomicUsize::from_ptr(addr_of_mut!(TIMER_SCRATCHES[hart_id()].ticked)) };
+    ticked.swap(0, Ordering::Relaxed) != 0
 }
@@ -0,0 +1,85 @@
+//! A spin lock that knows which hart holds it
+
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::{Mutex, MutexGuard};
+
+use crate::arch;
+
+/// the holder of an unlocked `KernelMutex`
+const NO_HOLDER: usize = usize::MAX;
+
+/// Like `spin::Mutex`, but locking it again on the hart holding it panics
+/// instead of spinning forever, e.g. logging through a lock that is held while logging
+pub struct KernelMutex<T> {
+    name: &'static str,
+    holder: AtomicUsize,
+    inner: Mutex<T>,
+}
+
+impl<T> KernelMutex<T> {
+    /// `name` is reported when it is locked recursively
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            holder: AtomicUsize::new(NO_HOLDER),
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> KernelMutexGuard<'_, T> {
+        let hartid = arch::hart_id();
+        // only this hart stores its own id, another hart cannot make it look held by us
+        if self.holder.load(Ordering::Relaxed) == hartid {
+            panic!(
+                "KernelMutex::lock: {} is already held by hart-{}",
+                self.name, hartid
+            );
+        }
+        let guard = self.inner.lock();
+        self.holder.store(hartid, Ordering::Relaxed);
+        KernelMutexGuard {
+            holder: &self.holder,
+            guard,
+        }
+    }
+}
+
+pub struct KernelMutexGuard<'a, T> {
+    holder: &'a AtomicUsize,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T> Deref for KernelMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for KernelMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for KernelMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // before `guard` unlocks it
+        self.holder.store(NO_HOLDER, Ordering::Relaxed);
+    }
+}
+
+/// Locking it twice on one hart should panic rather than hang.
+/// It does not return, so it is only built with the `self_deadlock_test` feature
+#[cfg(feature = "self_deadlock_test")]
+pub fn test_recursive_lock_panics() -> ! {
+    static LOCK: KernelMutex<usize> = KernelMutex::new("LOCK", 0);
+    let _guard = LOCK.lock();
+    crate::info!("kernel_mutex::test_recursive_lock_panics: expecting a panic");
+    let _again = LOCK.lock();
+    unreachable!("kernel_mutex::test_recursive_lock_panics: locked twice");
+}
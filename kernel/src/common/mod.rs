@@ -1 +1,2 @@
-pub mod resource_table;
\ No newline at end of file
+pub mod kernel_mutex;
+pub mod resource_table;
@@ -24,10 +24,12 @@ pub struct ResourceTable<T> {
 }
 
 impl<T> ResourceTable<T> {
-    pub fn new(capacity: usize) -> Self {
+    /// ids start from `0..capacity`, which grows up to `max_capacity` as they run out
+    pub fn new(capacity: usize, max_capacity: usize) -> Self {
+        assert!(capacity > 0 && capacity <= max_capacity);
         Self {
             active_slots: RwLock::new(HashMap::new()),
-            free_slots: Mutex::new(FreeSlotsInner::new(capacity)),
+            free_slots: Mutex::new(FreeSlotsInner::new(capacity, max_capacity)),
             name: "Resource".into(),
         }
     }
@@ -36,11 +38,12 @@ impl<T> ResourceTable<T> {
         self.name = name.into();
     }
 
-    pub fn reserve_entry(&mut self) -> usize {
+    /// `None` if all `max_capacity` ids are in use
+    pub fn reserve_entry(&mut self) -> Option<usize> {
         // allocate id
         let id = {
             let mut free_slots = self.free_slots.lock();
-            free_slots.allocate_one()
+            free_slots.allocate_one()?
         };
 
         // this copies `resource` from stack to the heap, expensive
@@ -56,20 +59,40 @@ impl<T> ResourceTable<T> {
                 info!("{:?}Table::reserve: reserved id: {:?}", self.name, id);
             }
         };
-        id
+        Some(id)
     }
 
+    /// Give back an id from `reserve_entry` whose resource could not be built,
+    /// e.g. a PCB without frames left for it, so that it can be reserved again
+    pub fn rollback_entry(&mut self, id: usize) {
+        let mut active_slots = self.active_slots.write();
+        match active_slots.remove(&id) {
+            Some(None) => {}
+            Some(Some(_)) => {
+                panic!(
+                    "{:?}Table::rollback: id {:?} already initialised",
+                    self.name, id
+                );
+            }
+            None => panic!("{:?}Table::rollback: id {:?} not reserved", self.name, id),
+        }
+        self.free_slots.lock().return_one(id);
+        info!("{:?}Table::rollback: returned id: {:?}", self.name, id);
+    }
+
+    /// commit a reserved id with its resource, `get` finds it from now on
     pub fn initialise_entry(&self, id: usize, data: Arc<T>) {
         let mut active_slots  = self.active_slots.write();
         let entry = active_slots.get_mut(&id).unwrap();
         *entry = Some(data);
     }
 
+    /// lookups only clone the `Arc`, they hold the read lock so that harts do not serialise
     pub fn get(&self, id: usize) -> Arc<T> {
-        let mut active_slots = self.active_slots.write();
+        let active_slots = self.active_slots.read();
         match active_slots
-            .get_mut(&id)
-            .expect("ResourceManager::get_data_ref_mut: internal error")
+            .get(&id)
+            .expect("ResourceManager::get_data_ref: internal error")
         {
             Some(slot) => slot.clone(),
             None => {
@@ -81,6 +104,22 @@ impl<T> ResourceTable<T> {
         }
     }
 
+    /// like `get`, but `None` if `id` is not in use
+    pub fn try_get(&self, id: usize) -> Option<Arc<T>> {
+        self.active_slots.read().get(&id)?.clone()
+    }
+
+    /// call `f` on every initialised entry under the read lock, in no particular order.
+    /// `f` must not modify the table
+    pub fn for_each(&self, mut f: impl FnMut(usize, &Arc<T>)) {
+        let active_slots = self.active_slots.read();
+        for (&id, slot) in active_slots.iter() {
+            if let Some(data) = slot {
+                f(id, data);
+            }
+        }
+    }
+
     pub fn remove_entry(&mut self, id: usize) {
         let mut active_slots = self.active_slots.write();
         active_slots.remove(&id);
@@ -92,27 +131,87 @@ impl<T> ResourceTable<T> {
 struct FreeSlotsInner {
     free_ids: BTreeSet<usize>,
     capacity: usize,
+    max_capacity: usize,
 }
 
 impl FreeSlotsInner {
-    fn new(capacity: usize) -> Self {
+    fn new(capacity: usize, max_capacity: usize) -> Self {
         Self {
             free_ids: (0..capacity).collect(),
             capacity,
+            max_capacity,
         }
     }
-    fn allocate_one(&mut self) -> usize {
+    fn allocate_one(&mut self) -> Option<usize> {
         if self.free_ids.is_empty() {
-            (self.capacity..self.capacity * 2).for_each(|id| {
+            if self.capacity == self.max_capacity {
+                return None;
+            }
+            let new_capacity = (self.capacity * 2).min(self.max_capacity);
+            (self.capacity..new_capacity).for_each(|id| {
                 self.free_ids.insert(id);
             });
-            self.capacity *= 2;
+            self.capacity = new_capacity;
         }
         assert!(!self.free_ids.is_empty());
-        self.free_ids.pop_first().unwrap()
+        self.free_ids.pop_first()
     }
 
     fn return_one(&mut self, id: usize) {
         assert!(self.free_ids.remove(&id));
     }
-}
\ No newline at end of file
+}
+
+/// `get` should not wait for others reading the table,
+/// and a writer should still get in after repeated lookups
+pub fn test_get_takes_read_lock() {
+    let mut table = ResourceTable::new(4, 4);
+    let id = table.reserve_entry().unwrap();
+    table.initialise_entry(id, Arc::new(id));
+
+    {
+        // a lookup on another hart
+        let _reader = table.active_slots.read();
+        assert_eq!(*table.get(id), id);
+    }
+    for _ in 0..1000 {
+        assert_eq!(*table.get(id), id);
+    }
+    assert!(table.active_slots.try_write().is_some());
+    let other = table.reserve_entry().unwrap();
+    table.initialise_entry(other, Arc::new(other));
+    assert_eq!(*table.get(other), other);
+}
+
+/// the table grows up to its maximum capacity, then reserving fails
+pub fn test_reserve_up_to_max_capacity() {
+    let mut table: ResourceTable<usize> = ResourceTable::new(2, 5);
+    for expected in 0..5 {
+        assert_eq!(table.reserve_entry(), Some(expected));
+    }
+    assert_eq!(table.reserve_entry(), None);
+
+    // a returned id can be reserved again
+    table.remove_entry(3);
+    assert_eq!(table.reserve_entry(), Some(3));
+    assert_eq!(table.reserve_entry(), None);
+}
+
+/// an id whose resource failed to initialise should be reserved again, not leaked
+pub fn test_rollback_reserved_entry() {
+    let mut table: ResourceTable<usize> = ResourceTable::new(2, 2);
+    let first = table.reserve_entry().unwrap();
+    table.initialise_entry(first, Arc::new(first));
+
+    // building what `second` stands for fails
+    let second = table.reserve_entry().unwrap();
+    assert_eq!(table.reserve_entry(), None);
+    assert!(table.try_get(second).is_none());
+    table.rollback_entry(second);
+    assert!(table.try_get(second).is_none());
+
+    assert_eq!(table.reserve_entry(), Some(second));
+    table.initialise_entry(second, Arc::new(second));
+    assert_eq!(*table.get(second), second);
+    assert_eq!(*table.get(first), first);
+}
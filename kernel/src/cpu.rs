@@ -8,21 +8,44 @@ lazy_static! {
                 RwLock::new(PercpuBlock {
                     hartid,
                     running_process: None,
+                    ready_queue: Mutex::new(VecDeque::new()),
+                    intr_depth: AtomicUsize::new(0),
+                    intr_was_on: AtomicBool::new(false),
                 })
             })
             .collect()
     };
 }
 
-use alloc::{sync::Arc, vec::Vec};
-use spin::rwlock::RwLock;
+use core::{
+    cell::SyncUnsafeCell,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
-use crate::{arch::hart_id, process::process::ProcessControlBlock, symbols::N_CPUS};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use spin::{rwlock::RwLock, Mutex};
+
+use crate::{
+    arch::{self, hart_id},
+    info,
+    process::process::{ProcessControlBlock, MAX_PRIORITY, MIN_PRIORITY},
+    symbols::N_CPUS,
+};
 
 #[derive(Debug)]
 pub struct PercpuBlock {
     hartid: usize,
     running_process: Option<Arc<ProcessControlBlock>>,
+    // runnable processes of this hart, others may steal from it.
+    // NOTE: reach it through a read lock of `CPUS`, the write lock is only taken
+    //  briefly to switch `running_process`
+    ready_queue: Mutex<VecDeque<Arc<ProcessControlBlock>>>,
+    // nesting of `arch::push_off`, and whether interrupts were enabled before the outermost.
+    // NOTE: only touched by this hart with its interrupts off, atomic to be reached through
+    //  a read lock
+    intr_depth: AtomicUsize,
+    intr_was_on: AtomicBool,
 }
 
 impl PercpuBlock {
@@ -38,13 +61,282 @@ impl PercpuBlock {
     pub fn hartid(&self) -> usize {
         self.hartid
     }
+
+    /// one more level of `arch::push_off`, `was_on` is only kept for the outermost
+    pub fn push_intr_off(&self, was_on: bool) {
+        if self.intr_depth.fetch_add(1, Ordering::Relaxed) == 0 {
+            self.intr_was_on.store(was_on, Ordering::Relaxed);
+        }
+    }
+
+    /// Leave a level of `arch::push_off`.
+    /// Returns whether interrupts should be enabled again, i.e. the outermost is left
+    /// and they were enabled before it
+    pub fn pop_intr_off(&self) -> bool {
+        let depth = self.intr_depth.fetch_sub(1, Ordering::Relaxed);
+        assert!(depth > 0, "cpu::pop_intr_off: not pushed");
+        depth == 1 && self.intr_was_on.load(Ordering::Relaxed)
+    }
+
+    pub fn intr_depth(&self) -> usize {
+        self.intr_depth.load(Ordering::Relaxed)
+    }
 }
 
-/// returns the current process of the calling CPU
-pub fn current_process() -> Option<Arc<ProcessControlBlock>> {
+type CurrentProcess = SyncUnsafeCell<Option<Arc<ProcessControlBlock>>>;
+// only to initialise the array below
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PROCESS: CurrentProcess = SyncUnsafeCell::new(None);
+
+/// A copy of `running_process` of each hart, so that `current_process` does not lock `CPUS`.
+/// NOTE: an entry is only written by its own hart, in `set_current_process` and
+///  `take_current_process` while switching processes, and only read by it
+static CURRENT_PROCESSES: [CurrentProcess; N_CPUS] = [NO_PROCESS; N_CPUS];
+
+/// the process the calling hart switches to, see `process::schedule`
+pub fn set_current_process(pcb: Arc<ProcessControlBlock>) {
     let hartid = hart_id();
+    CPUS[hartid].write().set_executing_process(pcb.clone());
+    unsafe { *CURRENT_PROCESSES[hartid].get() = Some(pcb) };
+}
+
+/// the calling hart no longer runs its current process
+pub fn take_current_process() -> Option<Arc<ProcessControlBlock>> {
+    let hartid = hart_id();
+    unsafe { (*CURRENT_PROCESSES[hartid].get()).take() };
+    CPUS[hartid].write().take_executing_process()
+}
+
+/// Returns the current process of the calling CPU.
+/// It takes no lock, other harts should look at `CPUS` instead
+pub fn current_process() -> Option<Arc<ProcessControlBlock>> {
+    unsafe { (*CURRENT_PROCESSES[hart_id()].get()).clone() }
+}
+
+/// like `current_process`, it never waits, e.g. when panicking with `CPUS` locked
+pub fn try_current_process() -> Option<Arc<ProcessControlBlock>> {
+    current_process()
+}
+
+/// times a hart found a ready queue locked by another, to measure the contention
+static READY_QUEUE_SPINS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn ready_queue_spins() -> usize {
+    READY_QUEUE_SPINS.load(Ordering::Relaxed)
+}
+
+fn with_ready_queue<T>(
+    hartid: usize,
+    f: impl FnOnce(&mut VecDeque<Arc<ProcessControlBlock>>) -> T,
+) -> T {
     let cpu = CPUS[hartid].read();
-    assert_eq!(cpu.hartid, hartid);
-    let pcb = cpu.running_process.as_ref()?;
-    Some(pcb.clone())
+    let mut queue = loop {
+        match cpu.ready_queue.try_lock() {
+            Some(queue) => break queue,
+            None => {
+                READY_QUEUE_SPINS.fetch_add(1, Ordering::Relaxed);
+                spin_loop();
+            }
+        }
+    };
+    f(&mut queue)
+}
+
+/// make a `RUNNABLE` process wait on the ready queue of the calling hart
+pub fn push_ready(pcb: Arc<ProcessControlBlock>) {
+    with_ready_queue(hart_id(), |queue| queue.push_back(pcb));
+}
+
+/// Take `pcb` out of whichever ready queue it waits on, e.g. when it exits
+/// before running again. `None` if it is in none of them
+pub fn remove_ready(pcb: &Arc<ProcessControlBlock>) -> Option<Arc<ProcessControlBlock>> {
+    (0..N_CPUS).find_map(|hartid| {
+        with_ready_queue(hartid, |queue| {
+            let i = queue.iter().position(|other| Arc::ptr_eq(other, pcb))?;
+            queue.remove(i)
+        })
+    })
+}
+
+/// whether the calling hart has another process to run
+pub fn has_ready() -> bool {
+    with_ready_queue(hart_id(), |queue| !queue.is_empty())
+}
+
+/// the next process for the calling hart, see `pop_ready_on`
+pub fn pop_ready() -> Option<Arc<ProcessControlBlock>> {
+    pop_ready_on(hart_id())
+}
+
+/// The first process of the highest effective priority, so that the processes
+/// of the same priority take turns as they are pushed back
+fn pop_highest(queue: &mut VecDeque<Arc<ProcessControlBlock>>) -> Option<Arc<ProcessControlBlock>> {
+    let mut highest: Option<(usize, u8)> = None;
+    for (i, pcb) in queue.iter().enumerate() {
+        let priority = pcb.inner.read().effective_priority();
+        if highest.map_or(true, |(_, p)| priority > p) {
+            highest = Some((i, priority));
+        }
+    }
+    queue.remove(highest?.0)
+}
+
+/// The next process of the ready queue of `hartid`, see `pop_highest`.
+/// If it is empty, half of the busiest queue of the other harts is stolen
+fn pop_ready_on(hartid: usize) -> Option<Arc<ProcessControlBlock>> {
+    if let Some(pcb) = with_ready_queue(hartid, pop_highest) {
+        return Some(pcb);
+    }
+
+    let busiest = (0..N_CPUS)
+        .filter(|&other| other != hartid)
+        .map(|other| (other, with_ready_queue(other, |queue| queue.len())))
+        .max_by_key(|&(_, len)| len)?;
+    if busiest.1 == 0 {
+        return None;
+    }
+    // only one queue is locked at a time, it may have changed since
+    let mut stolen = with_ready_queue(busiest.0, |queue| {
+        let n = queue.len().div_ceil(2);
+        queue.split_off(queue.len() - n)
+    });
+    let next = pop_highest(&mut stolen);
+    with_ready_queue(hartid, |queue| queue.append(&mut stolen));
+    next
+}
+
+/// Raise the priority of every process waiting on a ready queue by one level,
+/// so that those of a low priority are not starved by busy ones of a higher priority.
+/// It is reset once they run, see `process::schedule`
+pub fn age_ready() {
+    (0..N_CPUS).for_each(age_ready_on);
+}
+
+fn age_ready_on(hartid: usize) {
+    with_ready_queue(hartid, |queue| {
+        for pcb in queue.iter() {
+            let mut inner = pcb.inner.write();
+            inner.age = inner.age.saturating_add(1);
+        }
+    });
+}
+
+/// an idle hart should take half of the busiest queue, the oldest process first
+pub fn test_work_stealing() {
+    // harts other than 0 are idle in qemu with `CPUS=1`
+    let (idle, busy) = (N_CPUS - 2, N_CPUS - 1);
+    let pids = [1000, 1001, 1002, 1003];
+    for pid in pids {
+        let pcb = Arc::new(ProcessControlBlock::allocate(pid));
+        with_ready_queue(busy, |queue| queue.push_back(pcb));
+    }
+
+    // the later two are stolen
+    assert_eq!(pop_ready_on(idle).unwrap().get_pid(), pids[2]);
+    assert_eq!(with_ready_queue(idle, |queue| queue.len()), 1);
+    assert_eq!(with_ready_queue(busy, |queue| queue.len()), 2);
+
+    assert_eq!(pop_ready_on(idle).unwrap().get_pid(), pids[3]);
+    assert_eq!(pop_ready_on(busy).unwrap().get_pid(), pids[0]);
+    assert_eq!(pop_ready_on(busy).unwrap().get_pid(), pids[1]);
+}
+
+/// A high priority busy process should run more often than a low priority one,
+/// which still runs once it has aged enough
+pub fn test_priority_scheduling() {
+    const N_TICKS: usize = 100;
+    const AGING_INTERVAL: usize = 4;
+    let hartid = N_CPUS - 1;
+    let high = Arc::new(ProcessControlBlock::allocate(4000));
+    let low = Arc::new(ProcessControlBlock::allocate(4001));
+    high.inner.write().priority = MAX_PRIORITY;
+    low.inner.write().priority = MIN_PRIORITY;
+    for pcb in [low.clone(), high.clone()] {
+        with_ready_queue(hartid, |queue| queue.push_back(pcb));
+    }
+
+    // what `process::preempt` and `process::schedule` do on every tick
+    let mut ran = [0; 2];
+    for tick in 1..=N_TICKS {
+        let pcb = pop_ready_on(hartid).unwrap();
+        pcb.inner.write().age = 0;
+        ran[pcb.get_pid() - 4000] += 1;
+        with_ready_queue(hartid, |queue| queue.push_back(pcb));
+        if tick % AGING_INTERVAL == 0 {
+            age_ready_on(hartid);
+        }
+    }
+    info!(
+        "cpu::test_priority_scheduling: high ran {:?} ticks, low ran {:?} ticks",
+        ran[0], ran[1]
+    );
+    assert!(ran[0] > ran[1]);
+    assert!(ran[1] > 0);
+
+    // the same priority takes turns
+    low.inner.write().priority = MAX_PRIORITY;
+    let first = pop_ready_on(hartid).unwrap();
+    with_ready_queue(hartid, |queue| queue.push_back(first.clone()));
+    let second = pop_ready_on(hartid).unwrap();
+    assert!(!Arc::ptr_eq(&first, &second));
+    with_ready_queue(hartid, |queue| queue.clear());
+}
+
+/// Popping for two harts in turn should hand each a process of its own.
+/// It runs on the calling hart only, it checks the bookkeeping of the ready queues
+/// and `CPUS`, not harts actually scheduling at once
+pub fn test_pop_ready_per_hart() {
+    let (first, second) = (N_CPUS - 2, N_CPUS - 1);
+    for pid in [2000, 2001] {
+        let pcb = Arc::new(ProcessControlBlock::allocate(pid));
+        with_ready_queue(first, |queue| queue.push_back(pcb));
+    }
+
+    // what `process::schedule` does on each of them
+    for hartid in [first, second] {
+        let pcb = pop_ready_on(hartid).unwrap();
+        CPUS[hartid].write().set_executing_process(pcb);
+    }
+    let a = CPUS[first].write().take_executing_process().unwrap();
+    let b = CPUS[second].write().take_executing_process().unwrap();
+    assert_eq!((a.get_pid(), b.get_pid()), (2000, 2001));
+    for hartid in [first, second] {
+        assert!(with_ready_queue(hartid, |queue| queue.is_empty()));
+    }
+}
+
+/// `current_process` should not take the lock of `CPUS`, and be cheaper than taking it
+pub fn test_current_process_lock_free() {
+    const N_CALLS: usize = 10_000;
+    let previous = take_current_process();
+    let pcb = Arc::new(ProcessControlBlock::allocate(3000));
+    set_current_process(pcb.clone());
+    {
+        // it would spin forever if it took the lock
+        let _cpu = CPUS[hart_id()].write();
+        assert!(Arc::ptr_eq(&current_process().unwrap(), &pcb));
+    }
+
+    // what every trap did before
+    let start = arch::ticks();
+    for _ in 0..N_CALLS {
+        let cpu = CPUS[hart_id()].read();
+        core::hint::black_box(cpu.running_process.clone());
+    }
+    let locked = arch::ticks() - start;
+    let start = arch::ticks();
+    for _ in 0..N_CALLS {
+        core::hint::black_box(current_process());
+    }
+    let cached = arch::ticks() - start;
+    info!(
+        "cpu::test_current_process_lock_free: {:?} calls, {:?} ticks locked, {:?} ticks cached",
+        N_CALLS, locked, cached
+    );
+
+    assert!(Arc::ptr_eq(&take_current_process().unwrap(), &pcb));
+    assert!(current_process().is_none());
+    if let Some(previous) = previous {
+        set_current_process(previous);
+    }
 }
@@ -0,0 +1,51 @@
+//! Device files: inodes tagged with a (major, minor) device number,
+//! their reads and writes go to the driver registered for the major number
+
+use crate::uart;
+
+use super::procfs;
+
+/// the UART, there is only minor 0
+pub const CONSOLE_MAJOR: u16 = 1;
+/// files generated on read, see `fs/procfs.rs`
+pub const PROC_MAJOR: u16 = 2;
+const N_DEVICES: usize = 4;
+
+/// the functions of a driver, they are given the minor number of the device file
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    /// reads from the offset of the open file, which the console ignores.
+    /// Returns the number of bytes read, see `FileHandle::read`
+    pub read: fn(u16, usize, &mut [u8]) -> usize,
+    /// returns the number of bytes written
+    pub write: fn(u16, &[u8]) -> usize,
+    /// whether a read has to wait, see `FileHandle::read_would_block`
+    pub read_would_block: fn(u16) -> bool,
+    /// the channel to sleep on while a read has to wait, the driver wakes it up
+    pub wait_channel: fn(u16) -> usize,
+}
+
+/// indexed by the major number
+static DEVICES: [Option<Device>; N_DEVICES] = [
+    None,
+    Some(Device {
+        read: |_, _, buf| uart::read_line(buf),
+        write: |_, buf| uart::write(buf),
+        read_would_block: |_| uart::line_would_block(),
+        wait_channel: |_| uart::input_channel(),
+    }),
+    Some(Device {
+        read: procfs::read,
+        // read-only
+        write: |_, _| 0,
+        // generated right away
+        read_would_block: |_| false,
+        wait_channel: |_| 0,
+    }),
+    None,
+];
+
+/// the driver of `major`, `None` if there is none
+pub fn lookup(major: u16) -> Option<Device> {
+    *DEVICES.get(major as usize)?
+}
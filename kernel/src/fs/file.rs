@@ -0,0 +1,288 @@
+//! Open files referred to by the file descriptors of a process
+
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use crate::process::{self, manager::with_test_process};
+
+use super::{
+    device::{self, Device, CONSOLE_MAJOR},
+    inode::{self, InodeRef, InodeType},
+    pipe::Pipe,
+    stat::Stat,
+};
+
+// flags of `open`, the same as xv6
+pub const O_RDONLY: usize = 0x000;
+pub const O_WRONLY: usize = 0x001;
+pub const O_RDWR: usize = 0x002;
+pub const O_CREATE: usize = 0x200;
+
+#[derive(Debug)]
+pub enum File {
+    /// `inode` is the device file it is opened from, if any
+    Device {
+        device: Device,
+        minor: u16,
+        inode: Option<InodeRef>,
+    },
+    Inode(InodeRef),
+    PipeRead(Arc<Mutex<Pipe>>),
+    PipeWrite(Arc<Mutex<Pipe>>),
+}
+
+/// An open file. `dup` and `fork` share it, together with its offset
+#[derive(Debug)]
+pub struct FileHandle {
+    file: File,
+    offset: Mutex<usize>,
+    readable: bool,
+    writable: bool,
+}
+
+impl FileHandle {
+    /// the console device without a device file, e.g. for the standard fds of a new process
+    pub fn console() -> Arc<Self> {
+        Arc::new(Self {
+            file: File::Device {
+                device: device::lookup(CONSOLE_MAJOR).unwrap(),
+                minor: 0,
+                inode: None,
+            },
+            offset: Mutex::new(0),
+            readable: true,
+            writable: true,
+        })
+    }
+
+    /// Open `inode` with the access mode in the `open` flags,
+    /// a device file goes to its driver. `None` if it has no driver
+    pub fn open(inode: InodeRef, flags: usize) -> Option<Arc<Self>> {
+        let Some((major, minor)) = inode.device() else {
+            return Some(Self::from_inode(inode, flags));
+        };
+        let mode = flags & (O_WRONLY | O_RDWR);
+        Some(Arc::new(Self {
+            file: File::Device {
+                device: device::lookup(major)?,
+                minor,
+                inode: Some(inode),
+            },
+            offset: Mutex::new(0),
+            readable: mode != O_WRONLY,
+            writable: mode != O_RDONLY,
+        }))
+    }
+
+    /// open the regular file or directory `inode` with the access mode in the `open` flags
+    pub fn from_inode(inode: InodeRef, flags: usize) -> Arc<Self> {
+        let mode = flags & (O_WRONLY | O_RDWR);
+        Arc::new(Self {
+            file: File::Inode(inode),
+            offset: Mutex::new(0),
+            readable: mode != O_WRONLY,
+            writable: mode != O_RDONLY,
+        })
+    }
+
+    /// the read end and the write end of a new pipe
+    pub fn pipe() -> (Arc<Self>, Arc<Self>) {
+        let pipe = Pipe::new();
+        let read_end = Arc::new(Self {
+            file: File::PipeRead(pipe.clone()),
+            offset: Mutex::new(0),
+            readable: true,
+            writable: false,
+        });
+        let write_end = Arc::new(Self {
+            file: File::PipeWrite(pipe),
+            offset: Mutex::new(0),
+            readable: false,
+            writable: true,
+        });
+        (read_end, write_end)
+    }
+
+    /// whether a read has to wait for a writer or the driver, see `FileHandle::read`
+    pub fn read_would_block(&self) -> bool {
+        match &self.file {
+            File::Device { device, minor, .. } => (device.read_would_block)(*minor),
+            File::PipeRead(pipe) => pipe.lock().read_would_block(),
+            _ => false,
+        }
+    }
+
+    /// whether a write has to wait for a reader, see `FileHandle::write`
+    pub fn write_would_block(&self) -> bool {
+        match &self.file {
+            File::PipeWrite(pipe) => pipe.lock().write_would_block(),
+            _ => false,
+        }
+    }
+
+    /// metadata of the inode, `None` if it is not on the disk, e.g. the console of `console()`
+    pub fn stat(&self) -> Option<Stat> {
+        match &self.file {
+            File::Inode(inode)
+            | File::Device {
+                inode: Some(inode), ..
+            } => Some(inode.stat()),
+            _ => None,
+        }
+    }
+
+    /// the channel to sleep on when a read or write would block,
+    /// woken up by the other end or the driver
+    pub fn wait_channel(&self) -> usize {
+        match &self.file {
+            File::Device { device, minor, .. } => (device.wait_channel)(*minor),
+            File::PipeRead(pipe) | File::PipeWrite(pipe) => pipe_channel(pipe),
+            _ => self as *const Self as usize,
+        }
+    }
+
+    /// Read into `buf` from the current offset.
+    /// Returns the number of bytes read, 0 at the end of the file,
+    /// `None` if the file is not readable.
+    /// Reading a device is up to its driver, e.g. the console hands out complete lines only.
+    /// Reading a pipe or a device does not wait, check `read_would_block` first
+    pub fn read(&self, buf: &mut [u8]) -> Option<usize> {
+        if !self.readable {
+            return None;
+        }
+        match &self.file {
+            File::Device { device, minor, .. } => {
+                let mut offset = self.offset.lock();
+                let n = (device.read)(*minor, *offset, buf);
+                *offset += n;
+                Some(n)
+            }
+            File::Inode(inode) => {
+                let mut offset = self.offset.lock();
+                let n = inode.read_at(*offset, buf);
+                *offset += n;
+                Some(n)
+            }
+            File::PipeRead(pipe) => {
+                let n = pipe.lock().read(buf);
+                // writers waiting for room
+                process::wakeup(pipe_channel(pipe));
+                Some(n)
+            }
+            File::PipeWrite(_) => unreachable!(),
+        }
+    }
+
+    /// Write `buf` at the current offset.
+    /// Returns the number of bytes written, `None` if the file is not writable.
+    /// Writing a pipe does not wait, check `write_would_block` first
+    pub fn write(&self, buf: &[u8]) -> Option<usize> {
+        if !self.writable {
+            return None;
+        }
+        match &self.file {
+            File::Device { device, minor, .. } => Some((device.write)(*minor, buf)),
+            File::Inode(inode) => {
+                let mut offset = self.offset.lock();
+                let n = inode.write_at(*offset, buf);
+                *offset += n;
+                Some(n)
+            }
+            File::PipeWrite(pipe) => {
+                let n = pipe.lock().write(buf);
+                // readers waiting for data
+                process::wakeup(pipe_channel(pipe));
+                n
+            }
+            File::PipeRead(_) => unreachable!(),
+        }
+    }
+}
+
+fn pipe_channel(pipe: &Arc<Mutex<Pipe>>) -> usize {
+    Arc::as_ptr(pipe) as usize
+}
+
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        // the last descriptor referring to this end is closed,
+        // the other end no longer waits for it
+        match &self.file {
+            File::PipeRead(pipe) => pipe.lock().close_read(),
+            File::PipeWrite(pipe) => pipe.lock().close_write(),
+            _ => return,
+        }
+        process::wakeup(self.wait_channel());
+    }
+}
+
+/// descriptors duplicated by `dup` should share the read position
+pub fn test_dup_shares_offset() {
+    const NAME: &str = "dup_test";
+    let content = b"0123456789";
+    // left over by an earlier run
+    inode::root().unlink(NAME);
+    let inode = inode::root().create(NAME, InodeType::File).unwrap();
+    inode.write_at(0, content);
+
+    with_test_process(|pcb| {
+        let mut inner = pcb.inner.write();
+        let fd = inner
+            .alloc_fd(FileHandle::from_inode(inode, O_RDONLY))
+            .unwrap();
+        let dup_fd = inner.dup_fd(fd).unwrap();
+        assert_ne!(fd, dup_fd);
+
+        let mut buf = [0; 4];
+        assert_eq!(inner.get_file(fd).unwrap().read(&mut buf), Some(4));
+        assert_eq!(&buf, &content[0..4]);
+        assert_eq!(inner.get_file(dup_fd).unwrap().read(&mut buf), Some(4));
+        assert_eq!(&buf, &content[4..8]);
+    });
+
+    inode::root().unlink(NAME).unwrap();
+}
+
+/// `fstat` should report the size of what has been written
+pub fn test_stat_reports_size() {
+    const NAME: &str = "stat_test";
+    let content = [0xAB; 1000];
+    // left over by an earlier run
+    inode::root().unlink(NAME);
+    let inode = inode::root().create(NAME, InodeType::File).unwrap();
+    let file = FileHandle::from_inode(inode.clone(), O_RDWR);
+    assert_eq!(file.write(&content), Some(content.len()));
+
+    let stat = file.stat().unwrap();
+    assert_eq!(stat.ino, inode.get_inum());
+    assert_eq!(stat.get_type(), Some(InodeType::File));
+    assert_eq!(stat.nlink, 1);
+    assert_eq!(stat.size, content.len() as u64);
+    assert!(FileHandle::console().stat().is_none());
+    inode::root().unlink(NAME).unwrap();
+}
+
+/// a device file should open as its driver, and not be created twice
+pub fn test_device_file() {
+    const PATH: &str = "/console_test";
+    const NO_DRIVER: &str = "/nodev_test";
+    // left over by an earlier run
+    inode::unlink(PATH);
+    inode::unlink(NO_DRIVER);
+    let inode = inode::mknod(PATH, CONSOLE_MAJOR, 0).unwrap();
+    assert!(inode::mknod(PATH, CONSOLE_MAJOR, 0).is_none());
+    assert_eq!(inode.device(), Some((CONSOLE_MAJOR, 0)));
+
+    let file = FileHandle::open(inode.clone(), O_WRONLY).unwrap();
+    assert!(matches!(file.file, File::Device { minor: 0, .. }));
+    assert_eq!(file.stat().unwrap().get_type(), Some(InodeType::Device));
+    assert_eq!(file.write(b""), Some(0));
+    assert_eq!(file.read(&mut [0; 1]), None);
+
+    // no driver for it
+    let inode = inode::mknod(NO_DRIVER, u16::MAX, 0).unwrap();
+    assert!(FileHandle::open(inode, O_RDWR).is_none());
+
+    inode::unlink(PATH).unwrap();
+    inode::unlink(NO_DRIVER).unwrap();
+}
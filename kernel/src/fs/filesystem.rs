@@ -0,0 +1,325 @@
+//! An xv6-style file system on the virtio block device.
+//!
+//! Disk layout, in `BLOCK_SIZE` blocks (see `fs/fs.cpp` for `mkfs`):
+//! [ boot | super | inodes ... | bitmap ... | data ... ]
+//!
+//! There is no block cache, every access goes to the disk.
+//! It is single-threaded: everything goes through the `FILE_SYSTEM` lock
+
+use core::mem::size_of;
+
+use alloc::{vec, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{error, info, warn};
+
+use super::{
+    inode::{DiskInode, InodeType},
+    virtio_blk::{BLOCK_DEVICE, BLOCK_SIZE},
+};
+
+pub const FS_MAGIC: u32 = 0x1020_3040;
+pub const SUPERBLOCK_NO: u32 = 1;
+pub const ROOT_INUM: u32 = 1;
+pub const N_INODES: u32 = 200;
+
+pub const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / size_of::<DiskInode>()) as u32;
+pub const BITS_PER_BLOCK: u32 = (BLOCK_SIZE * 8) as u32;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SuperBlock {
+    pub magic: u32,
+    /// size of the file system image in blocks
+    pub size: u32,
+    /// number of data blocks
+    pub n_blocks: u32,
+    pub n_inodes: u32,
+    /// block number of the first inode block
+    pub inode_start: u32,
+    /// block number of the first bitmap block
+    pub bitmap_start: u32,
+}
+
+impl SuperBlock {
+    /// layout of a file system of `size` blocks, `None` if there is no room for a data block
+    pub fn new(size: u32) -> Option<Self> {
+        let n_inode_blocks = N_INODES / INODES_PER_BLOCK + 1;
+        let n_bitmap_blocks = size / BITS_PER_BLOCK + 1;
+        let inode_start = SUPERBLOCK_NO + 1;
+        let bitmap_start = inode_start + n_inode_blocks;
+        let n_blocks = size.checked_sub(bitmap_start + n_bitmap_blocks)?;
+        if n_blocks == 0 {
+            return None;
+        }
+        Some(Self {
+            magic: FS_MAGIC,
+            size,
+            n_blocks,
+            n_inodes: N_INODES,
+            inode_start,
+            bitmap_start,
+        })
+    }
+
+    /// whether it is the layout `mkfs` would have made for a disk of `capacity` blocks,
+    /// a superblock read from a corrupted disk may have anything in it
+    pub fn is_consistent(&self, capacity: u32) -> bool {
+        self.size <= capacity && Self::new(self.size).as_ref() == Some(self)
+    }
+
+    /// the first data block
+    pub fn data_start(&self) -> u32 {
+        self.size - self.n_blocks
+    }
+
+    /// the block holding inode `inum`
+    pub fn inode_block(&self, inum: u32) -> u32 {
+        self.inode_start + inum / INODES_PER_BLOCK
+    }
+
+    /// the bitmap block holding the bit of block `block_no`
+    pub fn bitmap_block(&self, block_no: u32) -> u32 {
+        self.bitmap_start + block_no / BITS_PER_BLOCK
+    }
+}
+
+/// read a `T` stored at `offset` of a block
+pub fn read_struct<T: Copy>(block: &[u8], offset: usize) -> T {
+    assert!(offset + size_of::<T>() <= block.len());
+    unsafe { (block.as_ptr().add(offset) as *const T).read_unaligned() }
+}
+
+/// store `val` at `offset` of a block
+pub fn write_struct<T: Copy>(block: &mut [u8], offset: usize, val: &T) {
+    assert!(offset + size_of::<T>() <= block.len());
+    unsafe { (block.as_mut_ptr().add(offset) as *mut T).write_unaligned(*val) }
+}
+
+/// a zeroed block buffer. Blocks are kept on the heap since the kernel stack is small
+pub fn block_buf() -> Vec<u8> {
+    vec![0; BLOCK_SIZE]
+}
+
+pub struct FileSystem {
+    sb: SuperBlock,
+}
+
+impl FileSystem {
+    pub fn read_block(&self, block_no: u32, buf: &mut [u8]) {
+        BLOCK_DEVICE.lock().read_block(block_no as usize, buf);
+    }
+
+    pub fn write_block(&self, block_no: u32, buf: &[u8]) {
+        BLOCK_DEVICE.lock().write_block(block_no as usize, buf);
+    }
+
+    pub fn superblock(&self) -> &SuperBlock {
+        &self.sb
+    }
+
+    /// Read the superblock. A disk without a file system on it is only formatted
+    /// when asked for with the `format_disk` feature, it may hold something else.
+    /// `None` if the superblock is corrupted, there is no file system and formatting
+    /// is not asked for, or the disk is too small to be formatted
+    fn open() -> Option<Self> {
+        let mut buf = block_buf();
+        let size = {
+            let mut device = BLOCK_DEVICE.lock();
+            device.read_block(SUPERBLOCK_NO as usize, &mut buf);
+            u32::try_from(device.capacity()).ok()?
+        };
+        let sb: SuperBlock = read_struct(&buf, 0);
+        if sb.magic == FS_MAGIC {
+            return sb.is_consistent(size).then_some(Self { sb });
+        }
+
+        if !cfg!(feature = "format_disk") {
+            error!(
+                "FileSystem::open: no file system found, magic {:#x}, build with `format_disk` to format it",
+                sb.magic
+            );
+            return None;
+        }
+        warn!(
+            "FileSystem::open: no file system found, magic {:#x}, formatting {:?} blocks",
+            sb.magic, size
+        );
+        Self::format(size)
+    }
+
+    /// make an empty file system with only the root directory
+    fn format(size: u32) -> Option<Self> {
+        let sb = SuperBlock::new(size)?;
+        let mut zelf = Self { sb };
+
+        // clear the inodes and the bitmap
+        let zeroed = block_buf();
+        for block_no in sb.inode_start..sb.data_start() {
+            zelf.write_block(block_no, &zeroed);
+        }
+        // the blocks before the data blocks are never allocated
+        for block_no in 0..sb.data_start() {
+            zelf.set_allocated(block_no, true);
+        }
+
+        let mut buf = block_buf();
+        write_struct(&mut buf, 0, &sb);
+        zelf.write_block(SUPERBLOCK_NO, &buf);
+
+        // the root directory is its own parent
+        let root = zelf
+            .alloc_inode(InodeType::Directory)
+            .expect("FileSystem::format: cannot allocate the root inode");
+        assert_eq!(root, ROOT_INUM);
+        let mut root_inode = zelf.read_inode(root);
+        root_inode.nlink = 1;
+        root_inode
+            .dir_link(&mut zelf, ".", root)
+            .and_then(|_| root_inode.dir_link(&mut zelf, "..", root))
+            .expect("FileSystem::format: cannot make the root directory");
+        zelf.write_inode(root, &root_inode);
+        Some(zelf)
+    }
+
+    fn set_allocated(&mut self, block_no: u32, allocated: bool) {
+        let mut buf = block_buf();
+        let bitmap_block = self.sb.bitmap_block(block_no);
+        self.read_block(bitmap_block, &mut buf);
+        let bit = block_no % BITS_PER_BLOCK;
+        let byte = &mut buf[bit as usize / 8];
+        let mask = 1 << (bit % 8);
+        if allocated {
+            *byte |= mask;
+        } else {
+            assert!(
+                *byte & mask != 0,
+                "FileSystem::free_block: freeing free block"
+            );
+            *byte &= !mask;
+        }
+        self.write_block(bitmap_block, &buf);
+    }
+
+    /// allocate a zeroed data block, `None` if the disk is full
+    pub fn alloc_block(&mut self) -> Option<u32> {
+        let mut buf = block_buf();
+        let mut bitmap_block = None;
+        for block_no in self.sb.data_start()..self.sb.size {
+            // only read the bitmap block when we move onto a new one
+            if bitmap_block != Some(self.sb.bitmap_block(block_no)) {
+                bitmap_block = Some(self.sb.bitmap_block(block_no));
+                self.read_block(bitmap_block.unwrap(), &mut buf);
+            }
+            let bit = block_no % BITS_PER_BLOCK;
+            if buf[bit as usize / 8] & (1 << (bit % 8)) == 0 {
+                self.set_allocated(block_no, true);
+                self.write_block(block_no, &block_buf());
+                return Some(block_no);
+            }
+        }
+        None
+    }
+
+    /// number of data blocks not allocated
+    pub fn n_free_blocks(&self) -> usize {
+        let mut buf = block_buf();
+        let mut bitmap_block = None;
+        let mut n_free = 0;
+        for block_no in self.sb.data_start()..self.sb.size {
+            if bitmap_block != Some(self.sb.bitmap_block(block_no)) {
+                bitmap_block = Some(self.sb.bitmap_block(block_no));
+                self.read_block(bitmap_block.unwrap(), &mut buf);
+            }
+            let bit = block_no % BITS_PER_BLOCK;
+            if buf[bit as usize / 8] & (1 << (bit % 8)) == 0 {
+                n_free += 1;
+            }
+        }
+        n_free
+    }
+
+    pub fn free_block(&mut self, block_no: u32) {
+        assert!(block_no >= self.sb.data_start() && block_no < self.sb.size);
+        self.set_allocated(block_no, false);
+    }
+
+    pub fn read_inode(&self, inum: u32) -> DiskInode {
+        assert!(
+            inum < self.sb.n_inodes,
+            "FileSystem::read_inode: invalid inum"
+        );
+        let mut buf = block_buf();
+        self.read_block(self.sb.inode_block(inum), &mut buf);
+        let offset = (inum % INODES_PER_BLOCK) as usize * size_of::<DiskInode>();
+        read_struct(&buf, offset)
+    }
+
+    pub fn write_inode(&self, inum: u32, inode: &DiskInode) {
+        assert!(
+            inum < self.sb.n_inodes,
+            "FileSystem::write_inode: invalid inum"
+        );
+        let mut buf = block_buf();
+        let block_no = self.sb.inode_block(inum);
+        self.read_block(block_no, &mut buf);
+        let offset = (inum % INODES_PER_BLOCK) as usize * size_of::<DiskInode>();
+        write_struct(&mut buf, offset, inode);
+        self.write_block(block_no, &buf);
+    }
+
+    /// allocate a free inode of `type_`, `None` if there is none left.
+    /// inode 0 is never used, an empty directory entry refers to it
+    pub fn alloc_inode(&mut self, type_: InodeType) -> Option<u32> {
+        for inum in 1..self.sb.n_inodes {
+            let inode = self.read_inode(inum);
+            if inode.get_type() == Some(InodeType::Free) {
+                self.write_inode(inum, &DiskInode::new(type_));
+                return Some(inum);
+            }
+        }
+        None
+    }
+}
+
+lazy_static! {
+    pub static ref FILE_SYSTEM: Mutex<FileSystem> = Mutex::new(
+        FileSystem::open().expect("FileSystem::open: no usable file system on the disk")
+    );
+}
+
+pub fn init() {
+    let sb = *FILE_SYSTEM.lock().superblock();
+    info!(
+        "file system initialised: {:?} blocks, {:?} data blocks, {:?} inodes",
+        sb.size, sb.n_blocks, sb.n_inodes
+    );
+}
+
+/// a disk too small for the metadata has no layout, and a superblock is only
+/// trusted if it is the layout of its own size
+pub fn test_superblock_layout() {
+    assert!(SuperBlock::new(0).is_none());
+    assert!(SuperBlock::new(SUPERBLOCK_NO + 1).is_none());
+
+    let sb = *FILE_SYSTEM.lock().superblock();
+    assert!(sb.is_consistent(sb.size));
+    assert!(!sb.is_consistent(sb.size - 1));
+    for corrupted in [
+        SuperBlock {
+            n_blocks: sb.size,
+            ..sb
+        },
+        SuperBlock {
+            inode_start: 0,
+            ..sb
+        },
+        SuperBlock {
+            n_inodes: u32::MAX,
+            ..sb
+        },
+    ] {
+        assert!(!corrupted.is_consistent(sb.size));
+    }
+}
@@ -0,0 +1,826 @@
+//! Inodes and directories of the file system, see `fs/filesystem.rs` for the disk layout
+
+use core::mem::size_of;
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use primitive_enum::primitive_enum;
+use spin::Mutex;
+
+use crate::{cpu, process::manager::with_test_process};
+
+use super::{
+    filesystem::{block_buf, read_struct, write_struct, FileSystem, FILE_SYSTEM, ROOT_INUM},
+    stat::Stat,
+    virtio_blk::BLOCK_SIZE,
+};
+
+/// number of blocks addressed directly by the inode
+pub const N_DIRECT: usize = 11;
+/// number of blocks addressed by an indirect block
+pub const N_INDIRECT: usize = BLOCK_SIZE / size_of::<u32>();
+pub const MAX_FILE_BLOCKS: usize = N_DIRECT + N_INDIRECT + N_INDIRECT * N_INDIRECT;
+
+/// the maximum length of a file name in a directory entry
+pub const DIR_NAME_LEN: usize = 14;
+
+primitive_enum! {
+InodeType u16;
+    Free = 0,
+    Directory = 1,
+    File = 2,
+    Device = 3,
+}
+
+/// On-disk inode.
+/// `addrs[N_DIRECT]` is the indirect block, `addrs[N_DIRECT + 1]` the doubly-indirect one
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct DiskInode {
+    pub type_: u16,
+    pub major: u16,
+    pub minor: u16,
+    pub nlink: u16,
+    pub size: u32,
+    pub addrs: [u32; N_DIRECT + 2],
+}
+
+/// On-disk directory entry, an entry with inum 0 is free
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct DirEntry {
+    pub inum: u16,
+    pub name: [u8; DIR_NAME_LEN],
+}
+
+const DIR_ENTRY_SIZE: usize = size_of::<DirEntry>();
+
+impl DirEntry {
+    fn new(name: &str, inum: u32) -> Self {
+        let mut entry = Self {
+            inum: inum as u16,
+            name: [0; DIR_NAME_LEN],
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry
+    }
+
+    fn empty() -> Self {
+        Self {
+            inum: 0,
+            name: [0; DIR_NAME_LEN],
+        }
+    }
+
+    /// the name without its trailing zeros
+    pub fn name(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(DIR_NAME_LEN);
+        &self.name[..len]
+    }
+}
+
+impl DiskInode {
+    pub fn new(type_: InodeType) -> Self {
+        Self {
+            type_: type_ as u16,
+            major: 0,
+            minor: 0,
+            nlink: 0,
+            size: 0,
+            addrs: [0; N_DIRECT + 2],
+        }
+    }
+
+    /// `None` if the type on the disk is corrupted
+    pub fn get_type(&self) -> Option<InodeType> {
+        InodeType::from(self.type_)
+    }
+
+    /// the disk block holding the `n`th block of the file, `None` if it has not been allocated
+    fn block_of(&self, fs: &FileSystem, n: usize) -> Option<u32> {
+        let nonzero = |addr: u32| if addr == 0 { None } else { Some(addr) };
+        let indirect = |block_no: u32, idx: usize| {
+            let mut buf = block_buf();
+            fs.read_block(block_no, &mut buf);
+            nonzero(read_struct(&buf, idx * size_of::<u32>()))
+        };
+
+        if n < N_DIRECT {
+            return nonzero(self.addrs[n]);
+        }
+        let n = n - N_DIRECT;
+        if n < N_INDIRECT {
+            return indirect(nonzero(self.addrs[N_DIRECT])?, n);
+        }
+        let n = n - N_INDIRECT;
+        if n < N_INDIRECT * N_INDIRECT {
+            let level_1 = indirect(nonzero(self.addrs[N_DIRECT + 1])?, n / N_INDIRECT)?;
+            return indirect(level_1, n % N_INDIRECT);
+        }
+        None
+    }
+
+    /// like `block_of` but allocates the missing blocks,
+    /// `None` if the disk is full or the file is too large
+    fn block_of_allocate(&mut self, fs: &mut FileSystem, n: usize) -> Option<u32> {
+        fn slot(fs: &mut FileSystem, addr: &mut u32) -> Option<u32> {
+            if *addr == 0 {
+                *addr = fs.alloc_block()?;
+            }
+            Some(*addr)
+        }
+        fn indirect(fs: &mut FileSystem, block_no: u32, idx: usize) -> Option<u32> {
+            let mut buf = block_buf();
+            fs.read_block(block_no, &mut buf);
+            let mut addr: u32 = read_struct(&buf, idx * size_of::<u32>());
+            if addr == 0 {
+                addr = fs.alloc_block()?;
+                write_struct(&mut buf, idx * size_of::<u32>(), &addr);
+                fs.write_block(block_no, &buf);
+            }
+            Some(addr)
+        }
+
+        if n < N_DIRECT {
+            return slot(fs, &mut self.addrs[n]);
+        }
+        let n = n - N_DIRECT;
+        if n < N_INDIRECT {
+            let level_0 = slot(fs, &mut self.addrs[N_DIRECT])?;
+            return indirect(fs, level_0, n);
+        }
+        let n = n - N_INDIRECT;
+        if n < N_INDIRECT * N_INDIRECT {
+            let level_0 = slot(fs, &mut self.addrs[N_DIRECT + 1])?;
+            let level_1 = indirect(fs, level_0, n / N_INDIRECT)?;
+            return indirect(fs, level_1, n % N_INDIRECT);
+        }
+        None
+    }
+
+    /// read from `offset` into `buf`, returns the number of bytes read, 0 at the end of the file
+    pub fn read_at(&self, fs: &FileSystem, offset: usize, buf: &mut [u8]) -> usize {
+        let size = self.size as usize;
+        if offset >= size {
+            return 0;
+        }
+        let end = size.min(offset + buf.len());
+
+        let mut block = block_buf();
+        let mut pos = offset;
+        while pos < end {
+            let block_offset = pos % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - block_offset).min(end - pos);
+            let dst = &mut buf[pos - offset..pos - offset + len];
+            match self.block_of(fs, pos / BLOCK_SIZE) {
+                Some(block_no) => {
+                    fs.read_block(block_no, &mut block);
+                    dst.copy_from_slice(&block[block_offset..block_offset + len]);
+                }
+                // never written
+                None => dst.fill(0),
+            }
+            pos += len;
+        }
+        end - offset
+    }
+
+    /// write `buf` at `offset`, growing the file if needed.
+    /// Returns the number of bytes written, which is less than `buf.len()` if the disk is full.
+    /// Writing beyond the end of the file is not allowed
+    pub fn write_at(&mut self, fs: &mut FileSystem, offset: usize, buf: &[u8]) -> usize {
+        if offset > self.size as usize {
+            return 0;
+        }
+        let end = (offset + buf.len()).min(MAX_FILE_BLOCKS * BLOCK_SIZE);
+
+        let mut block = block_buf();
+        let mut pos = offset;
+        while pos < end {
+            let Some(block_no) = self.block_of_allocate(fs, pos / BLOCK_SIZE) else {
+                break;
+            };
+            let block_offset = pos % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - block_offset).min(end - pos);
+            // a partially written block keeps the rest of its content
+            if len < BLOCK_SIZE {
+                fs.read_block(block_no, &mut block);
+            }
+            block[block_offset..block_offset + len]
+                .copy_from_slice(&buf[pos - offset..pos - offset + len]);
+            fs.write_block(block_no, &block);
+            pos += len;
+        }
+        self.size = self.size.max(pos as u32);
+        pos - offset
+    }
+
+    /// the inum of the entry `name` in this directory
+    pub fn dir_lookup(&self, fs: &FileSystem, name: &str) -> Option<u32> {
+        assert!(self.get_type() == Some(InodeType::Directory));
+        let mut buf = [0; DIR_ENTRY_SIZE];
+        for offset in (0..self.size as usize).step_by(DIR_ENTRY_SIZE) {
+            self.read_at(fs, offset, &mut buf);
+            let entry: DirEntry = read_struct(&buf, 0);
+            if entry.inum != 0 && entry.name() == name.as_bytes() {
+                return Some(entry.inum as u32);
+            }
+        }
+        None
+    }
+
+    /// the name of an entry referring to `inum` in this directory, other than `.` and `..`
+    pub fn dir_name_of(&self, fs: &FileSystem, inum: u32) -> Option<String> {
+        self.dir_entries(fs)
+            .iter()
+            .filter(|entry| entry.name() != b"." && entry.name() != b"..")
+            .find(|entry| entry.inum as u32 == inum)
+            .map(|entry| String::from_utf8_lossy(entry.name()).into_owned())
+    }
+
+    /// Remove the entry `name` from this directory, returns the inum it referred to.
+    /// The inode itself is left alone
+    pub fn dir_unlink(&mut self, fs: &mut FileSystem, name: &str) -> Option<u32> {
+        assert!(self.get_type() == Some(InodeType::Directory));
+        let mut buf = [0; DIR_ENTRY_SIZE];
+        for offset in (0..self.size as usize).step_by(DIR_ENTRY_SIZE) {
+            self.read_at(fs, offset, &mut buf);
+            let entry: DirEntry = read_struct(&buf, 0);
+            if entry.inum != 0 && entry.name() == name.as_bytes() {
+                write_struct(&mut buf, 0, &DirEntry::empty());
+                self.write_at(fs, offset, &buf);
+                return Some(entry.inum as u32);
+            }
+        }
+        None
+    }
+
+    /// free every data block, including the indirect ones, and empty the file
+    pub fn truncate(&mut self, fs: &mut FileSystem) {
+        // frees `block_no` and, `depth` levels down, the blocks it refers to
+        fn free_tree(fs: &mut FileSystem, block_no: u32, depth: usize) {
+            if block_no == 0 {
+                return;
+            }
+            if depth > 0 {
+                let mut buf = block_buf();
+                fs.read_block(block_no, &mut buf);
+                for idx in 0..N_INDIRECT {
+                    free_tree(fs, read_struct(&buf, idx * size_of::<u32>()), depth - 1);
+                }
+            }
+            fs.free_block(block_no);
+        }
+
+        for n in 0..N_DIRECT {
+            free_tree(fs, self.addrs[n], 0);
+        }
+        free_tree(fs, self.addrs[N_DIRECT], 1);
+        free_tree(fs, self.addrs[N_DIRECT + 1], 2);
+        self.addrs = [0; N_DIRECT + 2];
+        self.size = 0;
+    }
+
+    /// the entries in use of this directory, including `.` and `..`
+    pub fn dir_entries(&self, fs: &FileSystem) -> Vec<DirEntry> {
+        assert!(self.get_type() == Some(InodeType::Directory));
+        let mut buf = [0; DIR_ENTRY_SIZE];
+        let mut entries = Vec::new();
+        for offset in (0..self.size as usize).step_by(DIR_ENTRY_SIZE) {
+            self.read_at(fs, offset, &mut buf);
+            let entry: DirEntry = read_struct(&buf, 0);
+            if entry.inum != 0 {
+                entries.push(entry);
+            }
+        }
+        entries
+    }
+
+    /// add the entry `name` -> `inum` to this directory.
+    /// `None` if the name is invalid, already taken, or the disk is full
+    pub fn dir_link(&mut self, fs: &mut FileSystem, name: &str, inum: u32) -> Option<()> {
+        assert!(self.get_type() == Some(InodeType::Directory));
+        if name.is_empty() || name.len() > DIR_NAME_LEN || name.contains('/') {
+            return None;
+        }
+        if self.dir_lookup(fs, name).is_some() {
+            return None;
+        }
+
+        // reuse a free entry, or append one
+        let mut buf = [0; DIR_ENTRY_SIZE];
+        let mut slot = self.size as usize;
+        for offset in (0..self.size as usize).step_by(DIR_ENTRY_SIZE) {
+            self.read_at(fs, offset, &mut buf);
+            let entry: DirEntry = read_struct(&buf, 0);
+            if entry.inum == 0 {
+                slot = offset;
+                break;
+            }
+        }
+
+        write_struct(&mut buf, 0, &DirEntry::new(name, inum));
+        if self.write_at(fs, slot, &buf) != DIR_ENTRY_SIZE {
+            // do not leave a partial entry behind
+            write_struct(&mut buf, 0, &DirEntry::empty());
+            self.write_at(fs, slot, &buf);
+            return None;
+        }
+        Some(())
+    }
+}
+
+/// Reference to an inode on the disk.
+/// It only records the inode number, every operation goes to the disk through `FILE_SYSTEM`.
+/// An unlinked inode is freed when its last reference, e.g. of an open file, is dropped
+#[derive(Debug)]
+pub struct Inode {
+    inum: u32,
+}
+
+pub type InodeRef = Arc<Inode>;
+
+/// the number of `Inode`s of each inum in memory.
+/// Locked after `FILE_SYSTEM` if both are needed
+static INODE_REFS: Mutex<BTreeMap<u32, usize>> = Mutex::new(BTreeMap::new());
+
+fn is_referenced(inum: u32) -> bool {
+    INODE_REFS.lock().contains_key(&inum)
+}
+
+/// give back the blocks and the inode of a file nobody links to
+fn free_inode(fs: &mut FileSystem, inum: u32, mut inode: DiskInode) {
+    inode.truncate(fs);
+    fs.write_inode(inum, &DiskInode::new(InodeType::Free));
+}
+
+impl Inode {
+    fn new(inum: u32) -> InodeRef {
+        *INODE_REFS.lock().entry(inum).or_insert(0) += 1;
+        Arc::new(Self { inum })
+    }
+
+    pub fn get_inum(&self) -> u32 {
+        self.inum
+    }
+
+    /// `None` if the type on the disk is corrupted
+    pub fn get_type(&self) -> Option<InodeType> {
+        FILE_SYSTEM.lock().read_inode(self.inum).get_type()
+    }
+
+    pub fn size(&self) -> usize {
+        FILE_SYSTEM.lock().read_inode(self.inum).size as usize
+    }
+
+    pub fn stat(&self) -> Stat {
+        let inode = FILE_SYSTEM.lock().read_inode(self.inum);
+        Stat {
+            ino: self.inum,
+            type_: inode.type_,
+            nlink: inode.nlink,
+            size: inode.size as u64,
+        }
+    }
+
+    /// read from `offset` into `buf`, returns the number of bytes read, 0 at the end of the file
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let fs = FILE_SYSTEM.lock();
+        fs.read_inode(self.inum).read_at(&fs, offset, buf)
+    }
+
+    /// write `buf` at `offset`, returns the number of bytes written
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let mut fs = FILE_SYSTEM.lock();
+        let mut inode = fs.read_inode(self.inum);
+        let written = inode.write_at(&mut fs, offset, buf);
+        fs.write_inode(self.inum, &inode);
+        written
+    }
+
+    /// look up `name` in this directory
+    pub fn lookup(&self, name: &str) -> Option<InodeRef> {
+        let fs = FILE_SYSTEM.lock();
+        let inode = fs.read_inode(self.inum);
+        if inode.get_type() != Some(InodeType::Directory) {
+            return None;
+        }
+        inode.dir_lookup(&fs, name).map(Inode::new)
+    }
+
+    /// names of the entries in this directory, `None` if this is not a directory
+    pub fn list(&self) -> Option<Vec<String>> {
+        let fs = FILE_SYSTEM.lock();
+        let inode = fs.read_inode(self.inum);
+        if inode.get_type() != Some(InodeType::Directory) {
+            return None;
+        }
+        let names = inode
+            .dir_entries(&fs)
+            .iter()
+            .map(|entry| String::from_utf8_lossy(entry.name()).into_owned())
+            .collect();
+        Some(names)
+    }
+
+    /// the name of `child` in this directory, `None` if it is not found there
+    pub fn name_of(&self, child: &Inode) -> Option<String> {
+        let fs = FILE_SYSTEM.lock();
+        let inode = fs.read_inode(self.inum);
+        if inode.get_type() != Some(InodeType::Directory) {
+            return None;
+        }
+        inode.dir_name_of(&fs, child.inum)
+    }
+
+    /// the (major, minor) number of a device file, `None` for other files
+    pub fn device(&self) -> Option<(u16, u16)> {
+        let inode = FILE_SYSTEM.lock().read_inode(self.inum);
+        (inode.get_type() == Some(InodeType::Device)).then_some((inode.major, inode.minor))
+    }
+
+    /// Create the entry `name` of `type_` in this directory.
+    /// `None` if this is not a directory, `name` exists, or the disk is full
+    pub fn create(&self, name: &str, type_: InodeType) -> Option<InodeRef> {
+        self.create_with(name, type_, |_| {})
+    }
+
+    /// like `create`, `init` fills in the new inode before it is linked into this directory
+    fn create_with(
+        &self,
+        name: &str,
+        type_: InodeType,
+        init: impl FnOnce(&mut DiskInode),
+    ) -> Option<InodeRef> {
+        let mut fs = FILE_SYSTEM.lock();
+        let mut dir = fs.read_inode(self.inum);
+        if dir.get_type() != Some(InodeType::Directory) || dir.dir_lookup(&fs, name).is_some() {
+            return None;
+        }
+
+        let inum = fs.alloc_inode(type_)?;
+        let mut inode = fs.read_inode(inum);
+        inode.nlink = 1;
+        init(&mut inode);
+        let linked = if type_ == InodeType::Directory {
+            // ".." refers to the parent
+            inode
+                .dir_link(&mut fs, ".", inum)
+                .and_then(|_| inode.dir_link(&mut fs, "..", self.inum))
+        } else {
+            Some(())
+        };
+        if linked
+            .and_then(|_| dir.dir_link(&mut fs, name, inum))
+            .is_none()
+        {
+            // nobody refers to it, give it back with the blocks of its entries
+            free_inode(&mut fs, inum, inode);
+            return None;
+        }
+        if type_ == InodeType::Directory {
+            dir.nlink += 1;
+        }
+        fs.write_inode(inum, &inode);
+        fs.write_inode(self.inum, &dir);
+        Some(Inode::new(inum))
+    }
+
+    /// Add the entry `name` in this directory for `inode`, which is not a directory.
+    /// `None` if this is not a directory, `name` exists, or the disk is full
+    pub fn link(&self, name: &str, inode: &Inode) -> Option<()> {
+        let mut fs = FILE_SYSTEM.lock();
+        let mut dir = fs.read_inode(self.inum);
+        let mut target = fs.read_inode(inode.inum);
+        if dir.get_type() != Some(InodeType::Directory)
+            || target.get_type() == Some(InodeType::Directory)
+        {
+            return None;
+        }
+        dir.dir_link(&mut fs, name, inode.inum)?;
+        fs.write_inode(self.inum, &dir);
+        target.nlink += 1;
+        fs.write_inode(inode.inum, &target);
+        Some(())
+    }
+
+    /// Remove the entry `name` from this directory, a directory only if it is empty.
+    /// The inode is freed once it has no links left and nobody refers to it.
+    /// `None` if `name` is missing, `.` or `..`
+    pub fn unlink(&self, name: &str) -> Option<()> {
+        if name == "." || name == ".." {
+            return None;
+        }
+        let mut fs = FILE_SYSTEM.lock();
+        let mut dir = fs.read_inode(self.inum);
+        if dir.get_type() != Some(InodeType::Directory) {
+            return None;
+        }
+        let inum = dir.dir_lookup(&fs, name)?;
+        let mut inode = fs.read_inode(inum);
+        if inode.get_type() == Some(InodeType::Directory) {
+            // only `.` and `..`
+            if inode.dir_entries(&fs).len() > 2 {
+                return None;
+            }
+            // its `..`
+            dir.nlink -= 1;
+        }
+        dir.dir_unlink(&mut fs, name).unwrap();
+        fs.write_inode(self.inum, &dir);
+
+        inode.nlink -= 1;
+        if inode.nlink == 0 && !is_referenced(inum) {
+            free_inode(&mut fs, inum, inode);
+        } else {
+            fs.write_inode(inum, &inode);
+        }
+        Some(())
+    }
+}
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        // `FILE_SYSTEM` first so that nobody looks it up in between
+        let mut fs = FILE_SYSTEM.lock();
+        {
+            let mut refs = INODE_REFS.lock();
+            let count = refs.get_mut(&self.inum).unwrap();
+            *count -= 1;
+            if *count > 0 {
+                return;
+            }
+            refs.remove(&self.inum);
+        }
+        let inode = fs.read_inode(self.inum);
+        if inode.nlink == 0 && inode.get_type() != Some(InodeType::Free) {
+            free_inode(&mut fs, self.inum, inode);
+        }
+    }
+}
+
+pub fn root() -> InodeRef {
+    Inode::new(ROOT_INUM)
+}
+
+/// the working directory of the running process, the root if there is none, e.g. in kernel tests
+fn current_dir() -> InodeRef {
+    cpu::try_current_process()
+        .map(|pcb| pcb.inner.read().cwd.clone())
+        .unwrap_or_else(root)
+}
+
+/// Resolve a path, e.g. `/dir/file` from the root directory or `dir/file` from `cwd`.
+/// Empty components are skipped, so `/` is the root itself
+pub fn namei_at(cwd: InodeRef, path: &str) -> Option<InodeRef> {
+    let mut inode = if path.starts_with('/') { root() } else { cwd };
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        inode = inode.lookup(name)?;
+    }
+    Some(inode)
+}
+
+/// Resolve the parent directory of `path` and return it with the last component,
+/// e.g. `/dir/file` gives `/dir` and `file`. `None` if there is no last component
+pub fn namei_parent_at(cwd: InodeRef, path: &str) -> Option<(InodeRef, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some(pair) => pair,
+        None => ("", path),
+    };
+    if name.is_empty() {
+        return None;
+    }
+    Some((namei_at(cwd, parent)?, name))
+}
+
+/// The absolute path of the directory `dir`, found by walking up its `..` entries
+/// and looking up the name of each directory in its parent.
+/// `None` if a directory on the way has been unlinked
+pub fn path_of(dir: InodeRef) -> Option<String> {
+    let mut names = Vec::new();
+    let mut inode = dir;
+    while inode.get_inum() != ROOT_INUM {
+        let parent = inode.lookup("..")?;
+        names.push(parent.name_of(&inode)?);
+        inode = parent;
+    }
+    let mut path = String::new();
+    for name in names.iter().rev() {
+        path.push('/');
+        path.push_str(name);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Some(path)
+}
+
+/// Resolve `path`, a relative one from the working directory of the running process.
+/// Do not call it holding the lock of the running process
+pub fn namei(path: &str) -> Option<InodeRef> {
+    namei_at(current_dir(), path)
+}
+
+/// `namei_parent_at` from the working directory of the running process
+pub fn namei_parent(path: &str) -> Option<(InodeRef, &str)> {
+    namei_parent_at(current_dir(), path)
+}
+
+/// Create the device file `path` for the driver of `major`, see `fs/device.rs`.
+/// `None` if it already exists or its parent does not
+pub fn mknod(path: &str, major: u16, minor: u16) -> Option<InodeRef> {
+    let (dir, name) = namei_parent(path)?;
+    dir.create_with(name, InodeType::Device, |inode| {
+        inode.major = major;
+        inode.minor = minor;
+    })
+}
+
+/// Add the entry `new_path` for the file at `old_path`.
+/// `None` if `old_path` is missing or a directory, or `new_path` exists
+pub fn link(old_path: &str, new_path: &str) -> Option<()> {
+    let inode = namei(old_path)?;
+    let (dir, name) = namei_parent(new_path)?;
+    dir.link(name, &inode)
+}
+
+/// Remove the entry `path`, see `Inode::unlink`
+pub fn unlink(path: &str) -> Option<()> {
+    let (dir, name) = namei_parent(path)?;
+    dir.unlink(name)
+}
+
+/// Create the directory `path` with its `.` and `..`.
+/// `None` if it already exists or its parent does not
+pub fn mkdir(path: &str) -> Option<InodeRef> {
+    let (dir, name) = namei_parent(path)?;
+    dir.create(name, InodeType::Directory)
+}
+
+/// a nested directory should appear in its parent, and be found by its path
+pub fn test_mkdir_nested() {
+    // left over by an earlier run
+    unlink("/a/b");
+    unlink("/a");
+    mkdir("/a").unwrap();
+    mkdir("/a/b").unwrap();
+    assert!(mkdir("/a/b").is_none());
+    assert!(mkdir("/missing/b").is_none());
+
+    let names = namei("/a").unwrap().list().unwrap();
+    for name in [".", "..", "b"] {
+        assert!(names.iter().any(|entry| entry == name));
+    }
+    let b = namei("/a/b").unwrap();
+    assert_eq!(b.get_type(), Some(InodeType::Directory));
+    assert_eq!(
+        b.lookup("..").unwrap().get_inum(),
+        namei("/a").unwrap().get_inum()
+    );
+    unlink("/a/b").unwrap();
+    unlink("/a").unwrap();
+}
+
+/// relative paths should be resolved from the working directory
+pub fn test_relative_lookup() {
+    // left over by an earlier run
+    unlink("/rel_a/b");
+    unlink("/rel_a");
+    let a = mkdir("/rel_a").unwrap();
+    let b = mkdir("/rel_a/b").unwrap();
+
+    with_test_process(|pcb| {
+        // what `chdir("/rel_a")` does
+        assert_eq!(pcb.inner.read().cwd.get_inum(), ROOT_INUM);
+        pcb.inner.write().cwd = a.clone();
+        let cwd = pcb.inner.read().cwd.clone();
+
+        assert_eq!(namei_at(cwd.clone(), "b").unwrap().get_inum(), b.get_inum());
+        assert_eq!(
+            namei_at(cwd.clone(), "./b/..").unwrap().get_inum(),
+            a.get_inum()
+        );
+        assert_eq!(namei_at(cwd.clone(), "..").unwrap().get_inum(), ROOT_INUM);
+        assert_eq!(
+            namei_at(cwd.clone(), "/rel_a").unwrap().get_inum(),
+            a.get_inum()
+        );
+        let (parent, name) = namei_parent_at(cwd.clone(), "b/c").unwrap();
+        assert_eq!((parent.get_inum(), name), (b.get_inum(), "c"));
+        let (parent, name) = namei_parent_at(cwd, "/c").unwrap();
+        assert_eq!((parent.get_inum(), name), (ROOT_INUM, "c"));
+    });
+
+    unlink("/rel_a/b").unwrap();
+    unlink("/rel_a").unwrap();
+}
+
+/// the path of the working directory should follow `chdir` into nested directories
+pub fn test_path_of() {
+    // left over by an earlier run
+    for path in ["/path_a/b/c", "/path_a/b", "/path_a"] {
+        unlink(path);
+    }
+    for path in ["/path_a", "/path_a/b", "/path_a/b/c"] {
+        mkdir(path).unwrap();
+    }
+    assert_eq!(path_of(root()).unwrap(), "/");
+
+    // what `chdir("/path_a")` and then `chdir("b/c")` do
+    let a = namei("/path_a").unwrap();
+    with_test_process(|pcb| {
+        pcb.inner.write().cwd = a.clone();
+        assert_eq!(path_of(pcb.inner.read().cwd.clone()).unwrap(), "/path_a");
+        let cwd = pcb.inner.read().cwd.clone();
+        pcb.inner.write().cwd = namei_at(cwd, "b/c").unwrap();
+        assert_eq!(
+            path_of(pcb.inner.read().cwd.clone()).unwrap(),
+            "/path_a/b/c"
+        );
+        let cwd = pcb.inner.read().cwd.clone();
+        pcb.inner.write().cwd = namei_at(cwd, "..").unwrap();
+        assert_eq!(path_of(pcb.inner.read().cwd.clone()).unwrap(), "/path_a/b");
+    });
+    assert_eq!(a.name_of(&namei("/path_a/b").unwrap()).unwrap(), "b");
+    assert!(a.name_of(&root()).is_none());
+
+    for path in ["/path_a/b/c", "/path_a/b", "/path_a"] {
+        unlink(path).unwrap();
+    }
+}
+
+/// the blocks of a linked file should be freed after its last name is unlinked
+/// and the last reference to it is dropped
+pub fn test_link_unlink() {
+    const PATH: &str = "/link_test";
+    const OTHER: &str = "/link_test2";
+    // left over by an earlier run
+    unlink(PATH);
+    unlink(OTHER);
+
+    let inode = root().create("link_test", InodeType::File).unwrap();
+    link(PATH, OTHER).unwrap();
+    assert!(link(PATH, OTHER).is_none());
+    assert!(link("/missing", "/link_test3").is_none());
+    assert!(link("/", "/link_test3").is_none());
+    assert!(unlink("/missing").is_none());
+    assert_eq!(inode.stat().nlink, 2);
+    let free_blocks = FILE_SYSTEM.lock().n_free_blocks();
+
+    // through the indirect block
+    let content = [0x5A; (N_DIRECT + 2) * BLOCK_SIZE];
+    assert_eq!(inode.write_at(0, &content), content.len());
+    assert!(FILE_SYSTEM.lock().n_free_blocks() < free_blocks);
+
+    unlink(PATH).unwrap();
+    assert!(namei(PATH).is_none());
+    assert_eq!(namei(OTHER).unwrap().get_inum(), inode.get_inum());
+    assert_eq!(inode.stat().nlink, 1);
+
+    // still open
+    unlink(OTHER).unwrap();
+    assert_eq!(inode.stat().nlink, 0);
+    let mut buf = [0; BLOCK_SIZE];
+    assert_eq!(inode.read_at(N_DIRECT * BLOCK_SIZE, &mut buf), BLOCK_SIZE);
+    assert_eq!(buf, [0x5A; BLOCK_SIZE]);
+
+    let inum = inode.get_inum();
+    drop(inode);
+    assert_eq!(FILE_SYSTEM.lock().n_free_blocks(), free_blocks);
+    assert_eq!(
+        FILE_SYSTEM.lock().read_inode(inum).get_type(),
+        Some(InodeType::Free)
+    );
+}
+
+/// a directory that cannot get a block for its entries should leave nothing behind
+pub fn test_create_rolls_back() {
+    let n_free_inodes = || {
+        let fs = FILE_SYSTEM.lock();
+        (1..fs.superblock().n_inodes)
+            .filter(|&inum| fs.read_inode(inum).get_type() == Some(InodeType::Free))
+            .count()
+    };
+    let dir = root();
+    let (free_blocks, free_inodes) = (FILE_SYSTEM.lock().n_free_blocks(), n_free_inodes());
+
+    let mut held = Vec::new();
+    while let Some(block_no) = FILE_SYSTEM.lock().alloc_block() {
+        held.push(block_no);
+    }
+    assert!(dir.create("full", InodeType::Directory).is_none());
+    assert!(dir.lookup("full").is_none());
+    assert_eq!(n_free_inodes(), free_inodes);
+
+    let mut fs = FILE_SYSTEM.lock();
+    for block_no in held {
+        fs.free_block(block_no);
+    }
+    assert_eq!(fs.n_free_blocks(), free_blocks);
+    drop(fs);
+}
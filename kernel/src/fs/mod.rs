@@ -1 +1,15 @@
+pub mod device;
+pub mod file;
+pub mod filesystem;
+pub mod inode;
 mod mock;
+pub mod pipe;
+pub mod procfs;
+pub mod stat;
+pub mod virtio_blk;
+
+pub fn init() {
+    virtio_blk::init(); // the disk
+    filesystem::init();
+    procfs::init();
+}
@@ -0,0 +1,81 @@
+//! A bounded in-kernel buffer connecting a read end and a write end
+
+use alloc::sync::Arc;
+use spin::Mutex;
+
+const PIPE_SIZE: usize = 512;
+
+pub struct Pipe {
+    buf: [u8; PIPE_SIZE],
+    read_idx: usize,  // next byte to read
+    write_idx: usize, // next slot to write
+    read_open: bool,
+    write_open: bool,
+}
+
+impl core::fmt::Debug for Pipe {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Pipe")
+            .field("len", &self.len())
+            .field("read_open", &self.read_open)
+            .field("write_open", &self.write_open)
+            .finish()
+    }
+}
+
+impl Pipe {
+    pub fn new() -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            buf: [0; PIPE_SIZE],
+            read_idx: 0,
+            write_idx: 0,
+            read_open: true,
+            write_open: true,
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.write_idx - self.read_idx
+    }
+
+    /// a reader has to wait: nothing to read but more may be written
+    pub fn read_would_block(&self) -> bool {
+        self.len() == 0 && self.write_open
+    }
+
+    /// a writer has to wait: no room but someone may read
+    pub fn write_would_block(&self) -> bool {
+        self.len() == PIPE_SIZE && self.read_open
+    }
+
+    /// read what is available into `buf`, 0 if it is empty
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.len());
+        for c in buf.iter_mut().take(n) {
+            *c = self.buf[self.read_idx % PIPE_SIZE];
+            self.read_idx += 1;
+        }
+        n
+    }
+
+    /// write as much of `buf` as fits, `None` if the read end is closed
+    pub fn write(&mut self, buf: &[u8]) -> Option<usize> {
+        if !self.read_open {
+            return None;
+        }
+        let n = buf.len().min(PIPE_SIZE - self.len());
+        for &c in buf.iter().take(n) {
+            self.buf[self.write_idx % PIPE_SIZE] = c;
+            self.write_idx += 1;
+        }
+        Some(n)
+    }
+
+    pub fn close_read(&mut self) {
+        self.read_open = false;
+    }
+
+    pub fn close_write(&mut self) {
+        self.write_open = false;
+    }
+}
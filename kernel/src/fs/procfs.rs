@@ -0,0 +1,108 @@
+//! Read-only files generated on read, without any data block on the disk.
+//! They are device files of `PROC_MAJOR`, the minor number picks what is generated
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    mm::{
+        address_space::AddrSpace,
+        layout::{PAGE_SIZE, TEXT_BASE_USER_VA},
+        memory::VirtAddr,
+    },
+    process::manager::PROCESS_MANAGER,
+};
+
+use super::{
+    device::PROC_MAJOR,
+    file::{FileHandle, O_RDONLY},
+    inode,
+};
+
+/// the processes with their pid, status and heap usage, one per line
+pub const STATUS_MINOR: u16 = 0;
+
+const PROC_DIR: &str = "/proc";
+const STATUS_PATH: &str = "/proc/status";
+
+/// create the files if the disk does not have them yet
+pub fn init() {
+    if inode::namei(PROC_DIR).is_none() {
+        inode::mkdir(PROC_DIR).expect("procfs::init: cannot create /proc");
+    }
+    if inode::namei(STATUS_PATH).is_none() {
+        inode::mknod(STATUS_PATH, PROC_MAJOR, STATUS_MINOR)
+            .expect("procfs::init: cannot create /proc/status");
+    }
+}
+
+/// The whole content is generated again on every read,
+/// `offset` lets a small buffer page through it
+pub fn read(minor: u16, offset: usize, buf: &mut [u8]) -> usize {
+    let content = match minor {
+        STATUS_MINOR => status(),
+        _ => return 0,
+    };
+    let Some(rest) = content.as_bytes().get(offset..) else {
+        return 0;
+    };
+    let n = rest.len().min(buf.len());
+    buf[..n].copy_from_slice(&rest[..n]);
+    n
+}
+
+fn status() -> String {
+    let processes = PROCESS_MANAGER.lock().list_processes();
+    let mut content = String::from("PID\tSTATUS\tHEAP\n");
+    for info in processes {
+        writeln!(
+            content,
+            "{}\t{:?}\t{}",
+            info.pid, info.status, info.heap_size
+        )
+        .unwrap();
+    }
+    content
+}
+
+/// the status file should list every process, also when read a few bytes at a time
+pub fn test_read_status() {
+    let pcbs: Vec<_> = (0..3)
+        .map(|_| PROCESS_MANAGER.lock().create_process().unwrap())
+        .collect();
+    // one with a user heap
+    let va = VirtAddr::new(TEXT_BASE_USER_VA);
+    {
+        let mut inner = pcbs[0].inner.write();
+        let kernel_stack_pa = pcbs[0].get_kernel_stack_phys_addr();
+        inner
+            .replace_user_space(AddrSpace::make_init(), va, va, kernel_stack_pa)
+            .unwrap();
+        let program_break = inner.program_break;
+        inner.set_program_break(program_break + PAGE_SIZE).unwrap();
+    }
+
+    let file = FileHandle::open(inode::namei(STATUS_PATH).unwrap(), O_RDONLY).unwrap();
+    assert_eq!(file.write(b"0"), None);
+    let mut content = Vec::new();
+    let mut buf = [0; 7];
+    loop {
+        let n = file.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        content.extend_from_slice(&buf[..n]);
+    }
+    let content = String::from_utf8(content).unwrap();
+    assert!(content.starts_with("PID\tSTATUS\tHEAP\n"));
+    for (pcb, heap_size) in pcbs.iter().zip([PAGE_SIZE, 0, 0]) {
+        let line = format!("\n{}\tRUNNABLE\t{}\n", pcb.get_pid(), heap_size);
+        assert!(content.contains(&line));
+    }
+
+    // the first may have `INIT_PID`, which cannot exit, they never ran anyway
+    let mut manager = PROCESS_MANAGER.lock();
+    for pcb in &pcbs {
+        manager.discard_process(pcb.get_pid());
+    }
+}
@@ -0,0 +1,20 @@
+//! Metadata of a file returned by `fstat`, shared with `user/src/stat.rs`
+
+use super::inode::InodeType;
+
+/// `#[repr(C)]` without padding, user programs define the same layout
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stat {
+    pub ino: u32,
+    /// an `InodeType`
+    pub type_: u16,
+    pub nlink: u16,
+    pub size: u64,
+}
+
+impl Stat {
+    pub fn get_type(&self) -> Option<InodeType> {
+        InodeType::from(self.type_)
+    }
+}
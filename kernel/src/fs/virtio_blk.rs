@@ -0,0 +1,135 @@
+//! The virtio block device on the virtio MMIO bus, see `VIRTIO_BASE` in `mm/layout.rs`
+
+use core::ptr::NonNull;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use virtio_drivers::{
+    device::blk::{VirtIOBlk, SECTOR_SIZE},
+    transport::{
+        mmio::{MmioTransport, VirtIOHeader},
+        DeviceType, Transport,
+    },
+    BufferDirection, Hal,
+};
+
+use crate::{
+    allocator::frame_allocator::{allocate_contiguous_frames, deallocate_contiguous_frames},
+    debug, info,
+    mm::{
+        layout::VIRTIO0,
+        memory::{Frame, FrameRange, PhysAddr},
+    },
+    plic::{PLIC, VIRTIO0_IRQ},
+};
+
+pub const BLOCK_SIZE: usize = SECTOR_SIZE;
+
+/// Bridges the DMA requests of `virtio_drivers` to the frame allocator.
+/// The kernel identically maps the physical memory, so virtual addresses are physical ones
+pub struct VirtioHal;
+
+unsafe impl Hal for VirtioHal {
+    fn dma_alloc(pages: usize, _direction: BufferDirection) -> (usize, NonNull<u8>) {
+        let range = allocate_contiguous_frames(pages).expect("VirtioHal::dma_alloc: out of frames");
+        for mut frame in range {
+            frame.zero();
+        }
+        let pa = range.get_begin().get_base_phys_addr().as_usize();
+        debug!(
+            "VirtioHal::dma_alloc: {:?} pages at pa {:?}",
+            pages, pa as *const usize
+        );
+        (pa, NonNull::new(pa as *mut u8).unwrap())
+    }
+
+    unsafe fn dma_dealloc(paddr: usize, _vaddr: NonNull<u8>, pages: usize) -> i32 {
+        let begin = Frame::from_phys_addr(PhysAddr::new(paddr));
+        let end = Frame::from_ppn(begin.number + pages);
+        deallocate_contiguous_frames(FrameRange::new(begin, end));
+        0
+    }
+
+    unsafe fn mmio_phys_to_virt(paddr: usize, _size: usize) -> NonNull<u8> {
+        NonNull::new(paddr as *mut u8).unwrap()
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, _direction: BufferDirection) -> usize {
+        // the buffers live in the kernel, which is identically mapped
+        buffer.as_ptr() as *mut u8 as usize
+    }
+
+    unsafe fn unshare(_paddr: usize, _buffer: NonNull<[u8]>, _direction: BufferDirection) {}
+}
+
+pub struct VirtIOBlock {
+    inner: VirtIOBlk<VirtioHal, MmioTransport>,
+}
+
+// Safety: the raw pointers inside point to the MMIO region and the DMA frames
+// owned by the device, it is only accessed through `BLOCK_DEVICE`'s lock
+unsafe impl Send for VirtIOBlock {}
+
+impl VirtIOBlock {
+    /// # Safety
+    /// `base_addr` must be the mapped MMIO region of a virtio block device
+    pub unsafe fn new(base_addr: usize) -> Self {
+        let header = NonNull::new(base_addr as *mut VirtIOHeader).unwrap();
+        let transport =
+            MmioTransport::new(header).expect("VirtIOBlock::new: invalid virtio MMIO header");
+        assert_eq!(
+            transport.device_type(),
+            DeviceType::Block,
+            "VirtIOBlock::new: not a block device"
+        );
+        let inner = VirtIOBlk::new(transport).expect("VirtIOBlock::new: failed to initialise");
+        Self { inner }
+    }
+
+    /// read the `BLOCK_SIZE`-byte block `block_no` into `buf`
+    pub fn read_block(&mut self, block_no: usize, buf: &mut [u8]) {
+        assert_eq!(
+            buf.len(),
+            BLOCK_SIZE,
+            "VirtIOBlock::read_block: bad buffer size"
+        );
+        self.inner
+            .read_blocks(block_no, buf)
+            .expect("VirtIOBlock::read_block: failed");
+    }
+
+    /// write `buf` to the `BLOCK_SIZE`-byte block `block_no`
+    pub fn write_block(&mut self, block_no: usize, buf: &[u8]) {
+        assert_eq!(
+            buf.len(),
+            BLOCK_SIZE,
+            "VirtIOBlock::write_block: bad buffer size"
+        );
+        self.inner
+            .write_blocks(block_no, buf)
+            .expect("VirtIOBlock::write_block: failed");
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity() as usize
+    }
+}
+
+lazy_static! {
+    pub static ref BLOCK_DEVICE: Mutex<VirtIOBlock> =
+        Mutex::new(unsafe { VirtIOBlock::new(VIRTIO0) });
+}
+
+/// The requests are polled for completion, the interrupt only has to be acknowledged
+fn handle_interrupt() {
+    // whoever holds the device is polling it, and it will be acknowledged next time
+    if let Some(mut device) = BLOCK_DEVICE.try_lock() {
+        device.inner.ack_interrupt();
+    }
+}
+
+pub fn init() {
+    let capacity = BLOCK_DEVICE.lock().capacity();
+    unsafe { (*PLIC.get()).register_handler(VIRTIO0_IRQ, handle_interrupt) };
+    info!("virtio block device initialised, {:?} blocks", capacity);
+}
@@ -5,6 +5,10 @@
 #![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
 #![feature(map_try_insert)]
+// `make test` runs the `#[test_case]`s at boot, see `testing.rs`
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![cfg_attr(test, no_main)]
 
 #[macro_use] // allows macros like `vec`
 extern crate alloc;
@@ -20,6 +24,8 @@ pub mod print;
 pub mod process;
 pub mod start;
 pub mod symbols;
+#[cfg(test)]
+pub mod testing;
 pub mod trap;
 pub mod uart;
 pub mod common;
@@ -28,8 +34,19 @@ pub mod common;
 extern "C" fn eh_personality() {}
 
 /// Panic handler
+/// It reports the trap registers, and the saved user context of the current process if any.
+/// Printing goes through `panic_println`, which does not wait for the `UART` lock
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use riscv::register::{scause, sepc, sstatus, stval};
+
+    // set by the first panic, a panic while reporting only prints where it is
+    static PANICKING: AtomicBool = AtomicBool::new(false);
+
+    // what has been logged before comes first
+    print::flush();
+
     // panic_println!("hart {} aborting: ", arch::hart_id());
     if let Some(p) = info.location() {
         panic_println!(
@@ -41,6 +58,33 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     } else {
         panic_println!("no information available.");
     }
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        panic_println!("panicked while panicking");
+        abort();
+    }
+
+    let sstatus = sstatus::read();
+    panic_println!(
+        "hart {}: sepc {:#x}, scause {:?}, stval {:#x}, sstatus: spp {:?}, sie {}, spie {}",
+        arch::hart_id(),
+        sepc::read(),
+        scause::read().cause(),
+        stval::read(),
+        sstatus.spp(),
+        sstatus.sie(),
+        sstatus.spie()
+    );
+    // do not wait for locks, the panicking code may hold them
+    if let Some(pcb) = cpu::try_current_process() {
+        panic_println!("PID {}", pcb.get_pid());
+        if let Some(ctx) = pcb.inner.try_read().and_then(|inner| inner.get_context_ref().cloned()) {
+            ctx.dump_registers();
+        }
+    }
+    // a failing test
+    #[cfg(test)]
+    testing::exit_qemu(testing::QemuExitCode::Failed);
+    #[cfg(not(test))]
     abort();
 }
 
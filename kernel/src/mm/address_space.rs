@@ -1,28 +1,41 @@
-use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec::Vec,
+};
+use bitflags::bitflags;
 use riscv::{asm::sfence_vma_all, register::satp};
 use virtio_drivers::PAGE_SIZE;
 
 use crate::{
-    debug, info,
+    allocator::frame_allocator,
+    arch, debug, info,
     mm::{
         layout::{
             __bss_end, __bss_start, __data_end, __data_start, __heap_end, __heap_start,
             __kernel_stack_end, __kernel_stack_start, __rodata_end, __rodata_start, __text_end,
             __text_start, __trampoline_start, MAX_VA, TRAMPOLINE_BASE_VA, TRAPFRAME_BASE_USER_VA,
-            TRAPFRAME_SIZE,
+            TRAPFRAME_SIZE, USER_STACK_MAX_PAGES,
         },
         memory::FrameGuard,
     },
-    process::process::init_code_bytes,
+    process::{
+        elf::{ElfImage, PF_R, PF_X},
+        process::init_code_bytes,
+    },
+    symbols::N_CPUS,
 };
 
 use super::{
+    asid::Asid,
     layout::{
-        CLINT_BASE, CLINT_SIZE, PLIC_BASE, PLIC_SIZE, TEXT_BASE_USER_VA, UART_BASE, UART_SIZE,
-        VIRTIO_BASE, VIRTIO_SIZE,
+        CLINT_BASE, CLINT_SIZE, KERNEL_BASE, PLIC_BASE, PLIC_SIZE, TEXT_BASE_USER_VA, UART_BASE,
+        UART_SIZE, USER_DEVICES, VIRTIO_BASE, VIRTIO_SIZE, VIRT_TEST_BASE, VIRT_TEST_SIZE,
     },
-    memory::{Frame, FrameRange, PhysAddr, VirtAddr, VirtFrameGuard, VirtFrameRange},
-    page_table::{PageFlags, PageTableGuard},
+    memory::{Frame, FrameRange, PhysAddr, VirtAddr, VirtFrame, VirtFrameGuard, VirtFrameRange},
+    page_table::{PTEFlags, PageFlags, PageTableGuard},
 };
 
 // ------------------------- Address Space -------------------------------------
@@ -33,8 +46,15 @@ use super::{
 pub struct AddrSpace {
     page_table: PageTableGuard,
     virt_areas: Vec<VirtArea>, // TODO: refactor into sections???
+    /// tags its TLB entries, see `mm::asid`
+    asid: Asid,
+    /// the hart it was last switched to on, `NO_HART` before, see `needs_flush_on_load`
+    last_hart: AtomicUsize,
 }
 
+/// the `last_hart` of a space never switched to
+const NO_HART: usize = usize::MAX;
+
 impl AddrSpace {
     /// load address space directly
     /// execution may be corrupted if not careful!
@@ -42,9 +62,16 @@ impl AddrSpace {
         // let ptr = self.page_table.make_satp();
         unsafe {
             // satp::write(ptr);
-            satp::set(satp::Mode::Sv39, 0, self.page_table.get_root_frame().number);
-            // asm!("sfence.vma"); // memory fence to flush TLB
-            sfence_vma_all();
+            satp::set(
+                satp::Mode::Sv39,
+                self.asid.get() as usize,
+                self.page_table.get_root_frame().number,
+            );
+            // entries tagged with an ASID of its own are still valid on the same hart
+            if self.needs_flush_on_load(arch::hart_id()) {
+                // asm!("sfence.vma"); // memory fence to flush TLB
+                sfence_vma_all();
+            }
         };
         // these are pretty much a wrapper function to the underlying RISC-V instructions
     }
@@ -57,7 +84,72 @@ impl AddrSpace {
     }
 
     pub fn make_satp(&self) -> usize {
-        self.page_table.make_satp()
+        self.page_table.make_satp() | (self.asid.get() as usize) << 44
+    }
+
+    /// Whether the TLB of `hartid` has to be flushed when switching to it there, and it
+    /// records that it now runs on `hartid`. So it is if its ASID is shared, or if it last
+    /// ran on another hart: its mappings are changed with a flush of that hart only,
+    /// and a recycled ASID may still tag entries of the space it was taken from.
+    /// Modifying its mappings should flush them on the calling hart whether or not
+    pub fn needs_flush_on_load(&self, hartid: usize) -> bool {
+        let last_hart = self.last_hart.swap(hartid, Ordering::Relaxed);
+        !self.asid.is_tagged() || last_hart != hartid
+    }
+
+    /// print the mappings of its page table, see `PageTableGuard::dump`
+    pub fn dump(&self) {
+        self.page_table.dump();
+    }
+
+    /// Compare the pages of the areas of both spaces, e.g. a forked child should
+    /// mirror its parent apart from the trapframe. Adjacent pages differing in
+    /// the same way are merged into one entry. Only valid leaves count as mapped,
+    /// an unmapped page next to mapped ones is only in the other space.
+    /// NOTE: it translates every page of every area, for debugging only
+    pub fn diff(&self, other: &AddrSpace) -> Vec<DiffEntry> {
+        let pages: BTreeSet<VirtFrame> = self
+            .virt_areas
+            .iter()
+            .chain(other.virt_areas.iter())
+            .flat_map(|area| area.virt_frame_range)
+            .collect();
+
+        let mut diffs: Vec<DiffEntry> = Vec::new();
+        for page in pages {
+            let va = page.get_base_virt_addr();
+            let kind = match (
+                self.page_table.translate(va),
+                other.page_table.translate(va),
+            ) {
+                (None, None) => continue,
+                (Some(_), None) => DiffKind::OnlyInSelf,
+                (None, Some(_)) => DiffKind::OnlyInOther,
+                (Some((pa, ours)), Some((other_pa, theirs))) => {
+                    // the hardware sets these as the pages are used
+                    let (ours, theirs) = (
+                        ours - (PTEFlags::ACCESSED | PTEFlags::DIRTY),
+                        theirs - (PTEFlags::ACCESSED | PTEFlags::DIRTY),
+                    );
+                    if ours != theirs {
+                        DiffKind::Flags { ours, theirs }
+                    } else if pa != other_pa {
+                        DiffKind::Frames
+                    } else {
+                        continue;
+                    }
+                }
+            };
+            match diffs.last_mut() {
+                Some(last) if last.end == va && last.kind == kind => last.end = va + PAGE_SIZE,
+                _ => diffs.push(DiffEntry {
+                    begin: va,
+                    end: va + PAGE_SIZE,
+                    kind,
+                }),
+            }
+        }
+        diffs
     }
 
     pub fn translate(&self, va: VirtAddr) -> Option<(PhysAddr, PageFlags)> {
@@ -65,6 +157,640 @@ impl AddrSpace {
         Some((pa, pte_flags.into()))
     }
 
+    /// Like `translate`, but the page has to be mapped for every access in `access`.
+    /// A page without a valid leaf is `NotMapped`, otherwise the first permission
+    /// missing is reported, `USER` before the others
+    pub fn translate_checked(
+        &self,
+        va: VirtAddr,
+        access: AccessMode,
+    ) -> Result<PhysAddr, TranslateError> {
+        // `translate` takes valid leaves only
+        let (pa, flags) = self.translate(va).ok_or(TranslateError::NotMapped)?;
+        let required = [
+            (AccessMode::USER, PageFlags::USER, TranslateError::NotUser),
+            (
+                AccessMode::READ,
+                PageFlags::READABLE,
+                TranslateError::NotReadable,
+            ),
+            (
+                AccessMode::WRITE,
+                PageFlags::WRITABLE,
+                TranslateError::NotWritable,
+            ),
+            (
+                AccessMode::EXECUTE,
+                PageFlags::EXECUTABLE,
+                TranslateError::NotExecutable,
+            ),
+        ];
+        for (mode, flag, err) in required {
+            if access.contains(mode) && !flags.contains(flag) {
+                return Err(err);
+            }
+        }
+        Ok(pa)
+    }
+
+    /// Whether `[va, va + len)` lies in the part of the user space a user pointer may refer to.
+    /// The trapframe, the trampoline and anything from `MAX_VA` up are rejected whatever
+    /// their mappings, so a syscall cannot be tricked into writing the trapframe.
+    /// `Err` with the first address outside of it
+    pub fn validate_user_ptr(va: VirtAddr, len: usize) -> Result<(), UserAccessFault> {
+        let end = va
+            .as_usize()
+            .checked_add(len)
+            .ok_or(UserAccessFault { va })?;
+        // the trampoline is above the trapframe, right below `MAX_VA`
+        if end > TRAPFRAME_BASE_USER_VA {
+            let va = VirtAddr::new(va.as_usize().max(TRAPFRAME_BASE_USER_VA));
+            return Err(UserAccessFault { va });
+        }
+        Ok(())
+    }
+
+    /// Copy `dst.len()` bytes at `va` of this user space into `dst`, page by page.
+    /// Pages not mapped yet are faulted in as if the user read them, see `fault_in`.
+    /// `Err` with the first address that is not mapped readable to the user
+    pub fn copy_from_user(&mut self, va: VirtAddr, dst: &mut [u8]) -> Result<(), UserAccessFault> {
+        Self::validate_user_ptr(va, dst.len())?;
+        let mut copied = 0;
+        while copied < dst.len() {
+            let va = va + copied;
+            let access = AccessMode::USER | AccessMode::READ;
+            let pa = match self.translate_checked(va, access) {
+                Ok(pa) => pa,
+                Err(TranslateError::NotMapped) => {
+                    self.fault_in(va).ok_or(UserAccessFault { va })?;
+                    self.translate_checked(va, access)
+                        .map_err(|_| UserAccessFault { va })?
+                }
+                Err(_) => return Err(UserAccessFault { va }),
+            };
+            // up to the end of this page
+            let n = (PAGE_SIZE - va.offset()).min(dst.len() - copied);
+            Frame::from(pa)
+                .copy_out(va.offset(), &mut dst[copied..copied + n])
+                .unwrap();
+            copied += n;
+        }
+        Ok(())
+    }
+
+    /// Copy `src` to `va` of this user space, page by page.
+    /// Copy-on-write pages are copied first and pages not mapped yet faulted in,
+    /// as if the user wrote to them.
+    /// `Err` with the first address that is not mapped writable to the user
+    pub fn copy_to_user(&mut self, va: VirtAddr, src: &[u8]) -> Result<(), UserAccessFault> {
+        Self::validate_user_ptr(va, src.len())?;
+        let mut copied = 0;
+        while copied < src.len() {
+            let va = va + copied;
+            let access = AccessMode::USER | AccessMode::WRITE;
+            let pa = match self.translate_checked(va, access) {
+                Ok(pa) => pa,
+                Err(TranslateError::NotWritable) => {
+                    self.resolve_cow_fault(va).ok_or(UserAccessFault { va })?;
+                    self.translate_checked(va, access)
+                        .map_err(|_| UserAccessFault { va })?
+                }
+                Err(TranslateError::NotMapped) => {
+                    self.fault_in(va).ok_or(UserAccessFault { va })?;
+                    self.translate_checked(va, access)
+                        .map_err(|_| UserAccessFault { va })?
+                }
+                Err(_) => return Err(UserAccessFault { va }),
+            };
+            let n = (PAGE_SIZE - va.offset()).min(src.len() - copied);
+            Frame::from(pa)
+                .copy_in(va.offset(), &src[copied..copied + n])
+                .unwrap();
+            copied += n;
+        }
+        Ok(())
+    }
+
+    /// Map the page at `va` like a page fault of the user would, see `trap::page_fault`:
+    /// a lazy page is loaded, or the user stack grows down to it.
+    /// `None` if neither applies
+    fn fault_in(&mut self, va: VirtAddr) -> Option<()> {
+        self.resolve_lazy_fault(va)
+            .or_else(|| self.grow_user_stack(va))
+    }
+
+    /// Push the arguments for `exec` to the top of the initial user stack page at
+    /// `user_stack_va`, like a C `main(argc, argv)` expects them: the strings, each
+    /// terminated by a 0, then below them `argv`, their addresses followed by a null one.
+    /// Returns the stack pointer, aligned to 16 bytes, it points to `argv`.
+    /// `None` if they do not fit in the page
+    pub fn push_args(&mut self, user_stack_va: VirtAddr, args: &[&[u8]]) -> Option<VirtAddr> {
+        const PTR_SIZE: usize = core::mem::size_of::<usize>();
+        let strings_size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+        let argv_size = (args.len() + 1) * PTR_SIZE;
+        let size = (strings_size.next_multiple_of(PTR_SIZE) + argv_size).next_multiple_of(16);
+        if size > PAGE_SIZE {
+            return None;
+        }
+
+        let top = (user_stack_va + PAGE_SIZE).as_usize();
+        let mut argv = Vec::with_capacity(args.len() + 1);
+        let mut string_va = VirtAddr::new(top);
+        for arg in args {
+            string_va = VirtAddr::new(string_va.as_usize() - (arg.len() + 1));
+            self.copy_to_user(string_va, arg).ok()?;
+            self.copy_to_user(string_va + arg.len(), &[0]).ok()?;
+            argv.push(string_va.as_usize());
+        }
+        argv.push(0);
+
+        let sp = VirtAddr::new(top - size);
+        let bytes: Vec<u8> = argv.iter().flat_map(|ptr| ptr.to_ne_bytes()).collect();
+        self.copy_to_user(sp, &bytes).ok()?;
+        Some(sp)
+    }
+
+    /// Map the page at `va` of a lazily mapped area on its first access,
+    /// with the content fetched from the area's backing.
+    /// Returns `None` if `va` is not in a lazy area, its page is already mapped,
+    /// or the frames run out
+    pub fn resolve_lazy_fault(&mut self, va: VirtAddr) -> Option<()> {
+        let va = va.align_down();
+        let virt_area = self.virt_areas.iter_mut().find(|area| area.contains(va))?;
+        let backing = virt_area.backing?;
+        if virt_area.virt_frames.contains_key(&va) {
+            return None;
+        }
+
+        let frame_guard = backing.load_page(va)?;
+        let pa = frame_guard.get_frame().get_base_phys_addr();
+        debug!(
+            "AddrSpace::resolve_lazy_fault: va {:?} -> pa {:?}",
+            va.as_usize() as *const usize,
+            pa.as_usize() as *const usize
+        );
+        self.page_table
+            .try_map_one_allocate(va, pa, virt_area.permissions().into())?;
+        virt_area.track_frame(va, VirtFrameGuard::ExclusivelyAllocated(frame_guard));
+        arch::sfence_vma_addr(va);
+        Some(())
+    }
+
+    /// Grow the user stack down to the page containing `va`, if `va` is below the stack
+    /// but within `USER_STACK_MAX_PAGES` pages from its top, and none of the pages it grows
+    /// over belongs to another area. One guard page is kept above any area below,
+    /// like `resize_user_heap` keeps below the stack.
+    /// Returns `None` if the fault is not for the stack, or if the frames run out,
+    /// then the stack is left as it was
+    pub fn grow_user_stack(&mut self, va: VirtAddr) -> Option<()> {
+        let va = va.align_down();
+        let stack = self.virt_areas.iter().find(|area| area.grows_down)?;
+        let stack_begin = stack.virt_frame_range.get_begin().get_base_virt_addr();
+        let stack_end = stack.virt_frame_range.get_end().get_base_virt_addr();
+        if va >= stack_begin
+            || stack_end.as_usize() - va.as_usize() > USER_STACK_MAX_PAGES * PAGE_SIZE
+        {
+            return None;
+        }
+        let guard_va = VirtAddr::new(va.as_usize().checked_sub(PAGE_SIZE)?);
+        if !self.is_range_free(guard_va, stack_begin) {
+            return None;
+        }
+        let stack = self.virt_areas.iter_mut().find(|area| area.grows_down)?;
+
+        let flags: PTEFlags = stack.permissions().into();
+        let mut page_va = va;
+        while page_va < stack_begin {
+            let mapped = FrameGuard::try_allocate_zeroed().and_then(|frame_guard| {
+                let pa = frame_guard.get_frame().get_base_phys_addr();
+                self.page_table.try_map_one_allocate(page_va, pa, flags)?;
+                stack.track_frame(page_va, VirtFrameGuard::ExclusivelyAllocated(frame_guard));
+                Some(())
+            });
+            if mapped.is_none() {
+                // out of frames, give back the pages grown so far, none of them was in use
+                while page_va > va {
+                    page_va = VirtAddr::new(page_va.as_usize() - PAGE_SIZE);
+                    self.page_table.unmap_one(page_va);
+                    arch::sfence_vma_addr(page_va);
+                    stack.virt_frames.remove(&page_va);
+                }
+                return None;
+            }
+            page_va = page_va + PAGE_SIZE;
+        }
+        stack.virt_frame_range = VirtFrameRange::new(va.into(), stack.virt_frame_range.get_end());
+        debug!(
+            "AddrSpace::grow_user_stack: user stack grown to {:?}",
+            va.as_usize() as *const usize
+        );
+        unsafe { sfence_vma_all() };
+        Some(())
+    }
+
+    /// Remove the area starting at `base`: its pages are unmapped and the frames it owns freed.
+    /// `None` if no area starts at `base`. Identically mapped areas are part of the kernel
+    /// and cannot be unmapped
+    pub fn unmap_area(&mut self, base: VirtAddr) -> Option<()> {
+        let i = self
+            .virt_areas
+            .iter()
+            .position(|area| area.virt_frame_range.get_begin().get_base_virt_addr() == base)?;
+        if self.virt_areas[i].is_identically_mapped {
+            return None;
+        }
+
+        let virt_area = self.virt_areas.remove(i);
+        for v_frame in virt_area.virt_frame_range {
+            // pages of a lazy area may have never been mapped
+            self.page_table.unmap_one(v_frame.get_base_virt_addr());
+        }
+        unsafe { sfence_vma_all() };
+        debug!(
+            "AddrSpace::unmap_area: area {:?} at {:?} unmapped",
+            virt_area.name,
+            base.as_usize() as *const usize
+        );
+        // the frames are recycled only after they are no longer mapped
+        drop(virt_area);
+        Some(())
+    }
+
+    /// Change the permissions of the area spanning `len` bytes from `base` to `new_perms`,
+    /// the foundation of `mprotect`. The mapped pages keep their frames, the lazy ones
+    /// get `new_perms` when they are mapped. Whether it is a user area does not change.
+    /// `None` if no area spans exactly that range, it would make kernel text writable,
+    /// or a page it tracks is not mapped, e.g. a guard page. The area is left as it was then
+    pub fn protect(&mut self, base: VirtAddr, len: usize, new_perms: PageFlags) -> Option<()> {
+        let end = base.as_usize().checked_add(len)?;
+        let end = VirtAddr::new(end).align_up();
+        let virt_area = self.virt_areas.iter_mut().find(|area| {
+            area.virt_frame_range.get_begin().get_base_virt_addr() == base
+                && area.virt_frame_range.get_end().get_base_virt_addr() == end
+        })?;
+        // W^X for the kernel itself
+        if virt_area.is_identically_mapped
+            && virt_area.permissions.contains(PageFlags::EXECUTABLE)
+            && new_perms.contains(PageFlags::WRITABLE)
+        {
+            return None;
+        }
+        // nothing changes unless every page can be remapped
+        let all_mapped = if virt_area.is_identically_mapped {
+            virt_area
+                .virt_frame_range
+                .into_iter()
+                .all(|v_frame| self.page_table.is_mapped(v_frame.get_base_virt_addr()))
+        } else {
+            virt_area
+                .virt_frames
+                .keys()
+                .all(|va| self.page_table.is_mapped(*va))
+        };
+        if !all_mapped {
+            return None;
+        }
+
+        let user = virt_area.permissions & PageFlags::USER;
+        virt_area.permissions = (new_perms - PageFlags::USER) | user;
+        if virt_area.is_identically_mapped {
+            let flags: PTEFlags = virt_area.permissions.into();
+            for v_frame in virt_area.virt_frame_range {
+                self.page_table
+                    .remap_flags(v_frame.get_base_virt_addr(), flags)
+                    .expect("AddrSpace::protect: not mapped");
+            }
+        } else {
+            // copy-on-write pages stay read-only
+            self.page_table.remap_virt_area_flags(virt_area);
+        }
+        unsafe { sfence_vma_all() };
+        debug!(
+            "AddrSpace::protect: area {:?} at {:?} is now {:?}",
+            virt_area.name,
+            base.as_usize() as *const usize,
+            virt_area.permissions
+        );
+        Some(())
+    }
+
+    /// Map `len` bytes of zero-filled user pages with `perms`, the foundation of `mmap`.
+    /// They go to `va_hint` if it is page aligned and the range is free, otherwise to
+    /// the lowest free gap between the user stack, with one guard page, and the trapframe.
+    /// Below the stack is left for the stack and the heap to grow into.
+    /// Returns the base of the new area, `None` if `len` is 0, there is no room
+    /// or the frames run out, then none are kept
+    pub fn map_anonymous(
+        &mut self,
+        va_hint: Option<VirtAddr>,
+        len: usize,
+        perms: PageFlags,
+    ) -> Option<VirtAddr> {
+        if len == 0 {
+            return None;
+        }
+        let len = len.checked_next_multiple_of(PAGE_SIZE)?;
+        let base = self.place_user_area(va_hint, len)?;
+
+        let mut virt_area = VirtArea::new(base, base + len, perms | PageFlags::USER);
+        for v_frame in virt_area.virt_frame_range {
+            // the frames taken so far are dropped with `virt_area`
+            let frame_guard = FrameGuard::try_allocate_zeroed()?;
+            virt_area.track_frame(
+                v_frame.get_base_virt_addr(),
+                VirtFrameGuard::ExclusivelyAllocated(frame_guard),
+            );
+        }
+        virt_area.set_name("anonymous");
+        self.try_map_virt_area(virt_area).ok()?;
+        debug!(
+            "AddrSpace::map_anonymous: {:?} bytes mapped at {:?}",
+            len,
+            base.as_usize() as *const usize
+        );
+        Some(base)
+    }
+
+    /// Map the device registers at `[pa, pa + len)` to the user space `READABLE | WRITABLE`,
+    /// laid out like `map_anonymous`. They have to be within one of `USER_DEVICES`.
+    /// Returns the base of the new area, `None` if the range is not allowed or there is no room
+    pub fn map_device(&mut self, pa: PhysAddr, len: usize) -> Option<VirtAddr> {
+        if len == 0 || !pa.is_page_aligned() {
+            return None;
+        }
+        let len = len.checked_next_multiple_of(PAGE_SIZE)?;
+        let pa_end = pa.as_usize().checked_add(len)?;
+        let allowed = USER_DEVICES
+            .iter()
+            .any(|&(begin, size)| begin <= pa.as_usize() && pa_end <= begin + size);
+        if !allowed {
+            return None;
+        }
+        let base = self.place_user_area(None, len)?;
+
+        let perms = PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::USER;
+        let mut virt_area = VirtArea::new(base, base + len, perms);
+        for (i, v_frame) in virt_area.virt_frame_range.iter().enumerate() {
+            // they are not ours to free
+            let frame = Frame::from_phys_addr(PhysAddr::new(pa.as_usize() + i * PAGE_SIZE));
+            virt_area.track_frame(
+                v_frame.get_base_virt_addr(),
+                VirtFrameGuard::PhysBorrowed(frame),
+            );
+        }
+        virt_area.set_name("device");
+        self.try_map_virt_area(virt_area).ok()?;
+        debug!(
+            "AddrSpace::map_device: {:?} mapped at {:?}",
+            pa.as_usize() as *const usize,
+            base.as_usize() as *const usize
+        );
+        Some(base)
+    }
+
+    /// Where `map_anonymous` puts `len` bytes, page aligned:
+    /// at `va_hint` if it is page aligned and the range is free, otherwise the lowest
+    /// free gap between the user stack, with one guard page, and the trapframe
+    fn place_user_area(&self, va_hint: Option<VirtAddr>, len: usize) -> Option<VirtAddr> {
+        let stack_end = self
+            .virt_areas
+            .iter()
+            .find(|area| area.grows_down)?
+            .virt_frame_range
+            .get_end()
+            .get_base_virt_addr();
+        let (lowest, highest) = (stack_end + PAGE_SIZE, VirtAddr::new(TRAPFRAME_BASE_USER_VA));
+
+        va_hint
+            .filter(|&va| {
+                va.is_page_aligned()
+                    && va >= lowest
+                    && va.as_usize() <= highest.as_usize().saturating_sub(len)
+                    && self.is_range_free(va, va + len)
+            })
+            .or_else(|| self.find_free_range(len, lowest, highest))
+    }
+
+    /// Map `virt_area` and keep track of it, unless it collides with what is there
+    /// or the frames for the page table run out.
+    /// Every page is checked before any is mapped, so on `Err` the space is left as it was,
+    /// but for page table nodes, and the frames of `virt_area` are dropped
+    pub fn try_map_virt_area(&mut self, virt_area: VirtArea) -> Result<(), MapError> {
+        let range = virt_area.virt_frame_range;
+        if let Some(other) = self
+            .virt_areas
+            .iter()
+            .find(|area| area.virt_frame_range.overlaps(&range))
+        {
+            let begin = range.get_begin().max(other.virt_frame_range.get_begin());
+            return Err(MapError::Overlap {
+                va: begin.get_base_virt_addr(),
+            });
+        }
+        // a valid leaf left behind by someone mapping outside of any area,
+        // unmapped pages sharing a level-0 node with mapped ones are free
+        if let Some(v_frame) = range
+            .into_iter()
+            .find(|v_frame| self.page_table.is_mapped(v_frame.get_base_virt_addr()))
+        {
+            return Err(MapError::AlreadyMapped {
+                va: v_frame.get_base_virt_addr(),
+            });
+        }
+
+        if self
+            .page_table
+            .try_map_virt_area_allocate(&virt_area)
+            .is_none()
+        {
+            // none of them was mapped before, give back those mapped so far
+            for v_frame in range {
+                self.page_table.unmap_one(v_frame.get_base_virt_addr());
+            }
+            return Err(MapError::OutOfFrames);
+        }
+        self.virt_areas.push(virt_area);
+        unsafe { sfence_vma_all() };
+        Ok(())
+    }
+
+    /// whether no area overlaps `[begin, end)`
+    fn is_range_free(&self, begin: VirtAddr, end: VirtAddr) -> bool {
+        let range = VirtFrameRange::new(begin.into(), end.into());
+        self.virt_areas
+            .iter()
+            .all(|area| !area.virt_frame_range.overlaps(&range))
+    }
+
+    /// the lowest base of `len` free bytes within `[lowest, highest)`
+    fn find_free_range(&self, len: usize, lowest: VirtAddr, highest: VirtAddr) -> Option<VirtAddr> {
+        let window = VirtFrameRange::new(lowest.into(), highest.into());
+        let mut taken: Vec<(VirtAddr, VirtAddr)> = self
+            .virt_areas
+            .iter()
+            .filter(|area| area.virt_frame_range.overlaps(&window))
+            .map(|area| {
+                let range = area.virt_frame_range;
+                (
+                    range.get_begin().get_base_virt_addr(),
+                    range.get_end().get_base_virt_addr(),
+                )
+            })
+            .collect();
+        taken.sort_unstable();
+
+        let mut candidate = lowest;
+        for (begin, end) in taken {
+            if candidate.as_usize() + len <= begin.as_usize() {
+                break;
+            }
+            candidate = candidate.max(end);
+        }
+        (candidate.as_usize() + len <= highest.as_usize()).then_some(candidate)
+    }
+
+    /// where the program break starts, that is the base of the user heap
+    pub fn initial_program_break(&self) -> VirtAddr {
+        self.virt_areas
+            .iter()
+            .find(|area| area.grows_up)
+            .expect("AddrSpace::initial_program_break: no user heap")
+            .virt_frame_range
+            .get_begin()
+            .get_base_virt_addr()
+    }
+
+    /// Move the program break from `old_break` to `new_break` for `sbrk`.
+    /// Pages entering the user heap are zero-filled, pages leaving it are unmapped and freed.
+    /// Returns `None` if the break would go below the heap base,
+    /// grow into the user stack or the guard page below it, or if the frames run out.
+    /// NOTE: the nodes of the page table are still allocated with `FrameGuard::allocate_zeroed`
+    pub fn resize_user_heap(&mut self, old_break: VirtAddr, new_break: VirtAddr) -> Option<()> {
+        let stack_begin = self
+            .virt_areas
+            .iter()
+            .find(|area| area.grows_down)?
+            .virt_frame_range
+            .get_begin()
+            .get_base_virt_addr();
+        let heap = self.virt_areas.iter_mut().find(|area| area.grows_up)?;
+        let heap_begin = heap.virt_frame_range.get_begin().get_base_virt_addr();
+        if new_break < heap_begin
+            || new_break >= stack_begin
+            || new_break.align_up() + PAGE_SIZE > stack_begin
+        {
+            return None;
+        }
+
+        let (old_end, new_end) = (old_break.align_up(), new_break.align_up());
+        let flags: PTEFlags = heap.permissions().into();
+        let mut page_va = old_end;
+        while page_va < new_end {
+            let Some(frame_guard) = FrameGuard::try_allocate_zeroed() else {
+                // out of frames, give back the pages grown so far
+                while page_va > old_end {
+                    page_va = VirtAddr::new(page_va.as_usize() - PAGE_SIZE);
+                    self.page_table.unmap_one(page_va);
+                    arch::sfence_vma_addr(page_va);
+                    heap.virt_frames.remove(&page_va);
+                }
+                return None;
+            };
+            let pa = frame_guard.get_frame().get_base_phys_addr();
+            heap.track_frame(page_va, VirtFrameGuard::ExclusivelyAllocated(frame_guard));
+            self.page_table.map_one_allocate(page_va, pa, flags);
+            arch::sfence_vma_addr(page_va);
+            page_va = page_va + PAGE_SIZE;
+        }
+        let mut page_va = new_end;
+        while page_va < old_end {
+            // unmapped and flushed before its frame is recycled
+            self.page_table.unmap_one(page_va);
+            arch::sfence_vma_addr(page_va);
+            heap.virt_frames.remove(&page_va);
+            page_va = page_va + PAGE_SIZE;
+        }
+        heap.virt_frame_range =
+            VirtFrameRange::new(heap.virt_frame_range.get_begin(), new_end.into());
+        Some(())
+    }
+
+    /// Resolve a write to a copy-on-write page by giving this address space
+    /// its own copy of the page, which is then mapped writable again.
+    /// Returns `None` if `va` is not in a COW-shared page of a writable area,
+    /// that is, the fault is not caused by copy-on-write, or if there is no frame for the copy
+    pub fn resolve_cow_fault(&mut self, va: VirtAddr) -> Option<()> {
+        let va = va.align_down();
+        let (_, pte_flags) = self.page_table.translate(va)?;
+        if !pte_flags.contains(PTEFlags::COW) {
+            return None;
+        }
+
+        let virt_area = self.virt_areas.iter_mut().find(|area| area.contains(va))?;
+        if !virt_area.permissions().contains(PageFlags::WRITABLE) {
+            return None;
+        }
+
+        let virt_frame_guard = virt_area.virt_frames.remove(&va)?;
+        let frame_guard = match virt_frame_guard {
+            VirtFrameGuard::CowShared(frame_guard) => {
+                if frame_guard.is_shared() {
+                    let data = unsafe { frame_guard.inner_ref().get_bytes() };
+                    let Some(copy) = FrameGuard::try_allocate_with_data(data) else {
+                        // out of frames, it keeps sharing the page
+                        virt_area.track_frame(va, VirtFrameGuard::CowShared(frame_guard));
+                        return None;
+                    };
+                    copy
+                } else {
+                    // we are the last one sharing it, simply take it back
+                    frame_guard
+                }
+            }
+            other => {
+                virt_area.track_frame(va, other);
+                return None;
+            }
+        };
+        debug!(
+            "AddrSpace::resolve_cow_fault: va {:?} -> pa {:?}",
+            va.as_usize() as *const usize,
+            frame_guard.get_frame().get_base_phys_addr().as_usize() as *const usize
+        );
+        virt_area.track_frame(va, VirtFrameGuard::ExclusivelyAllocated(frame_guard));
+        self.page_table.remap_virt_frame(virt_area, va)?;
+        arch::sfence_vma_addr(va);
+        Some(())
+    }
+
+    /// Unmap the identically mapped page at `va` so that touching it faults,
+    /// e.g. below a kernel stack, or map it back with the permissions of its area.
+    /// `None` if it is not identically mapped, already in that state,
+    /// or its megapage cannot be split. The page table nodes are written, see `mm::set_kernel_guard_page` for a locked space
+    pub fn set_guard_page(&mut self, va: VirtAddr, guard: bool) -> Option<()> {
+        let va = va.align_down();
+        let virt_area = self
+            .virt_areas
+            .iter()
+            .find(|area| area.is_identically_mapped && area.contains(va))?;
+        if guard {
+            // a megapage is unmapped as a whole
+            self.page_table.split_megapage(va)?;
+            self.page_table.unmap_one(va)
+        } else {
+            let pa = PhysAddr::new(va.as_usize());
+            let pte_flags: PTEFlags = virt_area.permissions().into();
+            if self
+                .page_table
+                .translate(va)
+                .is_some_and(|(_, flags)| !flags.is_empty())
+            {
+                return None;
+            }
+            self.page_table.map_one(va, pa, pte_flags)
+        }
+    }
+
     /// lock the space by making the node frames of its page table in the kernel space read-only
     pub fn lock_space(&mut self) {
         let another_space = Self::make_kernel();
@@ -189,6 +915,17 @@ impl AddrSpace {
             area
         });
 
+        // the test device, to exit qemu
+        virt_areas.push({
+            let pa_begin = PhysAddr::new(VIRT_TEST_BASE);
+            let pa_end = PhysAddr::new(VIRT_TEST_BASE + VIRT_TEST_SIZE);
+            let perms = PageFlags::READABLE | PageFlags::WRITABLE;
+            let mut area = VirtArea::identically_mapped(pa_begin, pa_end, perms);
+            area.set_name("virt_test");
+            area.print_info();
+            area
+        });
+
         // plic
         virt_areas.push({
             let pa_begin = PhysAddr::new(PLIC_BASE);
@@ -220,6 +957,8 @@ impl AddrSpace {
         Self {
             page_table,
             virt_areas,
+            asid: Asid::kernel(),
+            last_hart: AtomicUsize::new(NO_HART),
         }
     }
 
@@ -230,7 +969,8 @@ impl AddrSpace {
 
         let text_va_begin = VirtAddr::new(TEXT_BASE_USER_VA);
         let text_va_end = (text_va_begin + init_text.len()).align_up();
-        let user_stack_va = text_va_end + PAGE_SIZE;
+        // leave room for the stack to grow with one guard page
+        let user_stack_va = text_va_end + USER_STACK_MAX_PAGES * PAGE_SIZE;
 
         // trampoline
         virt_areas.push({
@@ -254,44 +994,222 @@ impl AddrSpace {
         virt_areas.push({
             let va_begin = text_va_begin;
             let va_end = text_va_end;
-            let perms = PageFlags::READABLE | PageFlags::EXECUTABLE | PageFlags::USER;
+            // a flat binary without program headers, all of it is one read-execute segment
+            let perms = PageFlags::from_elf_flags(PF_R | PF_X) | PageFlags::USER;
 
             let mut virt_area = VirtArea::new(va_begin, va_end, perms);
-            // Note: the init code is compiled into the kernel binary, so we do not own it
-            let phys_frame = FrameGuard::allocate_with_data(init_text);
-
-            virt_area.track_frame(va_begin, VirtFrameGuard::ExclusivelyAllocated(phys_frame));
+            // Note: the init code is compiled into the kernel binary, which is read-only,
+            // so it is copied page by page rather than mapped in place
+            let backing = AreaBacking {
+                va_begin,
+                data: init_text,
+            };
+            for v_frame in virt_area.virt_frame_range {
+                let page_va = v_frame.get_base_virt_addr();
+                let frame_guard = backing
+                    .load_page(page_va)
+                    .expect("AddrSpace::make_init: out of frames");
+                virt_area.track_frame(page_va, VirtFrameGuard::ExclusivelyAllocated(frame_guard));
+            }
             virt_area.set_name(".text");
             virt_area.print_info();
             virt_area
         });
 
+        // user heap, empty until `sbrk`
+        virt_areas.push({
+            let area = VirtArea::make_user_heap(text_va_end);
+            area.print_info();
+            area
+        });
+
         let mut page_table = PageTableGuard::allocate();
 
         for virt_area in &virt_areas {
             page_table.map_virt_area_allocate(virt_area);
         }
 
-        Self {
+        let space = Self {
             page_table,
             virt_areas,
+            asid: Asid::allocate(),
+            last_hart: AtomicUsize::new(NO_HART),
+        };
+        // the whole image, not only its first page
+        let mut page_va = text_va_begin;
+        while page_va < text_va_end {
+            let (_, flags) = space
+                .translate(page_va)
+                .expect("AddrSpace::make_init: init page not mapped");
+            assert!(
+                flags.contains(PageFlags::EXECUTABLE | PageFlags::USER),
+                "AddrSpace::make_init: init page at {:?} not executable",
+                page_va
+            );
+            page_va = page_va + PAGE_SIZE;
         }
+        space
+    }
+
+    /// Build a user address space from the `PT_LOAD` segments of an ELF image.
+    /// Each segment gets its own `VirtArea` backed by freshly allocated frames holding
+    /// its content, the user stack is placed above the highest segment with room to grow.
+    /// Returns the address space and the base of its user stack, `None` if the frames run out.
+    /// Like `make_init`, the trapframe is left to `AddrSpace::init_trapframe`
+    pub fn make_user(elf: &ElfImage<'static>) -> Option<(Self, VirtAddr)> {
+        debug!("AddrSpace::make_user: making address space from an ELF image");
+        let mut virt_areas = Vec::new();
+
+        // trampoline
+        virt_areas.push({
+            let area = VirtArea::make_trampoline();
+            area.print_info();
+            area
+        });
+
+        let mut highest_va = VirtAddr::new(TEXT_BASE_USER_VA);
+        for segment in &elf.segments {
+            let va_begin = VirtAddr::new(segment.vaddr);
+            let va_end = VirtAddr::new(segment.vaddr + segment.memsz);
+
+            // `elf::parse` has rejected writable text
+            let perms = PageFlags::from_elf_flags(segment.flags) | PageFlags::USER;
+
+            let mut virt_area = VirtArea::new(va_begin, va_end, perms);
+            let backing = AreaBacking {
+                va_begin,
+                data: segment.data,
+            };
+            if perms.contains(PageFlags::EXECUTABLE) {
+                // text is loaded on demand, see `AddrSpace::resolve_lazy_fault`
+                virt_area.set_lazy(backing);
+            } else {
+                for v_frame in virt_area.virt_frame_range {
+                    let page_va = v_frame.get_base_virt_addr();
+                    let frame_guard = backing.load_page(page_va)?;
+                    virt_area
+                        .track_frame(page_va, VirtFrameGuard::ExclusivelyAllocated(frame_guard));
+                }
+            }
+
+            highest_va = highest_va.max(va_end.align_up());
+            virt_area.set_name(if perms.contains(PageFlags::EXECUTABLE) {
+                ".text"
+            } else if perms.contains(PageFlags::WRITABLE) {
+                ".data"
+            } else {
+                ".rodata"
+            });
+            virt_area.print_info();
+            virt_areas.push(virt_area);
+        }
+
+        // user heap right above the segments, empty until `sbrk`
+        virt_areas.push({
+            let area = VirtArea::make_user_heap(highest_va);
+            area.print_info();
+            area
+        });
+
+        // user stack, leave room for it to grow with one guard page
+        let user_stack_va = highest_va + USER_STACK_MAX_PAGES * PAGE_SIZE;
+        virt_areas.push({
+            let (area, _) = VirtArea::try_make_initial_user_stack(user_stack_va)?;
+            area.print_info();
+            area
+        });
+
+        let mut page_table = PageTableGuard::try_allocate()?;
+        for virt_area in &virt_areas {
+            page_table.try_map_virt_area_allocate(virt_area)?;
+        }
+
+        let space = Self {
+            page_table,
+            virt_areas,
+            asid: Asid::allocate(),
+            last_hart: AtomicUsize::new(NO_HART),
+        };
+        Some((space, user_stack_va))
     }
 
     /// Don't forget to call it to allocate a trapframe!!
     /// User address space need it!!! (not the kernel though)
-    pub fn init_trapframe(&mut self) -> PhysAddr {
+    /// `None` if there are no frames left for it, the space is left without a trapframe
+    pub fn init_trapframe(&mut self) -> Option<PhysAddr> {
         info!("AddrSpace::init_trapframe: initialising trapframe, you should see this when allocating for user-spaces");
-        let (area, pa) = VirtArea::make_trapframe();
+        let (area, pa) = VirtArea::make_trapframe()?;
         area.print_info();
-        self.page_table.map_virt_area_allocate(&area);
+        self.page_table.try_map_virt_area_allocate(&area)?;
         self.virt_areas.push(area);
-        pa
+        Some(pa)
+    }
+
+    /// Where the trapframe is, `None` until `init_trapframe` has been called.
+    /// NOTE: its level-0 node is the trampoline's, it exists either way
+    pub fn trapframe_phys_addr(&self) -> Option<PhysAddr> {
+        self.virt_areas
+            .iter()
+            .find(|area| area.is_trapframe())
+            .and_then(|area| area.virt_frames.values().next())
+            .map(|virt_frame_guard| PhysAddr::new(virt_frame_guard.as_usize()))
+    }
+
+    /// frames `clone_cow` and `init_trapframe` take for the child,
+    /// at most as many page table nodes as this one plus the trapframe
+    pub fn n_frames_to_fork(&self) -> usize {
+        self.page_table.n_node_frames() + 1
+    }
+
+    /// Clone the address space for `fork` without copying its pages.
+    ///
+    /// Frames of the user areas are shared by the parent and the child as `CowShared`.
+    /// Pages of writable areas are mapped read-only and tagged `COW` in both page tables,
+    /// so that the first write to them faults and the writer gets its own copy.
+    /// Identically mapped areas do not own any frame, they are mapped as they are.
+    ///
+    /// The trapframe holds per-process states and is NEVER shared, not even copy-on-write:
+    /// it is written by `__uservec` with the translation off the TLB of the kernel space.
+    /// Call `AddrSpace::init_trapframe` on the child to allocate its own.
+    ///
+    /// `None` if there are no frames left for the child's page table.
+    /// The parent is left write-protected then, its next writes fault and take its pages back
+    pub fn clone_cow(&mut self) -> Option<Self> {
+        debug!("AddrSpace::clone_cow: cloning address space for copy-on-write");
+        let mut page_table = PageTableGuard::try_allocate()?;
+        let mut virt_areas = Vec::new();
+        for virt_area in &mut self.virt_areas {
+            if virt_area.is_trapframe() {
+                continue;
+            }
+            let child_area = virt_area.clone_cow();
+            // the parent's writable pages are now shared, write-protect them
+            self.page_table.remap_virt_area_flags(virt_area);
+            virt_areas.push(child_area);
+        }
+
+        // the parent may have cached writable translations
+        unsafe { sfence_vma_all() };
+
+        for virt_area in &virt_areas {
+            page_table.try_map_virt_area_allocate(virt_area)?;
+        }
+
+        Some(Self {
+            page_table,
+            virt_areas,
+            asid: Asid::allocate(),
+            last_hart: AtomicUsize::new(NO_HART),
+        })
     }
 }
 
 impl Drop for AddrSpace {
     fn drop(&mut self) {
+        // clear the mappings before the frames of the areas are recycled,
+        // this also flushes its entries before its ASID is handed out again
+        self.page_table.unmap_all();
+        unsafe { sfence_vma_all() };
         let pa = self
             .page_table
             .get_root_frame()
@@ -304,6 +1222,65 @@ impl Drop for AddrSpace {
     }
 }
 
+/// pages `[begin, end)` mapped differently by two spaces, see `AddrSpace::diff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffEntry {
+    pub begin: VirtAddr,
+    pub end: VirtAddr,
+    pub kind: DiffKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    /// mapped by `self` only
+    OnlyInSelf,
+    /// mapped by `other` only
+    OnlyInOther,
+    /// mapped by both with different flags
+    Flags { ours: PTEFlags, theirs: PTEFlags },
+    /// mapped by both with the same flags to different frames
+    Frames,
+}
+
+bitflags! {
+    /// the accesses `AddrSpace::translate_checked` checks a page for
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct AccessMode: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+        /// from the user mode
+        const USER = 1 << 3;
+    }
+}
+
+/// why `AddrSpace::translate_checked` refused an access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslateError {
+    NotMapped,
+    NotReadable,
+    NotWritable,
+    NotExecutable,
+    NotUser,
+}
+
+/// why `AddrSpace::try_map_virt_area` refused an area, `va` is the first page in the way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// it overlaps an area of the space
+    Overlap { va: VirtAddr },
+    /// the page is mapped, though by no area
+    AlreadyMapped { va: VirtAddr },
+    /// there is no frame left for a page table node
+    OutOfFrames,
+}
+
+/// an access on behalf of the user hit a page it may not access like that
+#[derive(Debug, Clone, Copy)]
+pub struct UserAccessFault {
+    pub va: VirtAddr,
+}
+
 // ------------------------- Virtual Area ---------------------------------------
 // a Virtual Area is a logically contiguous region in the virtual address space
 
@@ -328,6 +1305,16 @@ pub struct VirtArea {
     // TODO: maybe use an enum?
     pub is_identically_mapped: bool,
 
+    /// Some if the area is lazily mapped: its pages are not mapped
+    /// until they are first accessed
+    pub backing: Option<AreaBacking>,
+
+    /// whether the area is the user stack that grows downwards on page faults
+    pub grows_down: bool,
+
+    /// whether the area is the user heap that grows upwards with `sbrk`
+    pub grows_up: bool,
+
     // debug
     pub name: String,
 }
@@ -346,6 +1333,9 @@ impl VirtArea {
             virt_frames: BTreeMap::new(),
             permissions: perms,
             is_identically_mapped: false,
+            backing: None,
+            grows_down: false,
+            grows_up: false,
             name: "".into(),
         }
     }
@@ -383,6 +1373,9 @@ impl VirtArea {
             virt_frames: BTreeMap::new(),
             permissions: perms,
             is_identically_mapped: true,
+            backing: None,
+            grows_down: false,
+            grows_up: false,
             name: "".into(),
         }
     }
@@ -400,7 +1393,7 @@ impl VirtArea {
         virt_area
     }
 
-    pub fn make_trapframe() -> (Self, PhysAddr) {
+    pub fn make_trapframe() -> Option<(Self, PhysAddr)> {
         let va_begin = VirtAddr::new(TRAPFRAME_BASE_USER_VA);
         let va_end = VirtAddr::new(TRAPFRAME_BASE_USER_VA + TRAPFRAME_SIZE).align_up();
         let perms = PageFlags::READABLE | PageFlags::WRITABLE;
@@ -408,25 +1401,85 @@ impl VirtArea {
 
         // Note: the trapframe is allocated specifically for the process, and should
         // be managed by the user address space
-        let phys_frame = FrameGuard::allocate_zeroed();
+        let phys_frame = FrameGuard::try_allocate_zeroed()?;
         let pa = phys_frame.get_frame().get_base_phys_addr();
         virt_area.track_frame(va_begin, VirtFrameGuard::ExclusivelyAllocated(phys_frame));
         virt_area.set_name("trapframe");
-        (virt_area, pa)
+        Some((virt_area, pa))
     }
 
     pub fn make_initial_user_stack(user_stack_va: VirtAddr) -> (Self, PhysAddr) {
+        Self::try_make_initial_user_stack(user_stack_va)
+            .expect("VirtArea::make_initial_user_stack: out of frames")
+    }
+
+    /// `None` if there is no frame for the first page of the stack
+    pub fn try_make_initial_user_stack(user_stack_va: VirtAddr) -> Option<(Self, PhysAddr)> {
         let va_begin = user_stack_va;
         let va_end = user_stack_va + PAGE_SIZE;
         let perms = PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::USER;
         let mut virt_area = VirtArea::new(va_begin, va_end, perms);
 
         // We own the user stack since we explicitly called for its allocation
-        let phys_frame = FrameGuard::allocate_zeroed();
+        let phys_frame = FrameGuard::try_allocate_zeroed()?;
         let pa = phys_frame.get_frame().get_base_phys_addr();
         virt_area.track_frame(va_begin, VirtFrameGuard::ExclusivelyAllocated(phys_frame));
+        virt_area.grows_down = true;
         virt_area.set_name("user stack");
-        (virt_area, pa)
+        Some((virt_area, pa))
+    }
+
+    /// an empty user heap starting at `program_break`, see `AddrSpace::resize_user_heap`
+    pub fn make_user_heap(program_break: VirtAddr) -> Self {
+        let perms = PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::USER;
+        let mut virt_area = VirtArea::new(program_break, program_break, perms);
+        virt_area.grows_up = true;
+        virt_area.set_name("user heap");
+        virt_area
+    }
+
+    /// Creates an area of the same range and permissions for copy-on-write.
+    /// Its exclusively allocated frames are converted to `CowShared` and shared
+    /// with the new area, borrowed frames are borrowed again
+    pub fn clone_cow(&mut self) -> Self {
+        let mut virt_area = Self {
+            virt_frame_range: self.virt_frame_range,
+            virt_frames: BTreeMap::new(),
+            permissions: self.permissions,
+            is_identically_mapped: self.is_identically_mapped,
+            backing: self.backing,
+            grows_down: self.grows_down,
+            grows_up: self.grows_up,
+            name: self.name.clone(),
+        };
+
+        let virt_frames = core::mem::take(&mut self.virt_frames);
+        for (va, virt_frame_guard) in virt_frames {
+            let virt_frame_guard = virt_frame_guard.into_shared();
+            virt_area.track_frame(va, virt_frame_guard.share());
+            self.track_frame(va, virt_frame_guard);
+        }
+        virt_area
+    }
+
+    /// Do not map the pages of this area until they are first accessed,
+    /// by then they are loaded from `backing`
+    pub fn set_lazy(&mut self, backing: AreaBacking) {
+        self.backing = Some(backing);
+    }
+
+    /// whether `va` falls inside this area
+    pub fn contains(&self, va: VirtAddr) -> bool {
+        self.virt_frame_range
+            .contains(VirtFrame::from_virt_addr(va))
+    }
+
+    pub fn is_trapframe(&self) -> bool {
+        self.virt_frame_range
+            .get_begin()
+            .get_base_virt_addr()
+            .as_usize()
+            == TRAPFRAME_BASE_USER_VA
     }
 
     pub fn permissions(&self) -> PageFlags {
@@ -467,3 +1520,659 @@ impl VirtArea {
         );
     }
 }
+
+/// The content of a lazily mapped area: `data` is what should be at `va_begin`
+/// onwards, the rest of the area is zero-filled
+#[derive(Debug, Clone, Copy)]
+pub struct AreaBacking {
+    pub va_begin: VirtAddr,
+    pub data: &'static [u8],
+}
+
+impl AreaBacking {
+    /// allocate a frame holding the content of the page at `page_va`, `None` if the frames run out
+    pub fn load_page(&self, page_va: VirtAddr) -> Option<FrameGuard> {
+        let frame_guard = FrameGuard::try_allocate_zeroed()?;
+        let page_va = page_va.as_usize();
+        let data_va = self.va_begin.as_usize();
+
+        // the part of the content that falls into this page
+        let copy_begin = page_va.max(data_va);
+        let copy_end = (page_va + PAGE_SIZE).min(data_va + self.data.len());
+        if copy_begin < copy_end {
+            frame_guard
+                .get_frame()
+                .copy_in(
+                    copy_begin - page_va,
+                    &self.data[copy_begin - data_va..copy_end - data_va],
+                )
+                .unwrap();
+        }
+        Some(frame_guard)
+    }
+}
+
+/// dropping user address spaces should recycle all the frames they allocated
+pub fn test_drop_recycles_frames() {
+    let baseline = frame_allocator::n_allocated_frames();
+    for _ in 0..100 {
+        let mut space = AddrSpace::make_init();
+        space.init_trapframe().unwrap();
+        drop(space);
+    }
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+}
+
+/// the page given by `sbrk` should be writable, and freed once the break moves back
+pub fn test_resize_user_heap() {
+    let baseline = frame_allocator::n_allocated_frames();
+    let mut space = AddrSpace::make_init();
+    let program_break = space.initial_program_break();
+    let new_break = program_break + PAGE_SIZE;
+    space.resize_user_heap(program_break, new_break).unwrap();
+
+    let (pa, flags) = space.translate(program_break).unwrap();
+    assert!(flags.contains(PageFlags::USER | PageFlags::WRITABLE));
+    unsafe { pa.as_mut_ptr::<u8>().write(0x42) };
+
+    space.resize_user_heap(new_break, program_break).unwrap();
+    assert!(space.translate(program_break).is_none());
+    // it cannot grow into the user stack
+    assert!(space
+        .resize_user_heap(
+            program_break,
+            program_break + USER_STACK_MAX_PAGES * PAGE_SIZE
+        )
+        .is_none());
+    drop(space);
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+}
+
+/// copying to and from the user should cross page boundaries, but not unmapped pages
+pub fn test_copy_user_straddling_pages() {
+    let mut space = AddrSpace::make_init();
+    let program_break = space.initial_program_break();
+    let new_break = program_break + 2 * PAGE_SIZE;
+    space.resize_user_heap(program_break, new_break).unwrap();
+
+    let va = program_break + (PAGE_SIZE - 4);
+    let data = *b"straddle";
+    space.copy_to_user(va, &data).unwrap();
+    let mut buf = [0; 8];
+    space.copy_from_user(va, &mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    // the page right after the break is not mapped
+    let va = VirtAddr::new(new_break.as_usize() - 4);
+    assert!(space.copy_to_user(va, &data).is_err());
+    assert!(space.copy_from_user(va, &mut buf).is_err());
+}
+
+/// unmapping an area should free its frames, and kernel areas cannot be unmapped
+pub fn test_unmap_area() {
+    let baseline = frame_allocator::n_allocated_frames();
+    let mut space = AddrSpace::make_init();
+    let program_break = space.initial_program_break();
+    space
+        .resize_user_heap(program_break, program_break + 2 * PAGE_SIZE)
+        .unwrap();
+    let n_allocated = frame_allocator::n_allocated_frames();
+
+    space.unmap_area(program_break).unwrap();
+    assert_eq!(frame_allocator::n_allocated_frames(), n_allocated - 2);
+    assert!(space.translate(program_break).is_none());
+    // it is gone
+    assert!(space.unmap_area(program_break).is_none());
+    drop(space);
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+
+    let mut kernel_space = AddrSpace::make_kernel();
+    assert!(kernel_space
+        .unmap_area(VirtAddr::new(__text_start()))
+        .is_none());
+}
+
+/// anonymous areas should be zeroed, and a hint overlapping another area should be moved
+pub fn test_map_anonymous() {
+    let baseline = frame_allocator::n_allocated_frames();
+    let mut space = AddrSpace::make_init();
+    space.init_trapframe().unwrap();
+    let perms = PageFlags::READABLE | PageFlags::WRITABLE;
+
+    let base = space.map_anonymous(None, 3 * PAGE_SIZE - 1, perms).unwrap();
+    for i in 0..3 {
+        let (pa, flags) = space.translate(base + i * PAGE_SIZE).unwrap();
+        assert!(flags.contains(PageFlags::USER | PageFlags::WRITABLE));
+        let page = unsafe { core::slice::from_raw_parts(pa.as_mut_ptr::<u8>(), PAGE_SIZE) };
+        assert!(page.iter().all(|&b| b == 0));
+    }
+
+    // overlapping the first one, it goes right after it instead
+    let moved = space
+        .map_anonymous(Some(base + PAGE_SIZE), PAGE_SIZE, perms)
+        .unwrap();
+    assert_eq!(moved, base + 3 * PAGE_SIZE);
+    // a free hint is taken as it is
+    let hinted = base + 16 * PAGE_SIZE;
+    assert_eq!(
+        space.map_anonymous(Some(hinted), PAGE_SIZE, perms),
+        Some(hinted)
+    );
+    assert!(space.map_anonymous(None, 0, perms).is_none());
+    assert!(space.map_anonymous(None, MAX_VA, perms).is_none());
+
+    drop(space);
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+}
+
+/// a copy-on-write child should mirror its parent until one of them writes
+pub fn test_diff_cow_fork() {
+    let mut parent = AddrSpace::make_init();
+    parent.init_trapframe().unwrap();
+    let perms = PageFlags::READABLE | PageFlags::WRITABLE;
+    let base = parent.map_anonymous(None, 2 * PAGE_SIZE, perms).unwrap();
+
+    let mut child = parent.clone_cow().unwrap();
+    assert!(child.trapframe_phys_addr().is_none());
+    child.init_trapframe().unwrap();
+    assert_ne!(parent.trapframe_phys_addr(), child.trapframe_phys_addr());
+    assert_eq!(
+        child.trapframe_phys_addr(),
+        child
+            .translate(VirtAddr::new(TRAPFRAME_BASE_USER_VA))
+            .map(|(pa, _)| pa)
+    );
+    // the parent's trapframe is still writable
+    let (_, flags) = parent
+        .translate(VirtAddr::new(TRAPFRAME_BASE_USER_VA))
+        .unwrap();
+    assert!(flags.contains(PageFlags::WRITABLE));
+    // each has a trapframe of its own, nothing else differs
+    let diffs = parent.diff(&child);
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].begin.as_usize(), TRAPFRAME_BASE_USER_VA);
+    assert_eq!(diffs[0].kind, DiffKind::Frames);
+
+    // the child writes the second page, it gets a copy of its own
+    child.resolve_cow_fault(base + PAGE_SIZE).unwrap();
+    let diffs = parent.diff(&child);
+    assert_eq!(diffs.len(), 2);
+    assert_eq!(
+        (diffs[0].begin, diffs[0].end),
+        (base + PAGE_SIZE, base + 2 * PAGE_SIZE)
+    );
+    assert!(matches!(diffs[0].kind, DiffKind::Flags { .. }));
+
+    let extra = child.map_anonymous(None, PAGE_SIZE, perms).unwrap();
+    let diffs = parent.diff(&child);
+    assert!(diffs
+        .iter()
+        .any(|diff| diff.begin == extra && diff.kind == DiffKind::OnlyInOther));
+
+    // the same place in the parent, and one page more right after it
+    assert_eq!(
+        parent.map_anonymous(None, 2 * PAGE_SIZE, perms),
+        Some(extra)
+    );
+    let diffs = parent.diff(&child);
+    assert!(diffs
+        .iter()
+        .any(|diff| diff.begin == extra && diff.kind == DiffKind::Frames));
+    assert!(diffs
+        .iter()
+        .any(|diff| diff.begin == extra + PAGE_SIZE && diff.kind == DiffKind::OnlyInSelf));
+}
+
+/// running out of frames should fail the allocation for the user, not the kernel
+pub fn test_out_of_frames() {
+    let baseline = frame_allocator::n_allocated_frames();
+    let mut space = AddrSpace::make_init();
+    let program_break = space.initial_program_break();
+    // the page table nodes of the heap are there from now on
+    space
+        .resize_user_heap(program_break, program_break + PAGE_SIZE)
+        .unwrap();
+    space
+        .resize_user_heap(program_break + PAGE_SIZE, program_break)
+        .unwrap();
+
+    // leave two frames, not enough for four pages
+    let mut held = Vec::new();
+    while frame_allocator::n_free_frames() > 2 {
+        held.push(frame_allocator::allocate_one_frame().unwrap());
+    }
+    assert!(space
+        .resize_user_heap(program_break, program_break + 4 * PAGE_SIZE)
+        .is_none());
+    // the pages grown before running out are given back
+    assert_eq!(frame_allocator::n_free_frames(), 2);
+    assert!(space.translate(program_break).is_none());
+
+    while let Some(pa) = frame_allocator::allocate_one_frame() {
+        held.push(pa);
+    }
+    assert!(FrameGuard::try_allocate_zeroed().is_none());
+    assert!(space.clone_cow().is_none());
+    assert!(crate::process::process::ProcessControlBlock::try_allocate(usize::MAX).is_none());
+
+    for pa in held {
+        frame_allocator::deallocate_one_frame(pa);
+    }
+    drop(space);
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+}
+
+/// with the frames run out, a copy-on-write fault or a growing stack should fail
+/// and leave the pages as they were, the process is killed rather than the kernel
+pub fn test_faults_out_of_frames() {
+    let baseline = frame_allocator::n_allocated_frames();
+    let mut parent = AddrSpace::make_init();
+    let perms = PageFlags::READABLE | PageFlags::WRITABLE;
+    let base = parent.map_anonymous(None, PAGE_SIZE, perms).unwrap();
+    parent.copy_to_user(base, b"cow").unwrap();
+    let mut child = parent.clone_cow().unwrap();
+    let stack_begin = child
+        .virt_areas
+        .iter()
+        .find(|area| area.grows_down)
+        .unwrap()
+        .virt_frame_range
+        .get_begin()
+        .get_base_virt_addr();
+    let below_stack = VirtAddr::new(stack_begin.as_usize() - PAGE_SIZE);
+
+    let mut held = Vec::new();
+    while let Some(pa) = frame_allocator::allocate_one_frame() {
+        held.push(pa);
+    }
+    assert!(child.resolve_cow_fault(base).is_none());
+    assert!(child.copy_to_user(base, b"new").is_err());
+    // still shared read-only
+    let (pa, flags) = child.translate(base).unwrap();
+    assert!(!flags.contains(PageFlags::WRITABLE));
+    assert_eq!(parent.translate(base).map(|(pa, _)| pa), Some(pa));
+    assert!(child.grow_user_stack(below_stack).is_none());
+    assert!(child.translate(below_stack).is_none());
+
+    for pa in held {
+        frame_allocator::deallocate_one_frame(pa);
+    }
+    child.resolve_cow_fault(base).unwrap();
+    let mut buf = [0; 3];
+    child.copy_from_user(base, &mut buf).unwrap();
+    assert_eq!(&buf, b"cow");
+    child.grow_user_stack(below_stack).unwrap();
+    drop(child);
+    drop(parent);
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+}
+
+/// the stack should not grow over another area, nor right up to one
+pub fn test_grow_user_stack_collision() {
+    let mut space = AddrSpace::make_init();
+    let stack_begin = space
+        .virt_areas
+        .iter()
+        .find(|area| area.grows_down)
+        .unwrap()
+        .virt_frame_range
+        .get_begin()
+        .get_base_virt_addr();
+    let below_stack = |n_pages: usize| VirtAddr::new(stack_begin.as_usize() - n_pages * PAGE_SIZE);
+    let perms = PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::USER;
+    space
+        .try_map_virt_area(VirtArea::new(below_stack(4), below_stack(3), perms))
+        .unwrap();
+
+    // the page at `va` is free, the ones above it are not
+    assert!(space.grow_user_stack(below_stack(6)).is_none());
+    // no guard page would be left
+    assert!(space.grow_user_stack(below_stack(3)).is_none());
+    assert!(space.translate(below_stack(1)).is_none());
+
+    space.grow_user_stack(below_stack(2)).unwrap();
+    assert!(space.translate(below_stack(2)).is_some());
+    assert!(space.translate(below_stack(1)).is_some());
+}
+
+/// copying from or to a page of the stack it has not grown to yet should grow it,
+/// as the user touching it would, see `/stackwritetest`
+pub fn test_copy_user_grows_stack() {
+    let mut space = AddrSpace::make_init();
+    let stack_begin = space
+        .virt_areas
+        .iter()
+        .find(|area| area.grows_down)
+        .unwrap()
+        .virt_frame_range
+        .get_begin()
+        .get_base_virt_addr();
+    let below_stack = |n_pages: usize| VirtAddr::new(stack_begin.as_usize() - n_pages * PAGE_SIZE);
+
+    let mut buf = [1; 8];
+    space.copy_from_user(below_stack(2), &mut buf).unwrap();
+    assert_eq!(buf, [0; 8]);
+    assert!(space.translate(below_stack(1)).is_some());
+    space.copy_to_user(below_stack(3), b"grown").unwrap();
+    space.copy_from_user(below_stack(3), &mut buf[..5]).unwrap();
+    assert_eq!(&buf[..5], b"grown");
+
+    // out of its reach, where the heap starts
+    let va = below_stack(USER_STACK_MAX_PAGES);
+    assert_eq!(va, space.initial_program_break());
+    assert!(space.copy_from_user(va, &mut buf).is_err());
+    assert!(space.copy_to_user(va, &buf).is_err());
+}
+
+/// `map_anonymous` running out of frames should give back those it took and map nothing
+pub fn test_map_anonymous_out_of_frames() {
+    let baseline = frame_allocator::n_allocated_frames();
+    let mut space = AddrSpace::make_init();
+    let perms = PageFlags::READABLE | PageFlags::WRITABLE;
+    // the page table nodes for the area are there from now on
+    let base = space.map_anonymous(None, PAGE_SIZE, perms).unwrap();
+    space.unmap_area(base).unwrap();
+    let n_areas = space.virt_areas.len();
+
+    // leave two frames, not enough for four pages
+    let mut held = Vec::new();
+    while frame_allocator::n_free_frames() > 2 {
+        held.push(frame_allocator::allocate_one_frame().unwrap());
+    }
+    assert!(space.map_anonymous(None, 4 * PAGE_SIZE, perms).is_none());
+    assert_eq!(frame_allocator::n_free_frames(), 2);
+    assert_eq!(space.virt_areas.len(), n_areas);
+    assert!(space.translate(base).is_none());
+
+    for pa in held {
+        frame_allocator::deallocate_one_frame(pa);
+    }
+    assert_eq!(space.map_anonymous(None, 4 * PAGE_SIZE, perms), Some(base));
+    drop(space);
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+}
+
+/// a space should be flushed on its first switch, and whenever it moves to another hart
+pub fn test_flush_on_migration() {
+    let space = AddrSpace::make_init();
+    let hartid = arch::hart_id();
+    let other = (hartid + 1) % N_CPUS;
+    // its ASID may come from a dropped space
+    assert!(space.needs_flush_on_load(hartid));
+    assert_eq!(space.needs_flush_on_load(hartid), !space.asid.is_tagged());
+    assert!(space.needs_flush_on_load(other));
+    assert!(space.needs_flush_on_load(hartid));
+}
+
+/// user pointers into the trapframe, the trampoline or above `MAX_VA` should be rejected
+pub fn test_validate_user_ptr() {
+    let mut space = AddrSpace::make_init();
+    space.init_trapframe().unwrap();
+    let program_break = space.initial_program_break();
+    assert!(AddrSpace::validate_user_ptr(program_break, PAGE_SIZE).is_ok());
+
+    let mut buf = [0; 8];
+    for va in [
+        TRAPFRAME_BASE_USER_VA,
+        TRAMPOLINE_BASE_VA,
+        MAX_VA,
+        usize::MAX - 4,
+    ] {
+        let va = VirtAddr::new(va);
+        assert!(AddrSpace::validate_user_ptr(va, buf.len()).is_err());
+        assert!(space.copy_to_user(va, &buf).is_err());
+        assert!(space.copy_from_user(va, &mut buf).is_err());
+    }
+    // running into the trapframe from below
+    let below = VirtAddr::new(TRAPFRAME_BASE_USER_VA - 4);
+    assert_eq!(
+        AddrSpace::validate_user_ptr(below, buf.len())
+            .unwrap_err()
+            .va
+            .as_usize(),
+        TRAPFRAME_BASE_USER_VA
+    );
+}
+
+/// a page made read-only should refuse writes, and kernel text should never become writable
+pub fn test_protect() {
+    let mut space = AddrSpace::make_init();
+    space.init_trapframe().unwrap();
+    let base = space
+        .map_anonymous(
+            None,
+            2 * PAGE_SIZE,
+            PageFlags::READABLE | PageFlags::WRITABLE,
+        )
+        .unwrap();
+    space.copy_to_user(base, b"derek").unwrap();
+
+    space
+        .protect(base, 2 * PAGE_SIZE, PageFlags::READABLE)
+        .unwrap();
+    let (_, flags) = space.translate(base + PAGE_SIZE).unwrap();
+    assert!(flags.contains(PageFlags::USER | PageFlags::READABLE));
+    assert!(!flags.contains(PageFlags::WRITABLE));
+    assert_eq!(
+        space.copy_to_user(base, b"x").map_err(|fault| fault.va),
+        Err(base)
+    );
+    let mut buf = [0; 5];
+    space.copy_from_user(base, &mut buf).unwrap();
+    assert_eq!(&buf, b"derek");
+
+    // the same frames are writable again
+    space
+        .protect(
+            base,
+            2 * PAGE_SIZE,
+            PageFlags::READABLE | PageFlags::WRITABLE,
+        )
+        .unwrap();
+    space.copy_to_user(base, b"x").unwrap();
+    // only whole areas
+    assert!(space
+        .protect(base, PAGE_SIZE, PageFlags::READABLE)
+        .is_none());
+
+    let mut kernel = AddrSpace::make_kernel();
+    let text = VirtAddr::new(__text_start());
+    let text_len = __text_end() - __text_start();
+    let perms = PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::EXECUTABLE;
+    assert!(kernel.protect(text, text_len, perms).is_none());
+    let (_, flags) = kernel.translate(text).unwrap();
+    assert!(!flags.contains(PageFlags::WRITABLE));
+
+    // a guard page in the area, none of it is remapped
+    let heap = VirtAddr::new(__heap_start());
+    let heap_len = __heap_end() - __heap_start();
+    kernel.set_guard_page(heap, true).unwrap();
+    assert!(kernel
+        .protect(heap, heap_len, PageFlags::READABLE)
+        .is_none());
+    let last = VirtAddr::new(__heap_end() - PAGE_SIZE);
+    let (_, flags) = kernel.translate(last).unwrap();
+    assert!(flags.contains(PageFlags::WRITABLE));
+    let area = kernel.virt_areas.iter().find(|area| area.contains(heap));
+    assert!(area.unwrap().permissions().contains(PageFlags::WRITABLE));
+}
+
+/// each missing permission should be reported as such, in the order they are checked
+pub fn test_translate_checked() {
+    let mut space = AddrSpace::make_init();
+    space.init_trapframe().unwrap();
+    let text = VirtAddr::new(TEXT_BASE_USER_VA);
+    let user_rx = AccessMode::USER | AccessMode::READ | AccessMode::EXECUTE;
+    let (pa, _) = space.translate(text).unwrap();
+    assert_eq!(space.translate_checked(text, user_rx), Ok(pa));
+
+    // the heap is empty until `sbrk`
+    let unmapped = space.initial_program_break();
+    assert_eq!(
+        space.translate_checked(unmapped, AccessMode::READ),
+        Err(TranslateError::NotMapped)
+    );
+    let execute_only = space
+        .map_anonymous(None, PAGE_SIZE, PageFlags::EXECUTABLE)
+        .unwrap();
+    assert_eq!(
+        space.translate_checked(execute_only, AccessMode::USER | AccessMode::READ),
+        Err(TranslateError::NotReadable)
+    );
+    assert_eq!(
+        space.translate_checked(text, AccessMode::USER | AccessMode::WRITE),
+        Err(TranslateError::NotWritable)
+    );
+    let data = space
+        .map_anonymous(None, PAGE_SIZE, PageFlags::READABLE | PageFlags::WRITABLE)
+        .unwrap();
+    assert_eq!(
+        space.translate_checked(data, AccessMode::USER | AccessMode::EXECUTE),
+        Err(TranslateError::NotExecutable)
+    );
+    // right after it, under the same level-0 node
+    assert_eq!(
+        space.translate_checked(data + PAGE_SIZE, AccessMode::USER | AccessMode::READ),
+        Err(TranslateError::NotMapped)
+    );
+    // the trampoline is the kernel's, whatever else is asked
+    let trampoline = VirtAddr::new(TRAMPOLINE_BASE_VA);
+    assert_eq!(
+        space.translate_checked(trampoline, user_rx),
+        Err(TranslateError::NotUser)
+    );
+    assert!(space
+        .translate_checked(trampoline, AccessMode::READ | AccessMode::EXECUTE)
+        .is_ok());
+}
+
+/// an area overlapping another should be refused without mapping any of its pages
+pub fn test_try_map_virt_area() {
+    let mut space = AddrSpace::make_init();
+    space.init_trapframe().unwrap();
+    let perms = PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::USER;
+    let base = space.map_anonymous(None, 2 * PAGE_SIZE, perms).unwrap();
+    let n_areas = space.virt_areas.len();
+    let before: Vec<_> = (0..4)
+        .map(|i| {
+            space
+                .translate(base + i * PAGE_SIZE)
+                .map(|(pa, flags)| (pa.as_usize(), flags.bits()))
+        })
+        .collect();
+    let baseline = frame_allocator::n_allocated_frames();
+
+    // its last page would go right after the existing area
+    let make_area = |begin: VirtAddr, n_pages: usize| {
+        let mut area = VirtArea::new(begin, begin + n_pages * PAGE_SIZE, perms);
+        for v_frame in area.virt_frame_range {
+            let frame_guard = FrameGuard::allocate_zeroed();
+            area.track_frame(
+                v_frame.get_base_virt_addr(),
+                VirtFrameGuard::ExclusivelyAllocated(frame_guard),
+            );
+        }
+        area
+    };
+    assert_eq!(
+        space.try_map_virt_area(make_area(base + PAGE_SIZE, 2)),
+        Err(MapError::Overlap {
+            va: base + PAGE_SIZE
+        })
+    );
+    assert_eq!(space.virt_areas.len(), n_areas);
+    let after: Vec<_> = (0..4)
+        .map(|i| {
+            space
+                .translate(base + i * PAGE_SIZE)
+                .map(|(pa, flags)| (pa.as_usize(), flags.bits()))
+        })
+        .collect();
+    assert_eq!(before, after);
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+
+    // right after it is fine, though under the same level-0 node
+    space
+        .try_map_virt_area(make_area(base + 2 * PAGE_SIZE, 2))
+        .unwrap();
+    assert_eq!(space.virt_areas.len(), n_areas + 1);
+    assert!(space.translate(base + 3 * PAGE_SIZE).is_some());
+
+    // a page mapped by no area is in the way
+    let stray = base + 5 * PAGE_SIZE;
+    let frame_guard = FrameGuard::allocate_zeroed();
+    space.page_table.map_one_allocate(
+        stray,
+        frame_guard.get_frame().get_base_phys_addr(),
+        PTEFlags::READABLE | PTEFlags::USER,
+    );
+    assert_eq!(
+        space.try_map_virt_area(make_area(base + 4 * PAGE_SIZE, 2)),
+        Err(MapError::AlreadyMapped { va: stray })
+    );
+    space.page_table.unmap_one(stray).unwrap();
+    drop(frame_guard);
+}
+
+/// the arguments should be laid out as `argv` under a 16-byte aligned stack pointer,
+/// arguments too long for the stack page should be rejected
+pub fn test_push_args() {
+    let mut space = AddrSpace::make_init();
+    space.init_trapframe().unwrap();
+    let perms = PageFlags::READABLE | PageFlags::WRITABLE;
+    let stack = space.map_anonymous(None, PAGE_SIZE, perms).unwrap();
+
+    let argv = space.push_args(stack, &[b"hello", b"world"]).unwrap();
+    assert_eq!(argv.as_usize() % 16, 0);
+    assert!(argv > stack && argv < stack + PAGE_SIZE);
+    let mut ptrs = [0; 3 * core::mem::size_of::<usize>()];
+    space.copy_from_user(argv, &mut ptrs).unwrap();
+    let ptrs: Vec<usize> = ptrs
+        .chunks_exact(core::mem::size_of::<usize>())
+        .map(|ptr| usize::from_ne_bytes(ptr.try_into().unwrap()))
+        .collect();
+    assert_eq!(ptrs[2], 0);
+    for (&ptr, expected) in ptrs.iter().zip([b"hello\0", b"world\0"]) {
+        let mut arg = [0; 6];
+        space.copy_from_user(VirtAddr::new(ptr), &mut arg).unwrap();
+        assert_eq!(&arg, expected);
+    }
+
+    // no argument at all is a null `argv[0]`
+    let sp = space.push_args(stack, &[]).unwrap();
+    assert_eq!(sp.as_usize(), stack.as_usize() + PAGE_SIZE - 16);
+
+    let long = [0x61; PAGE_SIZE / 2];
+    assert!(space.push_args(stack, &[&long, &long]).is_none());
+}
+
+/// a whitelisted device should be mapped to the user space, anything else refused
+pub fn test_map_device() {
+    let mut space = AddrSpace::make_init();
+    space.init_trapframe().unwrap();
+    let baseline = frame_allocator::n_allocated_frames();
+    let n_areas = space.virt_areas.len();
+
+    let device = PhysAddr::new(VIRT_TEST_BASE);
+    let va = space.map_device(device, VIRT_TEST_SIZE).unwrap();
+    let (pa, flags) = space.translate(va).unwrap();
+    assert_eq!(pa, device);
+    assert!(flags.contains(PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::USER));
+    assert!(!flags.contains(PageFlags::EXECUTABLE));
+    // only page table nodes are allocated, the device is not ours
+    assert!(frame_allocator::n_allocated_frames() - baseline <= 2);
+
+    for (pa, len) in [
+        // beyond the device
+        (VIRT_TEST_BASE, 2 * VIRT_TEST_SIZE),
+        (VIRT_TEST_BASE + 1, 1),
+        (VIRT_TEST_BASE, 0),
+        (KERNEL_BASE, PAGE_SIZE),
+        (UART_BASE, UART_SIZE),
+        (PLIC_BASE, PAGE_SIZE),
+        (CLINT_BASE, PAGE_SIZE),
+    ] {
+        assert!(space.map_device(PhysAddr::new(pa), len).is_none());
+    }
+    assert_eq!(space.virt_areas.len(), n_areas + 1);
+}
@@ -1,4 +1,9 @@
-use super::{layout::PAGE_ORDER, memory::PhysAddr};
+use alloc::vec::Vec;
+
+use super::{
+    layout::{KERNEL_BASE, PAGE_ORDER},
+    memory::{Frame, FrameRange, PhysAddr},
+};
 
 #[inline]
 #[allow(non_snake_case)]
@@ -106,6 +111,10 @@ macro_rules! impl_address_arithmetics {
 /// used for iterator
 pub trait StepByOne {
     fn step_one(&mut self);
+    /// undo a `step_one`
+    fn step_back(&mut self);
+    /// the number of `step_one`s from `self` to `end`, 0 if `end` is not after it
+    fn steps_to(&self, end: &Self) -> usize;
 }
 
 /// We use this to represent a range of values
@@ -134,6 +143,25 @@ where
         self.end
     }
 
+    /// the number of points the iterator goes through
+    pub fn len(&self) -> usize {
+        self.begin.steps_to(&self.end)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.begin >= self.end
+    }
+
+    /// whether `point` is in `[begin, end)`, the same ones the iterator goes through
+    pub fn contains(&self, point: T) -> bool {
+        self.begin <= point && point < self.end
+    }
+
+    /// whether the two ranges share any point, an empty range shares none
+    pub fn overlaps(&self, other: &Self) -> bool {
+        !self.is_empty() && !other.is_empty() && self.begin < other.end && other.begin < self.end
+    }
+
     // TODO: is this a good design?
     pub fn iter(&self) -> SimpleRangeIterator<T> {
         SimpleRangeIterator {
@@ -185,13 +213,95 @@ where
             Some(ret)
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.current.steps_to(&self.end);
+        (len, Some(len))
+    }
+}
+
+/// from the last point down to `begin`, e.g. to unmap the leaves before what they hang off
+impl<T> DoubleEndedIterator for SimpleRangeIterator<T>
+where
+    T: StepByOne + Copy + PartialEq + PartialOrd,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.current == self.end {
+            None
+        } else {
+            self.end.step_back();
+            Some(self.end)
+        }
+    }
+}
+
+impl<T> ExactSizeIterator for SimpleRangeIterator<T> where
+    T: StepByOne + Copy + PartialEq + PartialOrd
+{
 }
 
 pub fn arithmetics_done_right() {
     {
-        let pa = PhysAddr::new(1);
+        let pa = PhysAddr::new(KERNEL_BASE + 1);
         let pa1 = pa;
-        assert_eq!(pa.align_down(), PhysAddr::new(0));
-        assert_eq!(pa1.align_up(), PhysAddr::new(4096));
+        assert_eq!(pa.align_down(), PhysAddr::new(KERNEL_BASE));
+        assert_eq!(pa1.align_up(), PhysAddr::new(KERNEL_BASE + 4096));
     }
 }
+
+/// `contains` and `overlaps` of adjacent, nested, disjoint and empty ranges
+pub fn test_simple_range_overlaps() {
+    let range = |begin, end| FrameRange::new(Frame::from_ppn(begin), Frame::from_ppn(end));
+    let a = range(2, 5);
+    assert!(!a.contains(Frame::from_ppn(1)));
+    assert!(a.contains(Frame::from_ppn(2)));
+    assert!(a.contains(Frame::from_ppn(4)));
+    assert!(!a.contains(Frame::from_ppn(5)));
+
+    // adjacent
+    assert!(!a.overlaps(&range(5, 8)));
+    assert!(!range(0, 2).overlaps(&a));
+    // nested, both ways
+    assert!(a.overlaps(&range(3, 4)));
+    assert!(range(3, 4).overlaps(&a));
+    assert!(a.overlaps(&a));
+    // partially
+    assert!(a.overlaps(&range(4, 9)));
+    // disjoint
+    assert!(!a.overlaps(&range(7, 9)));
+
+    // empty ones contain nothing, even inside another range
+    let empty = range(3, 3);
+    assert!(empty.is_empty());
+    assert!(!empty.contains(Frame::from_ppn(3)));
+    assert!(!empty.overlaps(&a));
+    assert!(!a.overlaps(&empty));
+    assert!(!empty.overlaps(&empty));
+    assert_eq!(empty.iter().count(), 0);
+}
+
+/// a 5-frame range should be gone through the same, forward and backward
+pub fn test_simple_range_double_ended() {
+    let range = FrameRange::new(Frame::from_ppn(10), Frame::from_ppn(15));
+    assert_eq!(range.len(), 5);
+    assert_eq!(range.n_pages(), 5);
+    assert!(!range.is_empty());
+
+    let forward: Vec<usize> = range.iter().map(|frame| frame.number).collect();
+    assert_eq!(forward, [10, 11, 12, 13, 14]);
+    let backward: Vec<usize> = range.iter().rev().map(|frame| frame.number).collect();
+    assert_eq!(backward, [14, 13, 12, 11, 10]);
+    assert_eq!(range.iter().len(), 5);
+
+    // both ends meet in the middle
+    let mut iter = range.iter();
+    assert_eq!(iter.next().unwrap().number, 10);
+    assert_eq!(iter.next_back().unwrap().number, 14);
+    assert_eq!(iter.len(), 3);
+    let middle: Vec<usize> = iter.map(|frame| frame.number).collect();
+    assert_eq!(middle, [11, 12, 13]);
+
+    let empty = FrameRange::new(Frame::from_ppn(10), Frame::from_ppn(10));
+    assert_eq!(empty.len(), 0);
+    assert!(empty.iter().next_back().is_none());
+}
@@ -0,0 +1,148 @@
+//! Address space identifiers (ASIDs) tag the TLB entries of a user address space,
+//! so that switching between two spaces with their own ASID does not flush the TLB.
+//! The kernel spaces use `KERNEL_ASID`. Once the pool runs out, the remaining spaces
+//! share `shared` and are flushed every time they are switched to, like without ASIDs.
+//! A space with its own is still flushed when it is switched to on another hart than the last
+
+use alloc::vec::Vec;
+use riscv::{asm::sfence_vma_all, register::satp};
+use spin::Mutex;
+
+use crate::info;
+
+pub const KERNEL_ASID: u16 = 0;
+
+/// at most that many ASIDs, even if the hart implements all 16 bits of the field
+const MAX_ASIDS: usize = 256;
+
+struct AsidPool {
+    /// ASIDs the harts implement, up to `MAX_ASIDS`. 0 until probed by `hart_init`
+    n_asids: usize,
+    /// the lowest ASID never handed out
+    next: u16,
+    /// given back by dropped spaces
+    free: Vec<u16>,
+}
+
+impl AsidPool {
+    /// the one for spaces that run out of ASIDs, `KERNEL_ASID` if there are too few
+    fn shared(&self) -> u16 {
+        self.n_asids.saturating_sub(1).max(KERNEL_ASID as usize) as u16
+    }
+}
+
+static ASID_POOL: Mutex<AsidPool> = Mutex::new(AsidPool {
+    n_asids: 0,
+    next: KERNEL_ASID + 1,
+    free: Vec::new(),
+});
+
+/// The ASID of an address space, it goes back to the pool on drop
+#[derive(Debug)]
+pub struct Asid {
+    id: u16,
+    /// no other space uses it, otherwise the TLB has to be flushed when switching to it
+    tagged: bool,
+}
+
+impl Asid {
+    pub fn kernel() -> Self {
+        Self {
+            id: KERNEL_ASID,
+            tagged: false,
+        }
+    }
+
+    /// an ASID of its own if there are any left, the shared one otherwise
+    pub fn allocate() -> Self {
+        let mut pool = ASID_POOL.lock();
+        let shared = pool.shared();
+        let id = match pool.free.pop() {
+            Some(id) => Some(id),
+            None if pool.next < shared => {
+                pool.next += 1;
+                Some(pool.next - 1)
+            }
+            None => None,
+        };
+        match id {
+            Some(id) => Self { id, tagged: true },
+            None => Self {
+                id: shared,
+                tagged: false,
+            },
+        }
+    }
+
+    pub fn get(&self) -> u16 {
+        self.id
+    }
+
+    pub fn is_tagged(&self) -> bool {
+        self.tagged
+    }
+}
+
+impl Drop for Asid {
+    fn drop(&mut self) {
+        // stale entries of the space have been flushed by `AddrSpace::drop` on this hart,
+        // the next space taking it flushes the others, see `AddrSpace::needs_flush_on_load`
+        if self.tagged {
+            ASID_POOL.lock().free.push(self.id);
+        }
+    }
+}
+
+/// ASIDs the current hart implements, found by writing ones to the field and reading them back
+fn probe_asids() -> usize {
+    let satp = satp::read();
+    unsafe {
+        satp::set(satp.mode(), 0xFFFF, satp.ppn());
+        let n_bits = satp::read().asid().count_ones();
+        satp::set(satp.mode(), satp.asid(), satp.ppn());
+        sfence_vma_all();
+        1 << n_bits
+    }
+}
+
+/// Call after paging is enabled, before any user address space is made.
+/// The pool only hands out ASIDs that every hart implements
+pub fn hart_init() {
+    let n_asids = probe_asids().min(MAX_ASIDS);
+    let mut pool = ASID_POOL.lock();
+    pool.n_asids = match pool.n_asids {
+        0 => n_asids,
+        n => n.min(n_asids),
+    };
+    info!("asid::hart_init: {:?} ASIDs", pool.n_asids);
+}
+
+/// dropped spaces should give their ASIDs back, and the pool should fall back to the shared one
+pub fn test_asid_recycling() {
+    let first = Asid::allocate();
+    if !first.is_tagged() {
+        // the hart has no ASIDs to spare
+        assert_eq!(first.get(), ASID_POOL.lock().shared());
+        return;
+    }
+    assert_ne!(first.get(), KERNEL_ASID);
+    let id = first.get();
+    drop(first);
+    let again = Asid::allocate();
+    assert_eq!(again.get(), id);
+
+    // use up the pool
+    let mut held = Vec::new();
+    loop {
+        let asid = Asid::allocate();
+        if !asid.is_tagged() {
+            assert_eq!(asid.get(), ASID_POOL.lock().shared());
+            break;
+        }
+        assert!(held.iter().all(|other: &Asid| other.get() != asid.get()));
+        assert_ne!(asid.get(), again.get());
+        held.push(asid);
+    }
+    let id = held.pop().unwrap().get();
+    assert_eq!(Asid::allocate().get(), id);
+}
@@ -74,12 +74,31 @@ pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024; // 1MB
 // Their RAII managing instance are allocated in the KERNEL_HEAP by the `KERNEL_HEAP_ALLOCATOR`
 pub const KERNEL_STACK_SIZE: usize = PAGE_SIZE * 2;
 
+// the stack each hart boots on, from `__kernel_stack_start` up, see `boot.S`.
+// `kmain` never returns, so the hart then schedules on it, see `process::schedule`
+pub const BOOT_STACK_SIZE: usize = 0x10000;
+// the assembly loads it from here rather than repeating it, see `boot.S` and `kernelvec.S`
+#[export_name = "__boot_stack_size"]
+static BOOT_STACK_SIZE_SYMBOL: usize = BOOT_STACK_SIZE;
+
 // proc's user stack
 // each process has its own user stack
 // They are allocated by the `FRAME_ALLOCATOR`
 pub const USER_STACK_SIZE: usize = PAGE_SIZE * 2;
+// the user stack starts with one page and grows on page faults up to this many pages,
+// the pages below it are left unmapped so that it has room to grow
+pub const USER_STACK_MAX_PAGES: usize = 16;
 
 // memory mapped registers
+// qemu's `sifive_test` device, writing to it powers off the machine, see `arch::shutdown`
+pub const VIRT_TEST_BASE: usize = 0x10_0000;
+pub const VIRT_TEST_SIZE: usize = PAGE_SIZE;
+
+/// `(base, size)` of the devices a process may map with `mapdevice`, see `AddrSpace::map_device`.
+/// The test device powers off no more than `shutdown` does. Not the memory, nor the UART and
+/// the disk the kernel drives, nor the PLIC and the CLINT, which would let it mask interrupts
+pub const USER_DEVICES: &[(usize, usize)] = &[(VIRT_TEST_BASE, VIRT_TEST_SIZE)];
+
 // qemu puts UART registers here in physical memory.
 pub const UART_BASE: usize = 0x1000_0000;
 pub const UART0: usize = UART_BASE;
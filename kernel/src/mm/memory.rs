@@ -1,19 +1,23 @@
 use core::{
-    arch::asm,
     ops::{Add, Sub},
     slice,
 };
 
-use alloc::sync::Arc;
-
 use crate::{
-    allocator::frame_allocator::{allocate_one_frame, deallocate_one_frame},
+    allocator::frame_allocator::{
+        allocate_contiguous_frames, allocate_one_frame, deallocate_contiguous_frames,
+        deallocate_one_frame, frame_ref_count, n_allocated_frames, share_one_frame,
+    },
     debug, impl_address_arithmetics,
 };
 
 use super::{
     arithmetics::{SimpleRange, StepByOne, PG_ROUND_DOWN, PG_ROUND_UP},
-    layout::PAGE_SIZE,
+    layout::{
+        CLINT_BASE, CLINT_SIZE, KERNEL_BASE, PAGE_SIZE, PHYS_TOP, PLIC_BASE, PLIC_SIZE,
+        TEXT_BASE_USER_VA, TRAMPOLINE_BASE_VA, UART_BASE, UART_SIZE, VIRTIO_BASE, VIRTIO_SIZE,
+        VIRT_TEST_BASE, VIRT_TEST_SIZE,
+    },
 };
 
 // --------------------------- Physical Address ------------------------ //
@@ -24,11 +28,42 @@ pub struct PhysAddr(usize);
 
 impl_address_arithmetics!(PhysAddr);
 
+/// the memory and the devices, each `[begin, end)`, see `AddrSpace::make_kernel`
+const PHYS_RANGES: [(usize, usize); 6] = [
+    (KERNEL_BASE, PHYS_TOP),
+    (VIRT_TEST_BASE, VIRT_TEST_BASE + VIRT_TEST_SIZE),
+    (UART_BASE, UART_BASE + UART_SIZE),
+    (VIRTIO_BASE, VIRTIO_BASE + VIRTIO_SIZE),
+    (CLINT_BASE, CLINT_BASE + CLINT_SIZE),
+    (PLIC_BASE, PLIC_BASE + PLIC_SIZE),
+];
+
 impl PhysAddr {
+    /// In debug builds it panics if `pa` is neither in the memory nor in a device,
+    /// e.g. a virtual address taken for a physical one, see `is_known`
     pub fn new(pa: usize) -> Self {
+        #[cfg(debug_assertions)]
+        assert!(
+            Self::is_known(pa),
+            "PhysAddr::new: {:?} is not a physical address",
+            pa as *const usize
+        );
         Self(pa)
     }
 
+    /// Without the check of `new`, e.g. for whatever an invalid PTE holds
+    pub fn new_unchecked(pa: usize) -> Self {
+        Self(pa)
+    }
+
+    /// Whether `pa` is in one of `PHYS_RANGES`. The end of each is taken as well,
+    /// it is the exclusive end of a range of addresses in it
+    pub fn is_known(pa: usize) -> bool {
+        PHYS_RANGES
+            .iter()
+            .any(|&(begin, end)| begin <= pa && pa <= end)
+    }
+
     pub fn as_mut_ptr<T>(&self) -> *mut T {
         self.0 as *mut T
     }
@@ -89,7 +124,7 @@ impl VirtAddr {
 
     pub fn offset(&self) -> usize {
         let va = self.0;
-        va & VA_OFFSET_WIDTH
+        va & ((1 << VA_OFFSET_WIDTH) - 1)
     }
 }
 
@@ -115,6 +150,11 @@ impl Frame {
         PhysAddr(self.number * PAGE_SIZE)
     }
 
+    /// The whole page as bytes.
+    /// # Safety
+    /// For the single owner of the frame only, e.g. right after allocating it:
+    /// nothing stops two callers from holding aliasing slices.
+    /// Prefer `copy_in` and `copy_out`
     pub unsafe fn get_bytes(&self) -> &'static mut [u8] {
         let pa = self.get_base_phys_addr().as_mut_ptr();
         let len = PAGE_SIZE;
@@ -127,6 +167,33 @@ impl Frame {
         }
     }
 
+    /// Copy `src` into the page from `offset`, without handing out a slice of it
+    pub fn copy_in(&self, offset: usize, src: &[u8]) -> Result<(), FrameBoundsError> {
+        Self::check_bounds(offset, src.len())?;
+        unsafe {
+            let dst = self.get_base_phys_addr().as_mut_ptr::<u8>().add(offset);
+            core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len());
+        }
+        Ok(())
+    }
+
+    /// Copy the page from `offset` into `dst`, without handing out a slice of it
+    pub fn copy_out(&self, offset: usize, dst: &mut [u8]) -> Result<(), FrameBoundsError> {
+        Self::check_bounds(offset, dst.len())?;
+        unsafe {
+            let src = self.get_base_phys_addr().as_ptr::<u8>().add(offset);
+            core::ptr::copy_nonoverlapping(src, dst.as_mut_ptr(), dst.len());
+        }
+        Ok(())
+    }
+
+    fn check_bounds(offset: usize, len: usize) -> Result<(), FrameBoundsError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= PAGE_SIZE => Ok(()),
+            _ => Err(FrameBoundsError { offset, len }),
+        }
+    }
+
     pub fn write(&mut self, data: &[u8]) -> usize {
         let total = PAGE_SIZE.min(data.len());
         let bytes = unsafe { self.get_bytes() };
@@ -138,6 +205,13 @@ impl Frame {
     }
 }
 
+/// `len` bytes from `offset` go beyond the end of the page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBoundsError {
+    pub offset: usize,
+    pub len: usize,
+}
+
 impl From<PhysAddr> for Frame {
     fn from(pa: PhysAddr) -> Self {
         Self::from_phys_addr(pa.align_down())
@@ -148,6 +222,14 @@ impl StepByOne for Frame {
     fn step_one(&mut self) {
         self.number += 1;
     }
+
+    fn step_back(&mut self) {
+        self.number -= 1;
+    }
+
+    fn steps_to(&self, end: &Self) -> usize {
+        end.number.saturating_sub(self.number)
+    }
 }
 
 // TODO: simialr to `VirtFrameRange`, macro?
@@ -155,7 +237,7 @@ pub type FrameRange = SimpleRange<Frame>;
 
 impl FrameRange {
     pub fn n_pages(&self) -> usize {
-        self.get_end().number - self.get_begin().number
+        self.len()
     }
 }
 
@@ -181,19 +263,31 @@ impl FrameGuard {
     //     Self { inner: Some(frame) }
     // }
 
-    pub fn allocate_zeroed() -> Self {
-        let mut frame: Frame = allocate_one_frame().into();
+    /// `None` if the frames run out, e.g. for allocations on behalf of the user
+    pub fn try_allocate_zeroed() -> Option<Self> {
+        let mut frame: Frame = allocate_one_frame()?.into();
         frame.zero();
         let zelf = Self { inner: Some(frame) };
         // let pa = frame.get_base_phys_addr().as_usize();
         // debug!("FrameGuard::allocate_zeroed: frame at pa {:?} allocated", pa as *const usize);
-        zelf
+        Some(zelf)
     }
 
-    pub fn allocate_with_data(data: &[u8]) -> Self {
-        let zelf = Self::allocate_zeroed();
+    /// like `try_allocate_zeroed`, but panics if the frames run out.
+    /// For allocations that cannot fail, e.g. when booting
+    pub fn allocate_zeroed() -> Self {
+        Self::try_allocate_zeroed().expect("FrameGuard::allocate_zeroed: out of frames")
+    }
+
+    /// `None` if the frames run out, like `try_allocate_zeroed`
+    pub fn try_allocate_with_data(data: &[u8]) -> Option<Self> {
+        let zelf = Self::try_allocate_zeroed()?;
         zelf.inner.unwrap().write(data);
-        zelf
+        Some(zelf)
+    }
+
+    pub fn allocate_with_data(data: &[u8]) -> Self {
+        Self::try_allocate_with_data(data).expect("FrameGuard::allocate_with_data: out of frames")
     }
 
     /// start managing the frame
@@ -227,6 +321,18 @@ impl FrameGuard {
             .expect("FrameGuard::get_frame: no inner")
     }
 
+    /// Another guard of the same frame, the frame is freed when both are dropped
+    pub fn share(&self) -> Self {
+        let frame = self.get_frame();
+        share_one_frame(frame.get_base_phys_addr());
+        Self { inner: Some(frame) }
+    }
+
+    /// whether another guard refers to the same frame
+    pub fn is_shared(&self) -> bool {
+        frame_ref_count(self.get_frame().get_base_phys_addr()) > 1
+    }
+
     // ignore the recycling process by taking the inner frame
     // It can only be called once, otherwise panic
     // Using it may result in memory leak!
@@ -236,6 +342,28 @@ impl FrameGuard {
             .expect("FrameGuard::take_frame: called more than once!")
     }
 
+    /// Give up managing the frame without freeing it, e.g. to hand it to a device.
+    /// Its reference is kept, the frame stays allocated until a guard re-adopts it
+    /// with `FrameGuard::from_phys_addr` and is dropped, otherwise it leaks
+    pub fn leak(self) -> PhysAddr {
+        unsafe { self.take() }.get_base_phys_addr()
+    }
+
+    /// Manage again a frame given up by `FrameGuard::leak`, freed when the guard drops.
+    /// # Safety
+    /// `pa` must be the base of a frame leaked before, and be re-adopted only once
+    /// per `leak`, the frame would be freed under another guard otherwise.
+    /// The device it was handed to should be done with it
+    pub unsafe fn from_phys_addr(pa: PhysAddr) -> Self {
+        debug_assert_eq!(pa.as_usize() % PAGE_SIZE, 0);
+        debug_assert!(
+            frame_ref_count(pa) > 0,
+            "FrameGuard::from_phys_addr: {:?} is not allocated",
+            pa.as_usize() as *const usize
+        );
+        Self::from_frame(Frame::from_phys_addr(pa))
+    }
+
     // pub unsafe fn set_unmapped(&mut self) {
     // self.unmapped = true;
     // }
@@ -259,15 +387,54 @@ impl Drop for FrameGuard {
                 "FrameGuard::drop: phys_addr: {:?}",
                 frame.get_base_phys_addr().as_usize() as *const usize,
             );
+            // NOTE: no TLB flush here, whoever unmaps the frame flushes it before dropping the guard
             deallocate_one_frame(frame.get_base_phys_addr());
-            unsafe {
-                // To expose bugs
-                asm!("sfence.vma");
-            }
         }
     }
 }
 
+/// RAII managing instance of physically contiguous frames, e.g. for DMA buffers.
+/// The whole range is recycled when it gets dropped
+#[derive(Debug)]
+pub struct FrameRangeGuard {
+    inner: FrameRange,
+}
+
+impl FrameRangeGuard {
+    /// allocates `n` contiguous zeroed frames,
+    /// `None` if the allocator has no contiguous run that long
+    pub fn allocate_zeroed(n: usize) -> Option<Self> {
+        let range = allocate_contiguous_frames(n)?;
+        for mut frame in range {
+            frame.zero();
+        }
+        Some(Self { inner: range })
+    }
+
+    pub fn get_range(&self) -> FrameRange {
+        self.inner
+    }
+
+    pub fn get_base_phys_addr(&self) -> PhysAddr {
+        self.inner.get_begin().get_base_phys_addr()
+    }
+
+    pub fn n_pages(&self) -> usize {
+        self.inner.n_pages()
+    }
+}
+
+impl Drop for FrameRangeGuard {
+    fn drop(&mut self) {
+        debug!(
+            "FrameRangeGuard::drop: phys_addr: {:?}, {:?} pages",
+            self.get_base_phys_addr().as_usize() as *const usize,
+            self.n_pages()
+        );
+        deallocate_contiguous_frames(self.inner);
+    }
+}
+
 // ------------------------- Vitural Page (Frame) -----------------------------------
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -314,6 +481,14 @@ impl StepByOne for VirtFrame {
     fn step_one(&mut self) {
         self.number += 1;
     }
+
+    fn step_back(&mut self) {
+        self.number -= 1;
+    }
+
+    fn steps_to(&self, end: &Self) -> usize {
+        end.number.saturating_sub(self.number)
+    }
 }
 
 pub type VirtFrameRange = SimpleRange<VirtFrame>;
@@ -332,14 +507,15 @@ impl VirtFrameRange {
     }
 
     pub fn n_pages(&self) -> usize {
-        self.get_end().number - self.get_begin().number
+        self.len()
     }
 }
 
 #[derive(Debug)]
 pub enum VirtFrameGuard {
     ExclusivelyAllocated(FrameGuard),
-    CowShared(Arc<FrameGuard>),
+    /// Shared with other address spaces, see `FrameGuard::share`
+    CowShared(FrameGuard),
     PhysBorrowed(Frame),
 }
 
@@ -349,12 +525,100 @@ impl VirtFrameGuard {
             VirtFrameGuard::ExclusivelyAllocated(frame_guard) => {
                 frame_guard.get_frame().get_base_phys_addr().as_usize()
             }
-            VirtFrameGuard::CowShared(frame_guard_arc) => frame_guard_arc
-                .as_ref()
-                .get_frame()
-                .get_base_phys_addr()
-                .as_usize(),
+            VirtFrameGuard::CowShared(frame_guard) => {
+                frame_guard.get_frame().get_base_phys_addr().as_usize()
+            }
             VirtFrameGuard::PhysBorrowed(frame) => frame.get_base_phys_addr().as_usize(),
         }
     }
+
+    /// Converts an exclusively allocated frame into a `CowShared` one
+    /// so that it can be shared by `VirtFrameGuard::share`
+    pub fn into_shared(self) -> Self {
+        match self {
+            VirtFrameGuard::ExclusivelyAllocated(frame_guard) => {
+                VirtFrameGuard::CowShared(frame_guard)
+            }
+            other => other,
+        }
+    }
+
+    /// Another reference to the same underlying frame.
+    /// An exclusively allocated frame cannot be shared, call `into_shared` first
+    pub fn share(&self) -> Self {
+        match self {
+            VirtFrameGuard::ExclusivelyAllocated(_) => {
+                panic!("VirtFrameGuard::share: exclusively allocated frame cannot be shared")
+            }
+            VirtFrameGuard::CowShared(frame_guard) => {
+                VirtFrameGuard::CowShared(frame_guard.share())
+            }
+            VirtFrameGuard::PhysBorrowed(frame) => VirtFrameGuard::PhysBorrowed(*frame),
+        }
+    }
+}
+
+/// copies within the page should go through, those beyond it should fail without copying
+pub fn test_frame_copy_bounds() {
+    let frame_guard = FrameGuard::allocate_zeroed();
+    let frame = frame_guard.get_frame();
+
+    frame.copy_in(PAGE_SIZE - 4, b"tail").unwrap();
+    let mut buf = [0; 4];
+    frame.copy_out(PAGE_SIZE - 4, &mut buf).unwrap();
+    assert_eq!(&buf, b"tail");
+    frame.copy_in(PAGE_SIZE, &[]).unwrap();
+
+    let err = FrameBoundsError {
+        offset: PAGE_SIZE - 3,
+        len: 4,
+    };
+    assert_eq!(frame.copy_in(PAGE_SIZE - 3, b"over"), Err(err));
+    assert_eq!(frame.copy_out(PAGE_SIZE - 3, &mut buf), Err(err));
+    assert!(frame.copy_in(usize::MAX, b"x").is_err());
+    let mut page = [0xff; PAGE_SIZE + 1];
+    assert!(frame.copy_out(0, &mut page).is_err());
+    // nothing was written by the failed copies
+    assert_eq!(page[0], 0xff);
+}
+
+/// a leaked frame should stay allocated until the guard re-adopting it is dropped
+pub fn test_leak_and_readopt_frame() {
+    let baseline = n_allocated_frames();
+    let pa = FrameGuard::allocate_with_data(b"dma").leak();
+    assert_eq!(n_allocated_frames(), baseline + 1);
+    assert_eq!(frame_ref_count(pa), 1);
+
+    let guard = unsafe { FrameGuard::from_phys_addr(pa) };
+    let mut buf = [0; 3];
+    guard.get_frame().copy_out(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"dma");
+    assert_eq!(n_allocated_frames(), baseline + 1);
+    drop(guard);
+    assert_eq!(n_allocated_frames(), baseline);
+    assert_eq!(frame_ref_count(pa), 0);
+}
+
+/// the memory and the devices should be physical addresses, virtual ones should not
+pub fn test_phys_addr_is_known() {
+    for pa in [
+        KERNEL_BASE,
+        PHYS_TOP - 1,
+        PHYS_TOP,
+        UART_BASE,
+        PLIC_BASE + 0x1000,
+    ] {
+        assert!(PhysAddr::is_known(pa), "{:#x} should be known", pa);
+        assert_eq!(PhysAddr::new(pa).as_usize(), pa);
+    }
+    for va in [
+        0,
+        TEXT_BASE_USER_VA,
+        PHYS_TOP + PAGE_SIZE,
+        TRAMPOLINE_BASE_VA,
+        usize::MAX,
+    ] {
+        assert!(!PhysAddr::is_known(va), "{:#x} should not be known", va);
+        assert_eq!(PhysAddr::new_unchecked(va).as_usize(), va);
+    }
 }
@@ -1,16 +1,22 @@
 use alloc::sync::Arc;
-use riscv::register::sscratch;
+use riscv::register::{satp, sscratch};
 use spin::RwLock;
 
-use crate::allocator;
+use crate::{allocator, arch};
 
-use self::address_space::AddrSpace;
+use self::{
+    address_space::AddrSpace,
+    layout::PAGE_SIZE,
+    memory::{VirtAddr, VirtFrameRange},
+};
 
 pub mod address_space;
 pub mod arithmetics;
+pub mod asid;
 pub mod layout;
 pub mod memory;
 pub mod page_table;
+pub mod tlb;
 
 // their kernel address space can be accessed by multiple cores
 // and heavily read dominated.
@@ -23,16 +29,47 @@ lazy_static::lazy_static! {
     };
 }
 
+/// Unmap the identically mapped kernel page at `va`, or map it back, see `AddrSpace::set_guard_page`.
+/// `None` if `va` is not identically mapped, or there is no frame to split its megapage
+pub fn set_kernel_guard_page(va: VirtAddr, guard: bool) -> Option<()> {
+    arch::push_off();
+    // it may split a megapage, see `PageTableGuard::split_megapage`
+    let mut kernel_space = KERNEL_ADDRESS_SPACE.write();
+    // its page table may be read-only in itself after `lock_space`, write it with the
+    // translation off. The kernel is identically mapped, so it runs on as it is, and
+    // switching to or from `Bare` does not flush the entries cached for the other pages
+    let satp = satp::read();
+    unsafe { satp::set(satp::Mode::Bare, 0, 0) };
+    let ret = kernel_space.set_guard_page(va, guard);
+    unsafe { satp::set(satp.mode(), satp.asid(), satp.ppn()) };
+    drop(kernel_space);
+    arch::pop_off();
+
+    // any hart may have cached the page, e.g. running on the stack below it
+    tlb_shootdown(VirtFrameRange::new(va.into(), (va + PAGE_SIZE).into()));
+    ret
+}
+
+/// Flush `range` from the TLB of every hart, after changing a mapping they may share,
+/// e.g. in the kernel space. It waits until all have flushed, do not hold a lock
+/// another hart may be spinning on with interrupts off, see `mm/tlb.rs`
+pub fn tlb_shootdown(range: VirtFrameRange) {
+    tlb::shootdown(range);
+}
+
 pub fn init() {
     allocator::init();
     // invoke init
-    KERNEL_ADDRESS_SPACE.read();
+    KERNEL_ADDRESS_SPACE.read().dump();
 }
 
 pub fn hart_init() {
     // load the kernel page table: this is the first time paging is enabled
     // try setting a breakpoint here and use `info mem` in QEMU to see what happens
     KERNEL_ADDRESS_SPACE.read().load();
+    // it probes the ASID field of `satp`, see `mm::asid`
+    asid::hart_init();
+    tlb::hart_init();
 
     // set `sscratch` to point to the TRAPFRAME in user space
     // We map each proc's TRAPFRAME to the same address, and makes sure
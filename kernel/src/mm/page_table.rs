@@ -3,18 +3,23 @@ use core::slice;
 use alloc::vec::Vec;
 use bitflags::bitflags;
 
+use crate::process::elf::{PF_R, PF_W, PF_X};
 #[allow(unused)]
 use crate::{debug, info};
 
 use super::{
     address_space::VirtArea,
     arithmetics::PTE2PA,
+    layout::{KERNEL_BASE, PAGE_SIZE},
     memory::{Frame, FrameGuard, PhysAddr, VirtAddr, VirtFrameGuard},
 };
 
 #[allow(unused)]
 const ENTRY_PER_TABLE: usize = 512;
 
+/// what a leaf PTE at level 1 maps, 2MB
+pub const MEGAPAGE_SIZE: usize = PAGE_SIZE * ENTRY_PER_TABLE;
+
 // This is a managing instance of a page table node
 #[repr(transparent)]
 #[derive(Debug)]
@@ -41,8 +46,13 @@ impl PageTableNode {
     //     table[index] // uses Copy
     // }
 
+    /// valid indices are `0..ENTRY_PER_TABLE`
+    const fn is_valid_index(index: usize) -> bool {
+        index < ENTRY_PER_TABLE
+    }
+
     pub unsafe fn entry_at(&self, index: usize) -> &PageTableEntry {
-        if index > ENTRY_PER_TABLE {
+        if !Self::is_valid_index(index) {
             panic!("PageTableNode: invalid index");
         }
         let table = self.table();
@@ -50,7 +60,7 @@ impl PageTableNode {
     }
 
     pub unsafe fn set_entry(&self, index: usize, entry: PageTableEntry) {
-        if index > ENTRY_PER_TABLE {
+        if !Self::is_valid_index(index) {
             panic!("PageTableNode: invalid index");
         }
         let table = self.table();
@@ -67,7 +77,7 @@ impl PageTableNode {
 // represents a PTE
 #[derive(Clone, Copy)]
 #[repr(transparent)]
-struct PageTableEntry {
+pub struct PageTableEntry {
     bits: usize,
 }
 
@@ -104,6 +114,23 @@ bitflags! {
     }
 }
 
+impl PageFlags {
+    /// the permissions an ELF segment asks for in its `p_flags`, without `USER`
+    pub fn from_elf_flags(p_flags: u32) -> Self {
+        let mut perms = Self::empty();
+        if p_flags & PF_R != 0 {
+            perms |= Self::READABLE;
+        }
+        if p_flags & PF_W != 0 {
+            perms |= Self::WRITABLE;
+        }
+        if p_flags & PF_X != 0 {
+            perms |= Self::EXECUTABLE;
+        }
+        perms
+    }
+}
+
 impl From<PTEFlags> for PageFlags {
     fn from(flags: PTEFlags) -> Self {
         Self::from_bits_retain(flags.bits())
@@ -128,14 +155,16 @@ impl PageTableEntry {
     }
 
     pub fn flags(&self) -> PTEFlags {
-        let flags_raw = self.bits & (1 << 10) - 1;
+        // the lower 10 bits: V, R, W, X, U, G, A, D and the two RSW bits
+        let flags_raw = self.bits & ((1 << 10) - 1);
         PTEFlags::from_bits(flags_raw as u16).unwrap()
     }
 
     /// get the referencing physical address (page-aligned)
     /// from PTE
     pub fn referencing_address(&self) -> PhysAddr {
-        PhysAddr::new(PTE2PA(self.bits))
+        // it may be invalid, e.g. the level-0 PTE `find` returns for an unmapped page
+        PhysAddr::new_unchecked(PTE2PA(self.bits))
     }
 
     /// get the referencing frame from PTE
@@ -153,6 +182,23 @@ impl PageTableEntry {
     pub fn is_valid(&self) -> bool {
         self.flags().contains(PTEFlags::VALID)
     }
+
+    /// a valid PTE is a leaf if any of R, W and X is set, otherwise it points to the next level
+    pub fn is_leaf(&self) -> bool {
+        self.flags()
+            .intersects(PTEFlags::READABLE | PTEFlags::WRITABLE | PTEFlags::EXECUTABLE)
+    }
+}
+
+impl core::fmt::Debug for PageTableEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "PTE({:?}, {:?})",
+            self.referencing_address().as_usize() as *const usize,
+            self.flags()
+        )
+    }
 }
 
 // impl Into<PhysicalAddress> for PageTableEntry {
@@ -180,6 +226,9 @@ pub struct PageTableGuard {
     /// Only contains physical `Frame`s that are allocated for this `PageTable`.
     /// Does not include physical `Frame`s that it maps to
     node_frames: Vec<FrameGuard>,
+
+    /// its node frames are read-only in itself, see `lock_table`
+    locked: bool,
 }
 
 impl PageTableGuard {
@@ -196,35 +245,167 @@ impl PageTableGuard {
     /// `PageTableGuard::allocate` allocates the root node of the page table
     /// From there use `PageTableGuard::map_one_allocate` can allocate its interior node
     pub fn allocate() -> Self {
-        let root_node_frame_guard = FrameGuard::allocate_zeroed();
+        Self::try_allocate().expect("PageTableGuard::allocate: out of frames")
+    }
+
+    /// `None` if there is no frame left for the root node
+    pub fn try_allocate() -> Option<Self> {
+        let root_node_frame_guard = FrameGuard::try_allocate_zeroed()?;
 
         // safety: it is allocated, hence valid
         let root_node = unsafe { PageTableNode::from_frame(root_node_frame_guard.inner_ref()) };
 
-        Self {
+        Some(Self {
             root_node,
             node_frames: vec![root_node_frame_guard],
-        }
+            locked: false,
+        })
+    }
+
+    /// frames taken by its nodes, the root included
+    pub fn n_node_frames(&self) -> usize {
+        self.node_frames.len()
     }
 
     /// Interior function to allocate one `PageTableNode` frame
     /// and tracks it as its interior `node_frame`
-    fn allocate_node(&mut self) -> Frame {
-        let node_frame = FrameGuard::allocate_zeroed();
+    fn try_allocate_node(&mut self) -> Option<Frame> {
+        let node_frame = FrameGuard::try_allocate_zeroed()?;
         let frame = node_frame.get_frame();
         self.node_frames.push(node_frame);
-        frame
+        Some(frame)
+    }
+
+    /// Clear every valid non-global leaf PTE, walking down from the root.
+    /// The interior nodes are left in place and recycled with `node_frames`
+    pub fn unmap_all(&mut self) {
+        let root_table = unsafe { self.root_node.table() };
+        Self::unmap_all_in(root_table, 2);
+    }
+
+    fn unmap_all_in(table: &mut [PageTableEntry], level: usize) {
+        for pte in table.iter_mut() {
+            let flags = pte.flags();
+            if !flags.contains(PTEFlags::VALID) {
+                continue;
+            }
+            if pte.is_leaf() {
+                if !flags.contains(PTEFlags::GLOBAL) {
+                    *pte = PageTableEntry::empty();
+                }
+            } else if level > 0 {
+                let next_table =
+                    unsafe { PageTableNode::from_frame(&pte.referencing_frame()).table() };
+                Self::unmap_all_in(next_table, level - 1);
+            }
+        }
+    }
+
+    /// Every PTE visited when translating `va`, from level 2 down,
+    /// it stops at the first invalid or leaf PTE
+    pub fn walk(&self, va: VirtAddr) -> Vec<(usize, PageTableEntry)> {
+        let mut visited = Vec::new();
+        let mut table = unsafe { self.root_node.table() };
+        for level in (0..=2).rev() {
+            let pte = table[va.pte_index(level)];
+            visited.push((level, pte));
+            if level == 0 || !pte.is_valid() || pte.is_leaf() {
+                break;
+            }
+            table = unsafe { PageTableNode::from_frame(&pte.referencing_frame()).table() };
+        }
+        visited
     }
 
+    /// Print every leaf mapping as `va_range -> pa_range flags`.
+    /// Contiguous pages mapped contiguously with the same flags are printed as one range
+    pub fn dump(&self) {
+        info!(
+            "PageTableGuard::dump: page table at pa {:?}",
+            self.root_node.base_addr.as_usize() as *const usize
+        );
+        let mut run = None;
+        let root_table = unsafe { self.root_node.table() };
+        Self::dump_in(root_table, 2, 0, &mut run);
+        if let Some(run) = run {
+            Self::print_run(run);
+        }
+    }
+
+    /// `run`: (va_begin, va_end, pa_begin, flags) of the range being coalesced
+    fn dump_in(
+        table: &[PageTableEntry],
+        level: usize,
+        va_base: usize,
+        run: &mut Option<(usize, usize, usize, PTEFlags)>,
+    ) {
+        let size = PAGE_SIZE << (level * 9);
+        for (i, pte) in table.iter().enumerate() {
+            if !pte.is_valid() {
+                continue;
+            }
+            let va = va_base + i * size;
+            if !pte.is_leaf() {
+                if level > 0 {
+                    let next_table =
+                        unsafe { PageTableNode::from_frame(&pte.referencing_frame()).table() };
+                    Self::dump_in(next_table, level - 1, va, run);
+                }
+                continue;
+            }
+            let (pa, flags) = (pte.referencing_address().as_usize(), pte.flags());
+            match run {
+                Some((va_begin, va_end, pa_begin, run_flags))
+                    if *va_end == va
+                        && *pa_begin + (*va_end - *va_begin) == pa
+                        && *run_flags == flags =>
+                {
+                    *va_end += size;
+                }
+                _ => {
+                    if let Some(prev) = run.take() {
+                        Self::print_run(prev);
+                    }
+                    *run = Some((va, va + size, pa, flags));
+                }
+            }
+        }
+    }
+
+    fn print_run((va_begin, va_end, pa_begin, flags): (usize, usize, usize, PTEFlags)) {
+        info!(
+            "\t{:?}-{:?} -> {:?}-{:?} {:?}",
+            va_begin as *const usize,
+            va_end as *const usize,
+            pa_begin as *const usize,
+            (pa_begin + (va_end - va_begin)) as *const usize,
+            flags
+        );
+    }
+
+    /// `None` unless `va` is mapped by a valid leaf
     pub fn translate(&self, va: VirtAddr) -> Option<(PhysAddr, PTEFlags)> {
-        let pte = self.find(va)?;
+        let (pte, level) = self.find_leaf(va)?;
+        // e.g. an unmapped page next to mapped ones, under the same level-0 node
+        if !pte.is_valid() {
+            return None;
+        }
+        // a leaf at level 1 or 2 maps a superpage, the offset takes the lower VPNs too
+        let offset = va.as_usize() & ((PAGE_SIZE << (level * 9)) - 1);
         Some((
-            pte.referencing_address().with_offset(va.offset()),
+            PhysAddr::new(pte.referencing_address().as_usize() + offset),
             pte.flags(),
         ))
     }
 
-    fn find_allocate(&mut self, va: VirtAddr) -> &'static mut PageTableEntry {
+    /// whether a valid leaf maps `va`
+    pub fn is_mapped(&self, va: VirtAddr) -> bool {
+        self.translate(va).is_some()
+    }
+
+    /// `None` if a missing interior node cannot be allocated,
+    /// the nodes allocated so far stay in the page table
+    fn try_find_allocate(&mut self, va: VirtAddr) -> Option<&'static mut PageTableEntry> {
         // debug!(
         //     "PageTableGuard::find_allocate: find PTE for virtaddr: {:?}",
         //     va.as_usize() as *const usize
@@ -251,13 +432,17 @@ impl PageTableGuard {
                 //     pte.referencing_address().as_usize() as *const u32,
                 //     pte.flags()
                 // );
-                return pte;
+                return Some(pte);
             }
 
+            assert!(
+                !(pte.is_valid() && pte.is_leaf()),
+                "PageTable::map: inside a superpage!"
+            );
             if !pte.is_valid() {
                 // for interior nodes, allocate its next-level node
                 // and fill the corresponding PTE
-                let node_pa = self.allocate_node().get_base_phys_addr();
+                let node_pa = self.try_allocate_node()?.get_base_phys_addr();
                 // debug!(
                 //     "Invalid PTE: allocated next-level node as: {:?}",
                 //     node_pa.as_usize() as *const usize
@@ -283,6 +468,12 @@ impl PageTableGuard {
     }
 
     fn find(&self, va: VirtAddr) -> Option<&'static mut PageTableEntry> {
+        Some(self.find_leaf(va)?.0)
+    }
+
+    /// The PTE mapping `va` and its level: 0 unless it is a superpage.
+    /// The level-0 PTE is returned even if it is invalid
+    fn find_leaf(&self, va: VirtAddr) -> Option<(&'static mut PageTableEntry, usize)> {
         let mut table = unsafe { self.root_node.table() };
 
         for level in (0..=2).rev() {
@@ -292,12 +483,15 @@ impl PageTableGuard {
                 .expect("PageTable::map: invalid entry index");
 
             if level == 0 {
-                return Some(pte);
+                return Some((pte, level));
             }
 
             if !pte.is_valid() {
                 return None;
             }
+            if pte.is_leaf() {
+                return Some((pte, level));
+            }
             // next-level node as a slice
             table = unsafe { PageTableNode::from_frame(&pte.referencing_frame()).table() };
         }
@@ -317,12 +511,23 @@ impl PageTableGuard {
     }
 
     pub fn map_one_allocate(&mut self, va: VirtAddr, pa: PhysAddr, flags: PTEFlags) {
+        self.try_map_one_allocate(va, pa, flags)
+            .expect("PageTableGuard::map_one_allocate: out of frames")
+    }
+
+    /// `None` if the interior nodes to reach `va` cannot be allocated
+    pub fn try_map_one_allocate(
+        &mut self,
+        va: VirtAddr,
+        pa: PhysAddr,
+        flags: PTEFlags,
+    ) -> Option<()> {
         // debug!(
         //     "PageTableGuard::map_one_allocate: try mapping {:?} -> {:?}",
         //     va.as_usize() as *const usize,
         //     pa.as_usize() as *const usize
         // );
-        let pte = self.find_allocate(va);
+        let pte = self.try_find_allocate(va)?;
         let flags = flags | PTEFlags::VALID;
         assert!(
             !pte.is_valid(),
@@ -342,20 +547,101 @@ impl PageTableGuard {
         //     va.as_usize() as *const usize,
         //     pa.as_usize() as *const usize
         // );
+        Some(())
+    }
+
+    /// Map the 2MB megapage at `va` to `pa` by a leaf PTE at level 1,
+    /// both addresses must be aligned to `MEGAPAGE_SIZE`.
+    /// NOTE: frames inside it cannot be remapped on their own until `split_megapage`
+    pub fn map_one_allocate_2m(&mut self, va: VirtAddr, pa: PhysAddr, flags: PTEFlags) {
+        self.try_map_one_allocate_2m(va, pa, flags)
+            .expect("PageTableGuard::map_one_allocate_2m: out of frames")
+    }
+
+    /// `None` if the level-1 node cannot be allocated
+    pub fn try_map_one_allocate_2m(
+        &mut self,
+        va: VirtAddr,
+        pa: PhysAddr,
+        flags: PTEFlags,
+    ) -> Option<()> {
+        assert!(
+            va.as_usize() % MEGAPAGE_SIZE == 0 && pa.as_usize() % MEGAPAGE_SIZE == 0,
+            "PageTable::map_one_allocate_2m: not aligned to 2MB"
+        );
+        assert!(
+            flags.intersects(PTEFlags::READABLE | PTEFlags::WRITABLE | PTEFlags::EXECUTABLE),
+            "PageTable::map_one_allocate_2m: a leaf needs R, W or X"
+        );
+        let root_table = unsafe { self.root_node.table() };
+        let pte = &mut root_table[va.pte_index(2)];
+        if !pte.is_valid() {
+            let node_pa = self.try_allocate_node()?.get_base_phys_addr();
+            *pte = PageTableEntry::new(node_pa, PTEFlags::VALID);
+        }
+        assert!(!pte.is_leaf(), "PageTable::map: inside a superpage!");
+
+        let table = unsafe { PageTableNode::from_frame(&pte.referencing_frame()).table() };
+        let pte = &mut table[va.pte_index(1)];
+        assert!(
+            !pte.is_valid(),
+            "PageTable::map: overwritting original mapping!"
+        );
+        *pte = PageTableEntry::new(pa, flags | PTEFlags::VALID);
+        Some(())
+    }
+
+    /// Map the megapage over `va` by a level-0 node of 4KB pages with the same flags instead,
+    /// so that they can be unmapped or remapped on their own, e.g. a guard page.
+    /// Nothing changes if `va` is not in a megapage. `None` if there is no frame for the node
+    pub fn split_megapage(&mut self, va: VirtAddr) -> Option<()> {
+        let Some((pte, level)) = self.find_leaf(va) else {
+            return Some(());
+        };
+        if level == 0 {
+            return Some(());
+        }
+        // gigapages are never made
+        assert_eq!(level, 1, "PageTable::split_megapage: not a megapage");
+        let (base_pa, flags) = (pte.referencing_address().as_usize(), pte.flags());
+        let node_frame = self.try_allocate_node()?;
+        let table = unsafe { PageTableNode::from_frame(&node_frame).table() };
+        for (idx, leaf) in table.iter_mut().enumerate() {
+            *leaf = PageTableEntry::new(PhysAddr::new(base_pa + idx * PAGE_SIZE), flags);
+        }
+        *pte = PageTableEntry::new(node_frame.get_base_phys_addr(), PTEFlags::VALID);
+        if self.locked {
+            self.lock_node(node_frame)?;
+        }
+        Some(())
     }
 
     /// map the given `virt_area` into the page table.
+    /// An identically mapped area takes megapages where it covers aligned 2MB
     pub fn map_virt_area_allocate(&mut self, virt_area: &VirtArea) {
+        self.try_map_virt_area_allocate(virt_area)
+            .expect("PageTableGuard::map_virt_area_allocate: out of frames")
+    }
+
+    /// `None` if the interior nodes cannot be allocated,
+    /// the pages mapped so far stay mapped
+    pub fn try_map_virt_area_allocate(&mut self, virt_area: &VirtArea) -> Option<()> {
         let flags: PTEFlags = virt_area.permissions().into();
         if virt_area.is_identically_mapped {
-            let rng = virt_area.virt_frame_range; // Copied
-            for v_frame in rng.into_iter() {
-                let va = v_frame.get_base_virt_addr();
+            let mut va = virt_area.virt_frame_range.get_begin().get_base_virt_addr();
+            let end = virt_area.virt_frame_range.get_end().get_base_virt_addr();
+            while va < end {
                 let pa = PhysAddr::new(va.as_usize());
-                assert_eq!(va.as_usize(), pa.as_usize());
                 assert!(va.is_page_aligned());
-                assert!(pa.is_page_aligned());
-                self.map_one_allocate(va, pa, flags);
+                if va.as_usize() % MEGAPAGE_SIZE == 0
+                    && end.as_usize() - va.as_usize() >= MEGAPAGE_SIZE
+                {
+                    self.try_map_one_allocate_2m(va, pa, flags)?;
+                    va = va + MEGAPAGE_SIZE;
+                } else {
+                    self.try_map_one_allocate(va, pa, flags)?;
+                    va = va + PAGE_SIZE;
+                }
             }
         } else {
             for (va, virt_frame_guard) in &virt_area.virt_frames {
@@ -364,20 +650,87 @@ impl PageTableGuard {
                         let pa = phys_frame_guard.inner_ref().get_base_phys_addr();
                         assert!(va.is_page_aligned());
                         assert!(pa.is_page_aligned());
-                        self.map_one_allocate(*va, pa, flags);
+                        self.try_map_one_allocate(*va, pa, flags)?;
                     }
-                    VirtFrameGuard::CowShared(_phys_frame_guard_arc) => {
-                        panic!("kernel does not support copy-on-write at the moment...");
+                    VirtFrameGuard::CowShared(phys_frame_guard) => {
+                        let pa = phys_frame_guard.inner_ref().get_base_phys_addr();
+                        assert!(va.is_page_aligned());
+                        assert!(pa.is_page_aligned());
+                        self.try_map_one_allocate(
+                            *va,
+                            pa,
+                            leaf_flags(virt_area, virt_frame_guard),
+                        )?;
                     }
                     VirtFrameGuard::PhysBorrowed(phys_frame) => {
                         let pa = phys_frame.get_base_phys_addr();
                         assert!(va.is_page_aligned());
                         assert!(pa.is_page_aligned());
-                        self.map_one_allocate(*va, pa, flags);
+                        self.try_map_one_allocate(*va, pa, flags)?;
                     }
                 }
             }
         }
+        Some(())
+    }
+
+    /// overwrite a valid leaf PTE to map `va` to `pa` instead
+    pub fn remap_one(&self, va: VirtAddr, pa: PhysAddr, flags: PTEFlags) -> Option<()> {
+        let pte = self.find(va)?;
+        if !pte.is_valid() {
+            return None;
+        }
+        *pte = PageTableEntry::new(pa, flags | PTEFlags::VALID);
+        Some(())
+    }
+
+    /// invalidate the leaf PTE of `va`, `None` if it is not mapped
+    pub fn unmap_one(&self, va: VirtAddr) -> Option<()> {
+        let pte = self.find(va)?;
+        if !pte.is_valid() {
+            return None;
+        }
+        *pte = PageTableEntry::empty();
+        Some(())
+    }
+
+    /// rewrite the flags of a valid leaf PTE, the frame it maps to is kept
+    pub fn remap_flags(&self, va: VirtAddr, flags: PTEFlags) -> Option<()> {
+        let pa = self.find(va)?.referencing_address();
+        self.remap_one(va, pa, flags)
+    }
+
+    /// remap the page at `va` to the frame currently tracked by `virt_area`,
+    /// e.g. after a copy-on-write page gets its own copy
+    pub fn remap_virt_frame(&self, virt_area: &VirtArea, va: VirtAddr) -> Option<()> {
+        let virt_frame_guard = virt_area.virt_frames.get(&va)?;
+        let pa = PhysAddr::new(virt_frame_guard.as_usize());
+        self.remap_one(va, pa, leaf_flags(virt_area, virt_frame_guard))
+    }
+
+    /// bring the flags of already mapped leaves of `virt_area` up to date,
+    /// e.g. after its frames are converted to `CowShared`
+    pub fn remap_virt_area_flags(&self, virt_area: &VirtArea) {
+        if virt_area.is_identically_mapped {
+            return;
+        }
+        for (va, virt_frame_guard) in &virt_area.virt_frames {
+            self.remap_flags(*va, leaf_flags(virt_area, virt_frame_guard))
+                .expect("PageTable::remap_virt_area_flags: not mapped");
+        }
+    }
+}
+
+/// The flags of a leaf PTE mapping `virt_frame_guard` in `virt_area`.
+/// Shared pages of a writable area are mapped read-only and tagged `COW`,
+/// so that the first write to them traps into the kernel
+fn leaf_flags(virt_area: &VirtArea, virt_frame_guard: &VirtFrameGuard) -> PTEFlags {
+    let flags: PTEFlags = virt_area.permissions().into();
+    match virt_frame_guard {
+        VirtFrameGuard::CowShared(_) if flags.contains(PTEFlags::WRITABLE) => {
+            (flags - PTEFlags::WRITABLE) | PTEFlags::COW
+        }
+        _ => flags,
     }
 }
 
@@ -389,9 +742,10 @@ impl PageTableGuard {
             for v_frame in rng.into_iter() {
                 let va = v_frame.get_base_virt_addr();
                 let pa = PhysAddr::new(va.as_usize());
-                if let Some(pte) = self.find(va) {
-                    assert_eq!(pte.referencing_address(), pa, "address mismatch");
-                    assert_eq!(pte.flags(), flags | PTEFlags::VALID, "flag mismatch");
+                // it may be in a megapage
+                if let Some((translated_pa, pte_flags)) = self.translate(va) {
+                    assert_eq!(translated_pa, pa, "address mismatch");
+                    assert_eq!(pte_flags, flags | PTEFlags::VALID, "flag mismatch");
                 }
             }
         } else {
@@ -404,8 +758,13 @@ impl PageTableGuard {
                             assert_eq!(pte.flags(), flags | PTEFlags::VALID, "flag mismatch");
                         }
                     }
-                    VirtFrameGuard::CowShared(_phys_frame_guard_arc) => {
-                        panic!("kernel does not support copy-on-write at the moment...");
+                    VirtFrameGuard::CowShared(phys_frame_guard) => {
+                        let pa = phys_frame_guard.inner_ref().get_base_phys_addr();
+                        let flags = leaf_flags(virt_area, virt_frame_guard);
+                        if let Some(pte) = self.find(*va) {
+                            assert_eq!(pte.referencing_address(), pa, "address mismatch");
+                            assert_eq!(pte.flags(), flags | PTEFlags::VALID, "flag mismatch");
+                        }
                     }
                     VirtFrameGuard::PhysBorrowed(phys_frame) => {
                         let pa = phys_frame.get_base_phys_addr();
@@ -422,18 +781,32 @@ impl PageTableGuard {
     /// lock the page table by making its node frames in the kernel space read-only
     /// so that accidental writing to itwill be caught
     /// NOTE: It should be called when using another address space!!! Otherwise the order is wrong
-    pub fn lock_table(&self) {
-        for frame in &self.node_frames {
-            let node_pa = frame.get_frame().get_base_phys_addr();
-            let pte = self.find(VirtAddr::from_identical(node_pa)).unwrap();
-            // clear writable flag to lock the table page
-            let flags = pte.flags() & (!PTEFlags::WRITABLE);
-            *pte = PageTableEntry::new(pte.referencing_address(), flags)
+    pub fn lock_table(&mut self) {
+        // splitting the megapage over a node adds a node, it is locked in turn
+        let mut idx = 0;
+        while idx < self.node_frames.len() {
+            let node_frame = self.node_frames[idx].get_frame();
+            self.lock_node(node_frame)
+                .expect("PageTableGuard::lock_table: out of frames");
+            idx += 1;
         }
+        self.locked = true;
+    }
+
+    /// make `node_frame` read-only in this page table, splitting the megapage over it
+    fn lock_node(&mut self, node_frame: Frame) -> Option<()> {
+        let node_va = VirtAddr::from_identical(node_frame.get_base_phys_addr());
+        self.split_megapage(node_va)?;
+        let pte = self.find(node_va).unwrap();
+        // clear writable flag to lock the table page
+        let flags = pte.flags() & (!PTEFlags::WRITABLE);
+        *pte = PageTableEntry::new(pte.referencing_address(), flags);
+        Some(())
     }
 
     /// unlock the page table by making its node frames in the kernel space writable
-    pub fn unlock_table(&self) {
+    pub fn unlock_table(&mut self) {
+        self.locked = false;
         for frame in &self.node_frames {
             let node_pa = frame.get_frame().get_base_phys_addr();
             let pte = self.find(VirtAddr::from_identical(node_pa)).unwrap();
@@ -445,9 +818,94 @@ impl PageTableGuard {
 }
 
 pub fn test() {
-    let pa = PhysAddr::new(12345).align_down();
+    let pa = PhysAddr::new(KERNEL_BASE + 12345).align_down();
     let flags = PTEFlags::VALID | PTEFlags::USER;
     let entry = PageTableEntry::new(pa, flags);
     assert_eq!(entry.referencing_address(), pa);
     assert_eq!(entry.flags().bits(), entry.flags().bits());
 }
+
+/// translating a va in the middle of a page should keep its offset
+pub fn test_translate_offset() {
+    let mut page_table = PageTableGuard::allocate();
+    let frame_guard = FrameGuard::allocate_zeroed();
+    let pa = frame_guard.get_frame().get_base_phys_addr();
+    page_table.map_one_allocate(VirtAddr::new(0x1_0000), pa, PTEFlags::READABLE);
+
+    let (translated_pa, _) = page_table.translate(VirtAddr::new(0x1_0ABC)).unwrap();
+    assert_eq!(translated_pa.as_usize(), pa.as_usize() + 0xABC);
+    // the next page shares the level-0 node, but is not mapped
+    assert!(page_table.translate(VirtAddr::new(0x1_1000)).is_none());
+}
+
+/// an entry built with all the defined flags should read them back
+pub fn test_flags_round_trip() {
+    let pa = PhysAddr::new(0x8020_0000);
+    let entry = PageTableEntry::new(pa, PTEFlags::all());
+    assert_eq!(entry.referencing_address(), pa);
+    assert_eq!(entry.flags(), PTEFlags::all());
+}
+
+/// an address inside a megapage should translate with its offset into the megapage
+pub fn test_map_megapage() {
+    let mut page_table = PageTableGuard::allocate();
+    let va = VirtAddr::new(0x4000_0000);
+    let pa = PhysAddr::new(0x8020_0000);
+    page_table.map_one_allocate_2m(va, pa, PTEFlags::READABLE | PTEFlags::WRITABLE);
+
+    let (translated_pa, flags) = page_table.translate(va + 0x12_3456).unwrap();
+    assert_eq!(translated_pa.as_usize(), pa.as_usize() + 0x12_3456);
+    assert!(flags.contains(PTEFlags::VALID | PTEFlags::READABLE | PTEFlags::WRITABLE));
+
+    // a 4KB page next to it shares the level-1 node
+    let frame_guard = FrameGuard::allocate_zeroed();
+    let page_pa = frame_guard.get_frame().get_base_phys_addr();
+    page_table.map_one_allocate(va + MEGAPAGE_SIZE, page_pa, PTEFlags::READABLE);
+    let (translated_pa, _) = page_table.translate(va + MEGAPAGE_SIZE + 0x10).unwrap();
+    assert_eq!(translated_pa.as_usize(), page_pa.as_usize() + 0x10);
+}
+
+/// a split megapage should map the same frames by 4KB pages, which can then be
+/// unmapped one by one
+pub fn test_split_megapage() {
+    let mut page_table = PageTableGuard::allocate();
+    let va = VirtAddr::new(0x4000_0000);
+    let pa = PhysAddr::new(0x8020_0000);
+    let flags = PTEFlags::READABLE | PTEFlags::WRITABLE;
+    page_table.map_one_allocate_2m(va, pa, flags);
+    let n_nodes = page_table.n_node_frames();
+
+    page_table.split_megapage(va + 0x1234).unwrap();
+    assert_eq!(page_table.n_node_frames(), n_nodes + 1);
+    // already split
+    page_table.split_megapage(va).unwrap();
+    assert_eq!(page_table.n_node_frames(), n_nodes + 1);
+    for offset in [0, PAGE_SIZE + 0x10, MEGAPAGE_SIZE - 1] {
+        let (translated_pa, pte_flags) = page_table.translate(va + offset).unwrap();
+        assert_eq!(translated_pa.as_usize(), pa.as_usize() + offset);
+        assert_eq!(pte_flags, flags | PTEFlags::VALID);
+    }
+
+    page_table.unmap_one(va + PAGE_SIZE).unwrap();
+    assert!(!page_table.is_mapped(va + PAGE_SIZE));
+    assert!(page_table.is_mapped(va));
+    assert!(page_table.is_mapped(va + 2 * PAGE_SIZE));
+}
+
+/// the last entry of a node is in bounds, one past it is not.
+/// A kernel panic cannot be caught, so we test the bounds check itself
+pub fn test_entry_index_bounds() {
+    assert!(PageTableNode::is_valid_index(ENTRY_PER_TABLE - 1));
+    assert!(!PageTableNode::is_valid_index(ENTRY_PER_TABLE));
+
+    let frame_guard = FrameGuard::allocate_zeroed();
+    let node = unsafe { PageTableNode::from_frame(&frame_guard.get_frame()) };
+    let pa = PhysAddr::new(0x8020_0000);
+    unsafe {
+        node.set_entry(
+            ENTRY_PER_TABLE - 1,
+            PageTableEntry::new(pa, PTEFlags::VALID),
+        );
+        assert_eq!(node.entry_at(ENTRY_PER_TABLE - 1).referencing_address(), pa);
+    }
+}
@@ -0,0 +1,160 @@
+//! `sfence.vma` only flushes the TLB of the hart executing it. A hart changing a mapping
+//! other harts may have cached posts the range to the mailbox of each online peer and
+//! sends it a machine software interrupt through the CLINT, `__timervec` forwards it as a
+//! S-mode software interrupt, and the peer flushes the range in `handle_shootdown`.
+//! The sender waits until every peer has flushed, see `mm::tlb_shootdown`
+
+use core::{
+    hint,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use riscv::asm::sfence_vma_all;
+use spin::Mutex;
+
+use crate::{arch, clint, symbols::N_CPUS};
+
+use super::memory::{VirtFrame, VirtFrameRange};
+
+/// a larger range is flushed all at once rather than page by page
+const MAX_PAGES_FLUSHED_ONE_BY_ONE: usize = 64;
+
+/// the shootdown requests to one hart
+struct Mailbox {
+    /// the hart has loaded the kernel space and takes shootdowns, see `hart_init`
+    online: AtomicBool,
+    /// pages to flush, requests not handled yet are merged
+    pending: Mutex<Option<VirtFrameRange>>,
+    /// requests posted, and requests flushed
+    posted: AtomicUsize,
+    done: AtomicUsize,
+}
+
+// only to initialise the array below
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_MAILBOX: Mailbox = Mailbox::new();
+
+static MAILBOXES: [Mailbox; N_CPUS] = [EMPTY_MAILBOX; N_CPUS];
+
+impl Mailbox {
+    const fn new() -> Self {
+        Self {
+            online: AtomicBool::new(false),
+            pending: Mutex::new(None),
+            posted: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+        }
+    }
+
+    /// the request to wait for with `is_done`
+    fn post(&self, range: VirtFrameRange) -> usize {
+        let mut pending = self.pending.lock();
+        *pending = Some(match *pending {
+            Some(posted) => merge(posted, range),
+            None => range,
+        });
+        self.posted.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The merged range of the requests posted so far, and the last of them
+    fn take(&self) -> Option<(VirtFrameRange, usize)> {
+        let mut pending = self.pending.lock();
+        let range = pending.take()?;
+        let request = self.posted.load(Ordering::Relaxed);
+        Some((range, request))
+    }
+
+    fn is_done(&self, request: usize) -> bool {
+        self.done.load(Ordering::Acquire) >= request
+    }
+}
+
+/// the smallest range covering both
+fn merge(a: VirtFrameRange, b: VirtFrameRange) -> VirtFrameRange {
+    VirtFrameRange::new(
+        a.get_begin().min(b.get_begin()),
+        a.get_end().max(b.get_end()),
+    )
+}
+
+/// flush `range` from the TLB of the calling hart, of every address space
+pub fn flush_local(range: VirtFrameRange) {
+    if range.n_pages() > MAX_PAGES_FLUSHED_ONE_BY_ONE {
+        unsafe { sfence_vma_all() };
+        return;
+    }
+    for page in range.iter() {
+        arch::sfence_vma_addr(page.get_base_virt_addr());
+    }
+}
+
+/// Flush what has been posted to the calling hart, on a S-mode software interrupt,
+/// or while itself waiting in `shootdown`
+pub fn handle_shootdown() {
+    let mailbox = &MAILBOXES[arch::hart_id()];
+    if let Some((range, request)) = mailbox.take() {
+        flush_local(range);
+        mailbox.done.store(request, Ordering::Release);
+    }
+}
+
+/// see `mm::tlb_shootdown`
+pub fn shootdown(range: VirtFrameRange) {
+    if range.is_empty() {
+        return;
+    }
+    // no interrupts while holding a mailbox
+    arch::push_off();
+    let this = arch::hart_id();
+    flush_local(range);
+
+    let mut requests = [None; N_CPUS];
+    for (hartid, mailbox) in MAILBOXES.iter().enumerate() {
+        if hartid != this && mailbox.online.load(Ordering::Acquire) {
+            requests[hartid] = Some(mailbox.post(range));
+            clint::send_soft_interrupt(hartid);
+        }
+    }
+    for (hartid, request) in requests.into_iter().enumerate() {
+        let Some(request) = request else {
+            continue;
+        };
+        while !MAILBOXES[hartid].is_done(request) {
+            // a peer may be waiting for us as well
+            handle_shootdown();
+            hint::spin_loop();
+        }
+    }
+    arch::pop_off();
+}
+
+/// The calling hart takes shootdowns from now on, after loading the kernel space.
+/// Until then, loading it flushes the TLB anyway
+pub fn hart_init() {
+    MAILBOXES[arch::hart_id()]
+        .online
+        .store(true, Ordering::Release);
+}
+
+/// requests should be merged until taken, and done once taken
+pub fn test_mailbox() {
+    let mailbox = Mailbox::new();
+    let range =
+        |begin, end| VirtFrameRange::new(VirtFrame::from_ppn(begin), VirtFrame::from_ppn(end));
+    assert!(mailbox.take().is_none());
+
+    let first = mailbox.post(range(0x10, 0x12));
+    let second = mailbox.post(range(0x20, 0x21));
+    assert!(!mailbox.is_done(first));
+    let (merged, request) = mailbox.take().unwrap();
+    assert_eq!(merged.get_begin(), VirtFrame::from_ppn(0x10));
+    assert_eq!(merged.get_end(), VirtFrame::from_ppn(0x21));
+    assert_eq!(request, second);
+    assert!(mailbox.take().is_none());
+    mailbox.done.store(request, Ordering::Release);
+    assert!(mailbox.is_done(first) && mailbox.is_done(second));
+
+    // no peer is online during the tests, only the calling hart flushes
+    shootdown(range(0x80000, 0x80001));
+    shootdown(range(0x80000, 0x80000 + 2 * MAX_PAGES_FLUSHED_ONE_BY_ONE));
+}
@@ -2,6 +2,7 @@ use core::cell::SyncUnsafeCell;
 
 use crate::{
     arch::hart_id,
+    info,
     mm::layout::{PLIC_BASE, PLIC_PENDING},
 };
 
@@ -56,11 +57,40 @@ pub const fn PLIC_SCLAIM(hart: usize) -> usize {
 pub const URT0_IRQ: u32 = 10;
 pub const VIRTIO0_IRQ: u32 = 1;
 
-pub struct Plic {}
+// the enable register is a 32-bit bitset, see `Plic::enable`
+const N_IRQ: usize = 32;
+
+pub struct Plic {
+    /// device interrupt handlers indexed by IRQ number
+    handlers: [Option<fn()>; N_IRQ],
+}
 
 impl Plic {
     pub fn new() -> Self {
-        Plic {}
+        Plic {
+            handlers: [None; N_IRQ],
+        }
+    }
+
+    /// let a device driver handle its interrupt, see `Plic::dispatch`
+    pub fn register_handler(&mut self, irq: u32, handler: fn()) {
+        assert!(
+            (irq as usize) < N_IRQ,
+            "Plic::register_handler: invalid IRQ {:?}",
+            irq
+        );
+        self.handlers[irq as usize] = Some(handler);
+    }
+
+    /// claim the next interrupt, invoke its registered handler and complete it
+    pub fn dispatch(&self) {
+        if let Some(irq) = self.next() {
+            match self.handlers.get(irq as usize).copied().flatten() {
+                Some(handler) => handler(),
+                None => info!("Plic::dispatch: no handler for IRQ {:?}", irq),
+            }
+            self.complete(irq);
+        }
     }
 
     /// retreve the next interrupt id available in S-mode.
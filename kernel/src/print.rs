@@ -1,7 +1,138 @@
-use core::fmt;
+use core::{
+    cell::SyncUnsafeCell,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch,
+    symbols::{N_CPUS, PRINT_BUFFERED},
+    uart::{Uart, UART, UART_BASE_ADDR},
+};
 
 pub static INFO_LOCK: spin::Mutex<()> = spin::Mutex::new(());
 
+/// bytes a hart may hold back, see `PrintBuffer`
+const PRINT_BUFFER_SIZE: usize = 4096;
+/// `_print` drains the buffer itself once it holds that many bytes
+const PRINT_BUFFER_HIGH_WATER: usize = PRINT_BUFFER_SIZE / 4 * 3;
+
+/// Bytes printed by one hart but not yet sent to the UART, so that printing does not
+/// wait for the UART to transmit. Only its own hart appends to it, in `_print`,
+/// and publishes a message as a whole once it is formatted, so a drain does not split it
+/// unless it is longer than the buffer. Any hart may drain it: a byte is taken by moving
+/// `head` past it, two harts draining at once never send the same byte
+pub struct PrintBuffer {
+    buf: SyncUnsafeCell<[u8; PRINT_BUFFER_SIZE]>,
+    /// the next byte to send
+    head: AtomicUsize,
+    /// the end of the published bytes
+    tail: AtomicUsize,
+}
+
+impl PrintBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: SyncUnsafeCell::new([0; PRINT_BUFFER_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// published bytes not yet sent
+    fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire) - self.head.load(Ordering::Acquire)
+    }
+
+    /// hand the published bytes to `put` in order
+    fn drain(&self, mut put: impl FnMut(u8)) {
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            if head == self.tail.load(Ordering::Acquire) {
+                break;
+            }
+            // NOTE: read before taking it, the slot may be reused right after
+            let c = unsafe {
+                (self.buf.get() as *const u8)
+                    .add(head % PRINT_BUFFER_SIZE)
+                    .read()
+            };
+            if self
+                .head
+                .compare_exchange(head, head + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                put(c);
+            }
+        }
+    }
+}
+
+// only to initialise the array below
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW_PRINT_BUFFER: PrintBuffer = PrintBuffer::new();
+
+static PRINT_BUFFERS: [PrintBuffer; N_CPUS] = [NEW_PRINT_BUFFER; N_CPUS];
+
+/// Appends a message to a `PrintBuffer` on behalf of its hart,
+/// `publish` makes it visible to the drains
+struct BufferWriter<'a> {
+    buffer: &'a PrintBuffer,
+    /// the end of the bytes written so far
+    tail: usize,
+}
+
+impl<'a> BufferWriter<'a> {
+    fn new(buffer: &'a PrintBuffer) -> Self {
+        Self {
+            buffer,
+            tail: buffer.tail.load(Ordering::Relaxed),
+        }
+    }
+
+    fn publish(&self) {
+        self.buffer.tail.store(self.tail, Ordering::Release);
+    }
+}
+
+impl fmt::Write for BufferWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            if self.tail - self.buffer.head.load(Ordering::Acquire) == PRINT_BUFFER_SIZE {
+                // a message longer than the buffer, send what it has so far
+                self.publish();
+                let uart = UART.lock();
+                self.buffer.drain(|c| uart.put(c));
+            }
+            let slot = self.tail % PRINT_BUFFER_SIZE;
+            unsafe { (self.buffer.buf.get() as *mut u8).add(slot).write(c) };
+            self.tail += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Send whatever the harts have buffered, e.g. before reporting a panic or powering off.
+/// It takes `UART` if it can, but does not wait for it since the holder may be stuck
+pub fn flush() {
+    let _uart = UART.try_lock();
+    let uart = Uart::new(UART_BASE_ADDR);
+    for buffer in &PRINT_BUFFERS {
+        buffer.drain(|c| uart.put(c));
+    }
+}
+
+/// Like `flush` but left for later if someone is printing, called on every timer tick
+pub fn try_flush() {
+    if let Some(uart) = UART.try_lock() {
+        for buffer in &PRINT_BUFFERS {
+            buffer.drain(|c| uart.put(c));
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! print
 {
@@ -29,15 +160,27 @@ macro_rules! println
 macro_rules! panic_println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => ({
-        $crate::print::_panic_print(format_args!($($arg)*));
+        $crate::print::_panic_print(format_args_nl!($($arg)*));
     })
 }
 
+/// Print to the UART, or to the buffer of the calling hart if `PRINT_BUFFERED`
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    let mut uart = crate::uart::UART.lock();
-    uart.write_fmt(args).unwrap();
+    if !PRINT_BUFFERED {
+        let mut uart = UART.lock();
+        uart.write_fmt(args).unwrap();
+        return;
+    }
+    let buffer = &PRINT_BUFFERS[arch::hart_id()];
+    let mut writer = BufferWriter::new(buffer);
+    writer.write_fmt(args).unwrap();
+    writer.publish();
+    if buffer.len() >= PRINT_BUFFER_HIGH_WATER {
+        let uart = UART.lock();
+        buffer.drain(|c| uart.put(c));
+    }
 }
 
 #[doc(hidden)]
@@ -48,69 +191,84 @@ pub fn _panic_print(args: fmt::Arguments) {
     uart.write_fmt(args).unwrap();
 }
 
+/// Prints a message with the timestamp and a colored level `tag` if `level` is enabled
+/// by `LOG_LEVEL`, with newline. Use the leveled macros below instead
+#[doc(hidden)]
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $tag:expr, $format_string:expr $(, $($arg:tt)*)?) => ({
+        if $crate::symbols::LogLevel::$level <= $crate::symbols::LOG_LEVEL {
+            #[allow(unused_imports)]
+            let _info_locker = $crate::print::INFO_LOCK.lock();
+
+            let timestamp = $crate::arch::time();
+            let timestamp_subsec_us = timestamp.subsec_micros();
+
+            $crate::print::_print(format_args_nl!(
+                concat!("\x1b[0;36m[  {:>3}.{:03}{:03}]\x1b[0m ", $tag, " ", $format_string),
+                timestamp.as_secs(),
+                timestamp_subsec_us / 1_000,
+                timestamp_subsec_us % 1_000
+                $(, $($arg)*)?
+            ));
+        }
+    });
+}
+
+/// Prints an error, with newline.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => ($crate::log!(Error, "\x1b[0;31mERROR\x1b[0m", $($arg)+));
+}
+
+/// Prints a warning, with newline.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => ($crate::log!(Warn, "\x1b[0;33mWARN \x1b[0m", $($arg)+));
+}
+
 /// Prints an info, with newline.
 #[macro_export]
 macro_rules! info {
-    ($string:expr) => ({
-        #[allow(unused_imports)]
-        let _info_locker = $crate::print::INFO_LOCK.lock();
-
-        let timestamp = $crate::arch::time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
-
-        $crate::print::_print(format_args_nl!(
-            concat!("\x1b[0;36m[  {:>3}.{:03}{:03}]\x1b[0m ", $string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000
-        ));
-    });
-    ($format_string:expr, $($arg:tt)*) => ({
-        #[allow(unused_imports)]
-        let _info_locker = $crate::print::INFO_LOCK.lock();
-
-        let timestamp = $crate::arch::time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
-
-        $crate::print::_print(format_args_nl!(
-            concat!("\x1b[0;36m[  {:>3}.{:03}{:03}]\x1b[0m ", $format_string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000,
-            $($arg)*
-        ));
-    })
+    ($($arg:tt)+) => ($crate::log!(Info, "\x1b[0;32mINFO \x1b[0m", $($arg)+));
 }
 
+/// Prints a debug message, with newline. Left out of release builds
 #[macro_export]
 macro_rules! debug {
-    ($string:expr) => ({
-        #[allow(unused_imports)]
-        let _info_locker = $crate::print::INFO_LOCK.lock();
-
-        let timestamp = $crate::arch::time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
-
-        $crate::print::_print(format_args_nl!(
-            concat!("\x1b[0;36m[  {:>3}.{:03}{:03}]\x1b[0m ", $string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000
-        ));
-    });
-    ($format_string:expr, $($arg:tt)*) => ({
-        #[allow(unused_imports)]
-        let _info_locker = $crate::print::INFO_LOCK.lock();
-
-        let timestamp = $crate::arch::time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
-
-        $crate::print::_print(format_args_nl!(
-            concat!("\x1b[0;36m[  {:>3}.{:03}{:03}]\x1b[0m ", $format_string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000,
-            $($arg)*
-        ));
-    })
+    ($($arg:tt)+) => ($crate::log!(Debug, "\x1b[0;90mDEBUG\x1b[0m", $($arg)+));
+}
+
+/// a message should only be drained once published, in order across the end of the buffer
+pub fn test_print_buffer() {
+    use core::fmt::Write;
+    let buffer = PrintBuffer::new();
+    let mut drained = Vec::new();
+
+    let mut writer = BufferWriter::new(&buffer);
+    write!(writer, "derek {}", 42).unwrap();
+    buffer.drain(|c| drained.push(c));
+    assert!(drained.is_empty());
+    writer.publish();
+    assert_eq!(buffer.len(), 8);
+    buffer.drain(|c| drained.push(c));
+    assert_eq!(drained, b"derek 42");
+    assert_eq!(buffer.len(), 0);
+
+    // wrapping around
+    let filler = [b'x'; PRINT_BUFFER_SIZE - 12];
+    let mut writer = BufferWriter::new(&buffer);
+    writer
+        .write_str(core::str::from_utf8(&filler).unwrap())
+        .unwrap();
+    writer.publish();
+    drained.clear();
+    buffer.drain(|c| drained.push(c));
+    assert_eq!(drained.len(), filler.len());
+    let mut writer = BufferWriter::new(&buffer);
+    writer.write_str("across the end").unwrap();
+    writer.publish();
+    drained.clear();
+    buffer.drain(|c| drained.push(c));
+    assert_eq!(drained, b"across the end");
 }
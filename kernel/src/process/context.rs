@@ -2,7 +2,11 @@ use riscv::register::stvec;
 use virtio_drivers::PAGE_SIZE;
 
 use crate::{
-    mm::memory::{PhysAddr, VirtAddr},
+    mm::{
+        layout::KERNEL_STACK_SIZE,
+        memory::{FrameGuard, PhysAddr, VirtAddr},
+    },
+    panic_println,
     symbols::__kernelvec,
     trap::syscall::Syscall,
 };
@@ -23,13 +27,65 @@ pub struct TrapContext {
     trap_handler: usize, // 36 Byte: entry point of the handler in the kernel space
 }
 
-const TP: usize = 4;
+// Indices into `user_regs`, x0 to x31 in the RISC-V ABI.
+// NOTE: the only place mapping ABI names to indices, use the accessors below
+const RA: usize = 1;
 const SP: usize = 2;
+const TP: usize = 4;
 const A0: usize = 10;
+const A1: usize = 11;
+const A2: usize = 12;
+const A3: usize = 13;
+const A4: usize = 14;
+const A5: usize = 15;
+const A6: usize = 16;
+const A7: usize = 17;
+// a0 to a5 carry the arguments of a syscall
+const N_SYSCALL_ARGS: usize = 6;
+
+/// a getter and a setter for each named register
+macro_rules! user_reg_accessors {
+    ($($get:ident, $set:ident => $idx:expr;)*) => {
+        $(
+            pub fn $get(&self) -> usize {
+                self.user_regs[$idx]
+            }
+
+            pub fn $set(&mut self, val: usize) {
+                self.user_regs[$idx] = val;
+            }
+        )*
+    };
+}
 
 impl TrapContext {
-    pub fn set_tp(&mut self, tp: usize) {
-        self.user_regs[TP] = tp;
+    user_reg_accessors! {
+        ra, set_ra => RA;
+        sp, set_sp => SP;
+        tp, set_tp => TP;
+        a0, set_a0 => A0;
+        a1, set_a1 => A1;
+        a2, set_a2 => A2;
+        a3, set_a3 => A3;
+        a4, set_a4 => A4;
+        a5, set_a5 => A5;
+        a6, set_a6 => A6;
+        a7, set_a7 => A7;
+    }
+
+    /// the pc the user resumes at, `sepc`
+    pub fn pc(&self) -> usize {
+        self.sepc
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.sepc = pc;
+    }
+
+    /// Access all the general purpose registers at once, e.g. to set several
+    /// return values together. Index them by their x-number, x0 is `zero`
+    pub fn with_user_regs<T>(&mut self, f: impl FnOnce(&mut [usize; 32]) -> T) -> T {
+        f(&mut self.user_regs)
     }
 
     pub fn set_trap_handler(&mut self, addr: VirtAddr) {
@@ -37,29 +93,33 @@ impl TrapContext {
     }
 
     pub fn set_user_space_pc(&mut self, addr: VirtAddr) {
-        self.sepc = addr.as_usize();
+        self.set_pc(addr.as_usize());
     }
 
-    pub fn get_user_space_pc(&mut self, addr: VirtAddr) {
-        self.sepc = addr.as_usize();
+    pub fn get_user_space_pc(&self) -> VirtAddr {
+        VirtAddr::new(self.pc())
     }
 
     pub fn incr_user_space_pc(&mut self, diff: usize) {
         self.sepc += diff;
     }
 
-    pub fn set_user_stack(&mut self, base_addr: PhysAddr) {
+    pub fn decr_user_space_pc(&mut self, diff: usize) {
+        self.sepc -= diff;
+    }
+
+    pub fn set_user_stack(&mut self, base_addr: VirtAddr) {
         // NOTE: since the stack grows downwards, we should convert
         // its base address to its top address
         assert!(base_addr.is_page_aligned());
-        self.user_regs[SP] = base_addr.as_usize() + PAGE_SIZE;
+        self.set_sp(base_addr.as_usize() + PAGE_SIZE);
     }
 
     pub fn set_kernel_stack(&mut self, base_addr: PhysAddr) {
         // NOTE: since the stack grows downwards, we should convert
         // its base address to its top address
         assert!(base_addr.is_page_aligned());
-        self.kernel_sp = base_addr.as_usize() + PAGE_SIZE;
+        self.kernel_sp = base_addr.as_usize() + KERNEL_STACK_SIZE;
     }
 
     pub fn set_kernel_page_table(&mut self, satp: usize) {
@@ -70,16 +130,89 @@ impl TrapContext {
         self.kernel_satp
     }
 
+    /// print the saved user registers with their ABI names, e.g. when panicking
+    pub fn dump_registers(&self) {
+        const NAMES: [&str; 32] = [
+            "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3",
+            "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+            "t3", "t4", "t5", "t6",
+        ];
+        panic_println!("user pc {:#018x}", self.sepc);
+        for (names, regs) in NAMES.chunks(4).zip(self.user_regs.chunks(4)) {
+            panic_println!(
+                "{:>4} {:#018x}  {:>4} {:#018x}  {:>4} {:#018x}  {:>4} {:#018x}",
+                names[0],
+                regs[0],
+                names[1],
+                regs[1],
+                names[2],
+                regs[2],
+                names[3],
+                regs[3]
+            );
+        }
+    }
+
+    /// The `idx`-th argument of a syscall. Like a function call in the RISC-V
+    /// calling convention, the user passes them in a0 (x10) to a5 (x15)
     pub fn get_arg(&self, idx: usize) -> usize {
-        if idx <= 7 {
+        if idx < N_SYSCALL_ARGS {
             self.user_regs[A0 + idx]
         } else {
             panic!("TrapContext::get_arg: arg {:?}: out of bounds", idx);
         }
     }
 
+    /// The value returned to the user is in a0 (x10), overwriting the first argument
+    pub fn set_return_value(&mut self, val: usize) {
+        self.set_a0(val);
+    }
+
+    /// The syscall number is in a7 (x17), see `user/src/usys.S`.
+    /// `None` if the number is unknown
     pub fn get_syscall(&self) -> Option<Syscall> {
-        Syscall::from(self.get_arg(7))
+        Syscall::from(self.a7())
+    }
+}
+
+/// The `TrapContext` of a process, in its trapframe page.
+/// The kernel reaches the page by its physical address, that is the kernel space maps
+/// all of the RAM identically, and the user address space owning the frame outlives
+/// the reference, see `PCBInner::free_user_space`
+#[derive(Debug)]
+pub struct TrapFrameRef {
+    pa: PhysAddr,
+}
+
+impl TrapFrameRef {
+    /// # Safety
+    /// `pa` has to be the base of a frame that is not otherwise aliased,
+    /// and stays allocated as long as the reference is in use
+    pub unsafe fn new(pa: PhysAddr) -> Self {
+        assert!(
+            pa.is_page_aligned(),
+            "TrapFrameRef::new: {:?} is not a frame",
+            pa.as_usize() as *const usize
+        );
+        Self { pa }
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.pa
+    }
+}
+
+impl AsRef<TrapContext> for TrapFrameRef {
+    fn as_ref(&self) -> &TrapContext {
+        // safety: see `TrapFrameRef::new`
+        self.pa.as_ref()
+    }
+}
+
+impl AsMut<TrapContext> for TrapFrameRef {
+    fn as_mut(&mut self) -> &mut TrapContext {
+        // safety: see `TrapFrameRef::new`, borrowing `self` mutably makes it exclusive
+        self.pa.as_ref_mut()
     }
 }
 
@@ -88,3 +221,42 @@ impl TrapContext {
 pub fn trap_init_hart() {
     unsafe { stvec::write(__kernelvec as usize, stvec::TrapMode::Direct) };
 }
+
+/// the named accessors should agree with the indices of the RISC-V ABI
+pub fn test_abi_register_mapping() {
+    let mut ctx = TrapContext::default();
+    ctx.with_user_regs(|regs| {
+        for (i, reg) in regs.iter_mut().enumerate() {
+            *reg = i;
+        }
+    });
+    assert_eq!((ctx.ra(), ctx.sp(), ctx.tp()), (1, 2, 4));
+    assert_eq!(ctx.a0(), 10);
+    assert_eq!(ctx.a7(), 17);
+    for idx in 0..N_SYSCALL_ARGS {
+        assert_eq!(ctx.get_arg(idx), 10 + idx);
+    }
+
+    ctx.set_return_value(42);
+    assert_eq!(ctx.with_user_regs(|regs| regs[10]), 42);
+    ctx.set_pc(0x1000);
+    assert_eq!(ctx.get_user_space_pc().as_usize(), 0x1000);
+}
+
+/// a trap context written through a `TrapFrameRef` should land in its frame
+pub fn test_trap_frame_ref() {
+    let frame_guard = FrameGuard::allocate_zeroed();
+    let pa = frame_guard.get_frame().get_base_phys_addr();
+    let mut trap_frame = unsafe { TrapFrameRef::new(pa) };
+    assert_eq!(trap_frame.as_ref().a0(), 0);
+
+    let ctx = trap_frame.as_mut();
+    ctx.set_return_value(42);
+    ctx.set_pc(0x1000);
+    assert_eq!(trap_frame.as_ref().get_arg(0), 42);
+    assert_eq!(trap_frame.as_ref().pc(), 0x1000);
+    // the very frame
+    let regs: &[usize; 32] = pa.as_ref();
+    assert_eq!(regs[10], 42);
+    assert_eq!(trap_frame.phys_addr().as_usize(), pa.as_usize());
+}
@@ -0,0 +1,260 @@
+//! A minimal ELF64 parser, just enough to load statically linked user programmes
+
+use alloc::vec::Vec;
+
+use crate::mm::{
+    layout::{PAGE_SIZE, TEXT_BASE_USER_VA, TRAPFRAME_BASE_USER_VA, USER_STACK_MAX_PAGES},
+    page_table::PageFlags,
+};
+
+// ELF64 header
+// https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+const ELF_HEADER_SIZE: usize = 64;
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const E_MACHINE: usize = 18;
+const E_ENTRY: usize = 24;
+const E_PHOFF: usize = 32;
+const E_PHENTSIZE: usize = 54;
+const E_PHNUM: usize = 56;
+
+// ELF64 program header
+const PROGRAM_HEADER_SIZE: usize = 56;
+const P_TYPE: usize = 0;
+const P_FLAGS: usize = 4;
+const P_OFFSET: usize = 8;
+const P_VADDR: usize = 16;
+const P_FILESZ: usize = 32;
+const P_MEMSZ: usize = 40;
+
+const PT_LOAD: u32 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+// segment permissions in `p_flags`
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+/// A loadable segment, `data` is its content in the file,
+/// the rest of its `memsz` bytes should be zeroed
+#[derive(Debug)]
+pub struct ElfSegment<'a> {
+    pub vaddr: usize,
+    pub memsz: usize,
+    pub flags: u32,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug)]
+pub struct ElfImage<'a> {
+    pub entry: usize,
+    pub segments: Vec<ElfSegment<'a>>,
+}
+
+/// why a binary is rejected by `parse`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// a header or a segment goes beyond the end of the bytes
+    Truncated,
+    BadMagic,
+    /// not a 64-bit little-endian binary
+    BadClass,
+    NotRiscV,
+    BadProgramHeader,
+    /// a segment is not within the user space, or leaves no room for
+    /// the user stack below the trapframe, see `AddrSpace::make_user`
+    SegmentOutOfRange,
+    /// a segment asks to be both writable and executable
+    WritableAndExecutable,
+}
+
+// the offsets come from the binary, they may overflow
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let raw = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(raw.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let raw = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(raw.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<usize> {
+    let raw = bytes.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(raw.try_into().ok()?) as usize)
+}
+
+/// Parse the entry point and the `PT_LOAD` segments of an ELF binary.
+/// The bytes are not trusted: the header should be of a 64-bit RISC-V binary,
+/// and the segments should be loadable into the user space
+pub fn parse(bytes: &[u8]) -> Result<ElfImage, ElfError> {
+    if bytes.len() < ELF_HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if bytes[0..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if bytes[EI_CLASS] != ELFCLASS64 || bytes[EI_DATA] != ELFDATA2LSB {
+        return Err(ElfError::BadClass);
+    }
+    if read_u16(bytes, E_MACHINE) != Some(EM_RISCV) {
+        return Err(ElfError::NotRiscV);
+    }
+
+    let truncated = ElfError::Truncated;
+    let entry = read_u64(bytes, E_ENTRY).ok_or(truncated)?;
+    let phoff = read_u64(bytes, E_PHOFF).ok_or(truncated)?;
+    let phentsize = read_u16(bytes, E_PHENTSIZE).ok_or(truncated)? as usize;
+    let phnum = read_u16(bytes, E_PHNUM).ok_or(truncated)? as usize;
+    if phentsize < PROGRAM_HEADER_SIZE {
+        return Err(ElfError::BadProgramHeader);
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff
+            .checked_add(i * phentsize)
+            .ok_or(ElfError::BadProgramHeader)?;
+        // so that `ph + P_*` below cannot overflow
+        if ph.checked_add(PROGRAM_HEADER_SIZE).is_none() {
+            return Err(truncated);
+        }
+        if read_u32(bytes, ph + P_TYPE).ok_or(truncated)? != PT_LOAD {
+            continue;
+        }
+        let offset = read_u64(bytes, ph + P_OFFSET).ok_or(truncated)?;
+        let filesz = read_u64(bytes, ph + P_FILESZ).ok_or(truncated)?;
+        let segment = ElfSegment {
+            vaddr: read_u64(bytes, ph + P_VADDR).ok_or(truncated)?,
+            memsz: read_u64(bytes, ph + P_MEMSZ).ok_or(truncated)?,
+            flags: read_u32(bytes, ph + P_FLAGS).ok_or(truncated)?,
+            data: offset
+                .checked_add(filesz)
+                .and_then(|end| bytes.get(offset..end))
+                .ok_or(truncated)?,
+        };
+        if filesz > segment.memsz {
+            return Err(ElfError::BadProgramHeader);
+        }
+        // W^X, code cannot be modified and data cannot be run
+        if segment.flags & (PF_W | PF_X) == PF_W | PF_X {
+            return Err(ElfError::WritableAndExecutable);
+        }
+        check_user_range(&segment)?;
+        segments.push(segment);
+    }
+
+    Ok(ElfImage { entry, segments })
+}
+
+/// the segment should be above the unmapped first pages, and leave room
+/// for the user stack and its guard page below the trapframe
+fn check_user_range(segment: &ElfSegment) -> Result<(), ElfError> {
+    const STACK_ROOM: usize = (USER_STACK_MAX_PAGES + 1) * PAGE_SIZE;
+    let end = segment
+        .vaddr
+        .checked_add(segment.memsz)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+        .ok_or(ElfError::SegmentOutOfRange)?;
+    if segment.vaddr < TEXT_BASE_USER_VA || end > TRAPFRAME_BASE_USER_VA - STACK_ROOM {
+        return Err(ElfError::SegmentOutOfRange);
+    }
+    Ok(())
+}
+
+/// malformed binaries should be rejected with their reason, not panic
+pub fn test_reject_malformed() {
+    let bytes = super::programs::lookup("/init").unwrap();
+    assert!(parse(bytes).is_ok());
+
+    assert_eq!(
+        parse(&bytes[..ELF_HEADER_SIZE / 2]).unwrap_err(),
+        ElfError::Truncated
+    );
+    // the program headers are cut off
+    assert_eq!(
+        parse(&bytes[..ELF_HEADER_SIZE]).unwrap_err(),
+        ElfError::Truncated
+    );
+
+    let mut wrong_arch = bytes.to_vec();
+    // x86-64
+    wrong_arch[E_MACHINE..E_MACHINE + 2].copy_from_slice(&62u16.to_le_bytes());
+    assert_eq!(parse(&wrong_arch).unwrap_err(), ElfError::NotRiscV);
+
+    let mut wrong_class = bytes.to_vec();
+    wrong_class[EI_CLASS] = 1;
+    assert_eq!(parse(&wrong_class).unwrap_err(), ElfError::BadClass);
+
+    let mut not_elf = bytes.to_vec();
+    not_elf[0] = 0;
+    assert_eq!(parse(&not_elf).unwrap_err(), ElfError::BadMagic);
+
+    // move the first `PT_LOAD` onto the trapframe
+    let mut out_of_range = bytes.to_vec();
+    let phoff = read_u64(bytes, E_PHOFF).unwrap();
+    let phentsize = read_u16(bytes, E_PHENTSIZE).unwrap() as usize;
+    let ph = (0..)
+        .map(|i| phoff + i * phentsize)
+        .find(|&ph| read_u32(bytes, ph + P_TYPE) == Some(PT_LOAD))
+        .unwrap();
+    out_of_range[ph + P_VADDR..ph + P_VADDR + 8]
+        .copy_from_slice(&(TRAPFRAME_BASE_USER_VA as u64).to_le_bytes());
+    assert_eq!(
+        parse(&out_of_range).unwrap_err(),
+        ElfError::SegmentOutOfRange
+    );
+
+    // program headers at the very end of the address space
+    let mut overflowing = bytes.to_vec();
+    overflowing[E_PHOFF..E_PHOFF + 8].copy_from_slice(&(u64::MAX - 2).to_le_bytes());
+    assert_eq!(parse(&overflowing).unwrap_err(), ElfError::Truncated);
+    let mut overflowing_segment = bytes.to_vec();
+    overflowing_segment[ph + P_OFFSET..ph + P_OFFSET + 8]
+        .copy_from_slice(&(u64::MAX - 2).to_le_bytes());
+    assert_eq!(
+        parse(&overflowing_segment).unwrap_err(),
+        ElfError::Truncated
+    );
+}
+
+/// segments should be mapped as their flags say, and never both writable and executable
+pub fn test_segment_flags() {
+    let bytes = super::programs::lookup("/init").unwrap();
+    for segment in parse(bytes).unwrap().segments {
+        let perms = PageFlags::from_elf_flags(segment.flags);
+        assert!(!perms.contains(PageFlags::WRITABLE | PageFlags::EXECUTABLE));
+        assert!(!perms.contains(PageFlags::USER));
+    }
+    assert_eq!(
+        PageFlags::from_elf_flags(PF_R | PF_X).bits(),
+        (PageFlags::READABLE | PageFlags::EXECUTABLE).bits()
+    );
+    assert_eq!(
+        PageFlags::from_elf_flags(PF_R | PF_W).bits(),
+        (PageFlags::READABLE | PageFlags::WRITABLE).bits()
+    );
+    // unknown bits, e.g. processor specific ones, are ignored
+    assert_eq!(
+        PageFlags::from_elf_flags(PF_R | 0xf000_0000).bits(),
+        PageFlags::READABLE.bits()
+    );
+
+    // make the first `PT_LOAD` writable too
+    let mut crafted = bytes.to_vec();
+    let phoff = read_u64(bytes, E_PHOFF).unwrap();
+    let phentsize = read_u16(bytes, E_PHENTSIZE).unwrap() as usize;
+    let ph = (0..)
+        .map(|i| phoff + i * phentsize)
+        .find(|&ph| read_u32(bytes, ph + P_TYPE) == Some(PT_LOAD))
+        .unwrap();
+    crafted[ph + P_FLAGS..ph + P_FLAGS + 4].copy_from_slice(&(PF_R | PF_W | PF_X).to_le_bytes());
+    assert_eq!(
+        parse(&crafted).unwrap_err(),
+        ElfError::WritableAndExecutable
+    );
+}
@@ -1,20 +1,44 @@
-use alloc::{collections::VecDeque, sync::Arc};
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 use lazy_static::lazy_static;
-use spin::Mutex;
 
-use crate::{common::resource_table::ResourceTable, process::process::ProcessControlBlock};
+use crate::{
+    allocator::frame_allocator,
+    common::{kernel_mutex::KernelMutex, resource_table::ResourceTable},
+    cpu, debug,
+    mm::{address_space::AddrSpace, layout::TEXT_BASE_USER_VA, memory::VirtAddr},
+    process::process::{PCBInner, ProcStatus, ProcessControlBlock, MAX_PRIORITY, MIN_PRIORITY},
+};
 
 use super::process;
 lazy_static! {
-    pub static ref PROCESS_MANAGER: Mutex<ProcessManager> = Mutex::new(ProcessManager::new());
+    pub static ref PROCESS_MANAGER: KernelMutex<ProcessManager> =
+        KernelMutex::new("PROCESS_MANAGER", ProcessManager::new());
 }
 
 const INTIIAL_MAX_N_PROCS: usize = 128;
+// `fork` fails beyond it, so that a fork bomb cannot take the whole kernel heap
+const MAX_N_PROCS: usize = 1024;
+
+/// `init` is the first process, see `create_initcode`
+pub const INIT_PID: usize = 0;
+
+/// a process as listed by `ProcessManager::list_processes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcInfo {
+    pub pid: usize,
+    pub status: ProcStatus,
+    /// see `PCBInner::heap_size`
+    pub heap_size: usize,
+}
 
 pub struct ProcessManager {
     pcb_table: ResourceTable<ProcessControlBlock>,
-    // runnable processes
-    ready_queue: VecDeque<Arc<ProcessControlBlock>>,
+    // runnable processes are kept on the ready queues of the harts, see `cpu::push_ready`
+    // processes waiting for their `wakeup_tick`
+    sleeping: Vec<Arc<ProcessControlBlock>>,
 }
 
 // unsafe impl Sync for ProcessManager {}
@@ -22,48 +46,220 @@ pub struct ProcessManager {
 impl ProcessManager {
     fn new() -> Self {
         Self {
-            pcb_table: ResourceTable::new(INTIIAL_MAX_N_PROCS),
-            ready_queue: VecDeque::new(),
+            pcb_table: ResourceTable::new(INTIIAL_MAX_N_PROCS, MAX_N_PROCS),
+            sleeping: Vec::new(),
         }
     }
 
-    pub fn create_process(&mut self) -> Arc<ProcessControlBlock> {
-        let pid = self.pcb_table.reserve_entry();
-        let pcb = Arc::new(ProcessControlBlock::allocate(pid));
+    /// allocate a PCB with a new pid.
+    /// It is not runnable until explicitly pushed to a ready queue by `cpu::push_ready`
+    /// since its address space and trap context are yet to be initialised.
+    /// `None` if there are already `MAX_N_PROCS` processes
+    pub fn create_process(&mut self) -> Option<Arc<ProcessControlBlock>> {
+        // the pid only sticks once its PCB is built
+        let pid = self.pcb_table.reserve_entry()?;
+        let Some(pcb) = ProcessControlBlock::try_allocate(pid) else {
+            self.pcb_table.rollback_entry(pid);
+            return None;
+        };
+        let pcb = Arc::new(pcb);
         self.pcb_table.initialise_entry(pid, pcb.clone());
-        self.ready_queue.push_front(pcb.clone());
-        pcb
+        Some(pcb)
     }
 
-    pub fn pop_one(&mut self) -> Option<Arc<ProcessControlBlock>> {
-        Some(self.ready_queue.pop_front()?)
+    /// all processes, sorted by pid
+    pub fn list_processes(&self) -> Vec<ProcInfo> {
+        let mut processes = Vec::new();
+        self.pcb_table.for_each(|pid, pcb| {
+            let inner = pcb.inner.read();
+            processes.push(ProcInfo {
+                pid,
+                status: inner.status,
+                heap_size: inner.heap_size(),
+            })
+        });
+        processes.sort_unstable_by_key(|info| info.pid);
+        processes
     }
 
-    pub fn push_one(&mut self, pid: usize) {
+    /// put a `SLEEPING` process aside until `wake_sleepers` finds its wakeup tick passed,
+    /// or `wake_channel` its channel
+    pub fn push_sleeping(&mut self, pid: usize) {
         let pcb = self.pcb_table.get(pid);
         assert_eq!(pcb.pid, pid);
-        self.ready_queue.push_back(pcb);
+        self.sleeping.push(pcb);
+    }
+
+    /// move the sleeping processes whose wakeup tick has been reached
+    /// to the ready queue of the calling hart as `RUNNABLE`
+    pub fn wake_sleepers(&mut self, now: u64) {
+        self.take_expired(now).into_iter().for_each(cpu::push_ready);
+    }
+
+    /// move the processes sleeping on `chan` to the ready queue of the calling hart as `RUNNABLE`
+    pub fn wake_channel(&mut self, chan: usize) {
+        self.take_channel(chan)
+            .into_iter()
+            .for_each(cpu::push_ready);
     }
 
-    pub fn exit_process(&mut self, _pid: usize) {
-        // if the process is running
+    fn take_expired(&mut self, now: u64) -> Vec<Arc<ProcessControlBlock>> {
+        self.take_sleepers(|inner| inner.sleep_chan.is_none() && inner.wakeup_tick <= now)
+    }
+
+    fn take_channel(&mut self, chan: usize) -> Vec<Arc<ProcessControlBlock>> {
+        self.take_sleepers(|inner| inner.sleep_chan == Some(chan))
+    }
+
+    /// `pcb` has left its hart after `process::sleep_on`. If it was woken up in the meantime,
+    /// it could not be handed to a ready queue yet, it goes to the one of the calling hart
+    pub fn settle_sleeper(&mut self, pcb: Arc<ProcessControlBlock>) {
+        let mut inner = pcb.inner.write();
+        inner.leaving_hart = false;
+        let woken = inner.status == ProcStatus::RUNNABLE;
+        drop(inner);
+        if woken {
+            cpu::push_ready(pcb);
+        }
+    }
+
+    /// Take the sleeping processes that `should_wake` as `RUNNABLE`.
+    /// Those still on their hart are left out, see `settle_sleeper`
+    fn take_sleepers(
+        &mut self,
+        should_wake: impl Fn(&PCBInner) -> bool,
+    ) -> Vec<Arc<ProcessControlBlock>> {
+        let mut woken = Vec::new();
+        let mut i = 0;
+        while i < self.sleeping.len() {
+            let mut inner = self.sleeping[i].inner.write();
+            if should_wake(&inner) {
+                inner.status = ProcStatus::RUNNABLE;
+                inner.sleep_chan = None;
+                let leaving_hart = inner.leaving_hart;
+                drop(inner);
+                let pcb = self.sleeping.swap_remove(i);
+                if !leaving_hart {
+                    woken.push(pcb);
+                }
+            } else {
+                i += 1;
+            }
+        }
+        woken
+    }
+
+    /// Turn the process into a `ZOMBIE` holding `exit_code` until its parent reaps it,
+    /// and wake up the parent if it waits. Its children are handed over to `init`.
+    /// It will not be scheduled again, even if it was waiting on a ready queue.
+    /// Its files should have been closed, see `process::exit`
+    pub fn exit_process(&mut self, pid: usize, exit_code: i32) {
+        assert_ne!(pid, INIT_PID, "ProcessManager::exit_process: init exiting");
+        let pcb = self.pcb_table.get(pid);
+        cpu::remove_ready(&pcb);
+        let (children, parent) = {
+            let mut inner = pcb.inner.write();
+            inner.status = ProcStatus::ZOMBIE;
+            inner.exit_code = exit_code;
+            (
+                core::mem::take(&mut inner.children),
+                inner.parent.as_ref().and_then(Weak::upgrade),
+            )
+        };
+        if let Some(parent) = parent {
+            self.wake_channel(parent.wait_channel());
+        }
+
+        if !children.is_empty() {
+            let init = self.pcb_table.get(INIT_PID);
+            let mut init_inner = init.inner.write();
+            let has_zombie = children.iter().any(|child| child.is_zombie());
+            for child in children {
+                child.inner.write().parent = Some(Arc::downgrade(&init));
+                init_inner.children.push(child);
+            }
+            drop(init_inner);
+            if has_zombie {
+                self.wake_channel(init.wait_channel());
+            }
+        }
+        debug!(
+            "ProcessManager::exit_process: PID {:?} exited with {:?}",
+            pid, exit_code
+        );
+    }
+
+    /// Ask the process to exit, see `PCBInner::killed`. A sleeping process is woken up.
+    /// `None` if there is no such process, `init` cannot be killed either
+    pub fn kill_process(&mut self, pid: usize) -> Option<()> {
+        if pid == INIT_PID {
+            return None;
+        }
+        let pcb = self.pcb_table.try_get(pid)?;
+        let mut inner = pcb.inner.write();
+        inner.killed = true;
+        if let ProcStatus::SLEEPING = inner.status {
+            inner.wakeup_tick = 0;
+            inner.sleep_chan = None;
+        }
+        Some(())
+    }
 
-        // if the process is blocked
+    /// Set the priority of `pid` for `caller`, clamped to `MIN_PRIORITY` and `MAX_PRIORITY`.
+    /// A process may only set its own or its children's, `init` may set anyone's.
+    /// Returns the priority it gets, `None` if there is no such process or it may not
+    pub fn set_priority(&mut self, caller: usize, pid: usize, priority: usize) -> Option<u8> {
+        let pcb = self.pcb_table.try_get(pid)?;
+        if caller != pid && caller != INIT_PID && pcb.inner.read().parent_pid() != caller {
+            return None;
+        }
+        let priority = priority.clamp(MIN_PRIORITY as usize, MAX_PRIORITY as usize) as u8;
+        pcb.inner.write().priority = priority;
+        Some(priority)
+    }
 
-        // if the process it not running
+    /// Free what is left of a `ZOMBIE` once its parent has collected its exit code:
+    /// its address space now, its kernel stack with the last reference to the PCB
+    pub fn reap_process(&mut self, pid: usize) {
+        let pcb = self.pcb_table.get(pid);
+        // a process cannot reap itself, it still runs on its kernel stack
+        assert!(
+            !cpu::current_process().is_some_and(|current| Arc::ptr_eq(&current, &pcb)),
+            "ProcessManager::reap_process: PID {:?} reaping itself",
+            pid
+        );
+        assert!(
+            pcb.is_zombie(),
+            "ProcessManager::reap_process: PID {:?} is not a zombie",
+            pid
+        );
+        pcb.inner.write().free_user_space();
+        self.pcb_table.remove_entry(pid);
     }
 
-    pub fn reap_process(&mut self, _pid: usize) {
-        // a process cannot reap itself, check it!
+    /// Give back the pid of a PCB from `create_process` that failed to initialise.
+    /// It must never have been pushed to a ready queue
+    pub fn discard_process(&mut self, pid: usize) {
+        let pcb = self.pcb_table.get(pid);
+        assert!(
+            pcb.inner.read().parent.is_none(),
+            "ProcessManager::discard_process: PID {:?} already has a parent",
+            pid
+        );
+        pcb.inner.write().free_user_space();
+        self.pcb_table.remove_entry(pid);
     }
 }
 
 impl ProcessManager {
     pub fn create_initcode(&mut self) {
-        let pid = self.pcb_table.reserve_entry();
+        let pid = self
+            .pcb_table
+            .reserve_entry()
+            .expect("ProcessManager::create_initcode: no pid for init");
         let pcb = Arc::new(process::make_initcode_uninitialised(pid));
         self.pcb_table.initialise_entry(pid, pcb.clone());
-        self.ready_queue.push_back(pcb);
+        cpu::push_ready(pcb);
     }
 }
 
@@ -73,3 +269,109 @@ pub fn init() {
     let mut process_manager = PROCESS_MANAGER.lock();
     process_manager.create_initcode();
 }
+
+/// Hand a PCB from `PROCESS_MANAGER` to `f`, for tests that need a process.
+/// It has a pid, a kernel stack and the root as its working directory but no user space,
+/// it never runs and is discarded once `f` returns
+pub fn with_test_process<R>(f: impl FnOnce(&Arc<ProcessControlBlock>) -> R) -> R {
+    let pcb = PROCESS_MANAGER
+        .lock()
+        .create_process()
+        .expect("manager::with_test_process: no pid left");
+    let ret = f(&pcb);
+    PROCESS_MANAGER.lock().discard_process(pcb.get_pid());
+    ret
+}
+
+/// the listing should have every created process, but not a reserved pid
+pub fn test_list_processes() {
+    let mut manager = ProcessManager::new();
+    let pids: Vec<usize> = (0..3)
+        .map(|_| manager.create_process().unwrap().get_pid())
+        .collect();
+    let reserved = manager.pcb_table.reserve_entry().unwrap();
+
+    let processes = manager.list_processes();
+    assert_eq!(processes.len(), 3);
+    for (info, &expected) in processes.iter().zip(&pids) {
+        assert_eq!(info.pid, expected);
+        assert_eq!(info.status, ProcStatus::RUNNABLE);
+        // no user address space yet
+        assert_eq!(info.heap_size, 0);
+    }
+    assert!(processes.iter().all(|info| info.pid != reserved));
+}
+
+/// a process sleeping on a channel should only be woken up by that channel
+pub fn test_wake_channel() {
+    let mut manager = ProcessManager::new();
+    let pcb = manager.create_process().unwrap();
+    let chan = pcb.wait_channel();
+    {
+        let mut inner = pcb.inner.write();
+        inner.status = ProcStatus::SLEEPING;
+        inner.sleep_chan = Some(chan);
+    }
+    manager.push_sleeping(pcb.get_pid());
+
+    assert!(manager.take_expired(u64::MAX).is_empty());
+    assert!(manager.take_channel(chan + 1).is_empty());
+
+    let woken = manager.take_channel(chan);
+    assert_eq!(woken.len(), 1);
+    assert_eq!(woken[0].get_pid(), pcb.get_pid());
+    assert_eq!(pcb.inner.read().status, ProcStatus::RUNNABLE);
+    assert_eq!(pcb.inner.read().sleep_chan, None);
+}
+
+/// a process woken up before it is off its hart should not be handed to a ready queue,
+/// another hart could run it on the kernel stack it is still using
+pub fn test_wake_leaving_hart() {
+    let mut manager = ProcessManager::new();
+    let pcb = manager.create_process().unwrap();
+    let chan = pcb.wait_channel();
+    {
+        let mut inner = pcb.inner.write();
+        inner.status = ProcStatus::SLEEPING;
+        inner.sleep_chan = Some(chan);
+        inner.leaving_hart = true;
+    }
+    manager.push_sleeping(pcb.get_pid());
+
+    assert!(manager.take_channel(chan).is_empty());
+    assert!(manager.sleeping.is_empty());
+    // up to `settle_sleeper`
+    assert_eq!(pcb.inner.read().status, ProcStatus::RUNNABLE);
+    assert_eq!(pcb.inner.read().sleep_chan, None);
+}
+
+/// the frames of a process should all be back once it has exited and been reaped
+pub fn test_exit_and_reap() {
+    let mut manager = ProcessManager::new();
+    // stands in for init, which never exits
+    let _init = manager.create_process().unwrap();
+    let baseline = frame_allocator::n_allocated_frames();
+
+    let pcb = manager.create_process().unwrap();
+    let pid = pcb.get_pid();
+    // enough to own some frames, it never runs
+    let va = VirtAddr::new(TEXT_BASE_USER_VA);
+    let kernel_stack_pa = pcb.get_kernel_stack_phys_addr();
+    pcb.inner
+        .write()
+        .replace_user_space(AddrSpace::make_init(), va, va, kernel_stack_pa)
+        .unwrap();
+    cpu::push_ready(pcb.clone());
+    assert!(frame_allocator::n_allocated_frames() > baseline);
+
+    manager.exit_process(pid, 3);
+    assert!(pcb.is_zombie());
+    assert_eq!(pcb.inner.read().exit_code, 3);
+    // it is not left on the ready queue
+    assert!(cpu::remove_ready(&pcb).is_none());
+
+    manager.reap_process(pid);
+    assert!(manager.pcb_table.try_get(pid).is_none());
+    drop(pcb);
+    assert_eq!(frame_allocator::n_allocated_frames(), baseline);
+}
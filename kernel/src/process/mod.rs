@@ -1,20 +1,204 @@
-use crate::{arch::hart_id, cpu::CPUS};
+use alloc::sync::Arc;
 
-use self::manager::PROCESS_MANAGER;
+use crate::{
+    arch::{self, hart_id},
+    common::kernel_mutex::KernelMutexGuard,
+    cpu, debug,
+    mm::layout::{__kernel_stack_start, BOOT_STACK_SIZE},
+    trap,
+};
+
+use self::{
+    manager::{ProcessManager, PROCESS_MANAGER},
+    process::{PCBInner, ProcStatus, ProcessControlBlock},
+};
 
 pub mod context;
+pub mod elf;
 pub mod manager;
 pub mod process;
+pub mod programs;
+pub mod times;
 
 pub fn init() {
-    // init the process manager and create the first user-space process
+    // init the process manager and create the first user-space process,
+    // the first hart entering `idle` picks it up
     manager::init();
+}
+
+/// Where every hart goes after booting, with nothing running on it yet.
+/// It waits in `schedule` until a process is runnable and returns into it
+pub fn idle() -> ! {
+    assert!(cpu::current_process().is_none());
+    schedule(Handoff::Leave)
+}
+
+/// What `schedule` does with the process leaving the hart, once it is off its kernel stack.
+/// Another hart may run it from the top of that stack as soon as it is handed off
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handoff {
+    /// back to the ready queue of the hart, it is preempted or yields
+    Ready,
+    /// nowhere, it is already among the sleepers of `PROCESS_MANAGER`, see `sleep_on`
+    Sleep,
+    /// nowhere, it has exited, or the hart runs none
+    Leave,
+}
+
+impl Handoff {
+    fn from_raw(raw: usize) -> Self {
+        match raw {
+            0 => Self::Ready,
+            1 => Self::Sleep,
+            _ => Self::Leave,
+        }
+    }
+}
+
+/// Switch the calling hart to the next process in its ready queue, or one stolen from another hart,
+/// and return into it with `usertrapret`.
+/// The current process is handed off as `handoff` says, the caller should only have set
+/// its status, a process going to sleep goes through `sleep_on` instead.
+/// Processes of the same priority are picked in the order they became ready,
+/// so processes yielding to each other take turns, see `/yieldtest`.
+///
+/// It first moves to the boot stack of the hart, which it keeps as its scheduler stack:
+/// the current process may run on another hart, or be reaped, once it is handed off.
+/// The next one does not need the stack it left, every trap starts on top of the
+/// process's kernel stack and its user context lives in its trapframe.
+/// Whatever the caller still holds on its stack is not dropped.
+///
+/// When a process blocks or exits and nothing else is runnable,
+/// the hart falls back to `wait_for_runnable`, the idle loop
+pub fn schedule(handoff: Handoff) -> ! {
+    let sp = __kernel_stack_start() + BOOT_STACK_SIZE * (hart_id() + 1);
+    arch::switch_stack(sp, scheduler, handoff as usize)
+}
+
+/// `schedule` on the scheduler stack of the hart
+extern "C" fn scheduler(handoff: usize) -> ! {
+    // the previous process is no longer executing on this hart.
+    // Do not hold the CPU while looking for the next, others may steal from its ready queue
+    if let Some(prev) = cpu::take_current_process() {
+        match Handoff::from_raw(handoff) {
+            Handoff::Ready => {
+                prev.inner.write().status = ProcStatus::RUNNABLE;
+                cpu::push_ready(prev);
+            }
+            Handoff::Sleep => PROCESS_MANAGER.lock().settle_sleeper(prev),
+            // the last reference of a reaped process frees its kernel stack
+            Handoff::Leave => drop(prev),
+        }
+    }
+
+    let next = wait_for_runnable();
+    debug!(
+        "process::schedule: hart {:?} switching to PID {:?}",
+        hart_id(),
+        next.get_pid()
+    );
+    {
+        let mut inner = next.inner.write();
+        inner.status = ProcStatus::RUNNING;
+        // it waits no more, see `cpu::age_ready`
+        inner.age = 0;
+    }
+    cpu::set_current_process(next);
+    trap::usertrapret()
+}
+
+/// The idle loop of a hart: instead of spinning, it sleeps with `wfi` until the
+/// next timer or device interrupt, then looks for a runnable process again
+fn wait_for_runnable() -> Arc<ProcessControlBlock> {
+    loop {
+        PROCESS_MANAGER.lock().wake_sleepers(trap::ticks());
+        if let Some(next) = cpu::pop_ready() {
+            return next;
+        }
+        // `kerneltrap` counts the ticks
+        trap::watchdog::set_idle(true);
+        arch::intr_on();
+        unsafe { riscv::asm::wfi() };
+        arch::intr_off();
+        trap::watchdog::set_idle(false);
+    }
+}
+
+/// ready processes gain a level of priority every that many ticks, see `cpu::age_ready`
+pub const AGING_INTERVAL: u64 = 10;
+
+/// On every timer tick of hart 0, see `trap::soft_interrupt`
+pub fn age_ready(ticks: u64) {
+    if hart_id() == 0 && ticks % AGING_INTERVAL == 0 {
+        cpu::age_ready();
+    }
+}
+
+/// Block the running process on `chan`, usually the address of the kernel object
+/// it waits for, until someone calls `wakeup(chan)`.
+/// The caller checks what it waits for holding `manager`, the lock of `PROCESS_MANAGER`
+/// `wakeup` takes too, so a wakeup cannot get in between the check and the sleep.
+/// Like `schedule` it switches to another process, so it should be the last thing
+/// a syscall does, and the syscall should be restarted to check its condition again
+pub fn sleep_on(manager: KernelMutexGuard<'_, ProcessManager>, chan: usize) -> ! {
+    sleep(manager, |inner| inner.sleep_chan = Some(chan))
+}
+
+/// Block the running process until the tick `wakeup_tick`, see `ProcessManager::wake_sleepers`
+pub fn sleep_until(wakeup_tick: u64) -> ! {
+    sleep(PROCESS_MANAGER.lock(), |inner| {
+        inner.wakeup_tick = wakeup_tick
+    })
+}
+
+/// Put the running process to the sleepers of `manager` before it leaves the hart,
+/// waking it up in the meantime is left to `schedule`, see `ProcessManager::settle_sleeper`
+fn sleep(
+    mut manager: KernelMutexGuard<'_, ProcessManager>,
+    wait_for: impl FnOnce(&mut PCBInner),
+) -> ! {
+    let pcb = cpu::current_process().expect("process::sleep: no running process");
+    {
+        let mut inner = pcb.inner.write();
+        wait_for(&mut inner);
+        inner.status = ProcStatus::SLEEPING;
+        inner.leaving_hart = true;
+    }
+    manager.push_sleeping(pcb.get_pid());
+    drop(manager);
+    // do not hold on to the PCB, `schedule` never returns
+    drop(pcb);
+    schedule(Handoff::Sleep)
+}
 
-    // now let's fake that a scheduler has chosen `init` to run it on the core-0
-    assert_eq!(hart_id(), 0);
-    CPUS[0]
-        .write()
-        .set_executing_process(PROCESS_MANAGER.lock().pop_one().unwrap());
+/// make the processes sleeping on `chan` runnable, do not call it holding `PROCESS_MANAGER`
+pub fn wakeup(chan: usize) {
+    PROCESS_MANAGER.lock().wake_channel(chan);
 }
 
-pub fn schedule() {}
+/// Close the files of `pcb` and turn it into a zombie, see `ProcessManager::exit_process`.
+/// Closing a pipe wakes up the other end, so it is done before locking `PROCESS_MANAGER`
+pub fn exit(pcb: &ProcessControlBlock, exit_code: i32) {
+    // a temporary would be dropped before the guard, see `PCBInner::close_fd`
+    let closed = pcb.inner.write().close_all_fds();
+    drop(closed);
+    PROCESS_MANAGER
+        .lock()
+        .exit_process(pcb.get_pid(), exit_code);
+}
+
+/// Preempt the process running on the calling hart in favour of the next
+/// runnable one of the hart. It keeps running if there is no runnable alternative.
+/// Its user context has already been saved in its trapframe by `__uservec`
+pub fn preempt() {
+    if cpu::current_process().is_none() {
+        return;
+    }
+
+    PROCESS_MANAGER.lock().wake_sleepers(trap::ticks());
+    if !cpu::has_ready() {
+        return;
+    }
+    schedule(Handoff::Ready);
+}
@@ -1,31 +1,56 @@
 use core::ptr::addr_of;
 
-use alloc::sync::Arc;
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use riscv::register::sstatus::SPP;
 use spin::rwlock::RwLock;
 
+use crate::allocator::slab::{SlabBox, SlabCache};
+use crate::fs::{
+    file::FileHandle,
+    inode::{self, InodeRef},
+};
 use crate::info;
 use crate::mm::layout::TRAPFRAME_BASE_USER_VA;
 use crate::mm::memory::{PhysAddr, VirtAddr};
 use crate::mm::KERNEL_ADDRESS_SPACE;
+use crate::symbols::SCHEDULER_INTERVAL;
 use crate::trap::usertrap;
 use crate::{
     debug,
     mm::{
+        self,
         address_space::AddrSpace,
-        layout::TEXT_BASE_USER_VA,
-        memory::{Frame, FrameGuard},
+        layout::{KERNEL_STACK_SIZE, PAGE_SIZE, TEXT_BASE_USER_VA},
+        memory::{Frame, FrameRangeGuard},
+        page_table::PageFlags,
     },
 };
 
-use super::context::TrapContext;
+use super::{
+    context::{TrapContext, TrapFrameRef},
+    manager::{with_test_process, INIT_PID},
+    times::Times,
+};
+
+// the maximum number of open files per process
+pub const NOFILE: usize = 16;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcStatus {
     RUNNING,
     RUNNABLE,
+    SLEEPING,
     ZOMBIE,
 }
 
+/// scheduling priorities, see `PCBInner::priority`
+pub const MIN_PRIORITY: u8 = 0;
+pub const MAX_PRIORITY: u8 = 7;
+pub const DEFAULT_PRIORITY: u8 = 4;
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct ProcessControlBlock {
@@ -33,9 +58,12 @@ pub struct ProcessControlBlock {
     // the kernel stack is not visible to its user address space, hence it is not managed by the `user_addr_space`
     // Dropping it results in the frame for its kernel stack being recycled
     pub kernel_stack: KernelStackGuard,
-    pub inner: RwLock<PCBInner>,
+    pub inner: SlabBox<RwLock<PCBInner>>,
 }
 
+/// PCBs come and go with every `fork` and `exit`, keep their bulk off the kernel heap
+static PCB_INNER_CACHE: SlabCache<RwLock<PCBInner>> = SlabCache::new();
+
 #[repr(C)]
 #[derive(Debug)]
 /// Safety: Every `PCBInner` should be wrapped within a lock!!
@@ -46,33 +74,77 @@ pub struct PCBInner {
     //
     // Here, we chose to allocate a whole page and store its address.
     // when the PCB is allocated, it's set to point to None
-    trap_context: Option<PhysAddr>,
+    trap_context: Option<TrapFrameRef>,
 
     // Dropping it reuslts in recycling of frames for page table and all user space
     // this includes the page containing `trap_context`
     user_addr_space: Option<AddrSpace>,
 
-    //
-    // children: Vec<Arc<ProcessControlBlock>>,
-    // parent: Option<Weak<ProcessControlBlock>>,
+    /// relative paths are resolved from it, changed by `chdir`
+    pub cwd: InodeRef,
+
+    /// indexed by file descriptors
+    open_files: [Option<Arc<FileHandle>>; NOFILE],
+
+    /// end of the user heap, moved by `sbrk` and `brk`, see `set_program_break`
+    pub program_break: VirtAddr,
+
+    /// the tick a `SLEEPING` process should be woken up at
+    pub wakeup_tick: u64,
+    /// what a `SLEEPING` process waits for instead of a tick, see `process::sleep_on`
+    pub sleep_chan: Option<usize>,
+    /// set by `process::sleep_on` until `schedule` has taken it off its hart,
+    /// a wakeup in between leaves it to `ProcessManager::settle_sleeper`
+    pub leaving_hart: bool,
+
+    /// `None` for `init`, orphans are handed over to it
+    pub parent: Option<Weak<ProcessControlBlock>>,
+    /// they are reaped by `wait`
+    pub children: Vec<Arc<ProcessControlBlock>>,
+    /// set by `exit`, for the parent to collect
+    pub exit_code: i32,
+    /// set by `kill`, the process exits the next time it returns to the user space
+    pub killed: bool,
+
+    /// set by `setpriority`, from `MIN_PRIORITY` to `MAX_PRIORITY`, the higher runs first
+    pub priority: u8,
+    /// added to `priority` while it waits on a ready queue, see `cpu::age_ready`
+    pub age: u8,
+
+    /// `arch::ticks` it has spent in the user space and in the kernel, see `sample_tick`
+    pub user_ticks: u64,
+    pub kernel_ticks: u64,
+
     pub status: ProcStatus,
 }
 
 impl PCBInner {
+    /// `INIT_PID` for `init` itself, or if the parent is gone without handing it over
+    pub fn parent_pid(&self) -> usize {
+        self.parent
+            .as_ref()
+            .and_then(Weak::upgrade)
+            .map_or(INIT_PID, |parent| parent.get_pid())
+    }
+
+    /// what the scheduler compares, see `cpu::pop_ready`
+    pub fn effective_priority(&self) -> u8 {
+        self.priority.saturating_add(self.age).min(MAX_PRIORITY)
+    }
+
     pub fn initialise_trap_context(&mut self, f: impl FnOnce() -> PhysAddr) {
-        let ctx = f();
+        // safety: it is the trapframe of `user_addr_space`, which is dropped together
+        //  with it, see `free_user_space` and `replace_user_space`
+        let ctx = unsafe { TrapFrameRef::new(f()) };
         self.trap_context = Some(ctx);
     }
 
     pub fn write_trap_context<T>(&mut self, f: impl FnOnce(&mut TrapContext) -> T) -> T {
-        let ptr = self
+        let ctx = self
             .trap_context
+            .as_mut()
             .expect("PCBInner::modify_trap_context: uninitialised trap context");
-        let ctx_ref_mut = unsafe {
-            // safety: it is never exposed, and initialised to a valid place
-            core::mem::transmute(ptr)
-        };
-        f(ctx_ref_mut)
+        f(ctx.as_mut())
     }
 
     pub fn write_user_space<T>(&mut self, f: impl FnOnce(&mut AddrSpace) -> T) -> T {
@@ -92,24 +164,79 @@ impl PCBInner {
         }
     }
 
+    /// `None` if the trap context is not initialised
+    pub fn get_context_ref(&self) -> Option<&TrapContext> {
+        self.trap_context.as_ref().map(TrapFrameRef::as_ref)
+    }
+
     pub fn get_context_ref_or_else_panic(&self) -> &TrapContext {
-        let ptr = self
-            .trap_context
-            .expect("PCBInner::modify_trap_context: uninitialised trap context");
-        unsafe {
-            // safety: it is never exposed, and initialised to a valid place
-            core::mem::transmute(ptr)
-        }
+        self.get_context_ref()
+            .expect("PCBInner::modify_trap_context: uninitialised trap context")
+    }
+
+    /// install `file` at the lowest free file descriptor, `None` if there is none left
+    pub fn alloc_fd(&mut self, file: Arc<FileHandle>) -> Option<usize> {
+        let fd = self.open_files.iter().position(|slot| slot.is_none())?;
+        self.open_files[fd] = Some(file);
+        Some(fd)
+    }
+
+    /// the file opened at `fd`, `None` if `fd` is invalid
+    pub fn get_file(&self, fd: usize) -> Option<Arc<FileHandle>> {
+        self.open_files.get(fd)?.clone()
+    }
+
+    /// share the file opened at `fd` with the lowest free file descriptor.
+    /// `None` if `fd` is invalid or there is no free file descriptor
+    pub fn dup_fd(&mut self, fd: usize) -> Option<usize> {
+        let file = self.get_file(fd)?;
+        self.alloc_fd(file)
+    }
+
+    /// The file that was opened at `fd`, `None` if `fd` is invalid.
+    /// NOTE: drop it only once the PCB is unlocked, dropping the last handle of a pipe
+    /// end wakes up the other end, which locks `PROCESS_MANAGER`, see `process::kill`
+    #[must_use]
+    pub fn close_fd(&mut self, fd: usize) -> Option<Arc<FileHandle>> {
+        self.open_files.get_mut(fd)?.take()
+    }
+
+    /// Close every open file, e.g. on `exit`.
+    /// Like `close_fd`, drop them once the PCB is unlocked
+    #[must_use]
+    pub fn close_all_fds(&mut self) -> Vec<Arc<FileHandle>> {
+        self.open_files
+            .iter_mut()
+            .filter_map(|slot| slot.take())
+            .collect()
+    }
+
+    /// file descriptors left to `alloc_fd`
+    pub fn n_free_fds(&self) -> usize {
+        self.open_files.iter().filter(|slot| slot.is_none()).count()
+    }
+
+    /// Drop the user address space together with the trapframe in it.
+    /// The process must never return to the user space afterwards
+    pub fn free_user_space(&mut self) {
+        self.trap_context = None;
+        self.user_addr_space = None;
     }
 
     /// Don't forget to call it!!!!
-    /// It allocates page for the trapframe and set its content
-    pub fn first_execution_init(&mut self, kernel_stack_pa: PhysAddr) {
+    /// It allocates page for the trapframe and set its content.
+    /// `None` if there is no frame for the trapframe
+    pub fn first_execution_init(&mut self, kernel_stack_pa: PhysAddr) -> Option<()> {
         // allocate the trapframe as a whole page
 
         // we now allocate the trapframe here
-        let trapframe_pa = self.write_user_space(|space| space.init_trapframe());
+        let trapframe_pa = self.write_user_space(|space| space.init_trapframe())?;
+        self.init_trap_context(trapframe_pa, kernel_stack_pa);
+        Some(())
+    }
 
+    /// set the content of the trapframe at `trapframe_pa`, mapped in the user space
+    fn init_trap_context(&mut self, trapframe_pa: PhysAddr, kernel_stack_pa: PhysAddr) {
         self.initialise_trap_context(|| {
             // Safety: since it is guarenteed to be allocated by the frame allocator
             //  and managed by the user space. It should be a valid physical address.
@@ -150,39 +277,168 @@ impl PCBInner {
         });
         // we do not set `tp` because we do not know on which core it will be scheduled
     }
+
+    /// Move the program break to `new_break`, the user heap grows or shrinks to match.
+    /// `None` if it would go below the initial break or into the user stack,
+    /// see `AddrSpace::resize_user_heap`
+    pub fn set_program_break(&mut self, new_break: VirtAddr) -> Option<()> {
+        let old_break = self.program_break;
+        self.write_user_space(|space| space.resize_user_heap(old_break, new_break))?;
+        self.program_break = new_break;
+        Some(())
+    }
+
+    /// Charge the whole `SCHEDULER_INTERVAL` before a timer tick to the side it interrupted,
+    /// the `sstatus.SPP` of the trap, see `trap::soft_interrupt`
+    pub fn sample_tick(&mut self, spp: SPP) {
+        match spp {
+            SPP::User => self.user_ticks += SCHEDULER_INTERVAL as u64,
+            SPP::Supervisor => self.kernel_ticks += SCHEDULER_INTERVAL as u64,
+        }
+    }
+
+    /// bytes between the initial and the current program break, 0 without a user address space
+    pub fn heap_size(&self) -> usize {
+        self.user_addr_space.as_ref().map_or(0, |space| {
+            self.program_break.as_usize() - space.initial_program_break().as_usize()
+        })
+    }
+
+    /// Replace the user address space for `exec` and re-initialise the trap context
+    /// to start executing at `entry` with a fresh user stack.
+    /// The old address space (including its trapframe) is dropped and its frames recycled.
+    /// `None` if there is no frame for the new trapframe, the old address space is kept then
+    pub fn replace_user_space(
+        &mut self,
+        mut user_space: AddrSpace,
+        entry: VirtAddr,
+        user_stack_va: VirtAddr,
+        kernel_stack_pa: PhysAddr,
+    ) -> Option<()> {
+        // before the old one is given up
+        let trapframe_pa = user_space.init_trapframe()?;
+        self.program_break = user_space.initial_program_break();
+        let old_user_space = self.user_addr_space.replace(user_space);
+        // it points to the trapframe of the old address space
+        self.trap_context = None;
+        self.init_trap_context(trapframe_pa, kernel_stack_pa);
+        self.write_trap_context(|ctx| {
+            ctx.set_user_space_pc(entry);
+            ctx.set_user_stack(user_stack_va);
+        });
+        drop(old_user_space);
+        Some(())
+    }
 }
 
 impl ProcessControlBlock {
     pub fn allocate(pid: usize) -> Self {
+        Self::try_allocate(pid).expect("ProcessControlBlock::allocate: out of frames")
+    }
+
+    /// `None` if there is no frame left for its kernel stack or its body
+    pub fn try_allocate(pid: usize) -> Option<Self> {
         let zelf = Self {
             pid,
-            kernel_stack: KernelStackGuard::allocate(),
-            inner: RwLock::new(PCBInner {
-                trap_context: None,
-                user_addr_space: None,
-                status: ProcStatus::RUNNABLE,
-            }),
+            kernel_stack: KernelStackGuard::try_allocate()?,
+            inner: SlabBox::try_new(
+                &PCB_INNER_CACHE,
+                RwLock::new(PCBInner {
+                    trap_context: None,
+                    user_addr_space: None,
+                    cwd: inode::root(),
+                    // stdin, stdout and stderr
+                    open_files: core::array::from_fn(|fd| (fd < 3).then(FileHandle::console)),
+                    program_break: VirtAddr::new(0),
+                    wakeup_tick: 0,
+                    sleep_chan: None,
+                    leaving_hart: false,
+                    parent: None,
+                    children: Vec::new(),
+                    exit_code: 0,
+                    killed: false,
+                    priority: DEFAULT_PRIORITY,
+                    age: 0,
+                    user_ticks: 0,
+                    kernel_ticks: 0,
+                    status: ProcStatus::RUNNABLE,
+                }),
+            )?,
         };
         debug!(
             "ProcessControlBlock::allocate: PCB for PID {:?} allocated",
             zelf.pid
         );
 
-        zelf
+        Some(zelf)
     }
 
     pub fn get_pid(&self) -> usize {
         self.pid
     }
 
+    /// the channel its parent sleeps on in `wait`, woken up when a child exits
+    pub fn wait_channel(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn is_zombie(&self) -> bool {
+        matches!(self.inner.read().status, ProcStatus::ZOMBIE)
+    }
+
     pub fn get_kernel_stack_phys_addr(&self) -> PhysAddr {
         self.kernel_stack.frame().get_base_phys_addr()
     }
 
-    pub fn first_execution_init(&mut self) {
+    /// Initialise a newly allocated PCB as a copy of `parent` for `fork`.
+    /// Its address space is cloned copy-on-write and it gets its own trapframe
+    /// with a copy of the parent's trap context, but runs on its own kernel stack.
+    /// The open files are shared with the parent.
+    /// The child sees 0 as the return value of `fork`.
+    /// `None` if there are no frames left for the child's page table or trapframe,
+    /// the child must then be discarded without ever running
+    pub fn fork_from(&self, parent: &ProcessControlBlock) -> Option<()> {
+        let mut parent_inner = parent.inner.write();
+        let mut inner = self.inner.write();
+
+        inner.user_addr_space = Some(parent_inner.write_user_space(|space| space.clone_cow())?);
+        let trapframe_pa = inner.write_user_space(|space| space.init_trapframe())?;
+        // writing the child's trap context below must not clobber the parent's
+        assert_ne!(
+            parent_inner
+                .get_user_space_ref_or_else_panic()
+                .trapframe_phys_addr(),
+            Some(trapframe_pa),
+            "ProcessControlBlock::fork_from: PID {:?} shares the trapframe of PID {:?}",
+            self.pid,
+            parent.pid
+        );
+        inner.initialise_trap_context(|| trapframe_pa);
+
+        inner.open_files = parent_inner.open_files.clone();
+        inner.cwd = parent_inner.cwd.clone();
+        inner.program_break = parent_inner.program_break;
+        inner.priority = parent_inner.priority;
+
+        let parent_ctx = parent_inner.get_context_ref_or_else_panic().clone();
+        let kernel_stack_pa = self.get_kernel_stack_phys_addr();
+        inner.write_trap_context(|ctx| {
+            *ctx = parent_ctx;
+            ctx.set_kernel_stack(kernel_stack_pa);
+            ctx.set_return_value(0);
+        });
+        debug!(
+            "ProcessControlBlock::fork_from: PID {:?} forked from PID {:?}",
+            self.pid, parent.pid
+        );
+        Some(())
+    }
+
+    /// see `PCBInner::first_execution_init`
+    pub fn first_execution_init(&mut self) -> Option<()> {
         self.inner
             .write()
-            .first_execution_init(self.get_kernel_stack_phys_addr());
+            .first_execution_init(self.get_kernel_stack_phys_addr())
     }
 }
 
@@ -195,42 +451,50 @@ impl Drop for ProcessControlBlock {
     }
 }
 
-// Kernel stack for a process
+/// frames of a kernel stack, the lowest one is its guard page
+pub const KERNEL_STACK_FRAMES: usize = KERNEL_STACK_SIZE / PAGE_SIZE + 1;
+
+// Kernel stack for a process.
+// `KERNEL_STACK_SIZE` bytes of contiguous frames above a guard frame, which is unmapped
+// from the kernel space so that overflowing the stack faults instead of corrupting the
+// frame below, see `__kernelvec`
 #[derive(Debug)]
 pub struct KernelStackGuard {
-    inner: FrameGuard,
+    inner: FrameRangeGuard,
 }
 
 impl KernelStackGuard {
-    pub fn allocate() -> Self {
+    pub fn try_allocate() -> Option<Self> {
         let zelf = Self {
-            inner: FrameGuard::allocate_zeroed(),
+            inner: FrameRangeGuard::allocate_zeroed(KERNEL_STACK_FRAMES)?,
         };
-        let pa = zelf.inner.get_frame().get_base_phys_addr().as_usize();
+        mm::set_kernel_guard_page(zelf.guard_va(), true)
+            .expect("KernelStackGuard::allocate: guard page not mapped");
         debug!(
             "KernelStackGuard::allocate: kernel stack at pa {:?} allocated",
-            pa as *const usize
+            zelf.frame().get_base_phys_addr().as_usize() as *const usize
         );
-        zelf
+        Some(zelf)
     }
 
-    pub fn from_frame(frame: Frame) -> Self {
-        Self {
-            inner: FrameGuard::from_frame(frame),
-        }
+    /// the lowest frame of the stack, right above the guard page
+    pub fn frame(&self) -> Frame {
+        Frame::from_phys_addr(self.inner.get_base_phys_addr().with_offset(PAGE_SIZE))
     }
 
-    pub fn frame(&self) -> Frame {
-        self.inner.get_frame()
+    pub fn guard_va(&self) -> VirtAddr {
+        VirtAddr::from_identical(self.inner.get_base_phys_addr())
     }
 }
 
 impl Drop for KernelStackGuard {
     fn drop(&mut self) {
-        let pa = self.inner.get_frame().get_base_phys_addr().as_usize();
+        // accessible again before the frame is recycled
+        mm::set_kernel_guard_page(self.guard_va(), false)
+            .expect("KernelStackGuard::drop: guard page mapped");
         debug!(
             "KernelStackGuard::drop: kernel stack at pa {:?} deallocated",
-            pa as *const usize
+            self.frame().get_base_phys_addr().as_usize() as *const usize
         );
     }
 }
@@ -240,10 +504,14 @@ pub fn make_initcode_uninitialised(pid: usize) -> ProcessControlBlock {
     let pcb = ProcessControlBlock::allocate(pid);
     let mut inner = pcb.inner.write();
 
-    inner.user_addr_space = Some(AddrSpace::make_init());
+    let user_space = AddrSpace::make_init();
+    inner.program_break = user_space.initial_program_break();
+    inner.user_addr_space = Some(user_space);
 
     // set its context
-    inner.first_execution_init(pcb.get_kernel_stack_phys_addr());
+    inner
+        .first_execution_init(pcb.get_kernel_stack_phys_addr())
+        .expect("process::make_initcode_uninitialised: no frame for the trapframe");
     // specifically drop inner, otherwise the compiler will assume we may
     // mutabily change its content in the destructor after it's been moved to Arc::new(pcb)
     drop(inner);
@@ -263,3 +531,111 @@ pub fn init_code_bytes() -> &'static [u8] {
     );
     data
 }
+
+/// `brk` should move the break both ways, but not below where it starts
+pub fn test_set_program_break() {
+    with_test_process(|pcb| {
+        let mut inner = pcb.inner.write();
+        let va = VirtAddr::new(TEXT_BASE_USER_VA);
+        let kernel_stack_pa = pcb.get_kernel_stack_phys_addr();
+        inner
+            .replace_user_space(AddrSpace::make_init(), va, va, kernel_stack_pa)
+            .unwrap();
+        let initial = inner.program_break;
+
+        let up = initial + 3 * PAGE_SIZE + 8;
+        inner.set_program_break(up).unwrap();
+        assert_eq!(inner.program_break, up);
+        let (_, flags) = inner
+            .get_user_space_ref_or_else_panic()
+            .translate(initial + 3 * PAGE_SIZE)
+            .unwrap();
+        assert!(flags.contains(PageFlags::USER | PageFlags::WRITABLE));
+
+        let down = initial + PAGE_SIZE;
+        inner.set_program_break(down).unwrap();
+        assert_eq!(inner.program_break, down);
+        assert!(inner
+            .get_user_space_ref_or_else_panic()
+            .translate(initial + 2 * PAGE_SIZE)
+            .is_none());
+
+        let below = VirtAddr::new(initial.as_usize() - PAGE_SIZE);
+        assert!(inner.set_program_break(below).is_none());
+        assert_eq!(inner.program_break, down);
+        inner.set_program_break(initial).unwrap();
+    });
+}
+
+/// the page below a kernel stack should be unmapped from the kernel space while it is in use
+pub fn test_kernel_stack_guard_page() {
+    let mapped = |va: VirtAddr| {
+        KERNEL_ADDRESS_SPACE
+            .read()
+            .translate(va)
+            .is_some_and(|(_, flags)| !flags.is_empty())
+    };
+    let stack = KernelStackGuard::try_allocate().unwrap();
+    let guard_va = stack.guard_va();
+    let stack_va = VirtAddr::from_identical(stack.frame().get_base_phys_addr());
+    assert_eq!(stack_va, guard_va + PAGE_SIZE);
+    assert!(!mapped(guard_va));
+    for i in 0..KERNEL_STACK_SIZE / PAGE_SIZE {
+        assert!(mapped(stack_va + i * PAGE_SIZE));
+    }
+    drop(stack);
+    assert!(mapped(guard_va));
+}
+
+/// ticks landing in the user space and in the kernel are charged to their own side
+pub fn test_cpu_time_accounting() {
+    with_test_process(|pcb| {
+        let mut inner = pcb.inner.write();
+        let interval = SCHEDULER_INTERVAL as u64;
+
+        inner.sample_tick(SPP::User);
+        assert_eq!((inner.user_ticks, inner.kernel_ticks), (interval, 0));
+
+        for _ in 0..10 {
+            inner.sample_tick(SPP::Supervisor);
+        }
+        inner.sample_tick(SPP::User);
+        assert_eq!(
+            (inner.user_ticks, inner.kernel_ticks),
+            (2 * interval, 10 * interval)
+        );
+
+        let times = Times::of(&inner);
+        assert_eq!((times.utime, times.stime), (2 * interval, 10 * interval));
+    });
+}
+
+/// Recursing without end on a kernel stack should panic with a kernel stack overflow.
+/// It does not return, so it is only built with the `stack_overflow_test` feature
+#[cfg(feature = "stack_overflow_test")]
+pub fn test_kernel_stack_overflow_panics() -> ! {
+    fn recurse(depth: usize) -> usize {
+        let frame = core::hint::black_box([depth; 16]);
+        if depth == usize::MAX {
+            return 0;
+        }
+        recurse(depth + 1) + frame[0]
+    }
+    extern "C" fn run() -> ! {
+        recurse(0);
+        unreachable!("process::test_kernel_stack_overflow_panics: returned");
+    }
+
+    let stack = KernelStackGuard::try_allocate().unwrap();
+    let top = stack.frame().get_base_phys_addr().as_usize() + KERNEL_STACK_SIZE;
+    info!("process::test_kernel_stack_overflow_panics: expecting a panic");
+    unsafe {
+        core::arch::asm!(
+            "mv sp, {top}",
+            "call {run}",
+            top = in(reg) top,
+            run = sym run,
+            options(noreturn)
+        )
+    }
+}
@@ -0,0 +1,54 @@
+//! User programmes compiled into the kernel binary.
+//! `exec` looks them up by name until we have a file system to load them from
+
+/// name -> ELF binary.
+/// Note that the user crate should be built before the kernel, see `Makefile`
+const PROGRAMS: &[(&str, &[u8])] = &[
+    (
+        "/init",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/init"),
+    ),
+    (
+        "/test1",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/test1"),
+    ),
+    (
+        "/test2",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/test2"),
+    ),
+    (
+        "/test3",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/test3"),
+    ),
+    (
+        "/sleeptest",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/sleeptest"),
+    ),
+    (
+        "/timetest",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/timetest"),
+    ),
+    (
+        "/ppidtest",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/ppidtest"),
+    ),
+    (
+        "/yieldtest",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/yieldtest"),
+    ),
+    (
+        "/argtest",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/argtest"),
+    ),
+    (
+        "/stackwritetest",
+        include_bytes!("../../../target/riscv64gc-unknown-none-elf/debug/stackwritetest"),
+    ),
+];
+
+pub fn lookup(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS
+        .iter()
+        .find(|(program_name, _)| *program_name == name)
+        .map(|(_, bytes)| *bytes)
+}
@@ -0,0 +1,23 @@
+//! CPU time returned by `times`, shared with `user/src/times.rs`
+
+use super::process::PCBInner;
+
+/// `#[repr(C)]` without padding, user programs define the same layout.
+/// In `arch::ticks`, `CLINT_FREQ_HZ` per second, sampled a `SCHEDULER_INTERVAL` at a time
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Times {
+    /// spent in the user space
+    pub utime: u64,
+    /// spent in the kernel on its behalf
+    pub stime: u64,
+}
+
+impl Times {
+    pub fn of(inner: &PCBInner) -> Self {
+        Self {
+            utime: inner.user_ticks,
+            stime: inner.kernel_ticks,
+        }
+    }
+}
@@ -1,10 +1,16 @@
-use crate::allocator::heap_allocator;
-use crate::arch::{hart_id, intr_off, intr_on};
-use crate::mm::KERNEL_ADDRESS_SPACE;
-use crate::trap::usertrapret;
+use crate::allocator::{frame_allocator, heap_allocator};
+use crate::arch::{hart_id, intr_on, pop_off, push_off};
+use crate::mm::{
+    layout::{__heap_end, __heap_start, PHYS_TOP},
+    memory::{VirtAddr, VirtFrame, VirtFrameRange},
+    KERNEL_ADDRESS_SPACE,
+};
 use crate::uart;
-use crate::{clint, info, mm, plic, process, trap};
-use core::arch::asm;
+use crate::{clint, fs, info, mm, plic, process, trap};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use riscv::register::*;
 
 // external
@@ -14,6 +20,13 @@ const SIE_STIE: usize = 1 << 5;
 // software
 const SIE_SSIE: usize = 1 << 1;
 
+// the configuration byte of a PMP entry
+const PMP_R: usize = 1 << 0;
+const PMP_W: usize = 1 << 1;
+const PMP_X: usize = 1 << 2;
+// address matching: top of range, from the address of the previous entry
+const PMP_A_TOR: usize = 1 << 3;
+
 #[no_mangle]
 unsafe extern "C" fn kstart() {
     // we are now in machine mode
@@ -39,7 +52,18 @@ unsafe extern "C" fn kstart() {
     asm!("csrw sie, {}", in(reg) sie | SIE_SEIE | SIE_STIE | SIE_SSIE);
 
     // physical memory protection: give S-mode access to all the physical memory
-    // TODO
+    // with a TOR entry, `pmpaddr0` holds the top address (exclusive) shifted by 2
+    // and entry 0 starts from 0, covering the devices below the kernel image
+    let pmpaddr = PHYS_TOP >> 2;
+    let pmpcfg = PMP_A_TOR | PMP_R | PMP_W | PMP_X;
+    asm!("csrw pmpaddr0, {}", in(reg) pmpaddr);
+    asm!("csrw pmpcfg0, {}", in(reg) pmpcfg);
+    // the fields are WARL, make sure they have been taken
+    let (pmpaddr_read, pmpcfg_read): (usize, usize);
+    asm!("csrr {}, pmpaddr0", out(reg) pmpaddr_read);
+    asm!("csrr {}, pmpcfg0", out(reg) pmpcfg_read);
+    debug_assert_eq!(pmpaddr_read, pmpaddr);
+    debug_assert_eq!(pmpcfg_read & 0xff, pmpcfg);
 
     // save cpuid to tp register
     asm!("csrr a1, mhartid");
@@ -54,7 +78,8 @@ unsafe extern "C" fn kstart() {
 
 /// Controls weather other harts may start boot procedure
 /// (They should wait for hear-0 to finish initialising)
-static mut HART0_STARTED: bool = false;
+/// NOTE: atomic, or the waiting loop may never see it change
+static HART0_STARTED: AtomicBool = AtomicBool::new(false);
 
 #[no_mangle]
 extern "C" fn kmain() {
@@ -76,16 +101,21 @@ extern "C" fn kmain() {
         plic::hart_init(); // ask for PLIC for device interrupts
         info!("PLIC initialised");
 
+        fs::init(); // the disk and the file system on it
+
+        // the self-tests do not return, see `testing.rs`
+        #[cfg(test)]
+        crate::test_main();
+
         process::init();
         heap_allocator::print_kernel_heap_status();
+        frame_allocator::print_frame_status();
 
-        unsafe {
-            HART0_STARTED = true;
-        }
+        HART0_STARTED.store(true, Ordering::Release);
     } else {
         // wait until hart-0 finishes
         loop {
-            if unsafe { HART0_STARTED } {
+            if HART0_STARTED.load(Ordering::Acquire) {
                 break;
             }
         }
@@ -98,9 +128,16 @@ extern "C" fn kmain() {
     }
 
     // debug: we lock the kernel page table in case of corruption
-    intr_off();
+    push_off();
     KERNEL_ADDRESS_SPACE.write().lock_space();
+    pop_off();
+    // harts locked before may still cache writable entries of its node frames in the heap
+    mm::tlb_shootdown(VirtFrameRange::new(
+        VirtFrame::from_virt_addr(VirtAddr::new(__heap_start())),
+        VirtFrame::from_virt_addr(VirtAddr::new(__heap_end())),
+    ));
+    // the first `schedule` takes interrupts
     intr_on();
 
-    usertrapret();
+    process::idle();
 }
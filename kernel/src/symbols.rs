@@ -3,7 +3,7 @@ extern "C" {
     /// `__uservec` in `trampoline.S`
     pub fn __uservec();
     /// `__userret` in `trampoline.S`
-    pub fn __userret(satp: usize);
+    pub fn __userret(satp: usize, flush: usize);
     /// `__kernelvec` in `kernelvec.S`
     pub fn __kernelvec();
     /// `__timervec` in `kernelvec.S`
@@ -19,3 +19,23 @@ pub const N_PROCS: usize = 128;
 
 /// Scheduler timer interrupt interval
 pub const SCHEDULER_INTERVAL: usize = 1_000_000;
+
+/// Log messages go to a buffer of each hart drained on timer ticks, instead of waiting
+/// for the UART to transmit them, see `print::PrintBuffer`
+pub const PRINT_BUFFERED: bool = true;
+
+/// Logging levels, a message is printed if its level is at most `LOG_LEVEL`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// `debug!` is left out of release builds
+pub const LOG_LEVEL: LogLevel = if cfg!(debug_assertions) {
+    LogLevel::Debug
+} else {
+    LogLevel::Info
+};
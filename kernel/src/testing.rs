@@ -0,0 +1,137 @@
+//! Boot-time self-tests, built by `make test` as a test harness of this crate.
+//! `kmain` hands hart 0 over to the harness once the kernel is initialised,
+//! it runs every `#[test_case]` and powers off qemu with the result.
+//! A failing test panics, the panic handler then reports the failure, see `lib.rs`
+
+use crate::{allocator, arch, common, cpu, fs, mm, print, println, process, trap, uart};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QemuExitCode {
+    Success,
+    /// qemu exits with 1
+    Failed,
+}
+
+/// Power off qemu, it exits with 0 on `Success`, see `arch::shutdown`
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    match code {
+        QemuExitCode::Success => arch::shutdown(0),
+        QemuExitCode::Failed => arch::shutdown(1),
+    }
+}
+
+/// a registered test, see `test_cases!`
+pub struct TestCase {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+/// the `#![test_runner]` of the crate, a failing test does not return
+pub fn test_runner(tests: &[&TestCase]) {
+    println!("running {} tests", tests.len());
+    for test in tests {
+        println!("test {} ...", test.name);
+        (test.run)();
+        // its log comes before the verdict
+        print::flush();
+        println!("test {} ok", test.name);
+    }
+    println!("test result: ok. {} passed", tests.len());
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Register the free `test_*` functions as `#[test_case]`s.
+/// They stay where they are, and callable by hand in normal builds
+macro_rules! test_cases {
+    ($($case:ident => $test:path),* $(,)?) => {
+        $(
+            #[test_case]
+            static $case: TestCase = TestCase {
+                name: stringify!($test),
+                run: $test,
+            };
+        )*
+    };
+}
+
+// Tests needing a process take one from `process::manager::with_test_process`.
+// The ones panicking on purpose are left out, they are built with their own features
+test_cases! {
+    ARITHMETICS => mm::arithmetics::arithmetics_done_right,
+    SIMPLE_RANGE_OVERLAPS => mm::arithmetics::test_simple_range_overlaps,
+    PAGE_TABLE_ENTRY => mm::page_table::test,
+    PAGE_TABLE_TRANSLATE_OFFSET => mm::page_table::test_translate_offset,
+    PAGE_TABLE_FLAGS_ROUND_TRIP => mm::page_table::test_flags_round_trip,
+    PAGE_TABLE_MAP_MEGAPAGE => mm::page_table::test_map_megapage,
+    PAGE_TABLE_ENTRY_INDEX_BOUNDS => mm::page_table::test_entry_index_bounds,
+    FRAME_COPY_BOUNDS => mm::memory::test_frame_copy_bounds,
+    ASID_RECYCLING => mm::asid::test_asid_recycling,
+    ELF_REJECT_MALFORMED => process::elf::test_reject_malformed,
+    ALLOCATE_MANY_FRAMES => allocator::frame_allocator::test_allocate_many_frames,
+    ALLOCATE_CONTIGUOUS_AMID_SCATTERED =>
+        allocator::frame_allocator::test_allocate_contiguous_amid_scattered,
+    SHARED_FRAME_FREED_ONCE => allocator::frame_allocator::test_shared_frame_freed_once,
+    ZERO_ON_FREE => allocator::frame_allocator::test_zero_on_free,
+    SLAB_CACHE_STRESS => allocator::slab::test_slab_cache_stress,
+    MEMINFO_COUNTS_FRAMES => allocator::meminfo::test_meminfo_counts_frames,
+    DIFF_COW_FORK => mm::address_space::test_diff_cow_fork,
+    KERNEL_STACK_GUARD_PAGE => process::process::test_kernel_stack_guard_page,
+    PROTECT => mm::address_space::test_protect,
+    RELATIVE_LOOKUP => fs::inode::test_relative_lookup,
+    DUP_SHARES_OFFSET => fs::file::test_dup_shares_offset,
+    WORK_STEALING => cpu::test_work_stealing,
+    CURRENT_PROCESS_LOCK_FREE => cpu::test_current_process_lock_free,
+    DROP_RECYCLES_FRAMES => mm::address_space::test_drop_recycles_frames,
+    RESIZE_USER_HEAP => mm::address_space::test_resize_user_heap,
+    COPY_USER_STRADDLING_PAGES => mm::address_space::test_copy_user_straddling_pages,
+    UNMAP_AREA => mm::address_space::test_unmap_area,
+    MAP_ANONYMOUS => mm::address_space::test_map_anonymous,
+    VALIDATE_USER_PTR => mm::address_space::test_validate_user_ptr,
+    STAT_REPORTS_SIZE => fs::file::test_stat_reports_size,
+    DEVICE_FILE => fs::file::test_device_file,
+    MKDIR_NESTED => fs::inode::test_mkdir_nested,
+    LINK_UNLINK => fs::inode::test_link_unlink,
+    CONSOLE_LINE_EDITING => uart::test_console_line_editing,
+    UART_DIVISOR => uart::test_uart_divisor,
+    RESOURCE_TABLE_GET_TAKES_READ_LOCK => common::resource_table::test_get_takes_read_lock,
+    RESOURCE_TABLE_MAX_CAPACITY => common::resource_table::test_reserve_up_to_max_capacity,
+    LIST_PROCESSES => process::manager::test_list_processes,
+    WAKE_CHANNEL => process::manager::test_wake_channel,
+    EXIT_AND_REAP => process::manager::test_exit_and_reap,
+    SET_PROGRAM_BREAK => process::process::test_set_program_break,
+    ABI_REGISTER_MAPPING => process::context::test_abi_register_mapping,
+    DELAY_US => arch::test_delay_us,
+    PUSH_OFF_NESTING => arch::test_push_off_nesting,
+    PROCFS_READ_STATUS => fs::procfs::test_read_status,
+    OUT_OF_FRAMES => mm::address_space::test_out_of_frames,
+    SIMPLE_RANGE_DOUBLE_ENDED => mm::arithmetics::test_simple_range_double_ended,
+    CPU_TIME_ACCOUNTING => process::process::test_cpu_time_accounting,
+    PATH_OF => fs::inode::test_path_of,
+    WATCHDOG_FIND_STUCK => trap::watchdog::test_find_stuck,
+    TRANSLATE_CHECKED => mm::address_space::test_translate_checked,
+    PRINT_BUFFER => print::test_print_buffer,
+    TRY_MAP_VIRT_AREA => mm::address_space::test_try_map_virt_area,
+    RESOURCE_TABLE_ROLLBACK => common::resource_table::test_rollback_reserved_entry,
+    TRAP_FRAME_REF => process::context::test_trap_frame_ref,
+    DISPATCH_EXCEPTION => trap::exception::test_dispatch_exception,
+    ELF_SEGMENT_FLAGS => process::elf::test_segment_flags,
+    FRAGMENTATION_RATIO => allocator::frame_allocator::test_fragmentation_ratio,
+    TLB_MAILBOX => mm::tlb::test_mailbox,
+    PRIORITY_SCHEDULING => cpu::test_priority_scheduling,
+    PHYS_ADDR_IS_KNOWN => mm::memory::test_phys_addr_is_known,
+    MAP_DEVICE => mm::address_space::test_map_device,
+    HEAP_POISON => allocator::heap_allocator::test_heap_poison,
+    SLEEP_DEADLINE => trap::test_sleep_deadline,
+    LEAK_AND_READOPT_FRAME => mm::memory::test_leak_and_readopt_frame,
+    PUSH_ARGS => mm::address_space::test_push_args,
+    SUPERBLOCK_LAYOUT => fs::filesystem::test_superblock_layout,
+    CREATE_ROLLS_BACK => fs::inode::test_create_rolls_back,
+    PAGE_TABLE_SPLIT_MEGAPAGE => mm::page_table::test_split_megapage,
+    POP_READY_PER_HART => cpu::test_pop_ready_per_hart,
+    FLUSH_ON_MIGRATION => mm::address_space::test_flush_on_migration,
+    WAKE_LEAVING_HART => process::manager::test_wake_leaving_hart,
+    FAULTS_OUT_OF_FRAMES => mm::address_space::test_faults_out_of_frames,
+    GROW_USER_STACK_COLLISION => mm::address_space::test_grow_user_stack_collision,
+    COPY_USER_GROWS_STACK => mm::address_space::test_copy_user_grows_stack,
+    MAP_ANONYMOUS_OUT_OF_FRAMES => mm::address_space::test_map_anonymous_out_of_frames,
+}
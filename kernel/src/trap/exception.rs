@@ -0,0 +1,118 @@
+//! What to do with each exception, looked up in a table rather than matched in
+//! `usertrap` and `kerneltrap`: handling another one is one more entry.
+//! Interrupts are dispatched on their own, see `trap/mod.rs`
+
+use riscv::register::{
+    scause::{Exception, Interrupt, Trap},
+    stval,
+};
+
+use crate::{cpu, error};
+
+use super::{
+    page_fault::{InstructionPageFaultHandler, LoadPageFaultHandler, StorePageFaultHandler},
+    syscall::SystemCallHandler,
+};
+
+pub type ExceptionHandler = fn();
+
+/// the bit of `scause` telling an interrupt from an exception
+const SCAUSE_INTERRUPT: usize = 1 << (usize::BITS - 1);
+
+/// exceptions taken from the user space
+pub static USER_EXCEPTIONS: &[(Exception, ExceptionHandler)] = &[
+    (Exception::UserEnvCall, SystemCallHandler::handle),
+    (
+        Exception::InstructionPageFault,
+        InstructionPageFaultHandler::handle,
+    ),
+    (Exception::LoadPageFault, LoadPageFaultHandler::handle),
+    (Exception::StorePageFault, StorePageFaultHandler::handle),
+    (Exception::IllegalInstruction, illegal_instruction),
+];
+
+/// exceptions taken in the kernel, none of them can be recovered from yet
+pub static KERNEL_EXCEPTIONS: &[(Exception, ExceptionHandler)] = &[];
+
+/// What the raw bits of `scause` report, like `Scause::cause`,
+/// e.g. for a value saved earlier by `watchdog::record_trap`
+pub fn decode_scause(bits: usize) -> Trap {
+    let code = bits & !SCAUSE_INTERRUPT;
+    if bits & SCAUSE_INTERRUPT != 0 {
+        Trap::Interrupt(Interrupt::from(code))
+    } else {
+        Trap::Exception(Exception::from(code))
+    }
+}
+
+pub fn handler_of(
+    table: &[(Exception, ExceptionHandler)],
+    exception: Exception,
+) -> Option<ExceptionHandler> {
+    table
+        .iter()
+        .find(|(registered, _)| *registered == exception)
+        .map(|&(_, handler)| handler)
+}
+
+/// run the handler of `exception` in `table`, `false` if it has none
+pub fn dispatch(table: &[(Exception, ExceptionHandler)], exception: Exception) -> bool {
+    match handler_of(table, exception) {
+        Some(handler) => {
+            handler();
+            true
+        }
+        None => false,
+    }
+}
+
+/// the user process is killed, it exits on its way back to the user space
+fn illegal_instruction() {
+    let pcb = cpu::current_process().unwrap();
+    error!(
+        "trap::usertrap: PID {:?} executed an illegal instruction {:#x}, killed",
+        pcb.get_pid(),
+        stval::read()
+    );
+    pcb.inner.write().killed = true;
+}
+
+/// a synthetic cause should reach its handler only, unregistered ones none
+pub fn test_dispatch_exception() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static LOADS: AtomicUsize = AtomicUsize::new(0);
+    static STORES: AtomicUsize = AtomicUsize::new(0);
+    static TABLE: &[(Exception, ExceptionHandler)] = &[
+        (Exception::LoadPageFault, || {
+            LOADS.fetch_add(1, Ordering::Relaxed);
+        }),
+        (Exception::StorePageFault, || {
+            STORES.fetch_add(1, Ordering::Relaxed);
+        }),
+    ];
+
+    let Trap::Exception(exception) = decode_scause(13) else {
+        panic!("exception::test_dispatch_exception: 13 is an exception");
+    };
+    assert_eq!(exception, Exception::LoadPageFault);
+    assert!(dispatch(TABLE, exception));
+    assert_eq!(LOADS.load(Ordering::Relaxed), 1);
+    assert_eq!(STORES.load(Ordering::Relaxed), 0);
+    assert!(!dispatch(TABLE, Exception::Breakpoint));
+    assert!(!dispatch(KERNEL_EXCEPTIONS, Exception::LoadPageFault));
+
+    assert_eq!(
+        decode_scause(SCAUSE_INTERRUPT | 1),
+        Trap::Interrupt(Interrupt::SupervisorSoft)
+    );
+    assert_eq!(decode_scause(14), Trap::Exception(Exception::Unknown));
+    for exception in [
+        Exception::UserEnvCall,
+        Exception::LoadPageFault,
+        Exception::StorePageFault,
+        Exception::IllegalInstruction,
+    ] {
+        assert!(handler_of(USER_EXCEPTIONS, exception).is_some());
+    }
+}
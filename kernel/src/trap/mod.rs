@@ -1,42 +1,132 @@
-// timer interrupt should be enabled in machine mode
-// hence not in this module, see `src/clint.rs` for its initialisation
+// Timer interrupts are taken in machine mode, see `src/clint.rs` for their initialisation.
+// `__timervec` re-arms the CLINT `mtimecmp` for the next tick and raises a S-mode
+// software interrupt, so S-mode observes every tick as `SupervisorSoft`, see `timer_tick`.
+// `SupervisorTimer` is not delegated to us and should never happen.
+// A kernel stack overflowing into its guard page does not get to `kerneltrap`,
+// `__kernelvec` sends it to `kernel_stack_overflow` on another stack.
+// Each tick is also a heartbeat of the hart, see `watchdog.rs`.
+// Other harts raise the same S-mode software interrupt for a TLB shootdown, see `mm/tlb.rs`
 
+pub mod exception;
 pub mod page_fault;
 pub mod syscall;
+pub mod watchdog;
+
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use riscv::register::{
     satp,
     scause::{self, Trap},
-    sscratch, sstatus, stval, stvec,
+    sepc, sip, sscratch, sstatus, stval, stvec,
 };
 
+use crate::mm::{layout::TRAPFRAME_BASE_USER_VA, memory::VirtAddr};
 use crate::{
-    arch, cpu, info,
-    mm::{layout::TRAMPOLINE_BASE_VA, KERNEL_ADDRESS_SPACE},
-    symbols::{__kernelvec, __userret, __uservec},
-};
-use crate::{
-    mm::{layout::TRAPFRAME_BASE_USER_VA, memory::VirtAddr},
-    trap::{page_fault::InstructionPageFaultHandler, syscall::SystemCallHandler},
+    arch,
+    clint::{self, CLINT_FREQ_HZ},
+    cpu, debug,
+    mm::{layout::TRAMPOLINE_BASE_VA, tlb, KERNEL_ADDRESS_SPACE},
+    plic::PLIC,
+    print,
+    process::{self, Handoff},
+    symbols::{__kernelvec, __userret, __uservec, SCHEDULER_INTERVAL},
 };
 
+/// The CLINT interrupts every `SCHEDULER_INTERVAL` of its `CLINT_FREQ_HZ` cycles,
+/// 10 ticks per second in qemu
+pub const TICKS_PER_SEC: u64 = CLINT_FREQ_HZ / SCHEDULER_INTERVAL as u64;
+
+/// Timer interrupts taken by hart 0 since boot.
+/// NOTE: 64 bits so that it does not wrap in practice, but arithmetic on it still
+/// should not assume so, see `ticks_elapsed_since` and `sleep_deadline`
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// ticks since `start`, even if the counter has wrapped around in between
+pub fn ticks_elapsed_since(start: u64) -> u64 {
+    elapsed_between(start, ticks())
+}
+
+fn elapsed_between(start: u64, now: u64) -> u64 {
+    now.wrapping_sub(start)
+}
+
+/// The tick to wake up at after sleeping `n_ticks` from `now`. It saturates rather
+/// than wrapping around, a wrapped deadline would be reached right away
+pub fn sleep_deadline(now: u64, n_ticks: u64) -> u64 {
+    now.saturating_add(n_ticks)
+}
+
+/// every hart takes timer interrupts, count those of one hart only
+fn clock_tick() {
+    if arch::hart_id() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A timer tick, or a shootdown from another hart, forwarded by `__timervec`.
+/// The pending bit has to be cleared, otherwise it traps again right after `sret`.
+/// Returns whether it is a timer tick
+fn soft_interrupt() -> bool {
+    unsafe { sip::clear_ssoft() };
+    tlb::handle_shootdown();
+    if !clint::take_tick() {
+        return false;
+    }
+    clock_tick();
+    // nobody is charged in the idle loop
+    if let Some(pcb) = cpu::current_process() {
+        pcb.inner.write().sample_tick(sstatus::read().spp());
+    }
+    watchdog::heartbeat(ticks());
+    process::age_ready(ticks());
+    print::try_flush();
+    true
+}
+
+/// `__timervec` handles the timer in machine mode, but tolerate a stray S-mode timer
+/// interrupt rather than crashing, e.g. when bringing up hardware delegating it
+fn stray_supervisor_timer() {
+    debug!(
+        "hart-{:?} trap: unexpected S-mode timer interrupt, ignored",
+        arch::hart_id()
+    );
+}
+
 // dispatchers
 
+/// `__kernelvec` found a store faulting right above the stack pointer,
+/// the kernel stack has overflowed into its guard page
+#[no_mangle]
+extern "C" fn kernel_stack_overflow(stval: usize, sp: usize) -> ! {
+    panic!(
+        "hart-{:?} kernel stack overflow: storing to {:?} with sp {:?}",
+        arch::hart_id(),
+        stval as *const usize,
+        sp as *const usize
+    );
+}
+
 #[no_mangle]
 pub fn kerneltrap() {
+    // `__kernelvec` borrows it, it holds the trapframe while in the kernel
+    sscratch::write(TRAPFRAME_BASE_USER_VA);
+    watchdog::record_trap(sepc::read(), scause::read().bits());
     let hartid = arch::hart_id();
     match scause::read().cause() {
         Trap::Interrupt(intr) => {
             match intr {
                 scause::Interrupt::SupervisorSoft => {
                     // info!("hart-{:?} kerneltrap: S-mode software", hartid);
+                    // we do not preempt the kernel
+                    soft_interrupt();
                 }
-                scause::Interrupt::SupervisorTimer => {
-                    info!("hart-{:?} kerneltrap: S-mode timer", hartid);
-                    panic!("We use CLINT to provide software interrupt for timer! What's this???")
-                }
+                scause::Interrupt::SupervisorTimer => stray_supervisor_timer(),
                 scause::Interrupt::SupervisorExternal => {
-                    info!("hart-{:?} kerneltrap: S-mode external", hartid);
+                    unsafe { &*PLIC.get() }.dispatch();
                 }
                 scause::Interrupt::Unknown => {
                     panic!("hart-{:?} kerneltrap: Unknown S-mode interrupt", hartid);
@@ -44,7 +134,13 @@ pub fn kerneltrap() {
             }
         }
         Trap::Exception(ex) => {
-            panic!("trap::kerneltrap: unexpected exception: {:?}", ex);
+            if !exception::dispatch(exception::KERNEL_EXCEPTIONS, ex) {
+                panic!(
+                    "trap::kerneltrap: unexpected exception: {:?}, stval: {:?}",
+                    ex,
+                    stval::read() as *const usize
+                );
+            }
         }
     }
 }
@@ -56,6 +152,7 @@ pub fn usertrap() {
         // change interrupt handler to the kernel trap handler
         stvec::write(__kernelvec as usize, stvec::TrapMode::Direct)
     };
+    watchdog::record_trap(sepc::read(), scause::read().bits());
 
     // let pcb = cpu::current_process().unwrap();
     // let pid = pcb.get_pid();
@@ -66,36 +163,29 @@ pub fn usertrap() {
     match scause::read().cause() {
         Trap::Interrupt(intr) => match intr {
             scause::Interrupt::SupervisorSoft => {
-                // TODO: schedule
-                info!("Supervisor Software Interrupt");
-                panic!();
+                // every `SCHEDULER_INTERVAL` cycles
+                if soft_interrupt() {
+                    process::preempt();
+                }
+            }
+            scause::Interrupt::SupervisorTimer => stray_supervisor_timer(),
+            scause::Interrupt::SupervisorExternal => {
+                unsafe { &*PLIC.get() }.dispatch();
             }
             _ => {
                 panic!("Unsupported exception: {:?}", intr);
             }
         },
 
-        Trap::Exception(ex) => match ex {
-            scause::Exception::UserEnvCall => {
-                SystemCallHandler::handle();
-            }
-            scause::Exception::InstructionPageFault => {
-                InstructionPageFaultHandler::handle();
-            }
-            scause::Exception::LoadPageFault => {
-                panic!(
-                    "trap::usertrap: Load Page Fault: trying to load {:?}",
-                    stval::read() as *const usize
-                );
-            }
-            _ => {
+        Trap::Exception(ex) => {
+            if !exception::dispatch(exception::USER_EXCEPTIONS, ex) {
                 panic!(
                     "Unsupported exception: {:?}, stval: {:?}",
                     ex,
                     stval::read() as *const usize
                 );
             }
-        },
+        }
     }
 
     usertrapret();
@@ -105,6 +195,23 @@ pub fn usertrap() {
 /// this call does not return and anything used before will not be dealllocated
 /// drop them manually or use a scope!!!
 pub fn usertrapret() -> ! {
+    // a killed process exits instead of returning to the user space
+    match cpu::current_process() {
+        // nothing has been selected for this hart yet
+        None => process::schedule(Handoff::Leave),
+        Some(pcb) if pcb.inner.read().killed => {
+            process::exit(&pcb, -1);
+            drop(pcb);
+            process::schedule(Handoff::Leave)
+        }
+        Some(_) => {}
+    }
+
+    // The kernel runs with interrupts off, a tick during this trap is still pending.
+    // Take it here on `__kernelvec` so it samples the supervisor, not the user space
+    arch::intr_on();
+    arch::intr_off();
+
     // We need to set stvec to uservec in the trampoline!
     unsafe {
         // Note that we use TRAMPOLINE_BASE_VA here to denote the universal mapping of
@@ -127,7 +234,7 @@ pub fn usertrapret() -> ! {
         sstatus::set_spie();
     }
 
-    let satp = {
+    let (satp, flush) = {
         // note that it's scoped to prevent holding on to resource
         let hartid = arch::hart_id();
         let pcb = cpu::current_process().expect("trap::userret: No runable process");
@@ -151,14 +258,18 @@ pub fn usertrapret() -> ! {
                 .get_kernel_page_table()
         );
 
-        inner.get_user_space_ref_or_else_panic().make_satp()
+        let user_space = inner.get_user_space_ref_or_else_panic();
+        (
+            user_space.make_satp(),
+            user_space.needs_flush_on_load(hartid),
+        )
     };
 
-    userret_on_trampoline(satp);
+    userret_on_trampoline(satp, flush);
 }
 
 #[inline]
-fn userret_on_trampoline(satp: usize) -> ! {
+fn userret_on_trampoline(satp: usize, flush: bool) -> ! {
     // we now turn the kernel interrupt off!!!
     arch::intr_off();
 
@@ -198,19 +309,38 @@ fn userret_on_trampoline(satp: usize) -> ! {
         addr
     };
 
-    let userret_virtual: extern "C" fn(usize) -> ! = unsafe { core::mem::transmute(addr) };
+    let userret_virtual: extern "C" fn(usize, usize) -> ! = unsafe { core::mem::transmute(addr) };
 
     // NOTE: we cannot directly call __userret(satp), here's the reason
     //  - the __userret is a linker symbol represents the physical position of the __userret function
     //  - the user-space does not have an idential mapping to the physical memory
     //  - the execution will fail
     // SO WE NEED TO USE THE UNIVERSALLY MAPPED SECTION ON THE TRAMPOLINE PAGE!
-    userret_virtual(satp);
+    userret_virtual(satp, flush as usize);
     // NOTE: when debugging, make sure to remove old breakpoints in the kernel space!
     // otherwise after the page table switch and memory fence, the debugger would not
     // be able to insert breakpoints in the kernel space!!!!
 }
 
+/// a deadline near the end of the counter should not be reached before its end,
+/// and elapsed ticks should count across a wrap
+pub fn test_sleep_deadline() {
+    assert_eq!(sleep_deadline(100, 20), 120);
+    let near_end = u64::MAX - 5;
+    let deadline = sleep_deadline(near_end, 10);
+    assert_eq!(deadline, u64::MAX);
+    // what `ProcessManager::take_expired` checks
+    let is_due = |now: u64| deadline <= now;
+    assert!(!is_due(near_end));
+    assert!(!is_due(u64::MAX - 1));
+    assert!(is_due(u64::MAX));
+
+    assert_eq!(elapsed_between(10, 25), 15);
+    assert_eq!(elapsed_between(u64::MAX - 1, 2), 4);
+    assert!(ticks_elapsed_since(ticks()) < TICKS_PER_SEC);
+}
+
 pub fn init_hart() {
     unsafe { stvec::write(__kernelvec as usize, stvec::TrapMode::Direct) };
+    watchdog::hart_started();
 }
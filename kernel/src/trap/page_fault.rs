@@ -1,8 +1,11 @@
+//! A user page fault is resolved if it can be, e.g. copy-on-write or a growing stack,
+//! otherwise the process is killed and exits on its way back to the user space
+
 use riscv::register::stval;
 
 use crate::{
-    cpu, info,
-    mm::{memory::VirtAddr, page_table::PageFlags},
+    cpu, error, info,
+    mm::{address_space::AccessMode, memory::VirtAddr},
 };
 
 pub struct InstructionPageFaultHandler {}
@@ -11,28 +14,90 @@ impl InstructionPageFaultHandler {
     pub fn handle() {
         let va = stval::read();
         info!("Instruction Page Fault: accessing {:?}", va as *const usize);
-        // let's check if this is mapped as executable
         let pcb = cpu::current_process().unwrap();
-        let inner = pcb.inner.read();
-        let user_space = inner.get_user_space_ref_or_else_panic();
-        match user_space.translate(VirtAddr::new(va)) {
-            Some((pa, flags)) => {
-                info!(
-                    "va: {:?} -> pa: {:?}, flags: {:?}",
-                    va as *const usize,
-                    pa.as_usize() as *const usize,
-                    flags
-                );
-                if !flags.contains(PageFlags::EXECUTABLE) {
-                    panic!("trap::usertrap: it's not even executable, what are you doing???");
-                }
-                if !flags.contains(PageFlags::USER) {
-                    panic!("trap::usertrap: did you rememeber to set the U-bit???");
-                }
-            }
-            None => {
-                panic!("NOT MAPPED?");
-            }
+        let mut inner = pcb.inner.write();
+        // the text may be loaded on demand
+        if inner
+            .write_user_space(|user_space| user_space.resolve_lazy_fault(VirtAddr::new(va)))
+            .is_some()
+        {
+            return;
+        }
+
+        // it faulted anyway if it is mapped executable, e.g. a stale TLB entry
+        let access = AccessMode::USER | AccessMode::EXECUTE;
+        let translated = inner
+            .get_user_space_ref_or_else_panic()
+            .translate_checked(VirtAddr::new(va), access);
+        if let Err(err) = translated {
+            error!(
+                "trap::usertrap: Instruction Page Fault: PID {:?} trying to execute {:?}: {:?}, killed",
+                pcb.get_pid(),
+                va as *const usize,
+                err
+            );
+            inner.killed = true;
+        }
+    }
+}
+
+pub struct LoadPageFaultHandler {}
+
+impl LoadPageFaultHandler {
+    pub fn handle() {
+        let va = stval::read();
+        info!("Load Page Fault: accessing {:?}", va as *const usize);
+        let pcb = cpu::current_process().unwrap();
+        let mut inner = pcb.inner.write();
+        let resolved = inner.write_user_space(|user_space| {
+            let va = VirtAddr::new(va);
+            user_space
+                .resolve_lazy_fault(va)
+                .or_else(|| user_space.grow_user_stack(va))
+        });
+        if resolved.is_none() {
+            let err = inner
+                .get_user_space_ref_or_else_panic()
+                .translate_checked(VirtAddr::new(va), AccessMode::USER | AccessMode::READ)
+                .err();
+            error!(
+                "trap::usertrap: Load Page Fault: PID {:?} trying to load {:?}: {:?}, killed",
+                pcb.get_pid(),
+                va as *const usize,
+                err
+            );
+            inner.killed = true;
+        }
+    }
+}
+
+pub struct StorePageFaultHandler {}
+
+impl StorePageFaultHandler {
+    pub fn handle() {
+        let va = stval::read();
+        info!("Store Page Fault: accessing {:?}", va as *const usize);
+        let pcb = cpu::current_process().unwrap();
+        let mut inner = pcb.inner.write();
+        // a write to a copy-on-write page, or a push beyond the user stack
+        let resolved = inner.write_user_space(|user_space| {
+            let va = VirtAddr::new(va);
+            user_space
+                .resolve_cow_fault(va)
+                .or_else(|| user_space.grow_user_stack(va))
+        });
+        if resolved.is_none() {
+            let err = inner
+                .get_user_space_ref_or_else_panic()
+                .translate_checked(VirtAddr::new(va), AccessMode::USER | AccessMode::WRITE)
+                .err();
+            error!(
+                "trap::usertrap: Store Page Fault: PID {:?} trying to store to {:?}: {:?}, killed",
+                pcb.get_pid(),
+                va as *const usize,
+                err
+            );
+            inner.killed = true;
         }
     }
 }
@@ -1,4 +1,33 @@
-use crate::{cpu, info};
+use core::mem::size_of;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+use crate::{
+    allocator::{frame_allocator, meminfo::MemInfo},
+    arch,
+    common::kernel_mutex::KernelMutexGuard,
+    cpu,
+    fs::{
+        file::{FileHandle, O_CREATE, O_RDWR, O_WRONLY},
+        filesystem::write_struct,
+        inode::{self, InodeType},
+        stat::Stat,
+    },
+    info,
+    mm::layout::PAGE_SIZE,
+    mm::{
+        address_space::AddrSpace,
+        memory::{PhysAddr, VirtAddr},
+    },
+    process::{
+        self, elf,
+        manager::{ProcessManager, INIT_PID, PROCESS_MANAGER},
+        process::{ProcessControlBlock, KERNEL_STACK_FRAMES},
+        programs,
+        times::Times,
+        Handoff,
+    },
+};
 use primitive_enum::primitive_enum;
 
 primitive_enum! {
@@ -24,23 +53,755 @@ Syscall usize;
     SysSbrk = 18,
     SysSleep = 19,
     SysUptime = 20,
+    SysYield = 21,
+    SysMeminfo = 22,
+    SysBrk = 23,
+    SysShutdown = 24,
+    SysTimes = 25,
+    SysGetcwd = 26,
+    SysSetpriority = 27,
+    SysGetppid = 28,
+    SysMapdevice = 29,
 }
 pub struct SystemCallHandler {}
 
 impl SystemCallHandler {
-    /// It requires inner read lock!
-
     pub fn handle() {
         let pcb = cpu::current_process().unwrap();
+
+        // scoped so that the inner lock is released before dispatching,
+        // handlers may need to lock it again or switch to another process
+        let call = {
+            let mut inner = pcb.inner.write();
+            inner.write_trap_context(|ctx| {
+                // we move the return address to the next instruction
+                // otherwies it's an infinite loop
+                ctx.incr_user_space_pc(4);
+            });
+
+            let ctx = inner.get_context_ref_or_else_panic();
+            ctx.get_syscall()
+        };
+        let Some(call) = call else {
+            info!("SYSCALL: unknown syscall number");
+            set_return_value(&pcb, None);
+            return;
+        };
+        info!("SYSCALL: {:?}", call);
+
+        match call {
+            Syscall::SysFork => Self::sys_fork(pcb),
+            Syscall::SysExit => Self::sys_exit(pcb),
+            Syscall::SysWait => Self::sys_wait(pcb),
+            Syscall::SysKill => Self::sys_kill(pcb),
+            Syscall::SysExec => Self::sys_exec(pcb),
+            Syscall::SysYield => Self::sys_yield(pcb),
+            Syscall::SysOpen => Self::sys_open(pcb),
+            Syscall::SysMkdir => Self::sys_mkdir(pcb),
+            Syscall::SysChdir => Self::sys_chdir(pcb),
+            Syscall::SysClose => Self::sys_close(pcb),
+            Syscall::SysRead => Self::sys_read(pcb),
+            Syscall::SysWrite => Self::sys_write(pcb),
+            Syscall::SysDup => Self::sys_dup(pcb),
+            Syscall::SysPipe => Self::sys_pipe(pcb),
+            Syscall::SysFstat => Self::sys_fstat(pcb),
+            Syscall::SysSbrk => Self::sys_sbrk(pcb),
+            Syscall::SysBrk => Self::sys_brk(pcb),
+            Syscall::SysSleep => Self::sys_sleep(pcb),
+            Syscall::SysUptime => Self::sys_uptime(pcb),
+            Syscall::SysMeminfo => Self::sys_meminfo(pcb),
+            Syscall::SysMknod => Self::sys_mknod(pcb),
+            Syscall::SysLink => Self::sys_link(pcb),
+            Syscall::SysUnlink => Self::sys_unlink(pcb),
+            Syscall::SysShutdown => Self::sys_shutdown(pcb),
+            Syscall::SysTimes => Self::sys_times(pcb),
+            Syscall::SysGetcwd => Self::sys_getcwd(pcb),
+            Syscall::SysSetpriority => Self::sys_setpriority(pcb),
+            Syscall::SysGetpid => Self::sys_getpid(pcb),
+            Syscall::SysGetppid => Self::sys_getppid(pcb),
+            Syscall::SysMapdevice => Self::sys_mapdevice(pcb),
+        }
+    }
+
+    /// create a child process sharing the address space copy-on-write.
+    /// The parent gets the child's pid and the child gets 0,
+    /// or -1 if there are too many processes or too few frames
+    fn sys_fork(pcb: Arc<ProcessControlBlock>) {
+        // fail early if its kernel stack and page tables cannot fit,
+        // `fork_from` still checks as other harts may take the frames in the meantime
+        let n_frames = pcb
+            .inner
+            .read()
+            .get_user_space_ref_or_else_panic()
+            .n_frames_to_fork();
+        if frame_allocator::n_free_frames() < n_frames + KERNEL_STACK_FRAMES {
+            set_return_value(&pcb, None);
+            return;
+        }
+        let Some(child) = PROCESS_MANAGER.lock().create_process() else {
+            set_return_value(&pcb, None);
+            return;
+        };
+        if child.fork_from(&pcb).is_none() {
+            PROCESS_MANAGER.lock().discard_process(child.get_pid());
+            set_return_value(&pcb, None);
+            return;
+        }
+
+        child.inner.write().parent = Some(Arc::downgrade(&pcb));
+        let mut inner = pcb.inner.write();
+        inner.children.push(child.clone());
+        inner.write_trap_context(|ctx| ctx.set_return_value(child.get_pid()));
+        drop(inner);
+
+        // it can be scheduled once it is fully initialised
+        cpu::push_ready(child);
+    }
+
+    /// a0: exit code. It does not return, the process stays a zombie until its parent waits for it
+    fn sys_exit(pcb: Arc<ProcessControlBlock>) {
+        let exit_code = pcb.inner.read().get_context_ref_or_else_panic().get_arg(0) as i32;
+        process::exit(&pcb, exit_code);
+        // do not hold on to the PCB, `schedule` never returns
+        drop(pcb);
+        process::schedule(Handoff::Leave);
+    }
+
+    /// a0: pid of the child to wait for, -1 for any child,
+    /// a1: where to store its exit code as an `i32`, 0 if it is not wanted.
+    /// Waits until the child exits, then reaps it and returns its pid.
+    /// Returns -1 if there is no such child
+    fn sys_wait(pcb: Arc<ProcessControlBlock>) {
+        // until it sleeps, so that an exiting child cannot wake it up before, see `process::sleep_on`
+        let manager = PROCESS_MANAGER.lock();
+        let (child, va) = {
+            let mut inner = pcb.inner.write();
+            let ctx = inner.get_context_ref_or_else_panic();
+            let (pid, va) = (ctx.get_arg(0) as isize, ctx.get_arg(1));
+            let is_target =
+                |child: &Arc<ProcessControlBlock>| pid == -1 || child.get_pid() == pid as usize;
+            if !inner.children.iter().any(is_target) {
+                drop(inner);
+                set_return_value(&pcb, None);
+                return;
+            }
+            let zombie = inner
+                .children
+                .iter()
+                .position(|child| is_target(child) && child.is_zombie());
+            match zombie {
+                Some(i) => (inner.children.swap_remove(i), va),
+                None => {
+                    drop(inner);
+                    let chan = pcb.wait_channel();
+                    Self::sleep_and_restart(pcb, manager, chan);
+                }
+            }
+        };
+        drop(manager);
+
+        let pid = child.get_pid();
+        let exit_code = child.inner.read().exit_code;
+        PROCESS_MANAGER.lock().reap_process(pid);
+        drop(child);
+
+        let stored = va == 0
+            || pcb
+                .inner
+                .write()
+                .write_user_space(|space| {
+                    space.copy_to_user(VirtAddr::new(va), &exit_code.to_le_bytes())
+                })
+                .is_ok();
+        set_return_value(&pcb, stored.then_some(pid));
+    }
+
+    /// a0: pid. The process exits the next time it is about to return to the user space.
+    /// Returns 0, or -1 if there is no such process
+    fn sys_kill(pcb: Arc<ProcessControlBlock>) {
+        let pid = pcb.inner.read().get_context_ref_or_else_panic().get_arg(0);
+        let ret = PROCESS_MANAGER.lock().kill_process(pid).map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    /// returns the pid of the calling process
+    fn sys_getpid(pcb: Arc<ProcessControlBlock>) {
+        set_return_value(&pcb, Some(pcb.get_pid()));
+    }
+
+    /// Returns the pid of the parent, `INIT_PID` for `init` and orphans,
+    /// which are handed over to it, see `ProcessManager::exit_process`
+    fn sys_getppid(pcb: Arc<ProcessControlBlock>) {
+        let ppid = pcb.inner.read().parent_pid();
+        set_return_value(&pcb, Some(ppid));
+    }
+
+    /// a0: pid, a1: priority, from `MIN_PRIORITY` to `MAX_PRIORITY`, the higher runs first,
+    /// others are clamped. Only for the caller itself or its children, unless it is `init`.
+    /// Returns the priority it gets, or -1 if there is no such process or it may not
+    fn sys_setpriority(pcb: Arc<ProcessControlBlock>) {
+        let (pid, priority) = {
+            let inner = pcb.inner.read();
+            let ctx = inner.get_context_ref_or_else_panic();
+            // a negative one is the lowest
+            (ctx.get_arg(0), (ctx.get_arg(1) as isize).max(0) as usize)
+        };
+        let ret = PROCESS_MANAGER
+            .lock()
+            .set_priority(pcb.get_pid(), pid, priority)
+            .map(usize::from);
+        set_return_value(&pcb, ret);
+    }
+
+    /// replace the programme of the calling process.
+    /// a0: path, a1: length of the path.
+    /// It does not return on success, otherwise -1 is returned
+    fn sys_exec(pcb: Arc<ProcessControlBlock>) {
+        if Self::exec(&pcb).is_none() {
+            set_return_value(&pcb, None);
+        }
+    }
+
+    /// a0, a1: the path, a2: the number of arguments, a3: their addresses,
+    /// a4: their lengths as `i32`. The program starts with `argc` in a0 and `argv`
+    /// in a1, see `AddrSpace::push_args`. Returns -1 to the caller if the program
+    /// cannot be found or loaded, e.g. the frames run out, or the arguments are too many or too long
+    fn exec(pcb: &ProcessControlBlock) -> Option<()> {
+        let name = fetch_user_path(pcb)?;
+        let args = fetch_user_args(pcb)?;
+        info!("SYSCALL: exec {:?} with {:?} arguments", name, args.len());
+
+        let elf = match elf::parse(programs::lookup(&name)?) {
+            Ok(elf) => elf,
+            Err(err) => {
+                info!("SYSCALL: exec {:?}: malformed binary: {:?}", name, err);
+                return None;
+            }
+        };
+        let (mut user_space, user_stack_va) = AddrSpace::make_user(&elf)?;
+        let args: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+        // `argv` is on top of the stack
+        let sp = user_space.push_args(user_stack_va, &args)?;
         let mut inner = pcb.inner.write();
+        inner.replace_user_space(
+            user_space,
+            VirtAddr::new(elf.entry),
+            user_stack_va,
+            pcb.get_kernel_stack_phys_addr(),
+        )?;
         inner.write_trap_context(|ctx| {
-            // we move the return address to the next instruction
-            // otherwies it's an infinite loop
-            ctx.incr_user_space_pc(4);
+            ctx.set_sp(sp.as_usize());
+            ctx.set_a0(args.len());
+            ctx.set_a1(sp.as_usize());
         });
+        Some(())
+    }
 
+    /// give up the CPU and let the scheduler pick the next process.
+    /// The user context is already saved in the trapframe, so when
+    /// it gets scheduled again it resumes right after its `ecall`
+    fn sys_yield(pcb: Arc<ProcessControlBlock>) {
+        // do not hold on to the PCB, `schedule` never returns
+        drop(pcb);
+        process::schedule(Handoff::Ready);
+    }
+
+    /// a0: signed increment of the program break.
+    /// Returns the old break, or -1 if the heap cannot be resized
+    fn sys_sbrk(pcb: Arc<ProcessControlBlock>) {
+        let ret = {
+            let mut inner = pcb.inner.write();
+            let increment = inner.get_context_ref_or_else_panic().get_arg(0) as isize;
+            let old_break = inner.program_break;
+            let new_break = old_break
+                .as_usize()
+                .checked_add_signed(increment)
+                .map(VirtAddr::new);
+            new_break.and_then(|new_break| {
+                inner.set_program_break(new_break)?;
+                Some(old_break.as_usize())
+            })
+        };
+        set_return_value(&pcb, ret);
+    }
+
+    /// a0: the new program break. Returns it, or -1 if it would go below the initial
+    /// break or into the user stack, or the frames run out
+    fn sys_brk(pcb: Arc<ProcessControlBlock>) {
+        let ret = {
+            let mut inner = pcb.inner.write();
+            let new_break = VirtAddr::new(inner.get_context_ref_or_else_panic().get_arg(0));
+            inner
+                .set_program_break(new_break)
+                .map(|_| new_break.as_usize())
+        };
+        set_return_value(&pcb, ret);
+    }
+
+    /// a0: exit code of qemu. Only `init` may power off the machine,
+    /// it does not return then. Returns -1 for other processes
+    fn sys_shutdown(pcb: Arc<ProcessControlBlock>) {
+        if pcb.get_pid() != INIT_PID {
+            set_return_value(&pcb, None);
+            return;
+        }
+        let exit_code = pcb.inner.read().get_context_ref_or_else_panic().get_arg(0);
+        drop(pcb);
+        arch::shutdown(exit_code as u32);
+    }
+
+    /// a0: number of ticks to sleep. Returns 0 once they have elapsed
+    fn sys_sleep(pcb: Arc<ProcessControlBlock>) {
+        let wakeup_tick = {
+            let mut inner = pcb.inner.write();
+            let n_ticks = inner.get_context_ref_or_else_panic().get_arg(0) as u64;
+            inner.write_trap_context(|ctx| ctx.set_return_value(0));
+            super::sleep_deadline(super::ticks(), n_ticks)
+        };
+        // do not hold on to the PCB, `sleep_until` never returns
+        drop(pcb);
+        process::sleep_until(wakeup_tick);
+    }
+
+    /// Returns the number of timer ticks since boot
+    fn sys_uptime(pcb: Arc<ProcessControlBlock>) {
+        set_return_value(&pcb, Some(super::ticks() as usize));
+    }
+
+    /// a0: physical base of the device registers, page aligned, a1: their length.
+    /// Only `init` may map them, and only the devices of `USER_DEVICES`,
+    /// see `AddrSpace::map_device`.
+    /// Returns the user address they are mapped at, or -1
+    fn sys_mapdevice(pcb: Arc<ProcessControlBlock>) {
+        if pcb.get_pid() != INIT_PID {
+            set_return_value(&pcb, None);
+            return;
+        }
+        let mut inner = pcb.inner.write();
         let ctx = inner.get_context_ref_or_else_panic();
-        let call = ctx.get_syscall().unwrap();
-        info!("SYSCALL: {:?}", call);
+        // anything from the user, `map_device` checks it
+        let (pa, len) = (PhysAddr::new_unchecked(ctx.get_arg(0)), ctx.get_arg(1));
+        let ret = inner
+            .write_user_space(|space| space.map_device(pa, len))
+            .map(|va| va.as_usize());
+        drop(inner);
+        set_return_value(&pcb, ret);
+    }
+
+    /// a0: `MemInfo` to be filled. Returns 0 or -1
+    fn sys_meminfo(pcb: Arc<ProcessControlBlock>) {
+        let ret = Self::meminfo(&pcb).map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    fn meminfo(pcb: &ProcessControlBlock) -> Option<()> {
+        let mut inner = pcb.inner.write();
+        let va = inner.get_context_ref_or_else_panic().get_arg(0);
+
+        let mut buf = [0; size_of::<MemInfo>()];
+        write_struct(&mut buf, 0, &MemInfo::collect());
+        inner
+            .write_user_space(|space| space.copy_to_user(VirtAddr::new(va), &buf))
+            .ok()
+    }
+
+    /// a0: `Times` to be filled with the CPU time of the caller so far. Returns 0 or -1
+    fn sys_times(pcb: Arc<ProcessControlBlock>) {
+        let ret = Self::times(&pcb).map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    fn times(pcb: &ProcessControlBlock) -> Option<()> {
+        let mut inner = pcb.inner.write();
+        let va = inner.get_context_ref_or_else_panic().get_arg(0);
+        let mut buf = [0; size_of::<Times>()];
+        write_struct(&mut buf, 0, &Times::of(&inner));
+        inner
+            .write_user_space(|space| space.copy_to_user(VirtAddr::new(va), &buf))
+            .ok()
+    }
+
+    /// a0: path, a1: length of the path, a2: flags.
+    /// Returns the lowest free file descriptor or -1
+    fn sys_open(pcb: Arc<ProcessControlBlock>) {
+        let fd = Self::open(&pcb);
+        set_return_value(&pcb, fd);
+    }
+
+    fn open(pcb: &ProcessControlBlock) -> Option<usize> {
+        let path = fetch_user_path(pcb)?;
+        let flags = pcb.inner.read().get_context_ref_or_else_panic().get_arg(2);
+        info!("SYSCALL: open {:?}", path);
+
+        let inode = match inode::namei(&path) {
+            Some(inode) => inode,
+            None if flags & O_CREATE != 0 => {
+                let (dir, name) = inode::namei_parent(&path)?;
+                dir.create(name, InodeType::File)?
+            }
+            None => return None,
+        };
+        // directories are only read
+        if inode.get_type() == Some(InodeType::Directory) && flags & (O_WRONLY | O_RDWR) != 0 {
+            return None;
+        }
+        let file = FileHandle::open(inode, flags)?;
+        pcb.inner.write().alloc_fd(file)
+    }
+
+    /// a0: path, a1: length of the path. Returns 0, or -1 if it exists or its parent does not
+    fn sys_mkdir(pcb: Arc<ProcessControlBlock>) {
+        let ret = fetch_user_path(&pcb)
+            .and_then(|path| inode::mkdir(&path))
+            .map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    /// a0: path, a1: length of the path, a2: major, a3: minor device number.
+    /// Returns 0, or -1 if the path exists already
+    fn sys_mknod(pcb: Arc<ProcessControlBlock>) {
+        let ret = Self::mknod(&pcb).map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    fn mknod(pcb: &ProcessControlBlock) -> Option<()> {
+        let path = fetch_user_path(pcb)?;
+        let (major, minor) = {
+            let inner = pcb.inner.read();
+            let ctx = inner.get_context_ref_or_else_panic();
+            (ctx.get_arg(2) as u16, ctx.get_arg(3) as u16)
+        };
+        inode::mknod(&path, major, minor)?;
+        Some(())
+    }
+
+    /// a0: existing path, a1: its length, a2: new path, a3: its length.
+    /// Returns 0, or -1 if the existing path is missing or a directory, or the new one exists
+    fn sys_link(pcb: Arc<ProcessControlBlock>) {
+        let ret = fetch_user_path_at(&pcb, 0)
+            .zip(fetch_user_path_at(&pcb, 2))
+            .and_then(|(old_path, new_path)| inode::link(&old_path, &new_path))
+            .map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    /// a0: path, a1: length of the path.
+    /// Returns 0, or -1 if it is missing or a directory that is not empty
+    fn sys_unlink(pcb: Arc<ProcessControlBlock>) {
+        let ret = fetch_user_path(&pcb)
+            .and_then(|path| inode::unlink(&path))
+            .map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    /// a0: path, a1: length of the path. Returns 0, or -1 if it is not a directory
+    fn sys_chdir(pcb: Arc<ProcessControlBlock>) {
+        let ret = Self::chdir(&pcb).map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    fn chdir(pcb: &ProcessControlBlock) -> Option<()> {
+        let path = fetch_user_path(pcb)?;
+        let inode = inode::namei(&path)?;
+        if inode.get_type() != Some(InodeType::Directory) {
+            return None;
+        }
+        pcb.inner.write().cwd = inode;
+        Some(())
+    }
+
+    /// a0: buffer, a1: its length. Fills it with the absolute path of the working directory
+    /// and returns the length of the path, or -1 if the buffer is too small
+    fn sys_getcwd(pcb: Arc<ProcessControlBlock>) {
+        let ret = Self::getcwd(&pcb);
+        set_return_value(&pcb, ret);
+    }
+
+    fn getcwd(pcb: &ProcessControlBlock) -> Option<usize> {
+        let (va, len, cwd) = {
+            let inner = pcb.inner.read();
+            let ctx = inner.get_context_ref_or_else_panic();
+            (ctx.get_arg(0), ctx.get_arg(1), inner.cwd.clone())
+        };
+        let path = inode::path_of(cwd)?;
+        if path.len() > len {
+            return None;
+        }
+        pcb.inner
+            .write()
+            .write_user_space(|space| space.copy_to_user(VirtAddr::new(va), path.as_bytes()))
+            .ok()?;
+        Some(path.len())
+    }
+
+    /// a0: fd. Returns 0, or -1 if fd is not open
+    fn sys_close(pcb: Arc<ProcessControlBlock>) {
+        let closed = {
+            let mut inner = pcb.inner.write();
+            let fd = inner.get_context_ref_or_else_panic().get_arg(0);
+            inner.close_fd(fd)
+        };
+        set_return_value(&pcb, closed.as_ref().map(|_| 0));
+        // with the PCB unlocked, see `PCBInner::close_fd`
+        drop(closed);
+    }
+
+    /// a0: fd. Returns a new file descriptor sharing the file and its offset, or -1
+    fn sys_dup(pcb: Arc<ProcessControlBlock>) {
+        let ret = {
+            let mut inner = pcb.inner.write();
+            let fd = inner.get_context_ref_or_else_panic().get_arg(0);
+            inner.dup_fd(fd)
+        };
+        set_return_value(&pcb, ret);
+    }
+
+    /// a0: array of two `i32`, filled with the read end and the write end.
+    /// Returns 0 or -1
+    fn sys_pipe(pcb: Arc<ProcessControlBlock>) {
+        let ret = Self::pipe(&pcb).map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    fn pipe(pcb: &ProcessControlBlock) -> Option<()> {
+        // declared before `inner`, so that an end not installed is dropped with the PCB
+        // unlocked, see `PCBInner::close_fd`
+        let (read_end, write_end) = FileHandle::pipe();
+        let mut closed = Vec::new();
+        let mut inner = pcb.inner.write();
+        let va = inner.get_context_ref_or_else_panic().get_arg(0);
+        if inner.n_free_fds() < 2 {
+            return None;
+        }
+        let read_fd = inner.alloc_fd(read_end).unwrap();
+        let write_fd = inner.alloc_fd(write_end).unwrap();
+
+        let mut fds = [0; 8];
+        fds[..4].copy_from_slice(&(read_fd as i32).to_le_bytes());
+        fds[4..].copy_from_slice(&(write_fd as i32).to_le_bytes());
+        if inner
+            .write_user_space(|space| space.copy_to_user(VirtAddr::new(va), &fds))
+            .is_err()
+        {
+            closed.extend(inner.close_fd(read_fd));
+            closed.extend(inner.close_fd(write_fd));
+            return None;
+        }
+        Some(())
+    }
+
+    /// a0: fd, a1: `Stat` to be filled. Returns 0 or -1, e.g. for the console
+    fn sys_fstat(pcb: Arc<ProcessControlBlock>) {
+        let ret = Self::fstat(&pcb).map(|_| 0);
+        set_return_value(&pcb, ret);
+    }
+
+    fn fstat(pcb: &ProcessControlBlock) -> Option<()> {
+        let mut inner = pcb.inner.write();
+        let ctx = inner.get_context_ref_or_else_panic();
+        let (fd, va) = (ctx.get_arg(0), ctx.get_arg(1));
+        let stat = inner.get_file(fd)?.stat()?;
+
+        let mut buf = [0; size_of::<Stat>()];
+        write_struct(&mut buf, 0, &stat);
+        inner
+            .write_user_space(|space| space.copy_to_user(VirtAddr::new(va), &buf))
+            .ok()
+    }
+
+    /// sleep on `chan`, the `ecall` is issued again once it is woken up.
+    /// `manager` should have been held while checking the condition, see `process::sleep_on`
+    fn sleep_and_restart(
+        pcb: Arc<ProcessControlBlock>,
+        manager: KernelMutexGuard<'_, ProcessManager>,
+        chan: usize,
+    ) -> ! {
+        pcb.inner
+            .write()
+            .write_trap_context(|ctx| ctx.decr_user_space_pc(4));
+        // do not hold on to the PCB, `sleep_on` never returns
+        drop(pcb);
+        process::sleep_on(manager, chan)
+    }
+
+    /// Sleep until reading or writing the file of fd a0 no longer `would_block`, see `sleep_and_restart`.
+    /// Returns `pcb` if it does not have to wait
+    fn wait_for_fd(
+        pcb: Arc<ProcessControlBlock>,
+        would_block: fn(&FileHandle) -> bool,
+    ) -> Arc<ProcessControlBlock> {
+        let manager = PROCESS_MANAGER.lock();
+        match Self::fd_would_block(&pcb, would_block) {
+            Some(chan) => Self::sleep_and_restart(pcb, manager, chan),
+            None => pcb,
+        }
+    }
+
+    /// the channel to sleep on if reading or writing the file of fd a0 has to wait
+    fn fd_would_block(
+        pcb: &ProcessControlBlock,
+        would_block: fn(&FileHandle) -> bool,
+    ) -> Option<usize> {
+        let inner = pcb.inner.read();
+        let fd = inner.get_context_ref_or_else_panic().get_arg(0);
+        let file = inner.get_file(fd)?;
+        would_block(&file).then(|| file.wait_channel())
+    }
+
+    /// a0: fd, a1: buffer, a2: size.
+    /// Returns the number of bytes read, 0 at the end of the file, or -1.
+    /// Reading an empty pipe waits until it is written or all its write ends are closed
+    fn sys_read(pcb: Arc<ProcessControlBlock>) {
+        let mut pcb = Self::wait_for_fd(pcb, FileHandle::read_would_block);
+        let n = Self::read(&pcb);
+        // another reader may have taken it in the meantime, e.g. the line of the console
+        if n == Some(0) {
+            pcb = Self::wait_for_fd(pcb, FileHandle::read_would_block);
+        }
+        set_return_value(&pcb, n);
+    }
+
+    fn read(pcb: &ProcessControlBlock) -> Option<usize> {
+        let (file, va, len) = {
+            let inner = pcb.inner.read();
+            let ctx = inner.get_context_ref_or_else_panic();
+            (
+                inner.get_file(ctx.get_arg(0))?,
+                ctx.get_arg(1),
+                ctx.get_arg(2),
+            )
+        };
+
+        // in chunks, so that a large buffer does not take up the kernel heap
+        let mut buf = vec![0; len.min(RW_CHUNK_SIZE)];
+        let mut total = 0;
+        while total < len {
+            let chunk_len = (len - total).min(RW_CHUNK_SIZE);
+            // no lock is held, reading the console may wait
+            let n = file.read(&mut buf[..chunk_len])?;
+            pcb.inner
+                .write()
+                .write_user_space(|space| space.copy_to_user(VirtAddr::new(va + total), &buf[..n]))
+                .ok()?;
+            total += n;
+            if n < chunk_len {
+                break;
+            }
+        }
+        Some(total)
+    }
+
+    /// a0: fd, a1: buffer, a2: size.
+    /// Returns the number of bytes written or -1.
+    /// Writing a full pipe waits until it is read, and fails once all its read ends are closed
+    fn sys_write(pcb: Arc<ProcessControlBlock>) {
+        let pcb = Self::wait_for_fd(pcb, FileHandle::write_would_block);
+        let n = Self::write(&pcb);
+        set_return_value(&pcb, n);
+    }
+
+    fn write(pcb: &ProcessControlBlock) -> Option<usize> {
+        let (file, va, len) = {
+            let inner = pcb.inner.read();
+            let ctx = inner.get_context_ref_or_else_panic();
+            (
+                inner.get_file(ctx.get_arg(0))?,
+                ctx.get_arg(1),
+                ctx.get_arg(2),
+            )
+        };
+
+        let mut buf = vec![0; len.min(RW_CHUNK_SIZE)];
+        let mut total = 0;
+        while total < len {
+            let chunk_len = (len - total).min(RW_CHUNK_SIZE);
+            pcb.inner
+                .write()
+                .write_user_space(|space| {
+                    space.copy_from_user(VirtAddr::new(va + total), &mut buf[..chunk_len])
+                })
+                .ok()?;
+            let n = file.write(&buf[..chunk_len])?;
+            total += n;
+            if n < chunk_len {
+                break;
+            }
+        }
+        Some(total)
+    }
+}
+
+const MAX_PATH: usize = 128;
+/// like `EXEC_MAX_ARGS` of the user library
+const EXEC_MAX_ARGS: usize = 10;
+const MAX_ARG: usize = 128;
+const RW_CHUNK_SIZE: usize = PAGE_SIZE;
+
+/// set the return value of the syscall, -1 for `None`
+fn set_return_value(pcb: &ProcessControlBlock, ret: Option<usize>) {
+    pcb.inner
+        .write()
+        .write_trap_context(|ctx| ctx.set_return_value(ret.unwrap_or(-1isize as usize)));
+}
+
+/// fetch the path passed in a0 (address) and a1 (length)
+fn fetch_user_path(pcb: &ProcessControlBlock) -> Option<String> {
+    fetch_user_path_at(pcb, 0)
+}
+
+/// Fetch the arguments of `exec`: their number in a2, their addresses in a3 and
+/// their lengths in a4. `None` if there are more than `EXEC_MAX_ARGS`, one is longer
+/// than `MAX_ARG`, or they cannot be read
+fn fetch_user_args(pcb: &ProcessControlBlock) -> Option<Vec<Vec<u8>>> {
+    let mut inner = pcb.inner.write();
+    let ctx = inner.get_context_ref_or_else_panic();
+    let (n_args, ptrs_va, lens_va) = (ctx.get_arg(2), ctx.get_arg(3), ctx.get_arg(4));
+    if n_args > EXEC_MAX_ARGS {
+        return None;
+    }
+    if n_args == 0 {
+        return Some(Vec::new());
+    }
+    inner.write_user_space(|user_space| {
+        let mut ptrs = vec![0; n_args * size_of::<usize>()];
+        let mut lens = vec![0; n_args * size_of::<i32>()];
+        user_space
+            .copy_from_user(VirtAddr::new(ptrs_va), &mut ptrs)
+            .ok()?;
+        user_space
+            .copy_from_user(VirtAddr::new(lens_va), &mut lens)
+            .ok()?;
+
+        let ptrs = ptrs
+            .chunks_exact(size_of::<usize>())
+            .map(|ptr| usize::from_ne_bytes(ptr.try_into().unwrap()));
+        let lens = lens
+            .chunks_exact(size_of::<i32>())
+            .map(|len| i32::from_ne_bytes(len.try_into().unwrap()));
+        ptrs.zip(lens)
+            .map(|(va, len)| {
+                let len = usize::try_from(len).ok().filter(|&len| len <= MAX_ARG)?;
+                let mut arg = vec![0; len];
+                user_space
+                    .copy_from_user(VirtAddr::new(va), &mut arg)
+                    .ok()?;
+                Some(arg)
+            })
+            .collect()
+    })
+}
+
+/// like `fetch_user_path`, but the path is in argument `n` and its length in `n + 1`
+fn fetch_user_path_at(pcb: &ProcessControlBlock, n: usize) -> Option<String> {
+    let mut inner = pcb.inner.write();
+    let ctx = inner.get_context_ref_or_else_panic();
+    let (va, len) = (ctx.get_arg(n), ctx.get_arg(n + 1));
+    if len > MAX_PATH {
+        return None;
     }
+    let mut bytes = vec![0; len];
+    inner
+        .write_user_space(|space| space.copy_from_user(VirtAddr::new(va), &mut bytes))
+        .ok()?;
+    String::from_utf8(bytes).ok()
 }
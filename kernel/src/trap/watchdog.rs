@@ -0,0 +1,151 @@
+//! A hart stuck in the kernel, e.g. deadlocked on a spin lock, runs with interrupts off
+//! and stops taking timer interrupts. Every hart bumps its heartbeat on each tick,
+//! hart 0 checks every `WATCHDOG_INTERVAL` ticks that the heartbeats of the other started
+//! harts have advanced since the last check, and panics with the last trap of a stuck one.
+//! A hart idle in `wfi` may legitimately miss ticks, it is skipped
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{arch, symbols::N_CPUS};
+
+use super::exception;
+
+/// about 5 seconds in qemu, see `SCHEDULER_INTERVAL`
+pub const WATCHDOG_INTERVAL: u64 = 50;
+
+/// NOTE: each field is only written by its own hart, except `checked` by the checking one
+struct HartState {
+    started: AtomicBool,
+    idle: AtomicBool,
+    heartbeat: AtomicUsize,
+    /// `heartbeat + 1` at the last check, 0 until the hart has been checked once
+    checked: AtomicUsize,
+    /// `sepc` and `scause` of the last trap taken
+    sepc: AtomicUsize,
+    scause: AtomicUsize,
+}
+
+// only to initialise the array below
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW_HART: HartState = HartState {
+    started: AtomicBool::new(false),
+    idle: AtomicBool::new(false),
+    heartbeat: AtomicUsize::new(0),
+    checked: AtomicUsize::new(0),
+    sepc: AtomicUsize::new(0),
+    scause: AtomicUsize::new(0),
+};
+
+struct Watchdog {
+    harts: [HartState; N_CPUS],
+}
+
+static WATCHDOG: Watchdog = Watchdog::new();
+
+/// what the watchdog knows about a hart that stopped ticking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StuckHart {
+    pub hartid: usize,
+    pub sepc: usize,
+    pub scause: usize,
+}
+
+impl Watchdog {
+    const fn new() -> Self {
+        Self {
+            harts: [NEW_HART; N_CPUS],
+        }
+    }
+
+    /// The first hart, other than `checker`, whose heartbeat has not advanced since the
+    /// last check. Harts that have not started, or are idle, are not stuck
+    fn find_stuck(&self, checker: usize) -> Option<StuckHart> {
+        let mut stuck = None;
+        for (hartid, hart) in self.harts.iter().enumerate() {
+            if hartid == checker || !hart.started.load(Ordering::Acquire) {
+                continue;
+            }
+            let beat = hart.heartbeat.load(Ordering::Relaxed) + 1;
+            let last = hart.checked.swap(beat, Ordering::Relaxed);
+            if last == beat && !hart.idle.load(Ordering::Relaxed) && stuck.is_none() {
+                stuck = Some(StuckHart {
+                    hartid,
+                    sepc: hart.sepc.load(Ordering::Relaxed),
+                    scause: hart.scause.load(Ordering::Relaxed),
+                });
+            }
+        }
+        stuck
+    }
+}
+
+/// the calling hart takes timer interrupts from now on, see `trap::init_hart`
+pub fn hart_started() {
+    WATCHDOG.harts[arch::hart_id()]
+        .started
+        .store(true, Ordering::Release);
+}
+
+/// remember the trap the calling hart is taking, to report it if the hart gets stuck
+pub fn record_trap(sepc: usize, scause: usize) {
+    let hart = &WATCHDOG.harts[arch::hart_id()];
+    hart.sepc.store(sepc, Ordering::Relaxed);
+    hart.scause.store(scause, Ordering::Relaxed);
+}
+
+/// the calling hart is about to wait in `wfi`, or has left it
+pub fn set_idle(idle: bool) {
+    WATCHDOG.harts[arch::hart_id()]
+        .idle
+        .store(idle, Ordering::Relaxed);
+}
+
+/// On every timer tick of every hart. Hart 0 checks the others every `WATCHDOG_INTERVAL`
+/// of its `ticks`, it panics if one is stuck
+pub fn heartbeat(ticks: u64) {
+    let hartid = arch::hart_id();
+    WATCHDOG.harts[hartid]
+        .heartbeat
+        .fetch_add(1, Ordering::Relaxed);
+    if hartid != 0 || ticks % WATCHDOG_INTERVAL != 0 {
+        return;
+    }
+    if let Some(stuck) = WATCHDOG.find_stuck(hartid) {
+        panic!(
+            "watchdog: hart-{:?} has not ticked for {:?} ticks, last trap at sepc {:?}: {:?}",
+            stuck.hartid,
+            WATCHDOG_INTERVAL,
+            stuck.sepc as *const usize,
+            exception::decode_scause(stuck.scause)
+        );
+    }
+}
+
+/// a started hart should only be reported once its heartbeat stops, and not while idle
+pub fn test_find_stuck() {
+    let watchdog = Watchdog::new();
+    let hart = &watchdog.harts[1];
+    assert_eq!(watchdog.find_stuck(0), None);
+
+    hart.started.store(true, Ordering::Release);
+    // not checked before
+    assert_eq!(watchdog.find_stuck(0), None);
+    hart.heartbeat.fetch_add(1, Ordering::Relaxed);
+    assert_eq!(watchdog.find_stuck(0), None);
+
+    hart.sepc.store(0x8000_1000, Ordering::Relaxed);
+    hart.scause.store(9, Ordering::Relaxed);
+    hart.idle.store(true, Ordering::Relaxed);
+    assert_eq!(watchdog.find_stuck(0), None);
+    hart.idle.store(false, Ordering::Relaxed);
+    assert_eq!(
+        watchdog.find_stuck(0),
+        Some(StuckHart {
+            hartid: 1,
+            sepc: 0x8000_1000,
+            scause: 9,
+        })
+    );
+    // the checker itself is skipped
+    assert_eq!(watchdog.find_stuck(1), None);
+}
@@ -1,8 +1,14 @@
+use alloc::vec::Vec;
 use core::fmt::Write;
 use lazy_static::lazy_static;
 
 use spin::Mutex;
 
+use crate::{
+    plic::{PLIC, URT0_IRQ},
+    process,
+};
+
 pub const UART_BASE_ADDR: usize = 0x1000_0000;
 
 // the UART control registers.
@@ -15,8 +21,215 @@ const IER: usize = 1; // interrupt enable register
 const FCR: usize = 2; // FIFO control register
 const LCR: usize = 3; // line control register
 const LSR: usize = 5; // line status register
+const LSR_RX_READY: u8 = 1 << 0; // input is waiting to be read from RHR
 const LSR_TX_IDLE: u8 = 1 << 5; // THR can accept another character to send
 
+// bytes received but not yet read, the oldest ones are dropped if nobody reads
+const INPUT_BUFFER_SIZE: usize = 128;
+
+/// Ring buffer of received bytes filled by the UART interrupt
+pub struct InputBuffer {
+    buf: [u8; INPUT_BUFFER_SIZE],
+    read_idx: usize,  // next byte to read
+    write_idx: usize, // next slot to write
+}
+
+impl InputBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; INPUT_BUFFER_SIZE],
+            read_idx: 0,
+            write_idx: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.read_idx == self.write_idx
+    }
+
+    fn is_full(&self) -> bool {
+        self.write_idx - self.read_idx == INPUT_BUFFER_SIZE
+    }
+
+    fn push(&mut self, c: u8) {
+        if self.is_full() {
+            self.read_idx += 1;
+        }
+        self.buf[self.write_idx % INPUT_BUFFER_SIZE] = c;
+        self.write_idx += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let c = self.buf[self.read_idx % INPUT_BUFFER_SIZE];
+        self.read_idx += 1;
+        Some(c)
+    }
+}
+
+// NOTE: it is separated from `UART` since the interrupt handler may run
+//  while `UART` is held for printing
+static INPUT_BUFFER: Mutex<InputBuffer> = Mutex::new(InputBuffer::new());
+
+// the longest line the console can edit, a longer one is handed out in pieces
+const CONSOLE_LINE_SIZE: usize = 128;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// Cooked console input: received bytes are echoed and edited
+/// until a line is complete, only then can it be read
+pub struct ConsoleState {
+    line: [u8; CONSOLE_LINE_SIZE],
+    len: usize,   // bytes in `line`
+    ready: usize, // bytes at the front of `line` that can be read
+}
+
+impl ConsoleState {
+    const fn new() -> Self {
+        Self {
+            line: [0; CONSOLE_LINE_SIZE],
+            len: 0,
+            ready: 0,
+        }
+    }
+
+    pub fn has_line(&self) -> bool {
+        self.ready > 0
+    }
+
+    /// Edit the line with a received byte, `echo` prints what the user should see.
+    /// `\r` is taken as `\n`, backspace erases the last byte not yet complete,
+    /// other control characters are dropped
+    pub fn feed(&mut self, c: u8, mut echo: impl FnMut(&[u8])) {
+        let c = if c == b'\r' { b'\n' } else { c };
+        match c {
+            BACKSPACE | DELETE => {
+                if self.len > self.ready {
+                    self.len -= 1;
+                    echo(b"\x08 \x08");
+                }
+            }
+            b'\n' | b' '..=b'~' => {
+                if self.len == CONSOLE_LINE_SIZE {
+                    return;
+                }
+                self.line[self.len] = c;
+                self.len += 1;
+                echo(&[c]);
+                // a full line is handed out, otherwise nobody could read it
+                if c == b'\n' || self.len == CONSOLE_LINE_SIZE {
+                    self.ready = self.len;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// take at most `buf.len()` bytes of the complete line, returns the number taken
+    pub fn take_line(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.ready);
+        buf[..n].copy_from_slice(&self.line[..n]);
+        self.line.copy_within(n..self.len, 0);
+        self.len -= n;
+        self.ready -= n;
+        n
+    }
+}
+
+static CONSOLE: Mutex<ConsoleState> = Mutex::new(ConsoleState::new());
+
+fn echo(bytes: &[u8]) {
+    write(bytes);
+}
+
+/// print `bytes` as they are, returns the number written
+pub fn write(bytes: &[u8]) -> usize {
+    let uart = UART.lock();
+    for &c in bytes {
+        uart.put(c);
+    }
+    bytes.len()
+}
+
+/// Whether there is no complete line of the console to read yet.
+/// The bytes received so far are edited into the line first, a reader should
+/// then sleep on `input_channel` until the UART interrupt brings more
+pub fn line_would_block() -> bool {
+    // do not hold `UART`, echoing takes it
+    let uart = Uart::new(UART_BASE_ADDR);
+    let mut console = CONSOLE.lock();
+    while !console.has_line() {
+        match uart.read_byte() {
+            Some(c) => console.feed(c, echo),
+            None => break,
+        }
+    }
+    !console.has_line()
+}
+
+/// Read at most `buf.len()` bytes of a complete line of the console, 0 if there is none,
+/// check `line_would_block` first. The rest of the line is left for the next read
+pub fn read_line(buf: &mut [u8]) -> usize {
+    if buf.is_empty() || line_would_block() {
+        return 0;
+    }
+    CONSOLE.lock().take_line(buf)
+}
+
+/// the channel console readers sleep on, see `line_would_block`
+pub fn input_channel() -> usize {
+    &INPUT_BUFFER as *const _ as usize
+}
+
+// the clock the divisor divides, 22.729 MHz
+const CLOCK_HZ: u32 = 22_729_000;
+
+/// Line settings programmed by `Uart::init`
+#[derive(Clone, Copy, Debug)]
+pub struct UartConfig {
+    /// signals per second
+    pub baud: u32,
+    /// bits of a word, 5 to 8
+    pub word_bits: u8,
+}
+
+impl Default for UartConfig {
+    /// 2400 baud and 8-bit words. QEMU ignores the divisor anyway
+    fn default() -> Self {
+        Self {
+            baud: 2400,
+            word_bits: 8,
+        }
+    }
+}
+
+impl UartConfig {
+    /// The formula given in the NS16500A specification:
+    /// divisor = ceil( (clock_hz) / (baud_sps x 16) )
+    pub fn divisor(&self) -> u16 {
+        let divisor = CLOCK_HZ.div_ceil(self.baud * 16);
+        assert!(
+            divisor <= u16::MAX as u32,
+            "UartConfig::divisor: baud {:?} too low",
+            self.baud
+        );
+        divisor as u16
+    }
+
+    /// word length bits 0 and 1 of LCR, 0b00 for 5 bits to 0b11 for 8 bits
+    fn word_length(&self) -> u8 {
+        assert!(
+            (5..=8).contains(&self.word_bits),
+            "UartConfig::word_length: invalid word length {:?}",
+            self.word_bits
+        );
+        self.word_bits - 5
+    }
+}
+
 pub struct Uart {
     base_addr: usize,
 }
@@ -28,7 +241,7 @@ impl Uart {
 
     pub fn get(&self) -> Option<u8> {
         let ptr = self.base_addr as *mut u8;
-        if unsafe { ptr.add(LCR).read_volatile() } & 1 == 0 {
+        if unsafe { ptr.add(LSR).read_volatile() } & LSR_RX_READY == 0 {
             // DR (Data ready) bit set to 0 -> no data
             None
         } else {
@@ -36,6 +249,11 @@ impl Uart {
             Some(unsafe { ptr.add(RHR).read_volatile() })
         }
     }
+    /// a received byte, if there is any
+    pub fn read_byte(&self) -> Option<u8> {
+        INPUT_BUFFER.lock().pop()
+    }
+
     pub fn put(&self, c: u8) {
         let ptr = self.base_addr as *mut u8;
         loop {
@@ -48,18 +266,13 @@ impl Uart {
         }
     }
 
-    pub fn init(&mut self) {
+    pub fn init(&mut self, config: UartConfig) {
         let ptr = self.base_addr as *mut u8;
         unsafe {
             // First, set the word length, which
             // are bits 0, and 1 of the line control register (LCR)
             // which is at base_address + 3
-            // We can easily write the value 3 here or 0b11, but I'm
-            // extending it so that it is clear we're setting two individual
-            // fields
-            //         Word 0     Word 1
-            //         ~~~~~~     ~~~~~~
-            let lcr = (1 << 0) | (1 << 1);
+            let lcr = config.word_length();
             ptr.add(LCR).write_volatile(lcr);
 
             // Now, enable the FIFO, which is bit index 0 of the FIFO
@@ -72,23 +285,16 @@ impl Uart {
             // 0 of the interrupt enable register (IER at offset 1).
             ptr.add(IER).write_volatile(1 << 0);
 
-            // If we cared about the divisor, the code below would set the divisor
-            // from a global clock rate of 22.729 MHz (22,729,000 cycles per second)
-            // to a signaling rate of 2400 (BAUD). We usually have much faster signalling
-            // rates nowadays, but this demonstrates what the divisor actually does.
-            // The formula given in the NS16500A specification for calculating the divisor
-            // is:
-            // divisor = ceil( (clock_hz) / (baud_sps x 16) )
-            // So, we substitute our values and get:
+            // The divisor divides the global clock rate of 22.729 MHz (22,729,000 cycles
+            // per second) into the signaling rate (BAUD), see `UartConfig::divisor`.
+            // e.g. for 2400 baud:
             // divisor = ceil( 22_729_000 / (2400 x 16) )
             // divisor = ceil( 22_729_000 / 38_400 )
             // divisor = ceil( 591.901 ) = 592
 
             // The divisor register is two bytes (16 bits), so we need to split the value
-            // 592 into two bytes. Typically, we would calculate this based on measuring
-            // the clock rate, but again, for our purposes [qemu], this doesn't really do
-            // anything.
-            let divisor: u16 = 592;
+            // into two bytes. For our purposes [qemu], this doesn't really do anything.
+            let divisor = config.divisor();
             let divisor_least: u8 = (divisor & 0xff) as u8;
             let divisor_most: u8 = (divisor >> 8) as u8;
 
@@ -107,7 +313,7 @@ impl Uart {
 
             // Now that we've written the divisor, we never have to touch this again. In
             // hardware, this will divide the global clock (22.729 MHz) into one suitable
-            // for 2,400 signals per second. So, to once again get access to the
+            // for `config.baud` signals per second. So, to once again get access to the
             // RBR/THR/IER registers, we need to close the DLAB bit by clearing it to 0.
             ptr.add(3).write_volatile(lcr);
         }
@@ -128,5 +334,55 @@ lazy_static! {
     pub static ref UART: Mutex<Uart> = Mutex::new(Uart::new(UART_BASE_ADDR));
 }
 pub fn init() {
-    UART.lock().init();
+    UART.lock().init(UartConfig::default());
+    unsafe { (*PLIC.get()).register_handler(URT0_IRQ, handle_interrupt) };
+}
+
+/// drain the received bytes into the input buffer and wake up the console readers,
+/// called on the UART interrupt
+pub fn handle_interrupt() {
+    // do not lock `UART`, we may have interrupted someone printing
+    let uart = Uart::new(UART_BASE_ADDR);
+    let mut input = INPUT_BUFFER.lock();
+    let mut received = false;
+    while let Some(c) = uart.get() {
+        input.push(c);
+        received = true;
+    }
+    drop(input);
+    if received {
+        process::wakeup(input_channel());
+    }
+}
+
+/// backspaces and carriage returns should be processed before a line is read
+pub fn test_console_line_editing() {
+    let mut console = ConsoleState::new();
+    let mut echoed = Vec::new();
+    for &c in b"\x7fab\x08c\r" {
+        console.feed(c, |bytes| echoed.extend_from_slice(bytes));
+    }
+    // erasing an empty line does nothing
+    assert_eq!(echoed, b"ab\x08 \x08c\n");
+
+    let mut buf = [0; 2];
+    assert_eq!(console.take_line(&mut buf), 2);
+    assert_eq!(&buf, b"ac");
+    // a complete line can no longer be erased
+    console.feed(DELETE, |_| panic!("nothing to erase"));
+    assert_eq!(console.take_line(&mut buf), 1);
+    assert_eq!(buf[0], b'\n');
+    assert!(!console.has_line());
+}
+
+/// the divisor should be rounded up, and the default should match what QEMU is given
+pub fn test_uart_divisor() {
+    assert_eq!(UartConfig::default().divisor(), 592);
+    let config = UartConfig {
+        baud: 115200,
+        word_bits: 8,
+    };
+    // ceil(22_729_000 / 1_843_200) = ceil(12.33)
+    assert_eq!(config.divisor(), 13);
+    assert_eq!(config.word_length(), 0b11);
 }
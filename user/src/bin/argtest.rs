@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use core::ffi::CStr;
+
+use user::println;
+use user::syscall::{exec, exit};
+
+const ARGS: [&str; 2] = ["hello", "world"];
+
+/// Started without arguments, it executes itself with `ARGS`,
+/// which the kernel passes as `argc` and `argv`
+/// # Safety
+/// `argv` holds `argc` strings and a null pointer, see `exec`
+#[no_mangle]
+pub unsafe extern "C" fn _start(argc: usize, argv: *const *const u8) -> ! {
+    if argc == 0 {
+        exec("/argtest", &ARGS);
+        println!("argtest: exec failed");
+        exit(-1);
+    }
+    assert_eq!(argc, ARGS.len());
+    let args = core::slice::from_raw_parts(argv, argc + 1);
+    for (i, expected) in ARGS.iter().enumerate() {
+        let arg = CStr::from_ptr(args[i] as *const _);
+        println!("argtest: argv[{}] = {:?}", i, arg);
+        assert_eq!(arg.to_bytes(), expected.as_bytes());
+    }
+    assert!(args[argc].is_null());
+    println!("argtest: passed");
+    exit(0);
+}
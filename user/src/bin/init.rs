@@ -7,12 +7,26 @@
 #![no_main]
 #![feature(format_args_nl)]
 
+use user::constant::O_RDWR;
 use user::println;
-use user::syscall::{fork, exec, open, dup};
+use user::syscall::{fork, exec, exit, open, dup, mknod, wait};
+
+/// user programs checking the kernel, run one after another at boot
+const SELF_TESTS: &[&str] = &[
+    "/sleeptest",
+    "/timetest",
+    "/ppidtest",
+    "/yieldtest",
+    "/argtest",
+    "/stackwritetest",
+];
 
 #[no_mangle]
 pub unsafe extern "C" fn _start() -> ! {
-    open("/console", 0);
+    if open("/console", O_RDWR) < 0 {
+        mknod("/console", 1, 0);
+        open("/console", O_RDWR);
+    }
     dup(0);
     dup(0);
     println!("ready to fork!");
@@ -20,7 +34,18 @@ pub unsafe extern "C" fn _start() -> ! {
     if p == 0 {
         println!("calling test1...");
         exec("/test1", &["test1", "test2"]);
+        exit(-1);
     } else {
+        for test in SELF_TESTS {
+            let pid = fork();
+            if pid == 0 {
+                exec(test, &[]);
+                exit(-1);
+            }
+            let mut exit_code = -1;
+            wait(pid, &mut exit_code);
+            println!("init: {} exited with {}", test, exit_code);
+        }
         loop {}
     }
 }
@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use user::println;
+use user::syscall::{exit, fork, getpid, getppid, wait};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let parent = getpid();
+    let pid = fork();
+    if pid == 0 {
+        // exit with whether it has found its parent
+        exit((getppid() == parent) as i32);
+    }
+    assert!(pid > 0);
+    let mut found = 0;
+    assert_eq!(wait(pid, &mut found), pid);
+    println!(
+        "ppidtest: parent {}, child {}, child saw its parent: {}",
+        parent,
+        pid,
+        found == 1
+    );
+    assert_eq!(found, 1);
+    println!("ppidtest: passed");
+    exit(0);
+}
@@ -0,0 +1,19 @@
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use user::println;
+use user::syscall::{exit, sleep, uptime};
+
+const N_TICKS: usize = 10;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let before = uptime();
+    sleep(N_TICKS);
+    let after = uptime();
+    println!("sleeptest: slept from tick {} to {}", before, after);
+    assert!(after - before >= N_TICKS);
+    println!("sleeptest: passed");
+    exit(0);
+}
@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use core::arch::asm;
+
+use user::println;
+use user::syscall::{exit, pipe, read, write};
+
+const PAGE_SIZE: usize = 4096;
+const LEN: usize = 64;
+
+/// `write` a buffer on a part of the stack it has not grown to yet,
+/// the kernel should grow the stack for it as if the process touched it
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let sp: usize;
+    unsafe { asm!("mv {}, sp", out(reg) sp) };
+    // a few pages below, well within how far the stack may grow
+    let va = (sp & !(PAGE_SIZE - 1)) - 4 * PAGE_SIZE;
+    let buf = unsafe { core::slice::from_raw_parts(va as *const u8, LEN) };
+
+    let mut fds = [0; 2];
+    assert_eq!(pipe(&mut fds), 0);
+    assert_eq!(write(fds[1], buf), LEN as i32);
+    let mut written = [1; LEN];
+    assert_eq!(read(fds[0], &mut written), LEN as i32);
+    // a fresh stack page is zero-filled
+    assert!(written.iter().all(|&byte| byte == 0));
+    println!("stackwritetest: passed");
+    exit(0);
+}
@@ -17,6 +17,7 @@ pub unsafe extern "C" fn _start() -> ! {
     if p == 0 {
         println!("forking test2...");
         exec("/test2", &["test1", "test2"]);
+        exit(-1);
     }
     println!("test1 running...");
     let fd = open("/test.txt", 0);
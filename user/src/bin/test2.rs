@@ -16,6 +16,7 @@ pub unsafe extern "C" fn _start() -> ! {
     if p == 0 {
         println!("forking test3...");
         exec("/test3", &["test1", "test2"]);
+        exit(-1);
     }
     println!("test2 running...");
     exit(0);
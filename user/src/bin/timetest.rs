@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use user::println;
+use user::syscall::{exit, times, uptime};
+use user::times::{Times, TICKS_PER_SEC};
+
+/// a tenth of a second
+const BUSY_TICKS: u64 = TICKS_PER_SEC / 10;
+const N_SYSCALLS: usize = 10_000;
+
+fn read_times() -> Times {
+    let mut t = Times::default();
+    assert_eq!(times(&mut t), 0);
+    t
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    // a busy loop, checking the time now and then, accrues user time
+    let before = read_times();
+    let mut x: u64 = 0;
+    while read_times().utime - before.utime < BUSY_TICKS {
+        for i in 0..10_000 {
+            x = x.wrapping_mul(31).wrapping_add(i);
+        }
+    }
+    let after = read_times();
+    println!(
+        "timetest: busy loop: utime {} -> {}, stime {} -> {} ({})",
+        before.utime, after.utime, before.stime, after.stime, x
+    );
+
+    // syscalls accrue kernel time
+    let before = after;
+    for _ in 0..N_SYSCALLS {
+        uptime();
+    }
+    let after = read_times();
+    println!(
+        "timetest: {} syscalls: stime {} -> {}",
+        N_SYSCALLS, before.stime, after.stime
+    );
+    assert!(after.stime > before.stime);
+    println!("timetest: passed");
+    exit(0);
+}
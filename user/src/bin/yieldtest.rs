@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use user::println;
+use user::syscall::{exit, fork, getpid, wait, yield_now};
+
+const ROUNDS: usize = 3;
+
+/// print a round, then let the other process run its own,
+/// on a single hart the two take turns
+fn take_turns(name: &str) {
+    for round in 0..ROUNDS {
+        println!("yieldtest: {} (PID {}) round {}", name, getpid(), round);
+        yield_now();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let pid = fork();
+    if pid == 0 {
+        take_turns("child");
+        exit(0);
+    }
+    assert!(pid > 0);
+    take_turns("parent");
+    let mut exit_code = -1;
+    assert_eq!(wait(pid, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    println!("yieldtest: passed");
+    exit(0);
+}
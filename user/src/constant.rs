@@ -8,3 +8,9 @@
 pub const STDIN: i32 = 0;
 pub const STDOUT: i32 = 1;
 pub const STDERR: i32 = 2;
+
+// flags of `open`
+pub const O_RDONLY: i32 = 0x000;
+pub const O_WRONLY: i32 = 0x001;
+pub const O_RDWR: i32 = 0x002;
+pub const O_CREATE: i32 = 0x200;
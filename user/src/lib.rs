@@ -11,6 +11,9 @@
 pub mod print;
 pub mod syscall;
 pub mod constant;
+pub mod stat;
+pub mod meminfo;
+pub mod times;
 mod syscall_internal;
 
 use core::panic::PanicInfo;
@@ -0,0 +1,14 @@
+//! Memory usage filled by `meminfo`, the same layout as `kernel/src/allocator/meminfo.rs`
+
+/// the layout version the kernel reports in `MemInfo::magic`
+pub const MEMINFO_MAGIC: u64 = 0x4d45_4d49_4e46_4f01;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemInfo {
+    pub magic: u64,
+    pub heap_used: u64,
+    pub heap_total: u64,
+    pub frames_used: u64,
+    pub frames_total: u64,
+}
@@ -0,0 +1,15 @@
+//! File metadata filled by `fstat`, the same layout as `kernel/src/fs/stat.rs`
+
+// types of a file
+pub const T_DIR: u16 = 1;
+pub const T_FILE: u16 = 2;
+pub const T_DEVICE: u16 = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stat {
+    pub ino: u32,
+    pub type_: u16,
+    pub nlink: u16,
+    pub size: u64,
+}
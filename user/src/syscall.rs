@@ -13,6 +13,9 @@
 //!
 //! Usage of syscalls is listed in their corresponding sub-page.
 
+use crate::meminfo::MemInfo;
+use crate::stat::Stat;
+use crate::times::Times;
 use crate::syscall_internal::*;
 use core::ptr::null;
 
@@ -53,14 +56,23 @@ pub const EXEC_MAX_ARGS: usize = 10;
 /// Replace current process image with the new one
 /// in the filesystem.
 ///
-/// This function will not return.
+/// The new program gets `args` as `argc` and `argv`, the first two arguments of its
+/// `_start`, each of them terminated by a 0. At most `EXEC_MAX_ARGS` of them,
+/// of up to 128 bytes each.
+///
+/// It does not return, unless it fails: it returns -1 if the program cannot be
+/// found or loaded, or the arguments are too many or too long.
 ///
 /// # Examples
 /// ```
-/// use user::syscall::exec;
+/// use user::syscall::{exec, exit};
 /// exec("/init", &[]);
+/// exit(-1);
 /// ```
-pub fn exec(path: &str, args: &[&str]) -> ! {
+pub fn exec(path: &str, args: &[&str]) -> i32 {
+    if args.len() > EXEC_MAX_ARGS {
+        return -1;
+    }
     let arg_cnt = args.len();
     let mut args_sz = [0; EXEC_MAX_ARGS];
     let mut args_ptr = [null(); EXEC_MAX_ARGS];
@@ -124,6 +136,82 @@ pub fn open(path: &str, mode: i32) -> i32 {
     }
 }
 
+/// Create the directory `path`, its parent has to exist.
+///
+/// Returns 0 on success. Negative value means error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::mkdir;
+/// mkdir("/dir");
+/// ```
+pub fn mkdir(path: &str) -> i32 {
+    unsafe { __mkdir(path.as_ptr(), path.len() as i32) }
+}
+
+/// Change the working directory to `path`, relative paths are resolved from it.
+///
+/// Returns 0 on success. Negative value means `path` is not a directory.
+///
+/// # Examples
+/// ```
+/// use user::syscall::chdir;
+/// chdir("/dir");
+/// ```
+pub fn chdir(path: &str) -> i32 {
+    unsafe { __chdir(path.as_ptr(), path.len() as i32) }
+}
+
+/// Make `new_path` another name of the file at `old_path`, directories cannot be linked.
+///
+/// Returns 0 on success. Negative value means `old_path` is missing or
+/// a directory, or `new_path` exists already.
+///
+/// # Examples
+/// ```
+/// use user::syscall::link;
+/// link("/file", "/other");
+/// ```
+pub fn link(old_path: &str, new_path: &str) -> i32 {
+    unsafe {
+        __link(
+            old_path.as_ptr(),
+            old_path.len() as i32,
+            new_path.as_ptr(),
+            new_path.len() as i32,
+        )
+    }
+}
+
+/// Remove the name `path`. The file is deleted with its last name,
+/// or when the last descriptor open on it is closed.
+///
+/// Returns 0 on success. Negative value means `path` is missing or
+/// a directory that is not empty.
+///
+/// # Examples
+/// ```
+/// use user::syscall::unlink;
+/// unlink("/file");
+/// ```
+pub fn unlink(path: &str) -> i32 {
+    unsafe { __unlink(path.as_ptr(), path.len() as i32) }
+}
+
+/// Create a device file at `path` for the device numbered `major` and `minor`,
+/// opening it reads and writes the device, e.g. major 1 is the console.
+///
+/// Returns 0 on success. Negative value means `path` exists already.
+///
+/// # Examples
+/// ```
+/// use user::syscall::mknod;
+/// mknod("/console", 1, 0);
+/// ```
+pub fn mknod(path: &str, major: i32, minor: i32) -> i32 {
+    unsafe { __mknod(path.as_ptr(), path.len() as i32, major, minor) }
+}
+
 /// Close a file with file descriptor `fd`.
 ///
 /// # Examples
@@ -149,6 +237,247 @@ pub fn dup(fd: i32) -> i32 {
     unsafe { __dup(fd) }
 }
 
-pub fn wait(pid: i32) -> i32 {
-    unsafe { __wait(pid) }
+/// Create a pipe, `fds[0]` is its read end and `fds[1]` its write end.
+///
+/// Returns 0 on success. Negative value means error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::pipe;
+/// let mut fds = [0; 2];
+/// pipe(&mut fds);
+/// ```
+pub fn pipe(fds: &mut [i32; 2]) -> i32 {
+    unsafe { __pipe(fds.as_mut_ptr()) }
+}
+
+/// Fill `stat` with the metadata of the file `fd` refers to.
+///
+/// Returns 0 on success. Negative value means error, e.g. `fd` is the console.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{fstat, open};
+/// use user::stat::Stat;
+/// use user::constant::O_RDONLY;
+/// let fd = open("/file", O_RDONLY);
+/// let mut stat = Stat::default();
+/// fstat(fd, &mut stat);
+/// ```
+pub fn fstat(fd: i32, stat: &mut Stat) -> i32 {
+    unsafe { __fstat(fd, stat) }
+}
+
+/// Move the end of the heap by `increment` bytes.
+///
+/// Returns the old end, which is the start of the new memory when growing.
+/// Negative value means error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::sbrk;
+/// let page = sbrk(4096);
+/// ```
+pub fn sbrk(increment: isize) -> isize {
+    unsafe { __sbrk(increment) }
+}
+
+/// Move the end of the heap to `addr`, growing or shrinking it.
+///
+/// Returns the new end. Negative value means `addr` is below the start
+/// of the heap or too close to the stack.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{brk, sbrk};
+/// let end = sbrk(0) as usize;
+/// brk(end + 4096);
+/// ```
+pub fn brk(addr: usize) -> isize {
+    unsafe { __brk(addr) }
+}
+
+/// Block the calling process for `ticks` timer interrupts.
+///
+/// # Examples
+/// ```
+/// use user::syscall::sleep;
+/// sleep(10);
+/// ```
+pub fn sleep(ticks: usize) -> i32 {
+    unsafe { __sleep(ticks) }
+}
+
+/// Number of timer ticks since boot, the same ticks `sleep` counts.
+///
+/// # Examples
+/// ```
+/// use user::syscall::uptime;
+/// let now = uptime();
+/// ```
+pub fn uptime() -> usize {
+    unsafe { __uptime() }
+}
+
+/// Wait for the child `pid` to exit, or any child if `pid` is -1.
+///
+/// Its exit code is stored in `exit_code`.
+/// Returns the pid of the child. Negative value means there is no such child.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{exit, fork, wait};
+/// let pid = fork();
+/// if pid == 0 {
+///     exit(1);
+/// }
+/// let mut exit_code = 0;
+/// wait(pid, &mut exit_code);
+/// ```
+pub fn wait(pid: i32, exit_code: &mut i32) -> i32 {
+    unsafe { __wait(pid, exit_code) }
+}
+
+/// Terminate the process `pid` the next time it enters the kernel.
+///
+/// Returns 0 on success. Negative value means there is no such process.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{fork, kill};
+/// let pid = fork();
+/// if pid != 0 {
+///     kill(pid);
+/// }
+/// ```
+pub fn kill(pid: i32) -> i32 {
+    unsafe { __kill(pid) }
+}
+
+/// Give up the CPU so that other processes can run.
+///
+/// The calling process is put back to the ready queue and
+/// resumes when it gets scheduled again.
+///
+/// # Examples
+/// ```
+/// use user::syscall::yield_now;
+/// yield_now();
+/// ```
+pub fn yield_now() -> i32 {
+    unsafe { __yield() }
+}
+
+/// Fill `info` with the memory usage of the kernel heap and of the physical frames.
+///
+/// Returns 0 on success. Check `info.magic` against `MEMINFO_MAGIC`
+/// before trusting the other fields.
+///
+/// # Examples
+/// ```
+/// use user::syscall::meminfo;
+/// use user::meminfo::MemInfo;
+/// let mut info = MemInfo::default();
+/// meminfo(&mut info);
+/// ```
+pub fn meminfo(info: &mut MemInfo) -> i32 {
+    unsafe { __meminfo(info) }
+}
+
+/// Power off the machine, qemu exits with `exit_code`.
+///
+/// Only `init` may do it, it does not return then.
+/// Negative value means the caller is not `init`.
+///
+/// # Examples
+/// ```
+/// use user::syscall::shutdown;
+/// shutdown(0);
+/// ```
+pub fn shutdown(exit_code: i32) -> i32 {
+    unsafe { __shutdown(exit_code) }
+}
+
+/// Fill `times` with the CPU time of the calling process so far.
+///
+/// Returns 0 on success.
+///
+/// # Examples
+/// ```
+/// use user::syscall::times;
+/// use user::times::Times;
+/// let mut t = Times::default();
+/// times(&mut t);
+/// ```
+pub fn times(times: &mut Times) -> i32 {
+    unsafe { __times(times) }
+}
+
+/// Fill `buf` with the absolute path of the working directory, without a trailing nul.
+///
+/// Returns the length of the path. Negative value means `buf` is too small.
+///
+/// # Examples
+/// ```
+/// use user::syscall::getcwd;
+/// let mut buf = [0u8; 128];
+/// let len = getcwd(&mut buf);
+/// ```
+pub fn getcwd(buf: &mut [u8]) -> i32 {
+    unsafe { __getcwd(buf.as_mut_ptr(), buf.len() as i32) }
+}
+
+/// Set the scheduling priority of process `pid`, from 0 to 7, the higher runs first.
+/// Only the calling process itself or its children, unless it is `init`.
+///
+/// Returns the priority it gets, others are clamped.
+/// Negative value means there is no such process, or it is not the caller's to set.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{fork, setpriority};
+/// let pid = fork();
+/// if pid > 0 {
+///     setpriority(pid, 0);
+/// }
+/// ```
+pub fn setpriority(pid: i32, priority: i32) -> i32 {
+    unsafe { __setpriority(pid, priority) }
+}
+
+/// Pid of the calling process.
+///
+/// # Examples
+/// ```
+/// use user::syscall::getpid;
+/// let pid = getpid();
+/// ```
+pub fn getpid() -> i32 {
+    unsafe { __getpid() }
+}
+
+/// Pid of the parent of the calling process, that of `init` if the parent has exited.
+///
+/// # Examples
+/// ```
+/// use user::syscall::getppid;
+/// let ppid = getppid();
+/// ```
+pub fn getppid() -> i32 {
+    unsafe { __getppid() }
+}
+
+/// Map `len` bytes of device registers at physical address `phys_base` into the calling process,
+/// readable and writable. Only `init` may call it, and only a few devices are allowed,
+/// e.g. qemu's test device at `0x100000`.
+///
+/// Returns the address they are mapped at. Negative value means the caller or the device is not allowed.
+///
+/// # Examples
+/// ```
+/// use user::syscall::mapdevice;
+/// let regs = mapdevice(0x10_0000, 4096);
+/// ```
+pub fn mapdevice(phys_base: usize, len: usize) -> isize {
+    unsafe { __mapdevice(phys_base, len) }
 }
@@ -6,6 +6,10 @@
 //! this module will finally trap into kernel.
 
 use core::arch::global_asm;
+
+use crate::meminfo::MemInfo;
+use crate::stat::Stat;
+use crate::times::Times;
 global_asm!(include_str!("usys.S"));
 
 extern "C" {
@@ -13,9 +17,30 @@ extern "C" {
     pub fn __read(fd: i32, content: *mut u8, sz: i32) -> i32;
     pub fn __exit(code: i32) -> !;
     pub fn __fork() -> i32;
-    pub fn __exec(path: *const u8, path_sz: i32, arg_cnt: i32, args: *const *const u8, args_sz: *const i32) -> !;
+    pub fn __exec(path: *const u8, path_sz: i32, arg_cnt: i32, args: *const *const u8, args_sz: *const i32) -> i32;
     pub fn __open(path: *const u8, sz: i32, mode: i32) -> i32;
     pub fn __close(fd: i32) -> i32;
+    pub fn __mkdir(path: *const u8, sz: i32) -> i32;
+    pub fn __chdir(path: *const u8, sz: i32) -> i32;
+    pub fn __link(old_path: *const u8, old_sz: i32, new_path: *const u8, new_sz: i32) -> i32;
+    pub fn __unlink(path: *const u8, sz: i32) -> i32;
+    pub fn __mknod(path: *const u8, sz: i32, major: i32, minor: i32) -> i32;
     pub fn __dup(fd: i32) -> i32;
-    pub fn __wait(pid: i32) -> i32;
+    pub fn __pipe(fds: *mut i32) -> i32;
+    pub fn __fstat(fd: i32, stat: *mut Stat) -> i32;
+    pub fn __sbrk(increment: isize) -> isize;
+    pub fn __brk(addr: usize) -> isize;
+    pub fn __sleep(ticks: usize) -> i32;
+    pub fn __uptime() -> usize;
+    pub fn __wait(pid: i32, exit_code: *mut i32) -> i32;
+    pub fn __kill(pid: i32) -> i32;
+    pub fn __yield() -> i32;
+    pub fn __meminfo(info: *mut MemInfo) -> i32;
+    pub fn __shutdown(exit_code: i32) -> i32;
+    pub fn __times(times: *mut Times) -> i32;
+    pub fn __getcwd(buf: *mut u8, sz: i32) -> i32;
+    pub fn __setpriority(pid: i32, priority: i32) -> i32;
+    pub fn __getpid() -> i32;
+    pub fn __getppid() -> i32;
+    pub fn __mapdevice(phys_base: usize, len: usize) -> isize;
 }
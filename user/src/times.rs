@@ -0,0 +1,14 @@
+//! CPU time filled by `times`, the same layout as `kernel/src/process/times.rs`
+
+/// `mtime` ticks per second of the QEMU virt machine
+pub const TICKS_PER_SEC: u64 = 10_000_000;
+
+/// both in `mtime` ticks, `TICKS_PER_SEC` per second
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Times {
+    /// spent in the user space
+    pub utime: u64,
+    /// spent in the kernel on behalf of the process
+    pub stime: u64,
+}
@@ -19,5 +19,14 @@ syscalls = [
     "getpid",
     "sbrk",
     "sleep",
-    "uptime"
+    "uptime",
+    "yield",
+    "meminfo",
+    "brk",
+    "shutdown",
+    "times",
+    "getcwd",
+    "setpriority",
+    "getppid",
+    "mapdevice"
 ]